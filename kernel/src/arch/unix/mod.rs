@@ -5,7 +5,6 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use arrayvec::ArrayVec;
 use ctor::ctor;
 use log::{debug, info};
 use node_replication::{Log, Replica};
@@ -22,6 +21,7 @@ pub mod kcb;
 pub mod memory;
 pub mod process;
 pub mod timer;
+pub mod tlb;
 pub mod vspace;
 
 pub use bootloader_shared::*;
@@ -79,9 +79,7 @@ fn init_setup() {
     let frame = mm
         .allocate_frame(2 * HUGE_PAGE_SIZE)
         .expect("We don't have vRAM available");
-    let mut annotated_regions = ArrayVec::new();
-    annotated_regions.push(frame);
-    let global_memory = unsafe { Box::new(GlobalMemory::new(annotated_regions).unwrap()) };
+    let global_memory = unsafe { Box::new(GlobalMemory::new(&[frame]).unwrap()) };
     let global_memory_static: &'static GlobalMemory = Box::leak(global_memory);
 
     // Construct the Kcb so we can access these things later on in the code
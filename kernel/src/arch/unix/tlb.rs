@@ -0,0 +1,15 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::memory::vspace::TlbFlusher;
+use crate::memory::VAddr;
+
+/// There is no TLB to manage when running as a process, so flushing does
+/// nothing.
+pub struct LocalTlb;
+
+impl TlbFlusher for LocalTlb {
+    fn flush_page(&mut self, _vaddr: VAddr) {}
+
+    fn flush_all(&mut self) {}
+}
@@ -4,18 +4,24 @@
 //! A dummy vspace implementation for the unix platform.
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt;
+use core::ops::Range;
 use core::pin::Pin;
+use fallible_collections::vec::FallibleVec;
 use hashbrown::HashMap;
 
 use crate::error::KError;
-use crate::memory::vspace::{AddressSpace, MapAction, MappingInfo, TlbFlushHandle};
+use crate::memory::vspace::{
+    checked_vrange, AddressSpace, MapAction, MappingInfo, SharedFrame, TlbFlushHandle,
+    UnmapRangeError, UnmappedRange,
+};
 use crate::memory::Frame;
 
 use x86::bits64::paging::*;
 
 pub struct VSpace {
-    pub mappings: HashMap<core::ops::Range<usize>, MappingInfo>,
+    pub mappings: HashMap<Range<usize>, MappingInfo>,
     pub pml4: Pin<Box<PML4>>,
 }
 
@@ -50,6 +56,14 @@ impl VSpace {
     ) -> Result<(), KError> {
         Ok(())
     }
+
+    /// Returns an iterator over all mappings in the address space (in no
+    /// particular order).
+    pub fn mappings(&self) -> impl Iterator<Item = (Range<usize>, &MappingInfo)> + '_ {
+        self.mappings
+            .iter()
+            .map(|(range, mapping)| (range.clone(), mapping))
+    }
 }
 
 impl AddressSpace for VSpace {
@@ -59,6 +73,18 @@ impl AddressSpace for VSpace {
         unimplemented!("map_frame");
     }
 
+    fn map_frame_shared(
+        &mut self,
+        base: VAddr,
+        shared: &SharedFrame,
+        action: MapAction,
+    ) -> Result<(), KError> {
+        self.mappings.try_reserve(1)?;
+        let ma = MappingInfo::new_shared(shared, action);
+        self.mappings.insert(ma.vrange(base), ma);
+        Ok(())
+    }
+
     fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
         unimplemented!("map_memory_requirements");
     }
@@ -71,9 +97,46 @@ impl AddressSpace for VSpace {
         unimplemented!("resolve");
     }
 
+    fn is_mapped(&self, vaddr: VAddr) -> bool {
+        self.mappings
+            .keys()
+            .any(|range| range.contains(&vaddr.as_usize()))
+    }
+
     fn unmap(&mut self, _vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
         unimplemented!("unmap");
     }
+
+    fn unmap_range(&mut self, base: VAddr, size: usize) -> Result<UnmappedRange, UnmapRangeError> {
+        let vrange = checked_vrange(base, size)?;
+        let overlaps = |range: &Range<usize>| range.start < vrange.end && vrange.start < range.end;
+
+        // Reserve upfront, nothing can fail once we start removing mappings
+        let mut frames: Vec<Frame> = Vec::new();
+        frames
+            .try_reserve(self.mappings.keys().filter(|range| overlaps(range)).count())
+            .map_err(KError::from)?;
+
+        let mut handle: Option<TlbFlushHandle> = None;
+        self.mappings.retain(|range, mapping| {
+            if !overlaps(range) {
+                return true;
+            }
+
+            let unmapped = TlbFlushHandle::new(VAddr::from(range.start), mapping.frame);
+            frames.push(mapping.frame);
+            if let Some(handle) = handle.as_mut() {
+                handle.merge(&unmapped);
+            } else {
+                handle = Some(unmapped);
+            }
+            false
+        });
+
+        handle
+            .map(|handle| UnmappedRange { handle, frames })
+            .ok_or_else(|| KError::NotMapped.into())
+    }
 }
 
 impl Drop for VSpace {
@@ -81,3 +144,151 @@ impl Drop for VSpace {
         panic!("Drop for VSpace!");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+    use alloc::vec::Vec;
+
+    /// `is_mapped` finds addresses inside a mapping and nothing else.
+    #[test]
+    fn is_mapped() {
+        let mut vspace = VSpace::new();
+        let frame = Frame::new(PAddr::from(0x20_0000u64), BASE_PAGE_SIZE, 0);
+        let mapping = MappingInfo::new(frame, MapAction::ReadUser);
+        vspace
+            .mappings
+            .insert(mapping.vrange(VAddr::from(0x1000u64)), mapping);
+
+        assert!(vspace.is_mapped(VAddr::from(0x1000u64)));
+        assert!(vspace.is_mapped(VAddr::from(0x1fffu64)));
+        assert!(!vspace.is_mapped(VAddr::from(0x0u64)));
+        assert!(!vspace.is_mapped(VAddr::from(0x2000u64)));
+
+        // Drop for VSpace panics
+        core::mem::forget(vspace);
+    }
+
+    /// `mappings` yields every mapping in the address space.
+    #[test]
+    fn mappings() {
+        let mut vspace = VSpace::new();
+        let frames = [
+            (0x1000u64, Frame::new(PAddr::from(0x20_0000u64), BASE_PAGE_SIZE, 0)),
+            (0x5000u64, Frame::new(PAddr::from(0x30_0000u64), 2 * BASE_PAGE_SIZE, 0)),
+            (0x20_0000u64, Frame::new(PAddr::from(0x40_0000u64), LARGE_PAGE_SIZE, 0)),
+        ];
+        for (base, frame) in frames.iter() {
+            let mapping = MappingInfo::new(*frame, MapAction::ReadUser);
+            vspace
+                .mappings
+                .insert(mapping.vrange(VAddr::from(*base)), mapping);
+        }
+
+        let mut mapped: Vec<(Range<usize>, Frame)> = vspace
+            .mappings()
+            .map(|(range, mapping)| (range, mapping.frame))
+            .collect();
+        mapped.sort_by_key(|(range, _frame)| range.start);
+        assert_eq!(
+            mapped,
+            [
+                (0x1000..0x2000, frames[0].1),
+                (0x5000..0x7000, frames[1].1),
+                (0x20_0000..0x40_0000, frames[2].1),
+            ]
+        );
+
+        // Drop for VSpace panics
+        core::mem::forget(vspace);
+    }
+
+    /// A shared frame can be mapped into several address spaces and is only
+    /// free again once every mapping of it is gone.
+    #[test]
+    fn map_frame_shared() {
+        let frame = Frame::new(PAddr::from(0x20_0000u64), BASE_PAGE_SIZE, 0);
+        let shared = SharedFrame::new(frame).expect("Can't share frame");
+        let mut a = VSpace::new();
+        let mut b = VSpace::new();
+
+        a.map_frame_shared(VAddr::from(0x1000u64), &shared, MapAction::ReadUser)
+            .expect("Can't map shared frame");
+        b.map_frame_shared(VAddr::from(0x5000u64), &shared, MapAction::ReadWriteUser)
+            .expect("Can't map shared frame");
+        assert_eq!(shared.mapping_count(), 2);
+        assert!(a.is_mapped(VAddr::from(0x1000u64)));
+        assert!(!a.is_mapped(VAddr::from(0x5000u64)));
+        assert!(b.is_mapped(VAddr::from(0x5000u64)));
+
+        let (range, mapping) = a.mappings().next().expect("Frame is mapped");
+        assert_eq!(range, 0x1000..0x2000);
+        assert_eq!(mapping.frame, frame);
+        assert_eq!(mapping.rights, MapAction::ReadUser);
+        assert!(mapping.shared.is_some());
+
+        let shared = match shared.declassify() {
+            Ok(_frame) => panic!("Frame is still mapped"),
+            Err(shared) => shared,
+        };
+        a.unmap_range(VAddr::from(0x1000u64), BASE_PAGE_SIZE).expect("Can't unmap");
+        assert_eq!(shared.mapping_count(), 1);
+        b.unmap_range(VAddr::from(0x5000u64), BASE_PAGE_SIZE).expect("Can't unmap");
+        assert_eq!(shared.declassify().expect("Frame isn't mapped"), frame);
+
+        // Drop for VSpace panics
+        core::mem::forget(a);
+        core::mem::forget(b);
+    }
+
+    /// `unmap_range` removes every mapping in the range and returns one
+    /// handle for all of them.
+    #[test]
+    fn unmap_range() {
+        let mut vspace = VSpace::new();
+        for page in 1..5usize {
+            let pbase = PAddr::from(0x20_0000u64) + page * BASE_PAGE_SIZE;
+            let frame = Frame::new(pbase, BASE_PAGE_SIZE, 0);
+            let mapping = MappingInfo::new(frame, MapAction::ReadUser);
+            vspace
+                .mappings
+                .insert(mapping.vrange(VAddr::from(page * BASE_PAGE_SIZE)), mapping);
+        }
+
+        let mut unmapped = vspace
+            .unmap_range(VAddr::from(0x1000u64), 3 * BASE_PAGE_SIZE)
+            .expect("Can't unmap range");
+        assert_eq!(unmapped.handle.vaddr, VAddr::from(0x1000u64));
+        assert_eq!(unmapped.handle.vrange(), 0x1000..0x4000);
+        unmapped.frames.sort_by_key(|frame| frame.base);
+        let expected: Vec<Frame> = (1..4usize)
+            .map(|page| {
+                let pbase = PAddr::from(0x20_0000u64) + page * BASE_PAGE_SIZE;
+                Frame::new(pbase, BASE_PAGE_SIZE, 0)
+            })
+            .collect();
+        assert_eq!(unmapped.frames, expected);
+        for vaddr in &[0x1000u64, 0x2000, 0x3000] {
+            assert!(!vspace.is_mapped(VAddr::from(*vaddr)));
+        }
+        assert!(vspace.is_mapped(VAddr::from(0x4000u64)));
+
+        assert_eq!(
+            vspace
+                .unmap_range(VAddr::from(0x1000u64), 3 * BASE_PAGE_SIZE)
+                .map_err(|e| e.error),
+            Err(KError::NotMapped)
+        );
+        assert_eq!(
+            vspace
+                .unmap_range(VAddr::from(0x4000u64), usize::MAX)
+                .map_err(|e| e.error),
+            Err(KError::BaseOverflow { base: 0x4000 })
+        );
+        assert!(vspace.is_mapped(VAddr::from(0x4000u64)));
+
+        // Drop for VSpace panics
+        core::mem::forget(vspace);
+    }
+}
@@ -334,6 +334,14 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
                 r.resume()
             }
             Err(_) => {
+                // Not mapped yet, could be part of a lazily loaded ELF segment
+                if nrproc::NrProcess::<Ring3Process>::handle_page_fault(pid, faulting_address_va)
+                    .is_ok()
+                {
+                    trace!("Loaded page {} of pid {} on demand", faulting_address_va, pid);
+                    let r = kcb_iret_handle(kcb);
+                    r.resume()
+                }
                 // unresolved page-fault, proceed with abort below
             }
         }
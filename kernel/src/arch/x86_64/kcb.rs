@@ -227,7 +227,7 @@ impl Arch86Kcb {
     /// Initialized the dummy file-system to measure the write() system call overhead.
     pub fn init_cnrfs(&mut self) {
         self.cnrfs = Some(Default::default());
-        let _result = self.cnrfs.as_ref().unwrap().create("nrk", 0x007);
+        let _result = self.cnrfs.as_ref().unwrap().create_file("nrk", 0x007);
     }
 
     pub fn id(&self) -> usize {
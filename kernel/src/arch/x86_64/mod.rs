@@ -361,8 +361,8 @@ fn boot_app_cores(
 
         // A simple stack for the app core (non bootstrap core)
         let coreboot_stack: OwnedStack = OwnedStack::new(BASE_PAGE_SIZE * 512);
-        let mem_region = global_memory.node_caches[node as usize]
-            .lock()
+        let mem_region = global_memory
+            .lock_node(node as usize)
             .allocate_large_page()
             .expect("Can't allocate large page");
 
@@ -509,6 +509,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Parse the command line arguments
     let cmdline = BootloaderArguments::from_str(kernel_args.command_line);
     klogger::init(cmdline.log_filter).expect("Can't set-up logging");
+    crate::memory::set_oom_policy(cmdline.oom_policy);
 
     info!(
         "Started at {} with {:?} since CPU startup",
@@ -675,7 +676,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // all this work is done in GlobalMemory.
     //
     // This call is safe here because we assume that our `annotated_regions` is correct.
-    let global_memory = unsafe { GlobalMemory::new(annotated_regions).unwrap() };
+    let global_memory = unsafe { GlobalMemory::new(&annotated_regions).unwrap() };
     // Also GlobalMemory should live forver, (we hand out a reference to `global_memory` to every core)
     // that's fine since it is allocated on our BSP init stack (which isn't reclaimed):
     let global_memory_static =
@@ -25,12 +25,13 @@ use crate::fs::{Fd, MAX_FILES_PER_PROCESS};
 use crate::kcb::ArchSpecificKcb;
 use crate::kcb::{self, Kcb};
 use crate::memory::detmem::DA;
-use crate::memory::vspace::{AddressSpace, MapAction};
+use crate::memory::vspace::{AddressSpace, MapAction, SharedFrame};
 use crate::memory::{paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, VAddr};
 use crate::nrproc::NrProcess;
 use crate::process::{
-    Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
-    MAX_WRITEABLE_SECTIONS_PER_PROCESS,
+    binary_hash, Eid, ElfLoading, Executor, LazySegments, Pid, Process, ResumeHandle,
+    SegmentKey, SharedSegments, MAX_FRAMES_PER_PROCESS, MAX_LAZY_SEGMENTS_PER_PROCESS,
+    MAX_PROCESSES, MAX_WRITEABLE_SECTIONS_PER_PROCESS,
 };
 use crate::round_up;
 
@@ -41,6 +42,13 @@ use super::MAX_NUMA_NODES;
 
 const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
 
+lazy_static! {
+    /// Read-only ELF segments shared between processes (see
+    /// `Ring3Process::share_segments`).
+    static ref SHARED_SEGMENTS: spin::Mutex<SharedSegments> =
+        spin::Mutex::new(SharedSegments::default());
+}
+
 lazy_static! {
     pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<Ring3Process>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
         // Want at least one replica...
@@ -744,6 +752,24 @@ pub struct Ring3Process {
     /// (TODO(robustness): assumes that all read-only segments come before
     /// writable segments).
     pub read_only_offset: VAddr,
+    /// Map read-only ELF segments on first access instead of during
+    /// loading (see `NrProcess::handle_page_fault`), set with the
+    /// `elfload=lazy` boot option.
+    pub lazy_load: bool,
+    /// Read-only ELF segments that haven't been (fully) mapped yet.
+    pub lazy_segments: LazySegments,
+    /// Map read-only ELF segments from frames shared with other processes
+    /// running the same binary (writable segments stay private), set with
+    /// the `elfload=shared` boot option.
+    pub share_segments: bool,
+    /// Hash of the ELF binary (only computed if `share_segments` is set).
+    pub binary: u64,
+    /// Shared read-only pages that were already filled (and relocated) by
+    /// an earlier process, loading skips them.
+    pub reused_segments: ArrayVec<core::ops::Range<usize>, MAX_LAZY_SEGMENTS_PER_PROCESS>,
+    /// Shared read-only pages this process allocated, they are published in
+    /// `SHARED_SEGMENTS` once loading filled (and relocated) them.
+    pending_segments: Vec<(SegmentKey, SharedFrame)>,
 }
 
 impl Ring3Process {
@@ -772,8 +798,76 @@ impl Ring3Process {
             frames,
             writeable_sections: ArrayVec::new(),
             read_only_offset: VAddr::zero(),
+            lazy_load: false,
+            lazy_segments: Default::default(),
+            share_segments: false,
+            binary: 0,
+            reused_segments: ArrayVec::new(),
+            pending_segments: Vec::new(),
         })
     }
+
+    /// Maps the read-only large page at `base` with a frame shared between
+    /// all processes running the same binary on this NUMA node.
+    fn map_shared_segment(&mut self, base: VAddr, rights: MapAction) -> Result<(), KError> {
+        let kcb = crate::kcb::get_kcb();
+        let key = SegmentKey {
+            binary: self.binary,
+            node: kcb.physical_memory.affinity,
+            vaddr: base,
+        };
+        let fresh = SHARED_SEGMENTS.lock().map(&mut self.vspace, key, rights, || {
+            kcb.mem_manager().allocate_large_page()
+        })?;
+
+        if let Some(shared) = fresh {
+            self.pending_segments.try_push((key, shared))?;
+        } else {
+            let page = base.as_usize()..base.as_usize() + LARGE_PAGE_SIZE;
+            match self.reused_segments.last_mut() {
+                Some(range) if range.end == page.start => range.end = page.end,
+                _ => self
+                    .reused_segments
+                    .try_push(page)
+                    .map_err(|_e| KError::TooManyRegisteredFrames)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Is `vaddr` in a shared page that an earlier process already loaded?
+    fn is_reused_segment(&self, vaddr: VAddr) -> bool {
+        self.reused_segments
+            .iter()
+            .any(|range| range.contains(&vaddr.as_usize()))
+    }
+
+    /// Maps and fills the page of a lazily loaded ELF segment that
+    /// contains `fault_addr`.
+    ///
+    /// Returns `NotMapped` if `fault_addr` isn't part of a lazy segment.
+    pub fn handle_page_fault(&mut self, fault_addr: VAddr) -> Result<(), KError> {
+        if !self.lazy_segments.contains(fault_addr) {
+            return Err(KError::NotMapped);
+        }
+
+        // One page for the data, 4 as estimate of worst case pt requirements
+        KernelAllocator::try_refill_tcache(5, 0)?;
+        let kcb = crate::kcb::get_kcb();
+        let frame = kcb.mem_manager().allocate_base_page()?;
+
+        match self
+            .lazy_segments
+            .handle_page_fault(&mut self.vspace, fault_addr, frame)
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => kcb.mem_manager().release_base_page(frame),
+            Err(e) => {
+                kcb.mem_manager().release_base_page(frame)?;
+                Err(e)
+            }
+        }
+    }
 }
 
 impl fmt::Debug for Ring3Process {
@@ -830,6 +924,15 @@ impl elfloader::ElfLoader for Ring3Process {
                 map_action
             );
 
+            if self.lazy_load && !flags.is_write() {
+                // Pages get mapped on first access by `handle_page_fault`
+                let start = (self.offset + page_base).as_usize();
+                self.lazy_segments
+                    .add(start..start + size_page, map_action)
+                    .map_err(|_e| "Can't register lazy ELF segment.")?;
+                continue;
+            }
+
             let large_pages = size_page / LARGE_PAGE_SIZE;
             debug!("page_base {} lps: {}", page_base, large_pages);
 
@@ -846,6 +949,13 @@ impl elfloader::ElfLoader for Ring3Process {
             // we provide initially doesn't work out...
             let mut wsection_idx = 0;
             for i in 0..large_pages {
+                if self.share_segments && !flags.is_write() {
+                    let base = self.offset + page_base + i * LARGE_PAGE_SIZE;
+                    self.map_shared_segment(base, map_action)
+                        .map_err(|_e| "Can't map shared ELF region.")?;
+                    continue;
+                }
+
                 let frame = if flags.is_write() {
                     // Writeable program-headers we can't replicate:
                     assert!(
@@ -907,7 +1017,15 @@ impl elfloader::ElfLoader for Ring3Process {
 
         // Only write to the read-only sections, writable frames already have
         // the right content (see DataSecLoader in src/process.rs)
-        if !flags.is_write() {
+        if !flags.is_write() && self.lazy_load {
+            self.read_only_offset = destination + region.len();
+            // Safety: ELF binaries are boot modules, they are never freed
+            let region: &'static [u8] =
+                unsafe { core::slice::from_raw_parts(region.as_ptr(), region.len()) };
+            self.lazy_segments
+                .set_content(destination.as_usize(), region)
+                .map_err(|_e| "Can't find lazy ELF segment for region.")?;
+        } else if !flags.is_write() {
             self.read_only_offset = destination + region.len();
             info!(
                 "ELF Load of read-only region at {:#x} -- {:#x}",
@@ -918,6 +1036,10 @@ impl elfloader::ElfLoader for Ring3Process {
             // Load the region at destination in the kernel space
             for (idx, val) in region.iter().enumerate() {
                 let vaddr = VAddr::from(destination + idx);
+                if self.is_reused_segment(vaddr) {
+                    // Already has the content, and others might be running it
+                    continue;
+                }
                 let (paddr, _rights) = self
                     .vspace
                     .resolve(vaddr)
@@ -970,6 +1092,16 @@ impl elfloader::ElfLoader for Ring3Process {
             // Don't relocate anything in write-able section, already done
             return Ok(());
         }
+        if self.is_reused_segment(addr) {
+            // Relocated by the process that loaded the shared page first
+            return Ok(());
+        }
+
+        if self.lazy_load && self.vspace.resolve(addr).is_err() {
+            // Relocations force the page in, it has to be written now
+            self.handle_page_fault(addr)
+                .map_err(|_e| "Can't load page for relocation.")?;
+        }
 
         // Translate `addr` into a kernel vaddr we can write to:
         let (paddr, _rights) = self.vspace.resolve(addr).expect("Can't resolve address");
@@ -1049,6 +1181,15 @@ impl Process for Ring3Process {
         // Load the Module into the process address-space
         // This needs mostly sanitation work on elfloader and
         // ElfLoad trait impl for process to be safe
+        self.reused_segments.clear();
+        self.lazy_segments = Default::default();
+        self.pending_segments.clear();
+        let elf_loading = kcb::get_kcb().cmdline.elf_loading;
+        self.lazy_load = elf_loading == ElfLoading::Lazy;
+        self.share_segments = elf_loading == ElfLoading::Shared;
+        if self.share_segments {
+            self.binary = binary_hash(module.as_slice());
+        }
         unsafe {
             let e = elfloader::ElfBinary::new(module.as_slice())?;
             if !e.is_pie() {
@@ -1059,6 +1200,16 @@ impl Process for Ring3Process {
             e.load(self)?;
         }
 
+        // Everything is loaded and relocated now, other processes can use it
+        let mut shared_segments = SHARED_SEGMENTS.lock();
+        for (key, shared) in self.pending_segments.drain(..) {
+            if let Err(e) = shared_segments.publish(key, shared) {
+                // The process keeps its private copy, it just isn't shared
+                warn!("Can't share ELF segment at {:#x}: {:?}", key.vaddr, e);
+            }
+        }
+        drop(shared_segments);
+
         // Install the kernel mappings
         // TODO(efficiency): These should probably be global mappings
         // TODO(broken): Big (>= 2 MiB) allocations should be inserted here too
@@ -1229,6 +1380,10 @@ impl Process for Ring3Process {
             _ => Err(KError::InvalidFileDescriptor),
         }
     }
+
+    fn lazy_segments(&self) -> Option<&LazySegments> {
+        Some(&self.lazy_segments)
+    }
 }
 
 /// Spawns a new process
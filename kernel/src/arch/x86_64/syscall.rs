@@ -6,7 +6,7 @@
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use klogger::{sprint, sprintln};
@@ -15,6 +15,7 @@ use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::bits64::rflags;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 
+use kpi::io::{FileAdvice, IoVec, LockOp};
 use kpi::process::FrameId;
 use kpi::{
     FileOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation, VSpaceOperation,
@@ -25,7 +26,7 @@ use crate::fs::FileSystem;
 use crate::kcb::ArchSpecificKcb;
 use crate::memory::vspace::MapAction;
 use crate::memory::{Frame, PhysicalPageProvider, KERNEL_BASE};
-use crate::process::{Pid, ResumeHandle};
+use crate::process::{KernSlice, Pid, ResumeHandle};
 use crate::{cnrfs, nr, nrproc};
 
 use super::gdt::GdtTable;
@@ -287,11 +288,10 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                     }
                 }
                 for _i in 0..bp {
-                    let mut frame = pmanager
-                        .allocate_base_page()
+                    let frame = pmanager
+                        .allocate_clean_base_page()
                         .expect("We refilled so allocation should work.");
                     total_len += frame.size;
-                    unsafe { frame.zero() };
                     frames
                         .try_push(frame)
                         .expect("Can't fail see `try_with_capacity`");
@@ -375,9 +375,17 @@ fn handle_fileio(
             let pathname = arg2;
             let flags = arg3;
             let modes = arg4;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
+            let _r = user_str_valid(pid, pathname)?;
             cnrfs::MlnrKernelNode::map_fd(pid, pathname, flags, modes)
         }
+        FileOperation::OpenWithSize => {
+            let pathname = arg2;
+            let flags = arg3;
+            let modes = arg4;
+            let size = arg5;
+            let _r = user_str_valid(pid, pathname)?;
+            cnrfs::MlnrKernelNode::map_fd_with_size(pid, pathname, flags, modes, size)
+        }
         FileOperation::Read | FileOperation::Write => {
             let fd = arg2;
             let buffer = arg3;
@@ -395,6 +403,15 @@ fn handle_fileio(
             let _r = user_virt_addr_valid(pid, buffer, len)?;
             cnrfs::MlnrKernelNode::file_io(op, pid, fd, buffer, len, offset)
         }
+        FileOperation::ReadV | FileOperation::WriteV => {
+            let fd = arg2;
+            let iovecs = arg3;
+            let count = arg4;
+            let offset = arg5 as i64;
+
+            let iovecs = user_iovecs(pid, iovecs, count)?;
+            cnrfs::MlnrKernelNode::file_io_v(op, pid, fd, iovecs, offset)
+        }
         FileOperation::Close => {
             let fd = arg2;
             cnrfs::MlnrKernelNode::unmap_fd(pid, fd)
@@ -403,13 +420,13 @@ fn handle_fileio(
             let name = arg2;
             let info_ptr = arg3;
 
-            let _r = user_virt_addr_valid(pid, name, 0)?;
+            let _r = user_str_valid(pid, name)?;
             cnrfs::MlnrKernelNode::file_info(pid, name, info_ptr)
         }
         FileOperation::Delete => {
             let name = arg2;
 
-            let _r = user_virt_addr_valid(pid, name, 0)?;
+            let _r = user_str_valid(pid, name)?;
             cnrfs::MlnrKernelNode::file_delete(pid, name)
         }
         FileOperation::WriteDirect => {
@@ -430,19 +447,89 @@ fn handle_fileio(
         FileOperation::FileRename => {
             let oldname = arg2;
             let newname = arg3;
+            let flags = arg4;
 
-            let _r = user_virt_addr_valid(pid, oldname, 0)?;
-            let _r = user_virt_addr_valid(pid, newname, 0)?;
+            let _r = user_str_valid(pid, oldname)?;
+            let _r = user_str_valid(pid, newname)?;
 
-            cnrfs::MlnrKernelNode::file_rename(pid, oldname, newname)
+            cnrfs::MlnrKernelNode::file_rename(pid, oldname, newname, flags)
         }
         FileOperation::MkDir => {
             let pathname = arg2;
             let modes = arg3;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
+            let _r = user_str_valid(pid, pathname)?;
 
             cnrfs::MlnrKernelNode::mkdir(pid, pathname, modes)
         }
+        FileOperation::RmDir => {
+            let pathname = arg2;
+            let _r = user_str_valid(pid, pathname)?;
+
+            cnrfs::MlnrKernelNode::rmdir(pid, pathname)
+        }
+        FileOperation::Mount => {
+            let pathname = arg2;
+            let _r = user_str_valid(pid, pathname)?;
+
+            cnrfs::MlnrKernelNode::mount(pid, pathname)
+        }
+        FileOperation::Unmount => {
+            let pathname = arg2;
+            let _r = user_str_valid(pid, pathname)?;
+
+            cnrfs::MlnrKernelNode::unmount(pid, pathname)
+        }
+        FileOperation::OpenByMnode => {
+            let mnode = arg2;
+            let flags = arg3;
+            cnrfs::MlnrKernelNode::map_fd_by_mnode(pid, mnode, flags)
+        }
+        FileOperation::SeekData | FileOperation::SeekHole => {
+            let fd = arg2;
+            let offset = arg3;
+            let data = op == FileOperation::SeekData;
+            cnrfs::MlnrKernelNode::file_seek(pid, fd, offset, data)
+        }
+        FileOperation::FAdvise => {
+            let fd = arg2;
+            let advice = FileAdvice::try_from(arg3).map_err(|_| KError::InvalidFlags)?;
+            cnrfs::MlnrKernelNode::file_advise(pid, fd, advice)
+        }
+        FileOperation::SyncAll => cnrfs::MlnrKernelNode::sync_all(pid),
+        FileOperation::Chmod => {
+            let pathname = arg2;
+            let modes = arg3;
+            let _r = user_str_valid(pid, pathname)?;
+
+            cnrfs::MlnrKernelNode::chmod(pid, pathname, modes)
+        }
+        FileOperation::CompareAndWrite => {
+            let fd = arg2;
+            let iovecs = arg3;
+            let offset = arg4;
+
+            // The expected data followed by the new data
+            let iovecs = user_iovecs(pid, iovecs, 2)?;
+            cnrfs::MlnrKernelNode::file_compare_and_write(pid, fd, iovecs[0], iovecs[1], offset)
+        }
+        FileOperation::FLock => {
+            let fd = arg2;
+            let op = LockOp::try_from(arg3).map_err(|_| KError::InvalidFlags)?;
+            cnrfs::MlnrKernelNode::file_lock(pid, fd, op)
+        }
+        FileOperation::ChDir => {
+            let pathname = arg2;
+            let _r = user_str_valid(pid, pathname)?;
+
+            cnrfs::MlnrKernelNode::chdir(pid, pathname)
+        }
+        FileOperation::GetCwd => {
+            let buffer = arg2;
+            let len = arg3;
+            let _r = user_virt_addr_valid(pid, buffer, len)?;
+
+            cnrfs::MlnrKernelNode::getcwd(pid, buffer, len)
+        }
         FileOperation::Unknown => {
             unreachable!("FileOperation not allowed");
             Err(KError::NotSupported)
@@ -461,14 +548,11 @@ fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KE
         while base <= upper_addr {
             // Validate addresses for the buffer end.
             if upper_addr - base <= BASE_PAGE_SIZE as u64 {
-                let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-                return nrproc::NrProcess::<Ring3Process>::resolve(
-                    pid,
-                    VAddr::from(upper_addr - 1),
-                );
+                let _r = user_page_resolve(pid, VAddr::from(base))?;
+                return user_page_resolve(pid, VAddr::from(upper_addr - 1));
             }
 
-            let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
+            let _r = user_page_resolve(pid, VAddr::from(base))?;
             base += BASE_PAGE_SIZE as u64;
         }
         return Ok((base, size));
@@ -476,6 +560,61 @@ fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KE
     Err(KError::BadAddress)
 }
 
+/// Checks that the NUL-terminated string at `base` is in valid user memory.
+///
+/// Unlike `user_virt_addr_valid` this also covers every page the string
+/// spans.
+fn user_str_valid(pid: Pid, base: u64) -> Result<(u64, u64), KError> {
+    let mut start = base;
+    while start < KERNEL_BASE {
+        let _r = user_page_resolve(pid, VAddr::from(start))?;
+
+        // The page is mapped now, look for the end of the string in it
+        let page_end = (start & !(BASE_PAGE_SIZE as u64 - 1)) + BASE_PAGE_SIZE as u64;
+        let bytes = super::process::UserSlice::new(start, (page_end - start) as usize);
+        if bytes.contains(&0) {
+            return Ok((base, page_end - base));
+        }
+        start = page_end;
+    }
+    Err(KError::BadAddress)
+}
+
+/// Resolves the user address `vaddr` of `pid`, the page is loaded first in
+/// case it's part of a lazily loaded ELF segment that wasn't touched yet.
+///
+/// The kernel can't recover from its own page-faults on user memory, so
+/// everything it accesses on behalf of a process has to be checked with this.
+fn user_page_resolve(pid: Pid, vaddr: VAddr) -> Result<(u64, u64), KError> {
+    nrproc::NrProcess::<Ring3Process>::resolve(pid, vaddr).or_else(|e| {
+        nrproc::NrProcess::<Ring3Process>::handle_page_fault(pid, vaddr).map_err(|_| e)?;
+        nrproc::NrProcess::<Ring3Process>::resolve(pid, vaddr)
+    })
+}
+
+/// Copies the array of `count` `IoVec`s at `base` from user-space and checks
+/// that all buffers in it are valid.
+fn user_iovecs(pid: Pid, base: u64, count: u64) -> Result<Vec<IoVec>, KError> {
+    const IOVEC_SIZE: usize = core::mem::size_of::<IoVec>();
+    let size = count
+        .checked_mul(IOVEC_SIZE as u64)
+        .ok_or(KError::BadAddress)?;
+    let _r = user_virt_addr_valid(pid, base, size)?;
+
+    let raw = KernSlice::new(base, size as usize);
+    let mut iovecs = Vec::new();
+    iovecs.try_reserve(count as usize)?;
+    for entry in raw.buffer.chunks_exact(IOVEC_SIZE) {
+        let iovec = IoVec {
+            base: u64::from_ne_bytes(entry[0..8].try_into().unwrap()),
+            len: u64::from_ne_bytes(entry[8..16].try_into().unwrap()),
+        };
+        let _r = user_virt_addr_valid(pid, iovec.base, iovec.len)?;
+        iovecs.push(iovec);
+    }
+    Ok(iovecs)
+}
+
 #[allow(unused)]
 fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
     sprint!("syscall: {:?}", SystemCall::new(function));
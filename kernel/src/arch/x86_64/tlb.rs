@@ -19,7 +19,8 @@ use x86::apic::{
 
 use super::memory::BASE_PAGE_SIZE;
 use crate::kcb;
-use crate::memory::vspace::TlbFlushHandle;
+use crate::memory::vspace::{flush_range, TlbFlushHandle, TlbFlusher};
+use crate::memory::VAddr;
 use crate::{cnrfs, is_page_aligned, nr};
 
 // In the xAPIC mode, the Destination Format Register (DFR) through the MMIO
@@ -93,17 +94,23 @@ impl Shootdown {
         // before this function completes:
         self.acknowledge();
 
-        let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-        if it.count() > 20 {
-            trace!("flush the entire TLB");
-            unsafe { x86::tlb::flush_all() };
-        } else {
-            let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-            for va in it {
-                trace!("flushing TLB page {:#x}", va);
-                unsafe { x86::tlb::flush(va as usize) };
-            }
-        }
+        let vregion = self.vregion.start as usize..self.vregion.end as usize;
+        flush_range(&mut LocalTlb, vregion);
+    }
+}
+
+/// Invalidates TLB entries of the current core (with `invlpg`).
+pub struct LocalTlb;
+
+impl TlbFlusher for LocalTlb {
+    fn flush_page(&mut self, vaddr: VAddr) {
+        trace!("flushing TLB page {:#x}", vaddr);
+        unsafe { x86::tlb::flush(vaddr.as_usize()) };
+    }
+
+    fn flush_all(&mut self) {
+        trace!("flush the entire TLB");
+        unsafe { x86::tlb::flush_all() };
     }
 }
 
@@ -241,7 +248,8 @@ pub fn shootdown(handle: TlbFlushHandle) {
     let num_cores = atopology::MACHINE_TOPOLOGY.num_threads();
     let mut shootdowns: Vec<Arc<Shootdown>> = Vec::try_with_capacity(num_cores)
         .expect("TODO(error-handling): ideally: no possible failure during shootdown");
-    let range = handle.vaddr.as_u64()..(handle.vaddr + handle.frame.size).as_u64();
+    let vrange = handle.vrange();
+    let range = vrange.start as u64..vrange.end as u64;
 
     for gtid in handle.cores() {
         if gtid != my_gtid {
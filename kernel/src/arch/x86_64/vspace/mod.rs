@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use core::ops::Bound::*;
+use core::ops::Range;
 
 use fallible_collections::btree::BTreeMap;
 
@@ -12,10 +13,17 @@ mod test;
 
 use crate::error::KError;
 use crate::memory::{detmem::DA, vspace::*};
-use crate::memory::{Frame, PAddr, VAddr};
+use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
 
 use page_table::PageTable;
 
+/// Lowest address handed out by `VSpace::map_anywhere` (so the null page
+/// and everything close to it stays unmapped).
+pub const MAP_ANYWHERE_START: VAddr = VAddr(0x10_0000);
+
+/// `VSpace::map_anywhere` only places mappings below the kernel.
+pub const MAP_ANYWHERE_END: VAddr = VAddr(kpi::KERNEL_BASE);
+
 pub struct VSpace {
     pub mappings: BTreeMap<VAddr, MappingInfo>,
     pub page_table: PageTable,
@@ -23,6 +31,86 @@ pub struct VSpace {
 
 impl AddressSpace for VSpace {
     fn map_frame(&mut self, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError> {
+        self.map_mapping(base, MappingInfo::new(frame, action))
+    }
+
+    fn map_frame_shared(
+        &mut self,
+        base: VAddr,
+        shared: &SharedFrame,
+        action: MapAction,
+    ) -> Result<(), KError> {
+        self.map_mapping(base, MappingInfo::new_shared(shared, action))
+    }
+
+    fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
+        // Implementation specific, the model does not require additional
+        // memory for page-tables
+        0
+    }
+
+    fn resolve(&self, addr: VAddr) -> Result<(PAddr, MapAction), KError> {
+        self.page_table.resolve(addr)
+    }
+
+    fn is_mapped(&self, addr: VAddr) -> bool {
+        self.page_table.is_mapped(addr)
+    }
+
+    fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
+        for (&existing_base, existing_mapping) in
+            self.mappings.range((Unbounded, Included(base))).rev()
+        {
+            let existing_map_range = existing_mapping.vrange(existing_base);
+            if existing_map_range.contains(&base.as_usize()) {
+                break;
+            } else {
+                return Err(KError::NotMapped);
+            }
+        }
+
+        let r = self.page_table.unmap(base)?;
+        // Dropping the MappingInfo also releases a (potential) reference to a
+        // shared frame
+        let rbt = self.mappings.remove(&r.vaddr);
+        debug_assert!(rbt.is_some());
+        Ok(r)
+    }
+
+    fn adjust(&mut self, base: VAddr, new_rights: MapAction) -> Result<(VAddr, usize), KError> {
+        let r = self.page_table.adjust(base, new_rights)?;
+        let mapping = self.mappings.get_mut(&r.0).ok_or(KError::NotMapped)?;
+        mapping.rights = new_rights;
+        Ok(r)
+    }
+}
+
+impl Drop for VSpace {
+    fn drop(&mut self) {
+        //panic!("Drop for VSpace!");
+    }
+}
+
+impl VSpace {
+    pub(crate) fn new(da: DA) -> Result<Self, KError> {
+        Ok(VSpace {
+            mappings: BTreeMap::new(),
+            page_table: PageTable::new(da)?,
+        })
+    }
+
+    /// Returns an iterator over all mappings in the address space (ordered by
+    /// virtual address).
+    pub fn mappings(&self) -> impl Iterator<Item = (Range<usize>, &MappingInfo)> + '_ {
+        self.mappings
+            .iter()
+            .map(|(base, mapping)| (mapping.vrange(*base), mapping))
+    }
+
+    /// Inserts `mapping` at `base` in the address space.
+    fn map_mapping(&mut self, base: VAddr, mapping: MappingInfo) -> Result<(), KError> {
+        let frame = mapping.frame;
+        let action = mapping.rights;
         if frame.size() == 0 {
             return Err(KError::InvalidFrame);
         }
@@ -63,59 +151,131 @@ impl AddressSpace for VSpace {
             }
         }
 
-        self.mappings
-            .try_insert(base, MappingInfo::new(frame, action))?;
+        self.mappings.try_insert(base, mapping)?;
         self.page_table.map_frame(base, frame, action)
     }
 
-    fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
-        // Implementation specific, the model does not require additional
-        // memory for page-tables
-        0
+    /// Maps `frames` back to back into the address space, starting at `base`.
+    ///
+    /// Does the same as calling `map_frame` for every frame, but in case all
+    /// frames are base-pages the page-table only gets walked once per PT
+    /// instead of once per frame. Fails with `AlreadyMapped` if anything in
+    /// the range is mapped already.
+    pub fn map_contiguous(
+        &mut self,
+        base: VAddr,
+        frames: &[Frame],
+        rights: MapAction,
+    ) -> Result<(), KError> {
+        if !frames.iter().all(|f| f.size() == BASE_PAGE_SIZE) {
+            let mut vaddr = base;
+            for frame in frames {
+                self.map_frame(vaddr, *frame, rights)?;
+                vaddr = vaddr + frame.size();
+            }
+            return Ok(());
+        }
+        if frames.iter().any(|f| f.base % BASE_PAGE_SIZE != 0) {
+            return Err(KError::InvalidFrame);
+        }
+        if base % BASE_PAGE_SIZE != 0 {
+            return Err(KError::InvalidBase);
+        }
+
+        let size = frames
+            .len()
+            .checked_mul(BASE_PAGE_SIZE)
+            .ok_or(KError::InvalidLength)?;
+        let tomap_range = base.as_usize()..base.as_usize() + size;
+        if let Some((&existing_base, existing_mapping)) = self
+            .mappings
+            .range((Unbounded, Excluded(VAddr::from(tomap_range.end))))
+            .next_back()
+        {
+            if existing_mapping.vrange(existing_base).end > tomap_range.start {
+                return Err(KError::AlreadyMapped {
+                    base: existing_base,
+                });
+            }
+        }
+
+        for (idx, frame) in frames.iter().enumerate() {
+            let vaddr = base + idx * BASE_PAGE_SIZE;
+            if let Err(e) = self
+                .mappings
+                .try_insert(vaddr, MappingInfo::new(*frame, rights))
+            {
+                self.forget_mappings(base, idx);
+                return Err(e.into());
+            }
+        }
+        self.page_table
+            .map_base_pages(base, frames, rights)
+            .map_err(|e| {
+                self.forget_mappings(base, frames.len());
+                e
+            })
     }
 
-    fn resolve(&self, addr: VAddr) -> Result<(PAddr, MapAction), KError> {
-        self.page_table.resolve(addr)
+    /// Drops the bookkeeping for `count` base-pages starting at `base`.
+    fn forget_mappings(&mut self, base: VAddr, count: usize) {
+        for idx in 0..count {
+            self.mappings.remove(&(base + idx * BASE_PAGE_SIZE));
+        }
     }
 
-    fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
+    /// Maps `frame` at the lowest free, suitably aligned address in the
+    /// user part of the address space.
+    ///
+    /// # Returns
+    /// The base address the frame got mapped at.
+    pub fn map_anywhere(&mut self, frame: Frame, rights: MapAction) -> Result<VAddr, KError> {
+        if frame.size() == 0 {
+            return Err(KError::InvalidFrame);
+        }
+        let base = self.find_free_range(frame.size())?;
+        self.map_frame(base, frame, rights)?;
+        Ok(base)
+    }
+
+    /// Finds the lowest gap of `size` bytes (aligned to `size`) between
+    /// `MAP_ANYWHERE_START` and `MAP_ANYWHERE_END`.
+    fn find_free_range(&self, size: usize) -> Result<VAddr, KError> {
+        let align_up = |addr: usize| {
+            addr.checked_add(size - 1)
+                .map(|a| a / size * size)
+                .ok_or(KError::OutOfMemory)
+        };
+        let mut candidate = align_up(MAP_ANYWHERE_START.as_usize())?;
+
         for (&existing_base, existing_mapping) in
-            self.mappings.range((Unbounded, Included(base))).rev()
+            self.mappings.range((Unbounded, Excluded(MAP_ANYWHERE_END)))
         {
             let existing_map_range = existing_mapping.vrange(existing_base);
-            if existing_map_range.contains(&base.as_usize()) {
+            if existing_map_range.end <= candidate {
+                continue;
+            }
+            if existing_map_range.start >= candidate + size {
+                // Gap in front of this mapping is big enough
                 break;
-            } else {
-                return Err(KError::NotMapped);
             }
+            candidate = align_up(existing_map_range.end)?;
         }
 
-        let r = self.page_table.unmap(base)?;
-        let rbt = self.mappings.remove(&r.vaddr);
-        debug_assert!(rbt.is_some());
-        Ok(r)
-    }
-
-    fn adjust(&mut self, base: VAddr, new_rights: MapAction) -> Result<(VAddr, usize), KError> {
-        let r = self.page_table.adjust(base, new_rights)?;
-        let mapping = self.mappings.get_mut(&r.0).ok_or(KError::NotMapped)?;
-        mapping.rights = new_rights;
-        Ok(r)
-    }
-}
-
-impl Drop for VSpace {
-    fn drop(&mut self) {
-        //panic!("Drop for VSpace!");
+        match candidate.checked_add(size) {
+            Some(end) if end <= MAP_ANYWHERE_END.as_usize() => Ok(VAddr::from(candidate)),
+            _ => Err(KError::OutOfMemory),
+        }
     }
-}
 
-impl VSpace {
-    pub(crate) fn new(da: DA) -> Result<Self, KError> {
-        Ok(VSpace {
-            mappings: BTreeMap::new(),
-            page_table: PageTable::new(da)?,
-        })
+    /// Allocates the page-tables needed to map [`base`, `base` + `size`)
+    /// with base-pages up front.
+    ///
+    /// A `map_frame` or `map_contiguous` of base-pages in the range
+    /// afterwards won't fail halfway because it ran out of memory for
+    /// page-tables. Nothing is allocated if this fails.
+    pub fn reserve_tables(&mut self, base: VAddr, size: usize) -> Result<(), KError> {
+        self.page_table.reserve_tables(base, size)
     }
 
     pub fn map_identity(
@@ -2,16 +2,20 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::mem::transmute;
 use core::pin::Pin;
 use core::ptr::NonNull;
 
+use fallible_collections::vec::FallibleVec;
+use fallible_collections::FallibleVecGlobal;
 use kpi::KERNEL_BASE;
 use log::{debug, trace};
 use x86::bits64::paging::*;
 
 use crate::error::KError;
+use crate::kcb;
 use crate::memory::detmem::DA;
 use crate::memory::vspace::*;
 use crate::memory::{kernel_vaddr_to_paddr, paddr_to_kernel_vaddr, Frame, PAddr, VAddr};
@@ -39,8 +43,6 @@ pub struct PageTable {
 
 impl Drop for PageTable {
     fn drop(&mut self) {
-        use alloc::alloc::dealloc;
-
         // Do a DFS and free all page-table memory allocated below kernel-base,
         // don't free the mapped frames -- we return them later through NR
         for pml4_idx in 0..PAGE_SIZE_ENTRIES {
@@ -58,18 +60,14 @@ impl Drop for PageTable {
                                             if pt[pt_idx].is_present() {}
                                         }
                                         // Free this PT (page-table)
-                                        let addr = pd[pd_idx].address();
-                                        let vaddr = paddr_to_kernel_vaddr(addr);
-                                        unsafe { dealloc(vaddr.as_mut_ptr(), PT_LAYOUT) };
+                                        self.free_table(pd[pd_idx].address());
                                     }
                                 } else {
                                     // Encountered a 2 MiB mapping, nothing to free
                                 }
                             }
                             // Free this PDPT entry (PD page-table)
-                            let addr = pdpt[pdpt_idx].address();
-                            let vaddr = paddr_to_kernel_vaddr(addr);
-                            unsafe { dealloc(vaddr.as_mut_ptr(), PT_LAYOUT) };
+                            self.free_table(pdpt[pdpt_idx].address());
                         } else {
                             // Encountered Page is a 1 GiB mapping, nothing to free
                         }
@@ -77,9 +75,7 @@ impl Drop for PageTable {
                 }
 
                 // Free this PML4 entry (PDPT page-table)
-                let addr = self.pml4[pml4_idx].address();
-                let vaddr = paddr_to_kernel_vaddr(addr);
-                unsafe { dealloc(vaddr.as_mut_ptr(), PT_LAYOUT) };
+                self.free_table(self.pml4[pml4_idx].address());
                 self.pml4[pml4_idx] = PML4Entry(0x0);
             }
         }
@@ -104,6 +100,16 @@ impl AddressSpace for PageTable {
         self.map_generic(base, (frame.base, frame.size()), action, true)
     }
 
+    fn map_frame_shared(
+        &mut self,
+        _base: VAddr,
+        _shared: &SharedFrame,
+        _action: MapAction,
+    ) -> Result<(), KError> {
+        // The page-table doesn't keep track of mappings, use `VSpace`
+        Err(KError::NotSupported)
+    }
+
     fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
         // TODO(correctness): Calculate this properly
         20
@@ -159,6 +165,28 @@ impl AddressSpace for PageTable {
         Err(KError::NotMapped)
     }
 
+    fn is_mapped(&self, addr: VAddr) -> bool {
+        let pml4_idx = pml4_index(addr);
+        if !self.pml4[pml4_idx].is_present() {
+            return false;
+        }
+
+        let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+        let pdpt_entry = pdpt[pdpt_index(addr)];
+        if !pdpt_entry.is_present() || pdpt_entry.is_page() {
+            return pdpt_entry.is_present();
+        }
+
+        let pd = self.get_pd(pdpt_entry);
+        let pd_entry = pd[pd_index(addr)];
+        if !pd_entry.is_present() || pd_entry.is_page() {
+            return pd_entry.is_present();
+        }
+
+        let pt = self.get_pt(pd_entry);
+        pt[pt_index(addr)].is_present()
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         if !base.is_base_page_aligned() {
             return Err(KError::InvalidBase);
@@ -243,6 +271,208 @@ impl PageTable {
         self.get_pdpt_mut(self.pml4[pml4_idx])
     }
 
+    /// Retrieves the PD entry of the PT that holds the 4 KiB mapping for
+    /// `vbase`.
+    ///
+    /// Allocates the PDPT, PD and PT if they don't exist yet. Fails with
+    /// `AlreadyMapped` in case a 1 GiB or 2 MiB page covers `vbase`.
+    fn get_or_alloc_pt(&mut self, vbase: VAddr) -> Result<PDEntry, KError> {
+        self.get_or_alloc_pdpt(vbase);
+        let pml4_entry = self.pml4[pml4_index(vbase)];
+
+        let pdpt_idx = pdpt_index(vbase);
+        if !self.get_pdpt(pml4_entry)[pdpt_idx].is_present() {
+            let pd = self.new_pd();
+            self.get_pdpt_mut(pml4_entry)[pdpt_idx] = pd;
+        }
+        let pdpt_entry = self.get_pdpt(pml4_entry)[pdpt_idx];
+        if pdpt_entry.is_page() {
+            return Err(KError::AlreadyMapped { base: vbase });
+        }
+
+        let pd_idx = pd_index(vbase);
+        if !self.get_pd(pdpt_entry)[pd_idx].is_present() {
+            let pt = self.new_pt();
+            self.get_pd_mut(pdpt_entry)[pd_idx] = pt;
+        }
+        let pd_entry = self.get_pd(pdpt_entry)[pd_idx];
+        if pd_entry.is_page() {
+            return Err(KError::AlreadyMapped { base: vbase });
+        }
+
+        Ok(pd_entry)
+    }
+
+    /// How many page-tables (PDPTs, PDs and PTs) we'd have to allocate to
+    /// map [`vbase`, `end`) with base-pages.
+    ///
+    /// Regions already covered by a 1 GiB or 2 MiB page are not counted.
+    pub(crate) fn missing_tables(&self, vbase: VAddr, end: usize) -> usize {
+        let start = vbase.align_down_to_large_page();
+        let mut missing = 0;
+
+        let mut cursor = start;
+        while cursor.as_usize() < end {
+            // A missing PDPT/PD is shared by all the 2 MiB regions below it,
+            // only count it for the first region of the range that needs it
+            let first = cursor == start;
+            let new_pd = first || pd_index(cursor) == 0;
+            let new_pdpt = new_pd && (first || pdpt_index(cursor) == 0);
+
+            let pml4_entry = self.pml4[pml4_index(cursor)];
+            if !pml4_entry.is_present() {
+                missing += new_pdpt as usize + new_pd as usize + 1;
+            } else {
+                let pdpt_entry = self.get_pdpt(pml4_entry)[pdpt_index(cursor)];
+                if !pdpt_entry.is_present() {
+                    missing += new_pd as usize + 1;
+                } else if !pdpt_entry.is_page()
+                    && !self.get_pd(pdpt_entry)[pd_index(cursor)].is_present()
+                {
+                    missing += 1;
+                }
+            }
+
+            cursor = cursor + LARGE_PAGE_SIZE;
+        }
+
+        missing
+    }
+
+    /// Counts the page-tables (PDPTs, PDs and PTs) below kernel-base.
+    #[cfg(test)]
+    pub(crate) fn table_count(&self) -> usize {
+        let mut count = 0;
+        for pml4_idx in 0..pml4_index(KERNEL_BASE.into()) {
+            if !self.pml4[pml4_idx].is_present() {
+                continue;
+            }
+            count += 1;
+            let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+            for pdpt_entry in pdpt.iter().filter(|e| e.is_present() && !e.is_page()) {
+                count += 1;
+                let pd = self.get_pd(*pdpt_entry);
+                count += pd.iter().filter(|e| e.is_present() && !e.is_page()).count();
+            }
+        }
+        count
+    }
+
+    /// Allocates all page-tables needed to map [`vbase`, `vbase` + `size`)
+    /// with base-pages.
+    ///
+    /// Afterwards, mapping base-pages in the range won't allocate anymore. The
+    /// tables are allocated before any of them is inserted, so if we run out
+    /// of memory the page-table is left as it was.
+    pub(crate) fn reserve_tables(&mut self, vbase: VAddr, size: usize) -> Result<(), KError> {
+        self.reserve_tables_with(vbase, size, |pt| pt.try_alloc_frame())
+    }
+
+    /// Same as `reserve_tables`, but gets the memory for new tables from
+    /// `alloc_table` (which has to return frames like `try_alloc_frame`).
+    pub(crate) fn reserve_tables_with(
+        &mut self,
+        vbase: VAddr,
+        size: usize,
+        mut alloc_table: impl FnMut(&PageTable) -> Result<Frame, KError>,
+    ) -> Result<(), KError> {
+        let end = vbase
+            .as_usize()
+            .checked_add(size)
+            .ok_or(KError::BaseOverflow {
+                base: vbase.as_u64(),
+            })?;
+
+        let missing = self.missing_tables(vbase, end);
+        let mut tables: Vec<Frame> = Vec::try_with_capacity(missing)?;
+        for _i in 0..missing {
+            match alloc_table(self) {
+                Ok(frame) => tables.push(frame),
+                Err(e) => {
+                    for frame in tables {
+                        self.free_frame(frame);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut cursor = vbase.align_down_to_large_page();
+        while cursor.as_usize() < end {
+            let pml4_idx = pml4_index(cursor);
+            if !self.pml4[pml4_idx].is_present() {
+                let frame = tables.pop().expect("Counted in missing_tables");
+                self.pml4[pml4_idx] =
+                    PML4Entry::new(frame.base, PML4Flags::P | PML4Flags::RW | PML4Flags::US);
+            }
+            let pml4_entry = self.pml4[pml4_idx];
+
+            let pdpt_idx = pdpt_index(cursor);
+            if !self.get_pdpt(pml4_entry)[pdpt_idx].is_present() {
+                let frame = tables.pop().expect("Counted in missing_tables");
+                self.get_pdpt_mut(pml4_entry)[pdpt_idx] =
+                    PDPTEntry::new(frame.base, PDPTFlags::P | PDPTFlags::RW | PDPTFlags::US);
+            }
+            let pdpt_entry = self.get_pdpt(pml4_entry)[pdpt_idx];
+
+            let pd_idx = pd_index(cursor);
+            if !pdpt_entry.is_page() && !self.get_pd(pdpt_entry)[pd_idx].is_present() {
+                let frame = tables.pop().expect("Counted in missing_tables");
+                self.get_pd_mut(pdpt_entry)[pd_idx] =
+                    PDEntry::new(frame.base, PDFlags::P | PDFlags::RW | PDFlags::US);
+            }
+
+            cursor = cursor + LARGE_PAGE_SIZE;
+        }
+        debug_assert!(tables.is_empty());
+
+        Ok(())
+    }
+
+    /// Maps the base-page sized `frames` back to back, starting at `vbase`.
+    ///
+    /// Unlike `map_generic` for every frame, this only walks down from the
+    /// PML4 whenever we enter a new PT (at every 2 MiB boundary) and fills in
+    /// consecutive PT entries in between.
+    pub(crate) fn map_base_pages(
+        &mut self,
+        vbase: VAddr,
+        frames: &[Frame],
+        rights: MapAction,
+    ) -> Result<(), KError> {
+        assert!(vbase.is_base_page_aligned());
+
+        let mut idx = 0;
+        while idx < frames.len() {
+            let cursor = vbase + idx * BASE_PAGE_SIZE;
+            let pd_entry = self.get_or_alloc_pt(cursor)?;
+            let pt = self.get_pt_mut(pd_entry);
+            let pt_start = pt_index(cursor);
+            let run = core::cmp::min(pt.len() - pt_start, frames.len() - idx);
+
+            // Check the whole run first so we don't leave a half-filled PT behind
+            for (i, frame) in frames[idx..idx + run].iter().enumerate() {
+                debug_assert_eq!(frame.size(), BASE_PAGE_SIZE);
+                let entry = pt[pt_start + i];
+                if entry.is_present() {
+                    let cur_rights: MapAction = entry.flags().into();
+                    if entry.address() != frame.base || cur_rights != rights {
+                        return Err(KError::AlreadyMapped {
+                            base: cursor + i * BASE_PAGE_SIZE,
+                        });
+                    }
+                }
+            }
+            for (i, frame) in frames[idx..idx + run].iter().enumerate() {
+                pt[pt_start + i] = PTEntry::new(frame.base, PTFlags::P | rights.to_pt_rights());
+            }
+
+            idx += run;
+        }
+
+        Ok(())
+    }
+
     /// Check if we can just insert a huge page for the current mapping
     fn can_map_as_huge_page(
         &mut self,
@@ -808,22 +1038,54 @@ impl PageTable {
     }
 
     fn alloc_frame(&self) -> Frame {
-        use core::alloc::Allocator;
-        let frame_ptr = self.da.as_ref().map_or_else(
-            || unsafe {
-                let ptr = alloc::alloc::alloc(PT_LAYOUT);
-                debug_assert!(!ptr.is_null());
+        self.try_alloc_frame().expect("Can't allocate memory for page-table")
+    }
 
-                let nptr = NonNull::new_unchecked(ptr);
-                NonNull::slice_from_raw_parts(nptr, PT_LAYOUT.size())
-            },
-            |da| da.allocate(PT_LAYOUT).unwrap(),
-        );
+    /// Allocates a zeroed page for a page-table.
+    ///
+    /// Page-tables without a `DA` take it from the page-table frame pool of
+    /// the core (`Kcb::alloc_table_frame`).
+    pub(crate) fn try_alloc_frame(&self) -> Result<Frame, KError> {
+        use core::alloc::Allocator;
+        let frame_ptr = match self.da.as_ref() {
+            Some(da) => da.allocate(PT_LAYOUT)?,
+            None => {
+                let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
+                return kcb.alloc_table_frame();
+            }
+        };
         let vaddr = VAddr::from(frame_ptr.as_ptr() as *const u8 as u64);
         let paddr = crate::arch::memory::kernel_vaddr_to_paddr(vaddr);
         let mut frame = Frame::new(paddr, PT_LAYOUT.size(), 0);
         unsafe { frame.zero() };
-        frame
+        Ok(frame)
+    }
+
+    /// Gives back a page-table page (from `try_alloc_frame`) to the
+    /// allocator it came from.
+    fn free_frame(&self, frame: Frame) {
+        self.free_table(frame.base);
+    }
+
+    /// Gives back the page-table page at `paddr`, to the `DA` if the
+    /// page-table has one, otherwise to the page-table frame pool of the core
+    /// (that's where `try_alloc_frame` got it from).
+    fn free_table(&self, paddr: PAddr) {
+        use core::alloc::Allocator;
+        let vaddr = paddr_to_kernel_vaddr(paddr);
+        match self.da.as_ref() {
+            Some(da) => unsafe {
+                da.deallocate(NonNull::new_unchecked(vaddr.as_mut_ptr()), PT_LAYOUT)
+            },
+            None => {
+                let kcb = kcb::get_kcb();
+                let frame = Frame::new(paddr, BASE_PAGE_SIZE, kcb.physical_memory.affinity);
+                if kcb.free_table_frame(frame).is_err() {
+                    // Pool and TCache are full, `dealloc` knows where else to put it
+                    unsafe { alloc::alloc::dealloc(vaddr.as_mut_ptr(), PT_LAYOUT) };
+                }
+            }
+        }
     }
 
     fn new_pt(&self) -> PDEntry {
@@ -129,3 +129,215 @@ proptest! {
         }
     }
 }
+
+/// `map_anywhere` picks non-overlapping regions above `MAP_ANYWHERE_START`.
+#[test]
+fn map_anywhere() {
+    use crate::memory::detmem::DA;
+
+    let mut vspace =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Can't create vspace");
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+
+    // Occupy the start of the range so we need to skip over it
+    let fixed = Frame::new(PAddr::from(0x4000_0000), BASE_PAGE_SIZE, 0);
+    vspace
+        .map_frame(MAP_ANYWHERE_START, fixed, MapAction::ReadUser)
+        .expect("Can't map fixed frame");
+
+    let f1 = Frame::new(PAddr::from(0x4020_0000), LARGE_PAGE_SIZE, 0);
+    let f2 = Frame::new(PAddr::from(0x4040_0000), BASE_PAGE_SIZE, 0);
+    let b1 = vspace
+        .map_anywhere(f1, MapAction::ReadWriteUser)
+        .expect("Can't map f1");
+    let b2 = vspace
+        .map_anywhere(f2, MapAction::ReadWriteUser)
+        .expect("Can't map f2");
+
+    assert!(b1 > MAP_ANYWHERE_START && b2 > MAP_ANYWHERE_START);
+    assert_eq!(b1 % LARGE_PAGE_SIZE, 0);
+    let (r1, r2) = (
+        b1.as_usize()..b1.as_usize() + f1.size(),
+        b2.as_usize()..b2.as_usize() + f2.size(),
+    );
+    assert!(r1.end <= r2.start || r2.end <= r1.start);
+
+    assert_eq!(
+        vspace.resolve(b1 + 0x1000),
+        Ok((f1.base + 0x1000, MapAction::ReadWriteUser))
+    );
+    assert_eq!(vspace.resolve(b2), Ok((f2.base, MapAction::ReadWriteUser)));
+}
+
+/// `map_contiguous` ends up with the same page-table as mapping page by page.
+#[test]
+fn map_contiguous() {
+    use crate::memory::detmem::DA;
+
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+    let mut contiguous =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Can't create vspace");
+    let mut per_page =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Can't create vspace");
+
+    // Starts in the middle of a PT so we have to cross a 2 MiB boundary,
+    // backing frames are spread out so nothing could be a large-page
+    let base = VAddr::from(0x5_0010_0000usize);
+    let frames: Vec<Frame> = (0..512)
+        .map(|i| {
+            Frame::new(
+                PAddr::from(0x4000_0000 + i * 2 * BASE_PAGE_SIZE as u64),
+                BASE_PAGE_SIZE,
+                0,
+            )
+        })
+        .collect();
+
+    contiguous
+        .map_contiguous(base, &frames, MapAction::ReadWriteUser)
+        .expect("Can't map contiguous");
+    for (i, frame) in frames.iter().enumerate() {
+        per_page
+            .map_frame(base + i * BASE_PAGE_SIZE, *frame, MapAction::ReadWriteUser)
+            .expect("Can't map frame");
+    }
+
+    for i in 0..frames.len() {
+        let vaddr = base + i * BASE_PAGE_SIZE;
+        assert_eq!(
+            contiguous.resolve(vaddr),
+            Ok((frames[i].base, MapAction::ReadWriteUser))
+        );
+        assert_eq!(contiguous.resolve(vaddr), per_page.resolve(vaddr));
+    }
+    let end = base + frames.len() * BASE_PAGE_SIZE;
+    assert_eq!(contiguous.resolve(end), Err(KError::NotMapped));
+    assert_eq!(
+        contiguous.resolve(base - BASE_PAGE_SIZE),
+        Err(KError::NotMapped)
+    );
+    assert_eq!(contiguous.mappings.len(), per_page.mappings.len());
+
+    // Overlapping an existing mapping fails without touching anything
+    assert_eq!(
+        contiguous.map_contiguous(end - BASE_PAGE_SIZE, &frames[..2], MapAction::ReadUser),
+        Err(KError::AlreadyMapped {
+            base: end - BASE_PAGE_SIZE
+        })
+    );
+    assert_eq!(contiguous.resolve(end), Err(KError::NotMapped));
+    assert_eq!(contiguous.mappings.len(), frames.len());
+}
+
+/// If allocating a table fails, the tables allocated before it go back to
+/// the `DA` and the page-table is left as it was.
+#[test]
+fn reserve_tables_rollback() {
+    use crate::memory::detmem::DA;
+
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+    let mut vspace =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Can't create vspace");
+    let base = VAddr::from(0x5_3fe0_0000usize);
+    let size = LARGE_PAGE_SIZE + 2 * BASE_PAGE_SIZE;
+    let end = base.as_usize() + size;
+
+    let mut allocated = 0;
+    let reserved = vspace.page_table.reserve_tables_with(base, size, |pt| {
+        if allocated == 3 {
+            return Err(KError::OutOfMemory);
+        }
+        allocated += 1;
+        pt.try_alloc_frame()
+    });
+    assert_eq!(reserved, Err(KError::OutOfMemory));
+    assert_eq!(vspace.page_table.table_count(), 0);
+    assert_eq!(vspace.page_table.missing_tables(base, end), 5);
+
+    vspace
+        .reserve_tables(base, size)
+        .expect("Can't reserve tables");
+    assert_eq!(vspace.page_table.table_count(), 5);
+}
+
+#[test]
+fn reserve_tables() {
+    use crate::memory::detmem::DA;
+
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+    let mut vspace =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Can't create vspace");
+
+    // Crosses a 1 GiB boundary so we need a PDPT, two PDs and two PTs
+    let base = VAddr::from(0x5_3fe0_0000usize);
+    let size = LARGE_PAGE_SIZE + 2 * BASE_PAGE_SIZE;
+    let end = base.as_usize() + size;
+    assert_eq!(vspace.page_table.missing_tables(base, end), 5);
+
+    vspace
+        .reserve_tables(base, size)
+        .expect("Can't reserve tables");
+    assert_eq!(vspace.page_table.missing_tables(base, end), 0);
+    assert_eq!(vspace.page_table.table_count(), 5);
+    // Reserving again is a no-op
+    vspace
+        .reserve_tables(base, size)
+        .expect("Can't reserve tables");
+    assert_eq!(vspace.page_table.table_count(), 5);
+
+    // Mapping the range doesn't allocate any more tables
+    let frames: Vec<Frame> = (0..size / BASE_PAGE_SIZE)
+        .map(|i| {
+            Frame::new(
+                PAddr::from(0x4000_0000 + (i * 2 * BASE_PAGE_SIZE) as u64),
+                BASE_PAGE_SIZE,
+                0,
+            )
+        })
+        .collect();
+    vspace
+        .map_contiguous(base, &frames, MapAction::ReadWriteUser)
+        .expect("Can't map");
+    assert_eq!(vspace.page_table.table_count(), 5);
+
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(
+            vspace.resolve(base + i * BASE_PAGE_SIZE),
+            Ok((frame.base, MapAction::ReadWriteUser))
+        );
+    }
+}
+
+/// Page-tables without a `DA` take their tables from the page-table frame
+/// pool and give them back to it when they're dropped.
+#[test]
+fn table_frames_from_pool() {
+    use crate::memory::pool::MAX_TABLE_FRAMES;
+    use alloc::boxed::Box;
+    use x86::bits64::paging::{PML4Entry, PML4Flags, PAGE_SIZE_ENTRIES};
+
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+    let kcb = crate::kcb::get_kcb();
+    let pooled = kcb.table_frames.borrow().len();
+
+    let pml4 = Box::try_new(
+        [PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES],
+    )
+    .expect("Can't allocate PML4");
+    let mut page_table = PageTable {
+        pml4: Box::into_pin(pml4),
+        da: None,
+    };
+    let base = VAddr::from(0x5_3fe0_0000usize);
+    page_table
+        .reserve_tables(base, LARGE_PAGE_SIZE + 2 * BASE_PAGE_SIZE)
+        .expect("Can't reserve tables");
+    assert_eq!(page_table.table_count(), 5);
+    assert_eq!(kcb.table_frames.borrow().len(), pooled.saturating_sub(5));
+
+    drop(page_table);
+    assert_eq!(
+        kcb.table_frames.borrow().len(),
+        core::cmp::min(core::cmp::max(pooled, 5), MAX_TABLE_FRAMES)
+    );
+}
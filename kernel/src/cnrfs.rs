@@ -6,14 +6,16 @@ use crate::error::KError;
 use crate::fs::fd::FileDesc;
 use crate::fs::{
     Buffer, FileDescriptor, FileSystem, Filename, Flags, Len, MlnrFS, Mnode, Modes, NrLock, Offset,
-    FD, MNODE_OFFSET,
+    FD, MNODE_OFFSET, READ_AHEAD_SIZE,
 };
 use crate::memory::VAddr;
 use crate::prelude::*;
 use crate::process::{userptr_to_str, KernSlice, Pid};
 
+use alloc::borrow::Cow;
 use alloc::sync::Arc;
 use cnr::{Dispatch, LogMapper};
+use core::sync::atomic::{AtomicU64, Ordering};
 use hashbrown::HashMap;
 use kpi::io::*;
 use kpi::FileOperation;
@@ -23,15 +25,41 @@ pub struct MlnrKernelNode {
     /// perform read() on lock. Make an array of hashmaps to distribute the
     /// load evenly for file-open benchmarks.
     process_map: NrLock<HashMap<Pid, FileDesc>>,
+    /// Current working directory of a process (absolute path), relative
+    /// paths are resolved against it. Processes without an entry are in `/`.
+    ///
+    /// Lock order: `process_map` before `cwds` before `mounts`.
+    cwds: NrLock<HashMap<Pid, String>>,
     /// MLNR kernel node primarily replicates the in-memory filesystem.
+    ///
+    /// This is the root filesystem, it owns every path that's not below
+    /// one of the `mounts`.
     fs: MlnrFS,
+    /// Filesystems mounted below the root (mount point, filesystem).
+    ///
+    /// Lock order: `process_map` before `mounts`.
+    mounts: NrLock<HashMap<MountId, (String, MlnrFS)>>,
+    /// Next id to hand out in `Modify::Mount`.
+    next_mount_id: AtomicU64,
 }
 
+/// Identifies a mounted filesystem, the root filesystem is `ROOT_MOUNT`.
+type MountId = u64;
+
+const ROOT_MOUNT: MountId = 0;
+
+/// Every filesystem hands out its own mnode numbers, so we store the
+/// `MountId` in the upper bits of the mnodes exposed by the kernel node.
+const MOUNT_ID_SHIFT: u64 = 48;
+
 impl Default for MlnrKernelNode {
     fn default() -> Self {
         MlnrKernelNode {
             process_map: NrLock::<HashMap<Pid, FileDesc>>::default(),
+            cwds: NrLock::<HashMap<Pid, String>>::default(),
             fs: MlnrFS::default(),
+            mounts: NrLock::<HashMap<MountId, (String, MlnrFS)>>::default(),
+            next_mount_id: AtomicU64::new(ROOT_MOUNT + 1),
         }
     }
 }
@@ -40,12 +68,22 @@ impl Default for MlnrKernelNode {
 pub enum Modify {
     ProcessAdd(Pid),
     ProcessRemove(Pid),
-    FileOpen(Pid, String, Flags, Modes),
+    FileOpen(Pid, String, Flags, Modes, Len),
     FileWrite(Pid, FD, Mnode, Arc<[u8]>, Len, Offset),
+    FileWriteV(Pid, FD, Mnode, Vec<Arc<[u8]>>, Offset),
     FileClose(Pid, FD),
     FileDelete(Pid, String),
-    FileRename(Pid, String, String),
+    FileRename(Pid, String, String, Flags),
     MkDir(Pid, String, Modes),
+    RmDir(Pid, String),
+    FileOpenByMnode(Pid, Mnode, Flags),
+    Mount(Pid, String),
+    Unmount(Pid, String),
+    ChDir(Pid, String),
+    FileAdvise(Pid, FD, FileAdvice),
+    FileChmod(Pid, String, Modes),
+    FileLock(Pid, FD, LockOp),
+    FileCompareAndWrite(Pid, FD, Mnode, Arc<[u8]>, Arc<[u8]>, u64),
 }
 
 // TODO: Stateless op to log mapping. Maintain some state for correct redirection.
@@ -56,14 +94,28 @@ impl LogMapper for Modify {
         match self {
             Modify::ProcessAdd(_pid) => push_to_all(nlogs, logs),
             Modify::ProcessRemove(_pid) => push_to_all(nlogs, logs),
-            Modify::FileOpen(_pid, _filename, _flags, _modes) => push_to_all(nlogs, logs),
+            Modify::FileOpen(_pid, _filename, _flags, _modes, _size) => push_to_all(nlogs, logs),
             Modify::FileWrite(_pid, _fd, mnode, _kernslice, _len, _offset) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
+            Modify::FileWriteV(_pid, _fd, mnode, _buffers, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
             Modify::FileClose(_pid, _fd) => push_to_all(nlogs, logs),
             Modify::FileDelete(_pid, _filename) => push_to_all(nlogs, logs),
-            Modify::FileRename(_pid, _oldname, _newname) => push_to_all(nlogs, logs),
+            Modify::FileRename(_pid, _oldname, _newname, _flags) => push_to_all(nlogs, logs),
             Modify::MkDir(_pid, _name, _modes) => push_to_all(nlogs, logs),
+            Modify::RmDir(_pid, _name) => push_to_all(nlogs, logs),
+            Modify::FileOpenByMnode(_pid, _mnode, _flags) => push_to_all(nlogs, logs),
+            Modify::Mount(_pid, _prefix) => push_to_all(nlogs, logs),
+            Modify::Unmount(_pid, _prefix) => push_to_all(nlogs, logs),
+            Modify::ChDir(_pid, _path) => push_to_all(nlogs, logs),
+            Modify::FileAdvise(_pid, _fd, _advice) => push_to_all(nlogs, logs),
+            Modify::FileChmod(_pid, _filename, _modes) => push_to_all(nlogs, logs),
+            Modify::FileLock(_pid, _fd, _op) => push_to_all(nlogs, logs),
+            Modify::FileCompareAndWrite(_pid, _fd, mnode, _expected, _new, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
         }
 
         fn push_to_all(nlogs: usize, logs: &mut Vec<usize>) {
@@ -77,9 +129,14 @@ impl LogMapper for Modify {
 #[derive(Hash, Clone, Debug, PartialEq)]
 pub enum Access {
     FileRead(Pid, FD, Mnode, Buffer, Len, Offset),
+    FileReadV(Pid, FD, Mnode, Vec<IoVec>, Offset),
     FileInfo(Pid, Filename, Mnode, u64),
     FdToMnode(Pid, FD),
     FileNameToMnode(Pid, Filename),
+    SeekData(Pid, FD, Mnode, u64),
+    SeekHole(Pid, FD, Mnode, u64),
+    GetCwd(Pid),
+    SyncAll(Pid),
     Synchronize(usize),
 }
 
@@ -92,12 +149,23 @@ impl LogMapper for Access {
             Access::FileRead(_pid, _fd, mnode, _buffer, _len, _offser) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
+            Access::FileReadV(_pid, _fd, mnode, _iovecs, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
             Access::FileInfo(_pid, _filename, mnode, _info_ptr) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
+            Access::SeekData(_pid, _fd, mnode, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
+            Access::SeekHole(_pid, _fd, mnode, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
             // TODO: Assume that all metadata modifying operations go through log 0.
             Access::FdToMnode(_pid, _fd) => logs.push(0),
             Access::FileNameToMnode(_pid, _filename) => logs.push(0),
+            Access::GetCwd(_pid) => logs.push(0),
+            Access::SyncAll(_pid) => logs.push(0),
             // Log number start with 1 in CNR, however, replica uses mod
             // operation which starts with 0; hence `log_id - 1`.
             Access::Synchronize(log_id) => logs.push((*log_id - 1) % nlogs),
@@ -109,14 +177,29 @@ impl LogMapper for Access {
 pub enum MlnrNodeResult {
     ProcessAdded(Pid),
     ProcessRemoved(Pid),
-    FileOpened(FD),
+    /// `created` is set if the open created the file.
+    FileOpened {
+        fd: FD,
+        created: bool,
+    },
     FileAccessed(Len),
     FileClosed(u64),
     FileDeleted,
     FileInfo(FileInfo),
     FileRenamed,
     DirCreated,
+    DirDeleted,
+    Mounted,
+    Unmounted,
     MappedFileToMnode(u64),
+    FileSeeked(u64),
+    DirChanged,
+    FileAdvised,
+    ModeChanged,
+    FileLocked,
+    /// Whether the file was written.
+    FileCompared(bool),
+    Cwd(String),
     Synchronized,
 }
 
@@ -139,17 +222,49 @@ impl MlnrKernelNode {
     }
 
     pub fn map_fd(pid: Pid, pathname: u64, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
+        MlnrKernelNode::map_fd_with_size(pid, pathname, flags, modes, 0)
+    }
+
+    /// Open a file, if the file gets created it starts out with `size`
+    /// (zeroed) bytes. The size is ignored if the file exists already.
+    pub fn map_fd_with_size(
+        pid: Pid,
+        pathname: u64,
+        flags: u64,
+        modes: u64,
+        size: u64,
+    ) -> Result<(FD, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(pathname)?;
+                let response = replica.execute_mut_scan(
+                    Modify::FileOpen(pid, filename, flags, modes, size),
+                    *token,
+                );
+
+                match response {
+                    Ok(MlnrNodeResult::FileOpened { fd, created }) => Ok((fd, created as u64)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Open a file by its mnode number (instead of resolving a path).
+    pub fn map_fd_by_mnode(pid: Pid, mnode: Mnode, flags: u64) -> Result<(FD, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let response =
-                    replica.execute_mut_scan(Modify::FileOpen(pid, filename, flags, modes), *token);
+                    replica.execute_mut_scan(Modify::FileOpenByMnode(pid, mnode, flags), *token);
 
                 match response {
-                    Ok(MlnrNodeResult::FileOpened(fd)) => Ok((fd, 0)),
+                    Ok(MlnrNodeResult::FileOpened { fd, .. }) => Ok((fd, 0)),
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -204,6 +319,118 @@ impl MlnrKernelNode {
         )
     }
 
+    /// Vectored read or write (`ReadV`/`WriteV`) of the file behind `fd`.
+    ///
+    /// The buffers in `iovecs` have to be valid user-space memory.
+    pub fn file_io_v(
+        op: FileOperation,
+        pid: Pid,
+        fd: u64,
+        iovecs: Vec<IoVec>,
+        offset: i64,
+    ) -> Result<(Len, u64), KError> {
+        let mnode = match MlnrKernelNode::fd_to_mnode(pid, fd) {
+            Ok((mnode, _)) => mnode,
+            Err(_) => return Err(KError::InvalidFileDescriptor),
+        };
+        let kcb = super::kcb::get_kcb();
+        kcb.arch.cnr_replica.as_ref().map_or(
+            Err(KError::ReplicaNotSet),
+            |(replica, token)| match op {
+                FileOperation::WriteV => {
+                    let mut buffers = Vec::new();
+                    buffers.try_reserve(iovecs.len())?;
+                    for iovec in iovecs.iter() {
+                        buffers.push(KernSlice::new(iovec.base, iovec.len as usize).buffer);
+                    }
+
+                    let response = replica
+                        .execute_mut(Modify::FileWriteV(pid, fd, mnode, buffers, offset), *token);
+
+                    match response {
+                        Ok(MlnrNodeResult::FileAccessed(len)) => Ok((len, 0)),
+                        Err(e) => Err(e),
+                        Ok(_) => unreachable!("Got unexpected response"),
+                    }
+                }
+
+                FileOperation::ReadV => {
+                    let response =
+                        replica.execute(Access::FileReadV(pid, fd, mnode, iovecs, offset), *token);
+
+                    match response {
+                        Ok(MlnrNodeResult::FileAccessed(len)) => Ok((len, 0)),
+                        Err(e) => Err(e),
+                        Ok(_) => unreachable!("Got unexpected response"),
+                    }
+                }
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    /// Writes the contents of `new` at `offset` of the file behind `fd`, but
+    /// only if the file holds the contents of `expected` there.
+    ///
+    /// Both buffers have to be valid user-space memory. Returns 1 if the
+    /// file was written and 0 if its contents didn't match.
+    pub fn file_compare_and_write(
+        pid: Pid,
+        fd: u64,
+        expected: IoVec,
+        new: IoVec,
+        offset: u64,
+    ) -> Result<(u64, u64), KError> {
+        let mnode = match MlnrKernelNode::fd_to_mnode(pid, fd) {
+            Ok((mnode, _)) => mnode,
+            Err(_) => return Err(KError::InvalidFileDescriptor),
+        };
+        let expected = KernSlice::new(expected.base, expected.len as usize).buffer;
+        let new = KernSlice::new(new.base, new.len as usize).buffer;
+
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let op = Modify::FileCompareAndWrite(pid, fd, mnode, expected, new, offset);
+                let response = replica.execute_mut(op, *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileCompared(written)) => Ok((written as u64, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Finds the next data (`data` is true) or hole offset in the file
+    /// behind `fd`, starting at `offset`.
+    pub fn file_seek(pid: Pid, fd: u64, offset: u64, data: bool) -> Result<(u64, u64), KError> {
+        let mnode = match MlnrKernelNode::fd_to_mnode(pid, fd) {
+            Ok((mnode, _)) => mnode,
+            Err(_) => return Err(KError::InvalidFileDescriptor),
+        };
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let op = if data {
+                    Access::SeekData(pid, fd, mnode, offset)
+                } else {
+                    Access::SeekHole(pid, fd, mnode, offset)
+                };
+                let response = replica.execute(op, *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileSeeked(offset)) => Ok((offset, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn unmap_fd(pid: Pid, fd: u64) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -263,7 +490,12 @@ impl MlnrKernelNode {
             })
     }
 
-    pub fn file_rename(pid: Pid, oldname: u64, newname: u64) -> Result<(u64, u64), KError> {
+    pub fn file_rename(
+        pid: Pid,
+        oldname: u64,
+        newname: u64,
+        flags: Flags,
+    ) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
             .cnr_replica
@@ -272,8 +504,8 @@ impl MlnrKernelNode {
                 let oldfilename = userptr_to_str(oldname)?;
                 let newfilename = userptr_to_str(newname)?;
 
-                let response = replica
-                    .execute_mut_scan(Modify::FileRename(pid, oldfilename, newfilename), *token);
+                let op = Modify::FileRename(pid, oldfilename, newfilename, flags);
+                let response = replica.execute_mut_scan(op, *token);
                 match response {
                     Ok(MlnrNodeResult::FileRenamed) => Ok((0, 0)),
                     Err(e) => Err(e),
@@ -300,6 +532,155 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn rmdir(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let filename = userptr_to_str(pathname)?;
+                let response = replica.execute_mut_scan(Modify::RmDir(pid, filename), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::DirDeleted) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    pub fn mount(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let prefix = userptr_to_str(pathname)?;
+                let response = replica.execute_mut_scan(Modify::Mount(pid, prefix), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Mounted) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    pub fn unmount(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let prefix = userptr_to_str(pathname)?;
+                let response = replica.execute_mut_scan(Modify::Unmount(pid, prefix), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Unmounted) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    pub fn chdir(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let path = userptr_to_str(pathname)?;
+                let response = replica.execute_mut_scan(Modify::ChDir(pid, path), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::DirChanged) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Changes the modes of the file at `pathname`.
+    pub fn chmod(pid: Pid, pathname: u64, modes: Modes) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let filename = userptr_to_str(pathname)?;
+                let response =
+                    replica.execute_mut_scan(Modify::FileChmod(pid, filename, modes), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::ModeChanged) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Sets the access pattern hint of `fd`, this decides whether reads on
+    /// it read ahead.
+    pub fn file_advise(pid: Pid, fd: u64, advice: FileAdvice) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut_scan(Modify::FileAdvise(pid, fd, advice), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileAdvised) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Takes or releases an advisory lock on the file behind `fd` (the
+    /// process is the owner of the lock).
+    pub fn file_lock(pid: Pid, fd: u64, op: LockOp) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::FileLock(pid, fd, op), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileLocked) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Copies the current working directory of `pid` (not NUL-terminated)
+    /// into the user buffer at `buffer` and returns its length.
+    pub fn getcwd(pid: Pid, buffer: u64, len: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::GetCwd(pid), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Cwd(cwd)) => {
+                        if cwd.len() > len as usize {
+                            return Err(KError::InvalidLength);
+                        }
+                        let mut user_slice = UserSlice::new(buffer, cwd.len());
+                        let copied = user_slice.copy_from(cwd.as_bytes())?;
+                        Ok((copied as u64, 0))
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     #[inline(always)]
     pub fn fd_to_mnode(pid: Pid, fd: FD) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
@@ -334,6 +715,22 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Flushes all filesystems (see `MlnrFS::sync_all`).
+    pub fn sync_all(pid: Pid) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::SyncAll(pid), *token);
+                match response {
+                    Ok(MlnrNodeResult::Synchronized) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn synchronize_log(log_id: usize) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -350,6 +747,164 @@ impl MlnrKernelNode {
     }
 }
 
+impl MlnrKernelNode {
+    /// Finds the filesystem that owns `path`.
+    ///
+    /// Returns the mount id, the filesystem and `path` relative to the
+    /// mount point (the longest mount point that matches wins).
+    fn resolve<'a>(
+        &'a self,
+        mounts: &'a HashMap<MountId, (String, MlnrFS)>,
+        path: &'a str,
+    ) -> (MountId, &'a MlnrFS, &'a str) {
+        let mut owner: Option<(MountId, &'a MlnrFS, usize)> = None;
+        for (id, (prefix, fs)) in mounts.iter() {
+            let is_below = path.starts_with(prefix.as_str())
+                && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'));
+            if is_below && owner.map_or(true, |(_, _, len)| prefix.len() > len) {
+                owner = Some((*id, fs, prefix.len()));
+            }
+        }
+
+        match owner {
+            Some((id, fs, len)) if len == path.len() => (id, fs, "/"),
+            Some((id, fs, len)) => (id, fs, &path[len..]),
+            None => (ROOT_MOUNT, &self.fs, path),
+        }
+    }
+
+    /// Turns `path` into an absolute path.
+    ///
+    /// Relative paths are resolved against the current working directory of
+    /// `pid` (`.` and `..` components are folded in), absolute paths are
+    /// returned as is.
+    fn absolute_path<'a>(&self, pid: Pid, path: &'a str) -> Cow<'a, str> {
+        if path.starts_with('/') {
+            return Cow::Borrowed(path);
+        }
+
+        let cwds = self.cwds.read();
+        let cwd = cwds.get(&pid).map_or("/", |cwd| cwd.as_str());
+        let mut absolute = String::with_capacity(cwd.len() + path.len() + 1);
+        for component in cwd.split('/').chain(path.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    let parent = absolute.rfind('/').unwrap_or(0);
+                    absolute.truncate(parent);
+                }
+                name => {
+                    absolute.push('/');
+                    absolute.push_str(name);
+                }
+            }
+        }
+        if absolute.is_empty() {
+            absolute.push('/');
+        }
+
+        Cow::Owned(absolute)
+    }
+
+    /// Finds the filesystem an mnode (as handed out by `global_mnode`)
+    /// belongs to and returns it together with the filesystem local mnode.
+    fn resolve_mnode<'a>(
+        &'a self,
+        mounts: &'a HashMap<MountId, (String, MlnrFS)>,
+        mnode: Mnode,
+    ) -> Result<(&'a MlnrFS, Mnode), KError> {
+        let id = mnode >> MOUNT_ID_SHIFT;
+        let local = mnode & ((1 << MOUNT_ID_SHIFT) - 1);
+        if id == ROOT_MOUNT {
+            Ok((&self.fs, local))
+        } else {
+            // The filesystem may have been unmounted in the mean-time
+            let (_prefix, fs) = mounts.get(&id).ok_or(KError::InvalidFile)?;
+            Ok((fs, local))
+        }
+    }
+
+    /// Handles `Modify::FileOpen` with `O_TMPFILE`: creates a file without a
+    /// name in the filesystem of the directory `path`.
+    ///
+    /// The file is gone once the last fd that refers to it is closed.
+    fn open_anonymous(
+        &self,
+        pid: Pid,
+        path: &str,
+        flags: FileFlags,
+        modes: Modes,
+    ) -> Result<MlnrNodeResult, KError> {
+        // Nobody could ever write to the file otherwise
+        if !flags.is_write() {
+            return Err(KError::InvalidFlags);
+        }
+
+        let mut pmap = self.process_map.write();
+        let path = self.absolute_path(pid, path);
+        let mounts = self.mounts.read();
+        let (id, fs, local) = self.resolve(&mounts, &path);
+        let dir = fs.lookup(local).ok_or(KError::InvalidFile)?;
+        if fs.file_info(*dir).ftype != FileType::Directory.into() {
+            return Err(KError::NotDirectory);
+        }
+
+        let p = pmap.get_mut(&pid).ok_or(KError::NoProcessFoundForPid)?;
+        let (fid, fd) = p.allocate_fd().ok_or(KError::NotSupported)?;
+        match fs.create_anonymous(modes) {
+            Ok(mnode) => {
+                fd.update_fd(MlnrKernelNode::global_mnode(id, mnode), flags);
+                Ok(MlnrNodeResult::FileOpened {
+                    fd: fid,
+                    created: true,
+                })
+            }
+            Err(e) => {
+                p.deallocate_fd(fid as usize)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes `mnode` if it's a file without a name (see `open_anonymous`)
+    /// and no fd in `pmap` refers to it anymore.
+    fn release_if_unreferenced(&self, pmap: &HashMap<Pid, FileDesc>, mnode: Mnode) {
+        let mounts = self.mounts.read();
+        if let Ok((fs, local)) = self.resolve_mnode(&mounts, mnode) {
+            if fs.is_anonymous(local)
+                && !pmap.values().any(|fdesc| fdesc.mnodes().any(|m| m == mnode))
+            {
+                fs.release_anonymous(local);
+            }
+        }
+    }
+
+    /// Handles `Access::SeekData` (`data` is true) and `Access::SeekHole`.
+    fn seek(&self, pid: Pid, fd: FD, offset: u64, data: bool) -> Result<MlnrNodeResult, KError> {
+        let process_lookup = self.process_map.read();
+        let p = process_lookup
+            .get(&pid)
+            .ok_or(KError::NoProcessFoundForPid)?;
+        let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+
+        let mounts = self.mounts.read();
+        let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
+        let found = if data {
+            fs.seek_data(mnode_num, offset as usize)
+        } else {
+            fs.seek_hole(mnode_num, offset as usize)
+        };
+        // Like ENXIO: offset is past the end of the file or there is no more data
+        let found = found.ok_or(KError::InvalidOffset)?;
+        Ok(MlnrNodeResult::FileSeeked(found as u64))
+    }
+
+    /// Turns the mnode of the filesystem with `id` into a kernel node mnode.
+    fn global_mnode(id: MountId, mnode: Mnode) -> Mnode {
+        (id << MOUNT_ID_SHIFT) | mnode
+    }
+}
+
 impl Dispatch for MlnrKernelNode {
     type ReadOperation = Access;
     type WriteOperation = Modify;
@@ -381,19 +936,61 @@ impl Dispatch for MlnrKernelNode {
                     curr_offset = fd.get_offset();
                 }
 
-                match self.fs.read(mnode_num, &mut userslice, curr_offset) {
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, mnode_num)?;
+                match fs.read(mnode_num, &mut userslice, curr_offset) {
                     Ok(len) => {
                         // Update the FD associated offset only when the
                         // offset wasn't given in the arguments.
                         if offset == -1 {
                             fd.update_offset(curr_offset + len);
                         }
+                        fd.record_read(curr_offset, len);
+                        if fd.read_ahead() {
+                            fs.read_ahead(mnode_num, curr_offset + len, READ_AHEAD_SIZE);
+                        }
                         Ok(MlnrNodeResult::FileAccessed(len as u64))
                     }
                     Err(e) => Err(e),
                 }
             }
 
+            Access::FileReadV(pid, fd, _mnode, iovecs, offset) => {
+                let mut userslices = Vec::new();
+                userslices.try_reserve(iovecs.len())?;
+                for iovec in iovecs.iter() {
+                    userslices.push(UserSlice::new(iovec.base, iovec.len as usize));
+                }
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+                let flags = fd.get_flags();
+                if !flags.is_read() {
+                    return Err(KError::PermissionError);
+                }
+
+                let curr_offset = if offset == -1 {
+                    fd.get_offset()
+                } else {
+                    offset as usize
+                };
+
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
+                let len = fs.readv(mnode_num, &mut userslices, curr_offset)?;
+                if offset == -1 {
+                    fd.update_offset(curr_offset + len);
+                }
+                fd.record_read(curr_offset, len);
+                if fd.read_ahead() {
+                    fs.read_ahead(mnode_num, curr_offset + len, READ_AHEAD_SIZE);
+                }
+                Ok(MlnrNodeResult::FileAccessed(len as u64))
+            }
+
             Access::FileInfo(pid, name, _mnode, _info_ptr) => {
                 let _p = self
                     .process_map
@@ -402,9 +999,12 @@ impl Dispatch for MlnrKernelNode {
                     .ok_or(KError::NoProcessFoundForPid)?;
 
                 let filename = userptr_to_str(name)?;
-                let mnode = self.fs.lookup(&filename).ok_or(KError::InvalidFile)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (_id, fs, path) = self.resolve(&mounts, &filename);
+                let mnode = fs.lookup(path).ok_or(KError::InvalidFile)?;
 
-                let f_info = self.fs.file_info(*mnode);
+                let f_info = fs.file_info(*mnode);
                 Ok(MlnrNodeResult::FileInfo(f_info))
             }
 
@@ -427,14 +1027,50 @@ impl Dispatch for MlnrKernelNode {
                     .ok_or(KError::NoProcessFoundForPid)?;
 
                 let filename = userptr_to_str(name)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (id, fs, path) = self.resolve(&mounts, &filename);
 
-                match self.fs.lookup(&filename) {
+                match fs.lookup(path) {
                     // match on (file_exists, mnode_number)
-                    Some(mnode) => Ok(MlnrNodeResult::MappedFileToMnode(*mnode)),
+                    Some(mnode) => Ok(MlnrNodeResult::MappedFileToMnode(
+                        MlnrKernelNode::global_mnode(id, *mnode),
+                    )),
                     None => Err(KError::InvalidFile),
                 }
             }
 
+            Access::SeekData(pid, fd, _mnode, offset) => self.seek(pid, fd, offset, true),
+
+            Access::SeekHole(pid, fd, _mnode, offset) => self.seek(pid, fd, offset, false),
+
+            Access::GetCwd(pid) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let cwd = self
+                    .cwds
+                    .read()
+                    .get(&pid)
+                    .map_or_else(|| "/".to_string(), |cwd| cwd.clone());
+                Ok(MlnrNodeResult::Cwd(cwd))
+            }
+
+            Access::SyncAll(pid) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                self.fs.sync_all()?;
+                for (_prefix, fs) in self.mounts.read().values() {
+                    fs.sync_all()?;
+                }
+                Ok(MlnrNodeResult::Synchronized)
+            }
+
             Access::Synchronize(_log_id) => {
                 // A NOP that just makes sure we've advanced the replica
                 Ok(MlnrNodeResult::Synchronized)
@@ -454,36 +1090,58 @@ impl Dispatch for MlnrKernelNode {
 
             Modify::ProcessRemove(pid) => {
                 let mut pmap = self.process_map.write();
-                let _file_desc = pmap.remove(&pid).ok_or(KError::NoFileDescForPid)?;
+                let mut file_desc = pmap.remove(&pid).ok_or(KError::NoFileDescForPid)?;
+                let mut mnodes = Vec::new();
+                mnodes.try_reserve(file_desc.open_count())?;
+                mnodes.extend(file_desc.mnodes());
+                let _closed = file_desc.close_all();
+                for mnode in mnodes {
+                    self.release_if_unreferenced(&pmap, mnode);
+                }
+                self.cwds.write().remove(&pid);
+                self.fs.release_locks(pid as u64);
+                for (_prefix, fs) in self.mounts.read().values() {
+                    fs.release_locks(pid as u64);
+                }
                 Ok(MlnrNodeResult::ProcessRemoved(pid))
             }
 
-            Modify::FileOpen(pid, filename, flags, modes) => {
+            Modify::FileOpen(pid, filename, flags, modes, size) => {
                 let flags = FileFlags::from(flags);
-                let mnode = self.fs.lookup(&filename);
+                if flags.is_tmpfile() {
+                    return self.open_anonymous(pid, &filename, flags, modes);
+                }
+                let mut pmap = self.process_map.write();
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (id, fs, path) = self.resolve(&mounts, &filename);
+                let mnode = fs.lookup(path);
                 if mnode.is_none() && !flags.is_create() {
                     return Err(KError::PermissionError);
                 }
+                if mnode.is_none() && flags.is_directory() {
+                    // We'd create a file, not a directory
+                    return Err(KError::InvalidFlags);
+                }
 
-                let mut pmap = self.process_map.write();
                 let p = pmap
                     .get_mut(&pid)
                     .expect("TODO: FileOpen process lookup failed");
                 let (fid, fd) = p.allocate_fd().ok_or(KError::NotSupported)?;
 
                 let mnode_num;
+                let created = mnode.is_none();
                 if let Some(mnode) = mnode {
-                    // File exists and FileOpen is called with O_TRUNC flag.
-                    if flags.is_truncate() {
-                        if let Err(e) = self.fs.truncate(&filename) {
-                            let fdesc = fid as usize;
-                            pmap.get_mut(&pid).unwrap().deallocate_fd(fdesc)?;
-                            return Err(e);
-                        }
+                    // File exists, truncates it in case of O_TRUNC and refuses
+                    // to open directories for writing.
+                    if let Err(e) = fs.open_by_mnode(*mnode, flags.into()) {
+                        let fdesc = fid as usize;
+                        pmap.get_mut(&pid).unwrap().deallocate_fd(fdesc)?;
+                        return Err(e);
                     }
                     mnode_num = *mnode;
                 } else {
-                    match self.fs.create(&filename, modes) {
+                    match fs.create_with_size(path, modes, size as usize) {
                         Ok(m_num) => mnode_num = m_num,
                         Err(e) => {
                             let fdesc = fid as usize;
@@ -493,8 +1151,29 @@ impl Dispatch for MlnrKernelNode {
                     }
                 }
 
-                fd.update_fd(mnode_num, flags);
-                Ok(MlnrNodeResult::FileOpened(fid))
+                fd.update_fd(MlnrKernelNode::global_mnode(id, mnode_num), flags);
+                Ok(MlnrNodeResult::FileOpened { fd: fid, created })
+            }
+
+            Modify::FileOpenByMnode(pid, mnode, flags) => {
+                let mut pmap = self.process_map.write();
+                let p = pmap.get_mut(&pid).ok_or(KError::NoProcessFoundForPid)?;
+                let (fid, fd) = p.allocate_fd().ok_or(KError::NotSupported)?;
+
+                let mounts = self.mounts.read();
+                let opened = self
+                    .resolve_mnode(&mounts, mnode)
+                    .and_then(|(fs, local)| fs.open_by_mnode(local, flags));
+                if let Err(e) = opened {
+                    p.deallocate_fd(fid as usize)?;
+                    return Err(e);
+                }
+
+                fd.update_fd(mnode, FileFlags::from(flags));
+                Ok(MlnrNodeResult::FileOpened {
+                    fd: fid,
+                    created: false,
+                })
             }
 
             Modify::FileWrite(pid, fd, _mnode, kernslice, _len, offset) => {
@@ -504,8 +1183,9 @@ impl Dispatch for MlnrKernelNode {
                     .expect("TODO: FileWrite process lookup failed");
                 let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
 
-                let mnode_num = fd.get_mnode();
                 let flags = fd.get_flags();
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
 
                 // Check if the file has write-only or read-write permissions before reading it.
                 if !flags.is_write() {
@@ -516,7 +1196,7 @@ impl Dispatch for MlnrKernelNode {
                 if offset == -1 {
                     if flags.is_append() {
                         // If offset value is not provided and file is opened with O_APPEND flag.
-                        let finfo = self.fs.file_info(mnode_num);
+                        let finfo = fs.file_info(mnode_num);
                         curr_offset = finfo.fsize as usize;
                     } else {
                         // If offset value is not provided and file is doesn't have O_APPEND flag.
@@ -524,7 +1204,7 @@ impl Dispatch for MlnrKernelNode {
                     }
                 }
 
-                match self.fs.write(mnode_num, &kernslice, curr_offset) {
+                match fs.write(mnode_num, &kernslice, curr_offset) {
                     Ok(len) => {
                         if offset == -1 {
                             // Update offset when FileWrite doesn't give an explicit offset value.
@@ -536,12 +1216,48 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
+            Modify::FileWriteV(pid, fd, _mnode, buffers, offset) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+
+                let flags = fd.get_flags();
+                if !flags.is_write() {
+                    return Err(KError::PermissionError);
+                }
+
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
+                let curr_offset = if offset != -1 {
+                    offset as usize
+                } else if flags.is_append() {
+                    fs.file_info(mnode_num).fsize as usize
+                } else {
+                    fd.get_offset()
+                };
+
+                let mut iovecs: Vec<&[u8]> = Vec::new();
+                iovecs.try_reserve(buffers.len())?;
+                iovecs.extend(buffers.iter().map(|buffer| &buffer[..]));
+                let len = fs.writev(mnode_num, &iovecs, curr_offset)?;
+                if offset == -1 {
+                    fd.update_offset(curr_offset + len);
+                }
+                Ok(MlnrNodeResult::FileAccessed(len as u64))
+            }
+
             Modify::FileClose(pid, fd) => {
                 let mut process_lookup = self.process_map.write();
                 let p = process_lookup
                     .get_mut(&pid)
                     .expect("TODO: FileClose process lookup failed");
+                let mnode = p.get_fd(fd as usize).map(|fd| fd.get_mnode());
                 p.deallocate_fd(fd as usize)?;
+                if let Some(mnode) = mnode {
+                    self.release_if_unreferenced(&process_lookup, mnode);
+                }
                 Ok(MlnrNodeResult::FileClosed(fd))
             }
 
@@ -551,17 +1267,29 @@ impl Dispatch for MlnrKernelNode {
                     .read()
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
-                let _is_deleted = self.fs.delete(&filename)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (_id, fs, path) = self.resolve(&mounts, &filename);
+                let _is_deleted = fs.delete(path)?;
                 Ok(MlnrNodeResult::FileDeleted)
             }
 
-            Modify::FileRename(pid, oldname, newname) => {
+            Modify::FileRename(pid, oldname, newname, flags) => {
                 let _p = self
                     .process_map
                     .read()
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
-                let _is_renamed = self.fs.rename(&oldname, &newname)?;
+                let oldname = self.absolute_path(pid, &oldname);
+                let newname = self.absolute_path(pid, &newname);
+                let mounts = self.mounts.read();
+                let (old_id, fs, oldpath) = self.resolve(&mounts, &oldname);
+                let (new_id, _fs, newpath) = self.resolve(&mounts, &newname);
+                if old_id != new_id {
+                    // Can't move files between filesystems
+                    return Err(KError::NotSupported);
+                }
+                fs.rename2(oldpath, newpath, RenameFlags::from(flags))?;
                 Ok(MlnrNodeResult::FileRenamed)
             }
 
@@ -571,9 +1299,443 @@ impl Dispatch for MlnrKernelNode {
                     .read()
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
-                let _is_created = self.fs.mkdir(&filename, modes)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (_id, fs, path) = self.resolve(&mounts, &filename);
+                let _is_created = fs.mkdir(path, modes)?;
                 Ok(MlnrNodeResult::DirCreated)
             }
+
+            Modify::RmDir(pid, filename) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (_id, fs, path) = self.resolve(&mounts, &filename);
+                fs.rmdir(path)?;
+                Ok(MlnrNodeResult::DirDeleted)
+            }
+
+            Modify::Mount(pid, prefix) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                if !prefix.starts_with('/') || prefix.ends_with('/') {
+                    // Also rejects mounting over the root
+                    return Err(KError::InvalidFile);
+                }
+
+                let mut mounts = self.mounts.write();
+                if mounts
+                    .values()
+                    .any(|(mounted_at, _fs)| *mounted_at == prefix)
+                {
+                    return Err(KError::AlreadyPresent);
+                }
+                mounts.try_reserve(1)?;
+                let id = self.next_mount_id.fetch_add(1, Ordering::Relaxed);
+                mounts.insert(id, (prefix, MlnrFS::default()));
+                Ok(MlnrNodeResult::Mounted)
+            }
+
+            Modify::Unmount(pid, prefix) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                // Open fds of the filesystem become stale (mount ids are
+                // never reused).
+                let mut mounts = self.mounts.write();
+                let id = mounts
+                    .iter()
+                    .find(|(_id, (mounted_at, _fs))| *mounted_at == prefix)
+                    .map(|(id, _)| *id)
+                    .ok_or(KError::InvalidFile)?;
+                mounts.remove(&id);
+                Ok(MlnrNodeResult::Unmounted)
+            }
+
+            Modify::ChDir(pid, path) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let path = self.absolute_path(pid, &path).into_owned();
+                {
+                    let mounts = self.mounts.read();
+                    let (_id, fs, local) = self.resolve(&mounts, &path);
+                    let mnode = fs.lookup(local).ok_or(KError::InvalidFile)?;
+                    if fs.file_info(*mnode).ftype != FileType::Directory.into() {
+                        return Err(KError::NotDirectory);
+                    }
+                }
+
+                let mut cwds = self.cwds.write();
+                cwds.try_reserve(1)?;
+                cwds.insert(pid, path);
+                Ok(MlnrNodeResult::DirChanged)
+            }
+
+            Modify::FileAdvise(pid, fd, advice) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                fd.set_advice(advice);
+                Ok(MlnrNodeResult::FileAdvised)
+            }
+
+            Modify::FileChmod(pid, filename, modes) => {
+                let _p = self
+                    .process_map
+                    .read()
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let filename = self.absolute_path(pid, &filename);
+                let mounts = self.mounts.read();
+                let (_id, fs, path) = self.resolve(&mounts, &filename);
+                fs.set_mode(path, modes)?;
+                Ok(MlnrNodeResult::ModeChanged)
+            }
+
+            Modify::FileLock(pid, fd, op) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
+                fs.flock(mnode_num, pid as u64, op)?;
+                Ok(MlnrNodeResult::FileLocked)
+            }
+
+            Modify::FileCompareAndWrite(pid, fd, _mnode, expected, new, offset) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+
+                // We read and write the file
+                let flags = fd.get_flags();
+                if !flags.is_read() || !flags.is_write() {
+                    return Err(KError::PermissionError);
+                }
+
+                let mounts = self.mounts.read();
+                let (fs, mnode_num) = self.resolve_mnode(&mounts, fd.get_mnode())?;
+                let written = fs.compare_and_write(mnode_num, &expected, &new, offset as usize)?;
+                Ok(MlnrNodeResult::FileCompared(written))
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Files created below a mount point end up in the mounted filesystem.
+    #[test]
+    fn mount_dispatch() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::Mount(1, "/tmp".to_string()));
+        assert!(matches!(r, Ok(MlnrNodeResult::Mounted)));
+        let r = node.dispatch_mut(Modify::Mount(1, "/tmp".to_string()));
+        assert!(matches!(r, Err(KError::AlreadyPresent)));
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/tmp/file".to_string(), flags, modes, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileOpened { .. })));
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/tmpfile".to_string(), flags, modes, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileOpened { .. })));
+
+        // Not visible in the root filesystem
+        assert!(node.fs.lookup("/tmp/file").is_none());
+        assert!(node.fs.lookup("/file").is_none());
+        assert!(node.fs.lookup("/tmpfile").is_some());
+        {
+            let mounts = node.mounts.read();
+            let (id, fs, path) = node.resolve(&mounts, "/tmp/file");
+            assert_ne!(id, ROOT_MOUNT);
+            assert_eq!(path, "/file");
+            assert!(fs.lookup(path).is_some());
+        }
+
+        // The fd refers to the mounted filesystem and goes stale on unmount
+        let mnode = match node.dispatch(Access::FdToMnode(1, 0)) {
+            Ok(MlnrNodeResult::MappedFileToMnode(mnode)) => mnode,
+            _ => panic!("Can't find mnode of the fd"),
+        };
+        assert_ne!(mnode >> MOUNT_ID_SHIFT, ROOT_MOUNT);
+        let r = node.dispatch_mut(Modify::Unmount(1, "/tmp".to_string()));
+        assert!(matches!(r, Ok(MlnrNodeResult::Unmounted)));
+        let r = node.dispatch_mut(Modify::FileOpenByMnode(1, mnode, flags));
+        assert!(matches!(r, Err(KError::InvalidFile)));
+    }
+
+    /// Relative paths resolve against the cwd set with `ChDir`.
+    #[test]
+    fn chdir_dispatch() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+        assert!(matches!(
+            node.dispatch(Access::GetCwd(1)),
+            Ok(MlnrNodeResult::Cwd(cwd)) if cwd == "/"
+        ));
+
+        let r = node.dispatch_mut(Modify::ChDir(1, "/a".to_string()));
+        assert!(matches!(r, Err(KError::InvalidFile)));
+        assert!(node
+            .dispatch_mut(Modify::MkDir(1, "/a".to_string(), modes))
+            .is_ok());
+        let r = node.dispatch_mut(Modify::ChDir(1, "/a".to_string()));
+        assert!(matches!(r, Ok(MlnrNodeResult::DirChanged)));
+        assert!(matches!(
+            node.dispatch(Access::GetCwd(1)),
+            Ok(MlnrNodeResult::Cwd(cwd)) if cwd == "/a"
+        ));
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "b".to_string(), flags, modes, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileOpened { .. })));
+        assert!(node.fs.lookup("/a/b").is_some());
+        assert!(node.fs.lookup("b").is_none());
+
+        // Absolute paths ignore the cwd
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/c".to_string(), flags, modes, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileOpened { .. })));
+        assert!(node.fs.lookup("/c").is_some());
+        assert!(node.fs.lookup("/a/c").is_none());
+
+        let r = node.dispatch_mut(Modify::ChDir(1, "b".to_string()));
+        assert!(matches!(r, Err(KError::NotDirectory)));
+        let r = node.dispatch_mut(Modify::ChDir(1, "./..".to_string()));
+        assert!(matches!(r, Ok(MlnrNodeResult::DirChanged)));
+        assert!(matches!(
+            node.dispatch(Access::GetCwd(1)),
+            Ok(MlnrNodeResult::Cwd(cwd)) if cwd == "/"
+        ));
+        let r = node.dispatch_mut(Modify::FileDelete(1, "a/b".to_string()));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileDeleted)));
+        assert!(node.fs.lookup("/a/b").is_none());
+    }
+
+    /// `FileLock` locks the file behind an fd on behalf of the process, the
+    /// locks are dropped when the process goes away.
+    #[test]
+    fn flock_dispatch() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+        assert!(node.dispatch_mut(Modify::ProcessAdd(2)).is_ok());
+
+        let mut fds = Vec::new();
+        for pid in 1..=2 {
+            let r = node.dispatch_mut(Modify::FileOpen(pid, "/file".to_string(), flags, modes, 0));
+            match r {
+                Ok(MlnrNodeResult::FileOpened { fd, .. }) => fds.push(fd),
+                _ => panic!("Can't open the file"),
+            }
+        }
+        let (fd1, fd2) = (fds[0], fds[1]);
+
+        let r = node.dispatch_mut(Modify::FileLock(1, fd1, LockOp::Exclusive));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileLocked)));
+        let r = node.dispatch_mut(Modify::FileLock(2, fd2, LockOp::Shared));
+        assert!(matches!(r, Err(KError::WouldBlock)));
+        let r = node.dispatch_mut(Modify::FileLock(2, fd2 + 1, LockOp::Shared));
+        assert!(matches!(r, Err(KError::InvalidFileDescriptor)));
+        let r = node.dispatch_mut(Modify::FileLock(3, fd2, LockOp::Shared));
+        assert!(matches!(r, Err(KError::NoProcessFoundForPid)));
+
+        assert!(node.dispatch_mut(Modify::ProcessRemove(1)).is_ok());
+        let r = node.dispatch_mut(Modify::FileLock(2, fd2, LockOp::Exclusive));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileLocked)));
+        let r = node.dispatch_mut(Modify::FileLock(2, fd2, LockOp::Unlock));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileLocked)));
+    }
+
+    /// `FileCompareAndWrite` only writes the file if it holds the expected
+    /// data, and needs an fd that can read and write.
+    #[test]
+    fn compare_and_write_dispatch() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/file".to_string(), flags, modes, 0));
+        let fd = match r {
+            Ok(MlnrNodeResult::FileOpened { fd, .. }) => fd,
+            _ => panic!("Can't open the file"),
+        };
+        let mnode = *node.fs.lookup("/file").unwrap();
+        let data: Arc<[u8]> = Arc::from(&[1u8, 2, 3, 4][..]);
+        let r = node.dispatch_mut(Modify::FileWrite(1, fd, mnode, data, 4, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileAccessed(4))));
+
+        let expected: Arc<[u8]> = Arc::from(&[2u8, 3][..]);
+        let new: Arc<[u8]> = Arc::from(&[5u8, 6][..]);
+        let op = Modify::FileCompareAndWrite(1, fd, mnode, expected.clone(), new.clone(), 0);
+        assert!(matches!(
+            node.dispatch_mut(op),
+            Ok(MlnrNodeResult::FileCompared(false))
+        ));
+        let op = Modify::FileCompareAndWrite(1, fd, mnode, expected.clone(), new.clone(), 1);
+        assert!(matches!(
+            node.dispatch_mut(op),
+            Ok(MlnrNodeResult::FileCompared(true))
+        ));
+
+        let mut rbuffer = [0u8; 4];
+        let read = node.fs.read(mnode, &mut UserSlice::from_slice(&mut rbuffer), 0);
+        assert_eq!(read, Ok(4));
+        assert_eq!(rbuffer, [1, 5, 6, 4]);
+
+        let rdonly = u64::from(FileFlags::O_RDONLY);
+        let r = node.dispatch_mut(Modify::FileOpenByMnode(1, mnode, rdonly));
+        let rdonly_fd = match r {
+            Ok(MlnrNodeResult::FileOpened { fd, .. }) => fd,
+            _ => panic!("Can't open the file read-only"),
+        };
+        let op = Modify::FileCompareAndWrite(1, rdonly_fd, mnode, new, expected, 1);
+        assert!(matches!(node.dispatch_mut(op), Err(KError::PermissionError)));
+    }
+
+    /// `MkDir` creates a directory in the filesystem, `FileInfo` reports it.
+    #[test]
+    fn mkdir_dispatch() {
+        let node: MlnrKernelNode = Default::default();
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::MkDir(1, "/dir".to_string(), modes));
+        assert!(matches!(r, Ok(MlnrNodeResult::DirCreated)));
+        let r = node.dispatch_mut(Modify::MkDir(1, "/dir".to_string(), modes));
+        assert!(matches!(r, Err(KError::AlreadyPresent)));
+
+        let name = b"/dir\0";
+        let r = node.dispatch(Access::FileInfo(1, name.as_ptr() as u64, 0, 0));
+        assert!(matches!(
+            r,
+            Ok(MlnrNodeResult::FileInfo(info)) if info.ftype == FileType::Directory.into()
+        ));
+    }
+
+    /// `FileOpen` reports whether it created the file.
+    #[test]
+    fn open_reports_created() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/file".to_string(), flags, modes, 0));
+        assert!(matches!(
+            r,
+            Ok(MlnrNodeResult::FileOpened { created: true, .. })
+        ));
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/file".to_string(), flags, modes, 0));
+        assert!(matches!(
+            r,
+            Ok(MlnrNodeResult::FileOpened { created: false, .. })
+        ));
+    }
+
+    /// A file opened with `O_TMPFILE` has no name and is removed once its
+    /// last fd is closed.
+    #[test]
+    fn open_anonymous_file() {
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_TMPFILE);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/".to_string(), flags, modes, 0));
+        let fd = match r {
+            Ok(MlnrNodeResult::FileOpened { fd, created: true }) => fd,
+            _ => panic!("Can't open an anonymous file"),
+        };
+        let mnode = match node.dispatch(Access::FdToMnode(1, fd)) {
+            Ok(MlnrNodeResult::MappedFileToMnode(mnode)) => mnode,
+            _ => panic!("Can't find mnode of the fd"),
+        };
+        assert!(node.fs.is_anonymous(mnode));
+
+        let data: Arc<[u8]> = Arc::from(&[1u8, 2, 3][..]);
+        let r = node.dispatch_mut(Modify::FileWrite(1, fd, mnode, data, 3, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileAccessed(3))));
+        let mut rbuffer = [0u8; 3];
+        let buffer = rbuffer.as_mut_ptr() as u64;
+        let r = node.dispatch(Access::FileRead(1, fd, mnode, buffer, 3, 0));
+        assert!(matches!(r, Ok(MlnrNodeResult::FileAccessed(3))));
+        assert_eq!(rbuffer, [1, 2, 3]);
+
+        // A second fd keeps the file alive
+        let r = node.dispatch_mut(Modify::FileOpenByMnode(1, mnode, flags));
+        let fd2 = match r {
+            Ok(MlnrNodeResult::FileOpened { fd, .. }) => fd,
+            _ => panic!("Can't open the anonymous file by its mnode"),
+        };
+        assert!(node.dispatch_mut(Modify::FileClose(1, fd)).is_ok());
+        assert!(node.fs.open_by_mnode(mnode, flags).is_ok());
+
+        assert!(node.dispatch_mut(Modify::FileClose(1, fd2)).is_ok());
+        assert!(!node.fs.is_anonymous(mnode));
+        assert_eq!(node.fs.open_by_mnode(mnode, flags), Err(KError::InvalidFile));
+
+        // Needs a directory and write access
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/a".to_string(), flags, modes, 0));
+        assert!(matches!(r, Err(KError::InvalidFile)));
+        let rdonly = u64::from(FileFlags::O_RDONLY | FileFlags::O_TMPFILE);
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/".to_string(), rdonly, modes, 0));
+        assert!(matches!(r, Err(KError::InvalidFlags)));
+    }
+
+    /// `FileOpen` with a size creates a zero-filled file of that size.
+    #[test]
+    fn open_with_size() {
+        const SIZE: u64 = 1024 * 1024;
+        let node: MlnrKernelNode = Default::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node.dispatch_mut(Modify::ProcessAdd(1)).is_ok());
+
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/file".to_string(), flags, modes, SIZE));
+        assert!(matches!(
+            r,
+            Ok(MlnrNodeResult::FileOpened { created: true, .. })
+        ));
+        let mnode = *node.fs.lookup("/file").unwrap();
+        assert_eq!(node.fs.file_info(mnode).fsize, SIZE);
+        let mut rbuffer: Vec<u8> = alloc::vec![0xff; SIZE as usize];
+        let read = node.fs.read(mnode, &mut UserSlice::from_slice(&mut rbuffer), 0);
+        assert_eq!(read, Ok(SIZE as usize));
+        assert!(rbuffer.iter().all(|b| *b == 0));
+
+        // The size only matters when the file is created
+        let r = node.dispatch_mut(Modify::FileOpen(1, "/file".to_string(), flags, modes, 1));
+        assert!(matches!(
+            r,
+            Ok(MlnrNodeResult::FileOpened { created: false, .. })
+        ));
+        assert_eq!(node.fs.file_info(mnode).fsize, SIZE);
+    }
+}
@@ -37,6 +37,8 @@ pub enum KError {
     ManagerAlreadyBorrowed,
     InvalidAffinityId,
     CapacityOverflow,
+    TooManyRegions { count: usize },
+    TooManyNodes { count: usize },
 
     // Process Errors
     ProcessLoadingFailed,
@@ -71,7 +73,11 @@ pub enum KError {
     PermissionError,
     AlreadyPresent,
     DirectoryError,
+    NotDirectory,
+    DirectoryNotEmpty,
     OpenFileLimit,
+    FileTooBig,
+    WouldBlock,
     FileDescForPidAlreadyAdded,
     NoFileDescForPid,
 }
@@ -128,12 +134,45 @@ impl From<KError> for SystemCallError {
     /// The idea is to reduce a big set of events into a smaller set of less precise errors.
     /// We can log the the precise errors before we return in the kernel since the conversion
     /// happens at the end of the system call.
+    ///
+    /// File-system errors are reported to user-space as they are, every one
+    /// of them has its own `SystemCallError`:
+    ///
+    /// | KError                  | SystemCallError     |
+    /// |-------------------------|---------------------|
+    /// | `InvalidFileDescriptor` | `BadFileDescriptor` |
+    /// | `InvalidFile`           | `InvalidFile`       |
+    /// | `InvalidFlags`          | `BadFlags`          |
+    /// | `InvalidOffset`         | `OffsetError`       |
+    /// | `PermissionError`       | `PermissionError`   |
+    /// | `AlreadyPresent`        | `AlreadyPresent`    |
+    /// | `DirectoryError`        | `DirectoryError`    |
+    /// | `NotDirectory`          | `NotDirectory`      |
+    /// | `DirectoryNotEmpty`     | `DirectoryNotEmpty` |
+    /// | `OpenFileLimit`         | `OpenFileLimit`     |
+    /// | `FileTooBig`            | `FileTooBig`        |
+    /// | `WouldBlock`            | `WouldBlock`        |
+    ///
+    /// Everything else that isn't listed in the match below is an `InternalError`.
     fn from(e: KError) -> SystemCallError {
         match e {
             KError::InvalidSyscallArgument1 { .. } => SystemCallError::NotSupported,
             KError::InvalidVSpaceOperation { .. } => SystemCallError::NotSupported,
             KError::InvalidProcessOperation { .. } => SystemCallError::NotSupported,
             KError::BadAddress { .. } => SystemCallError::BadAddress,
+
+            KError::InvalidFileDescriptor => SystemCallError::BadFileDescriptor,
+            KError::InvalidFile => SystemCallError::InvalidFile,
+            KError::InvalidFlags => SystemCallError::BadFlags,
+            KError::InvalidOffset => SystemCallError::OffsetError,
+            KError::PermissionError => SystemCallError::PermissionError,
+            KError::AlreadyPresent => SystemCallError::AlreadyPresent,
+            KError::DirectoryError => SystemCallError::DirectoryError,
+            KError::NotDirectory => SystemCallError::NotDirectory,
+            KError::DirectoryNotEmpty => SystemCallError::DirectoryNotEmpty,
+            KError::OpenFileLimit => SystemCallError::OpenFileLimit,
+            KError::FileTooBig => SystemCallError::FileTooBig,
+            KError::WouldBlock => SystemCallError::WouldBlock,
             _ => SystemCallError::InternalError,
         }
     }
@@ -233,6 +272,8 @@ impl fmt::Display for KError {
             KError::CantGrowFurther{count} => write!(f, "Cache full; only added {} elements.", count),
             KError::KcbUnavailable => write!(f, "KCB not set, memory allocation won't work at this point."),
             KError::ManagerAlreadyBorrowed => write!(f, "The memory manager was already borrowed (this is a bug)."),
+            KError::TooManyRegions{count} => write!(f, "Got {} physical memory regions, can handle at most {}.", count, crate::memory::MAX_PHYSICAL_REGIONS),
+            KError::TooManyNodes{count} => write!(f, "Got memory for {} NUMA nodes, can handle at most {}.", count, crate::arch::MAX_NUMA_NODES),
 
             KError::InvalidFileDescriptor => write!(f, "Supplied file descriptor was invalid"),
             KError::InvalidFile => write!(f, "Supplied file was invalid"),
@@ -241,7 +282,51 @@ impl fmt::Display for KError {
             KError::PermissionError => write!(f, "File/directory can't be read or written"),
             KError::AlreadyPresent => write!(f, "Fd/File already exists"),
             KError::DirectoryError => write!(f, "Can't read or write to a directory"),
+            KError::NotDirectory => write!(f, "Supplied path is not a directory"),
+            KError::DirectoryNotEmpty => write!(f, "Directory is not empty"),
             KError::OpenFileLimit => write!(f, "Maximum files are opened for a process"),
+            KError::FileTooBig => write!(f, "Write would exceed the maximum file size"),
+            KError::WouldBlock => write!(f, "File is locked by someone else"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every file-system error has its own, distinct system call error.
+    #[test]
+    fn fs_errors_to_syscall_errors() {
+        let mapping = [
+            (KError::InvalidFileDescriptor, SystemCallError::BadFileDescriptor),
+            (KError::InvalidFile, SystemCallError::InvalidFile),
+            (KError::InvalidFlags, SystemCallError::BadFlags),
+            (KError::InvalidOffset, SystemCallError::OffsetError),
+            (KError::PermissionError, SystemCallError::PermissionError),
+            (KError::AlreadyPresent, SystemCallError::AlreadyPresent),
+            (KError::DirectoryError, SystemCallError::DirectoryError),
+            (KError::NotDirectory, SystemCallError::NotDirectory),
+            (KError::DirectoryNotEmpty, SystemCallError::DirectoryNotEmpty),
+            (KError::OpenFileLimit, SystemCallError::OpenFileLimit),
+            (KError::FileTooBig, SystemCallError::FileTooBig),
+            (KError::WouldBlock, SystemCallError::WouldBlock),
+        ];
+
+        for (i, (kerror, expected)) in mapping.iter().enumerate() {
+            let e: SystemCallError = kerror.clone().into();
+            assert_eq!(e, *expected, "{:?}", kerror);
+            // Survives the trip through the error register
+            assert_eq!(SystemCallError::from(e as u64), e);
+            for (_, other) in mapping[i + 1..].iter() {
+                assert_ne!(e, *other);
+            }
         }
+
+        // Errors that don't concern the caller stay internal
+        assert_eq!(
+            SystemCallError::from(KError::ReplicaNotSet),
+            SystemCallError::InternalError
+        );
     }
 }
@@ -1,25 +1,65 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use super::{Fd, MAX_FILES_PER_PROCESS};
+use super::{Fd, FileDescriptor, Mnode, MAX_FILES_PER_PROCESS};
 use crate::error::KError;
 
+/// How `FileDesc::allocate_fd` picks the next file descriptor.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FdAllocPolicy {
+    /// Hand out the lowest free index (like POSIX).
+    LowestFree,
+    /// Hand out increasing indices, only wrap around once we reach the end
+    /// of the table. A closed fd isn't reused right away which helps to catch
+    /// use-after-close bugs.
+    Monotonic,
+}
+
+impl Default for FdAllocPolicy {
+    fn default() -> Self {
+        FdAllocPolicy::LowestFree
+    }
+}
+
 pub struct FileDesc {
     fds: arrayvec::ArrayVec<Option<Fd>, MAX_FILES_PER_PROCESS>,
+    policy: FdAllocPolicy,
+    /// Where `FdAllocPolicy::Monotonic` starts looking for a free index.
+    next: usize,
 }
 
 impl Default for FileDesc {
     fn default() -> Self {
+        FileDesc::with_policy(Default::default())
+    }
+}
+
+impl FileDesc {
+    /// Creates an empty file descriptor table that allocates according to
+    /// `policy`.
+    pub fn with_policy(policy: FdAllocPolicy) -> Self {
         const NONE_FD: Option<Fd> = None;
         FileDesc {
             fds: arrayvec::ArrayVec::from([NONE_FD; MAX_FILES_PER_PROCESS]),
+            policy,
+            next: 0,
         }
     }
-}
 
-impl FileDesc {
+    pub fn policy(&self) -> FdAllocPolicy {
+        self.policy
+    }
+
     pub fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
-        if let Some(fid) = self.fds.iter().position(|fd| fd.is_none()) {
+        let free = match self.policy {
+            FdAllocPolicy::LowestFree => self.fds.iter().position(|fd| fd.is_none()),
+            FdAllocPolicy::Monotonic => (0..self.fds.len())
+                .map(|i| (self.next + i) % self.fds.len())
+                .find(|&i| self.fds[i].is_none()),
+        };
+
+        if let Some(fid) = free {
+            self.next = (fid + 1) % self.fds.len();
             self.fds[fid] = Some(Default::default());
             Some((fid as u64, self.fds[fid as usize].as_mut().unwrap()))
         } else {
@@ -41,6 +81,28 @@ impl FileDesc {
     }
 
     pub fn get_fd(&self, index: usize) -> Option<&Fd> {
-        self.fds[index].as_ref()
+        self.fds.get(index).and_then(|fd| fd.as_ref())
+    }
+
+    /// The mnodes of all open file descriptors.
+    pub fn mnodes(&self) -> impl Iterator<Item = Mnode> + '_ {
+        self.fds.iter().flatten().map(|fd| fd.get_mnode())
+    }
+
+    /// Number of currently open file descriptors.
+    pub fn open_count(&self) -> usize {
+        self.fds.iter().filter(|fd| fd.is_some()).count()
+    }
+
+    /// Closes every open file descriptor.
+    ///
+    /// Returns how many file descriptors were closed.
+    pub fn close_all(&mut self) -> usize {
+        let mut closed = 0;
+        for fd in self.fds.iter_mut().filter(|fd| fd.is_some()) {
+            *fd = None;
+            closed += 1;
+        }
+        closed
     }
 }
@@ -8,12 +8,14 @@ use core::mem::size_of;
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use kpi::io::*;
 
+use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::memory::BASE_PAGE_SIZE;
 
 use super::Modes;
 
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(test, derive(Clone))]
 /// The buffer is used by the file. Each buffer is BASE_PAGE_SIZE
 /// long and a file consists of many such buffers.
 struct Buffer {
@@ -24,14 +26,32 @@ impl Buffer {
     /// This function tries to allocate a vector of BASE_PAGE_SIZE long
     /// and returns a buffer in case of the success; error otherwise.
     pub fn try_alloc_buffer() -> Result<Buffer, TryReserveError> {
+        #[cfg(test)]
+        test::take_buffer_budget()?;
         Vec::try_with_capacity(BASE_PAGE_SIZE).map(|data| Buffer { data })
     }
 }
 
+/// Data for an append, copied into fresh buffers ahead of time by
+/// `File::stage_append` (without access to the file).
+#[derive(Debug)]
+pub struct StagedAppend {
+    /// File size the append was staged for.
+    offset: usize,
+    /// How many bytes at the start of the data still go into the current
+    /// (partially filled) last buffer of the file.
+    head: usize,
+    /// Buffers holding everything after `head`.
+    buffers: Vec<Buffer>,
+}
+
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(test, derive(Clone))]
 /// File type has a list of buffers and modes to access the file
 pub struct File {
     mcache: Vec<Buffer>,
+    /// Buffers that are allocated (by `reserve`) but not yet part of the file.
+    reserved: Vec<Buffer>,
     modes: FileModes,
     // TODO: Add more file related attributes
 }
@@ -41,7 +61,11 @@ impl File {
     pub fn new(modes: Modes) -> Result<File, KError> {
         let modes = FileModes::from(modes);
         let mcache = Vec::try_with_capacity(64 * size_of::<Buffer>())?;
-        Ok(File { mcache, modes })
+        Ok(File {
+            mcache,
+            reserved: Vec::new(),
+            modes,
+        })
     }
 
     /// This method returns the current-size of the file. This method follows
@@ -73,13 +97,76 @@ impl File {
         }
     }
 
+    /// Computes a checksum (64-bit FNV-1a) over the contents of the file.
+    ///
+    /// The checksum only depends on the bytes in the file (holes read as
+    /// zeroes), not on how the file was written.
+    pub fn checksum(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        let mut remaining = self.get_size();
+        let mut hash = FNV_OFFSET_BASIS;
+        for buffer in &self.mcache {
+            let len = core::cmp::min(remaining, buffer.data.len());
+            for byte in &buffer.data[..len] {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+            remaining -= len;
+        }
+        hash
+    }
+
     /// This method returns the mode in which file is created.
     pub fn get_mode(&self) -> FileModes {
         self.modes
     }
 
+    /// Change the mode of the file.
+    pub fn set_mode(&mut self, modes: FileModes) {
+        self.modes = modes;
+    }
+
+    /// Pre-allocates all buffers needed to grow the file by `additional_bytes`.
+    ///
+    /// Once this succeeds, growing the file by up to `additional_bytes` (e.g.,
+    /// with `write_file`) no longer allocates and therefore can't fail
+    /// half-way. On error, the file is left unmodified.
+    pub fn reserve(&mut self, additional_bytes: usize) -> Result<(), KError> {
+        let new_len = self
+            .get_size()
+            .checked_add(additional_bytes)
+            .ok_or(KError::OutOfMemory)?;
+        let total_buffers = ceil(new_len, BASE_PAGE_SIZE);
+        let needed = total_buffers.saturating_sub(self.mcache.len() + self.reserved.len());
+        if needed == 0 {
+            return Ok(());
+        }
+
+        let mut buffers = Vec::try_with_capacity(needed)?;
+        for _i in 0..needed {
+            let buffer = Buffer::try_alloc_buffer()?;
+            debug_assert!(
+                buffers.len() < buffers.capacity(),
+                "ensured by try_with_capacity"
+            );
+            buffers.push(buffer);
+        }
+        // Make sure moving the buffers into the file later won't allocate:
+        self.mcache.try_reserve(total_buffers - self.mcache.len())?;
+        self.reserved.try_reserve(needed)?;
+
+        self.reserved.append(&mut buffers);
+        Ok(())
+    }
+
     /// This method is internally used by write_file() method. The additional
     /// length is initialzed to zero.
+    ///
+    /// All buffers are reserved before the file is touched, so growing the
+    /// file doesn't allocate. In case it still fails half-way the file is
+    /// left partially grown, `write_file` rolls that back with `shrink_to`.
     pub fn increase_file_size(
         &mut self,
         curr_file_len: usize,
@@ -88,6 +175,7 @@ impl File {
         if new_len == 0 {
             return Ok(());
         }
+        self.reserve(new_len - curr_file_len)?;
 
         let free_in_last_buffer = match self.mcache.last() {
             Some(buffer) => BASE_PAGE_SIZE - buffer.data.len(),
@@ -116,69 +204,57 @@ impl File {
 
             let remaining = add_new - free_in_last_buffer;
             let new_buffers = ceil(remaining, BASE_PAGE_SIZE);
-            let mut vec = Vec::try_with_capacity(new_buffers)?;
 
             for _i in 0..new_buffers {
-                let mut buffer = Buffer::try_alloc_buffer()?;
-                // TODO(error-handling): On failure, might want to
-                // shrink previous buffers again?
+                #[cfg(test)]
+                test::take_fill_budget()?;
+                let mut buffer = self.reserved.pop().expect("ensured by reserve()");
+                // Buffers have BASE_PAGE_SIZE capacity, this doesn't allocate
                 buffer.data.try_resize(BASE_PAGE_SIZE, 0)?;
 
-                debug_assert!(vec.len() < vec.capacity(), "ensured by try_with_capacity");
-                vec.push(buffer);
+                debug_assert!(
+                    self.mcache.len() < self.mcache.capacity(),
+                    "ensured by reserve()"
+                );
+                self.mcache.push(buffer);
             }
 
             // Filled all the buffers with zeros, resize the last buffer:
             if new_len % BASE_PAGE_SIZE != 0 {
-                let sure_bytes_to_write = (new_buffers - 1) * BASE_PAGE_SIZE;
-                let bytes_in_last_buffer = new_len - (self.get_size() + sure_bytes_to_write);
-
-                // TODO(error-handling): shrink others again on error?
-                vec.last_mut()
+                let bytes_in_last_buffer = new_len - (self.mcache.len() - 1) * BASE_PAGE_SIZE;
+                self.mcache
+                    .last_mut()
                     .unwrap()
                     .data
-                    .try_resize(bytes_in_last_buffer, 0)?;
+                    .truncate(bytes_in_last_buffer);
             }
 
-            self.mcache.try_append(&mut vec).map_err(|e| e.into())
+            Ok(())
         }
     }
 
     /// This method is internally call on a read() system-call. It reads the content of the
     /// file and copies it in a user provided slice. The data is read from start_offset till
     /// end_offset(not inclusive).
+    ///
+    /// In case `user_slice` is shorter than the requested range only
+    /// `user_slice.len()` bytes are read, the number of bytes copied is returned.
     pub fn read_file(
         &self,
-        user_slice: &mut [u8],
+        user_slice: &mut UserSlice,
         start_offset: usize,
         end_offset: usize,
     ) -> Result<usize, KError> {
         let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
         let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
         let mut copied = 0;
-        let mut dst_start = 0;
-        let mut dst_end;
 
-        let len = end_offset - start_offset;
+        let len = core::cmp::min(end_offset - start_offset, user_slice.len());
         while copied < len {
-            let useful_data_curr_buffer = self.mcache[buffer_num].data.len() - offset_in_buffer;
-            let remaining = len - copied;
-
-            let src_start = offset_in_buffer;
-            let src_end;
-            if remaining >= useful_data_curr_buffer {
-                dst_end = dst_start + useful_data_curr_buffer;
-                src_end = src_start + useful_data_curr_buffer;
-                copied += useful_data_curr_buffer;
-            } else {
-                dst_end = dst_start + remaining;
-                src_end = src_start + remaining;
-                copied += remaining;
-            }
-            user_slice[dst_start..dst_end]
-                .copy_from_slice(&self.mcache[buffer_num].data[src_start..src_end]);
+            let data = &self.mcache[buffer_num].data[offset_in_buffer..];
+            let remaining = core::cmp::min(len - copied, data.len());
+            copied += user_slice.copy_from_at(copied, &data[..remaining])?;
             buffer_num += 1;
-            dst_start = dst_end;
             offset_in_buffer = 0;
         }
 
@@ -198,11 +274,16 @@ impl File {
         // If offset is specified, then resize the file to the offset + len.
         // If offset is more than file size then fill the file with zeros till the offset.
         let curr_file_len = self.get_size();
+        let curr_reserved = self.reserved.len();
         let new_len = start_offset + len;
         if new_len > 0
             && new_len > curr_file_len
             && self.increase_file_size(curr_file_len, new_len).is_err()
         {
+            // Don't leave a partially grown file (or the buffers reserved for
+            // growing it) behind
+            self.shrink_to(curr_file_len);
+            self.reserved.truncate(curr_reserved);
             return Err(KError::OutOfMemory);
         }
 
@@ -238,9 +319,168 @@ impl File {
         Ok(len)
     }
 
+    /// Does the file hold exactly `data` at `offset`?
+    ///
+    /// Ranges that go past the end of the file never match.
+    pub fn contains_at(&self, data: &[u8], offset: usize) -> bool {
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= self.get_size() => {}
+            _ => return false,
+        }
+
+        let mut buffer_num = offset_to_buffernum(offset, BASE_PAGE_SIZE);
+        let mut offset_in_buffer = offset - (buffer_num * BASE_PAGE_SIZE);
+        let mut compared = 0;
+        while compared < data.len() {
+            let len = core::cmp::min(BASE_PAGE_SIZE - offset_in_buffer, data.len() - compared);
+            let stored = &self.mcache[buffer_num].data[offset_in_buffer..offset_in_buffer + len];
+            if stored != &data[compared..compared + len] {
+                return false;
+            }
+            compared += len;
+            buffer_num += 1;
+            offset_in_buffer = 0;
+        }
+
+        true
+    }
+
+    /// Copies `data` into new buffers for appending it to a file that is
+    /// `offset` bytes long.
+    ///
+    /// This does all allocations and most of the copying, so that
+    /// `commit_append` (which needs exclusive access to the file) is short.
+    pub fn stage_append(offset: usize, data: &[u8]) -> Result<StagedAppend, KError> {
+        let free_in_last_buffer = (BASE_PAGE_SIZE - offset % BASE_PAGE_SIZE) % BASE_PAGE_SIZE;
+        let head = core::cmp::min(free_in_last_buffer, data.len());
+
+        let mut buffers = Vec::try_with_capacity(ceil(data.len() - head, BASE_PAGE_SIZE))?;
+        for chunk in data[head..].chunks(BASE_PAGE_SIZE) {
+            let mut buffer = Buffer::try_alloc_buffer()?;
+            // Buffers have BASE_PAGE_SIZE capacity, this doesn't allocate
+            buffer.data.extend_from_slice(chunk);
+            debug_assert!(
+                buffers.len() < buffers.capacity(),
+                "ensured by try_with_capacity"
+            );
+            buffers.push(buffer);
+        }
+
+        Ok(StagedAppend {
+            offset,
+            head,
+            buffers,
+        })
+    }
+
+    /// Appends `data` which was staged with `stage_append`.
+    ///
+    /// The file only changes once everything is in place, so whoever reads
+    /// the file afterwards sees either none or all of the appended data.
+    /// Fails with `InvalidOffset` (and leaves the file as is) if the size of
+    /// the file changed since the append was staged.
+    pub fn commit_append(&mut self, staged: StagedAppend, data: &[u8]) -> Result<usize, KError> {
+        if self.get_size() != staged.offset || !self.reserved.is_empty() {
+            // Files with reserved buffers are grown with those first
+            return Err(KError::InvalidOffset);
+        }
+        self.mcache.try_reserve(staged.buffers.len())?;
+
+        if staged.head > 0 {
+            let last = self
+                .mcache
+                .last_mut()
+                .expect("head > 0 implies a last buffer");
+            // The last buffer has BASE_PAGE_SIZE capacity, this doesn't allocate
+            last.data.extend_from_slice(&data[..staged.head]);
+        }
+        self.mcache.extend(staged.buffers);
+
+        Ok(data.len())
+    }
+
+    /// Is buffer `buffer_num` a hole (i.e., all its bytes are zero)?
+    ///
+    /// Files are not stored sparsely, so zero-filled buffers are the closest
+    /// thing we have to holes.
+    fn is_hole(&self, buffer_num: usize) -> bool {
+        self.mcache[buffer_num].data.iter().all(|b| *b == 0)
+    }
+
+    /// Finds the first offset >= `from_offset` that is not in a hole.
+    ///
+    /// Returns `None` if `from_offset` is past the end of the file or there
+    /// is no more data after it (like `SEEK_DATA`).
+    pub fn seek_data(&self, from_offset: usize) -> Option<usize> {
+        let size = self.get_size();
+        if from_offset >= size {
+            return None;
+        }
+
+        let first = offset_to_buffernum(from_offset, BASE_PAGE_SIZE);
+        (first..ceil(size, BASE_PAGE_SIZE))
+            .find(|&buffer_num| !self.is_hole(buffer_num))
+            .map(|buffer_num| core::cmp::max(from_offset, buffer_num * BASE_PAGE_SIZE))
+    }
+
+    /// Finds the first offset >= `from_offset` that is in a hole.
+    ///
+    /// The end of the file counts as a hole. Returns `None` if `from_offset`
+    /// is past the end of the file (like `SEEK_HOLE`).
+    pub fn seek_hole(&self, from_offset: usize) -> Option<usize> {
+        let size = self.get_size();
+        if from_offset >= size {
+            return None;
+        }
+
+        let first = offset_to_buffernum(from_offset, BASE_PAGE_SIZE);
+        let hole = (first..ceil(size, BASE_PAGE_SIZE))
+            .find(|&buffer_num| self.is_hole(buffer_num))
+            .map_or(size, |buffer_num| {
+                core::cmp::max(from_offset, buffer_num * BASE_PAGE_SIZE)
+            });
+        Some(hole)
+    }
+
+    /// Prepares the buffers holding (up to) `len` bytes at `offset` for a
+    /// read. Returns how many bytes of the file that covers.
+    ///
+    /// The data is in memory already, so this only touches the buffers to
+    /// bring them into the cache.
+    pub fn read_ahead(&self, offset: usize, len: usize) -> usize {
+        let end = core::cmp::min(self.get_size(), offset.saturating_add(len));
+        if offset >= end {
+            return 0;
+        }
+
+        let first = offset_to_buffernum(offset, BASE_PAGE_SIZE);
+        let last = offset_to_buffernum(end - 1, BASE_PAGE_SIZE);
+        for buffer in &self.mcache[first..=last] {
+            if let Some(byte) = buffer.data.first() {
+                // Safety: Reads an initialized byte of the buffer
+                let _ = unsafe { core::ptr::read_volatile(byte) };
+            }
+        }
+        end - offset
+    }
+
+    /// Cuts the file back to `len` bytes, `len` must not be more than the
+    /// current size.
+    ///
+    /// Used to roll back a failed write, we don't allocate here.
+    fn shrink_to(&mut self, len: usize) {
+        debug_assert!(len <= self.get_size());
+        let buffers = ceil(len, BASE_PAGE_SIZE);
+        self.mcache.truncate(buffers);
+        if let Some(last) = self.mcache.last_mut() {
+            last.data.truncate(len - (buffers - 1) * BASE_PAGE_SIZE);
+        }
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) {
         self.mcache.clear();
+        self.reserved.clear();
     }
 }
 
@@ -264,6 +504,97 @@ fn offset_to_buffernum(offset: usize, buffer_size: usize) -> usize {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use core::cell::Cell;
+
+    /// How many more buffers `Buffer::try_alloc_buffer` can allocate on this
+    /// thread (`None` means no limit). Lets tests run out of memory in the
+    /// middle of an operation.
+    #[thread_local]
+    static BUFFER_BUDGET: Cell<Option<usize>> = Cell::new(None);
+
+    /// How many more reserved buffers `File::increase_file_size` can move into
+    /// the file on this thread (`None` means no limit). Lets tests fail a
+    /// write after its buffers were reserved.
+    #[thread_local]
+    static FILL_BUDGET: Cell<Option<usize>> = Cell::new(None);
+
+    pub(super) fn take_buffer_budget() -> Result<(), TryReserveError> {
+        take_budget(&BUFFER_BUDGET)
+    }
+
+    pub(super) fn take_fill_budget() -> Result<(), TryReserveError> {
+        take_budget(&FILL_BUDGET)
+    }
+
+    fn take_budget(budget: &Cell<Option<usize>>) -> Result<(), TryReserveError> {
+        match budget.get() {
+            Some(0) => Err(Vec::<u8>::new().try_reserve(usize::MAX).unwrap_err()),
+            Some(left) => {
+                budget.set(Some(left - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[test]
+    /// Running out of memory half-way through a write leaves the file as it was.
+    fn test_write_file_oom_rolls_back() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let wbuffer: &mut [u8] = &mut [0xb; 5000];
+        assert_eq!(file.write_file(wbuffer, 5000, 0), Ok(5000));
+
+        // Needs 10 buffers but only gets 3 of them, fails in `reserve`
+        let len = 10 * BASE_PAGE_SIZE;
+        let large = alloc::vec![0xc; len];
+        BUFFER_BUDGET.set(Some(3));
+        assert_eq!(file.write_file(&large, len, 0), Err(KError::OutOfMemory));
+        BUFFER_BUDGET.set(None);
+
+        assert_eq!(file.get_size(), 5000);
+        assert_eq!(file.mcache.len(), 2);
+        assert_eq!(file.reserved.len(), 0);
+        let rbuffer: &mut [u8] = &mut [0; 5000];
+        let rslice = &mut UserSlice::from_slice(rbuffer);
+        assert_eq!(file.read_file(rslice, 0, 5000), Ok(5000));
+        assert_eq!(rbuffer, wbuffer);
+
+        // All buffers are reserved, but only 3 make it into the file before
+        // the write fails: the file has already grown and gets rolled back
+        FILL_BUDGET.set(Some(3));
+        assert_eq!(file.write_file(&large, len, 0), Err(KError::OutOfMemory));
+        FILL_BUDGET.set(None);
+
+        assert_eq!(file.get_size(), 5000);
+        assert_eq!(file.mcache.len(), 2);
+        assert_eq!(file.mcache[1].data.len(), 5000 - BASE_PAGE_SIZE);
+        assert_eq!(file.reserved.len(), 0);
+        let rbuffer: &mut [u8] = &mut [0; 5000];
+        let rslice = &mut UserSlice::from_slice(rbuffer);
+        assert_eq!(file.read_file(rslice, 0, 5000), Ok(5000));
+        assert_eq!(rbuffer, wbuffer);
+
+        assert_eq!(file.write_file(&large, len, 0), Ok(len));
+        assert_eq!(file.get_size(), len);
+    }
+
+    #[test]
+    /// Rolling back a grown file restores its length.
+    fn test_shrink_to() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let wbuffer: &mut [u8] = &mut [0xb; 3 * BASE_PAGE_SIZE];
+        assert_eq!(file.write_file(wbuffer, 5000, 0), Ok(5000));
+        assert_eq!(file.increase_file_size(5000, 3 * BASE_PAGE_SIZE), Ok(()));
+
+        file.shrink_to(5000);
+        assert_eq!(file.get_size(), 5000);
+        assert_eq!(file.mcache.len(), 2);
+        file.shrink_to(BASE_PAGE_SIZE);
+        assert_eq!(file.get_size(), BASE_PAGE_SIZE);
+        assert_eq!(file.mcache.len(), 1);
+        file.shrink_to(0);
+        assert_eq!(file.get_size(), 0);
+    }
 
     #[test]
     /// This method test the offset to buffer number conversion for a file.
@@ -361,11 +692,92 @@ pub mod test {
         assert_eq!(file.get_size(), 10000);
 
         for i in 0..10000 {
-            file.read_file(&mut rbuffer[i..i + 1], i, i + 1).unwrap();
+            let rslice = &mut UserSlice::from_slice(&mut rbuffer[i..i + 1]);
+            file.read_file(rslice, i, i + 1).unwrap();
             assert_eq!(rbuffer[i], 0xb);
         }
     }
 
+    #[test]
+    /// Reserve buffers up-front and make sure a subsequent write uses them.
+    fn test_reserve_then_write() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let len = 64 * BASE_PAGE_SIZE + 100;
+
+        assert_eq!(file.reserve(len), Ok(()));
+        assert_eq!(file.get_size(), 0);
+        assert_eq!(file.reserved.len(), ceil(len, BASE_PAGE_SIZE));
+
+        let wbuffer = alloc::vec![0xb; len];
+        assert_eq!(file.write_file(&wbuffer, len, 0), Ok(len));
+        assert_eq!(file.get_size(), len);
+        assert_eq!(file.reserved.len(), 0);
+
+        let mut rbuffer = alloc::vec![0; len];
+        let rslice = &mut UserSlice::from_slice(&mut rbuffer);
+        assert_eq!(file.read_file(rslice, 0, len), Ok(len));
+        assert_eq!(rbuffer, wbuffer);
+    }
+
+    #[test]
+    /// A failing reserve should leave the file as it was.
+    fn test_reserve_fails_cleanly() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let wbuffer: &mut [u8] = &mut [0xb; 5000];
+        assert_eq!(file.write_file(wbuffer, 5000, 0), Ok(5000));
+
+        assert!(file.reserve(usize::MAX / 2).is_err());
+        assert!(file.reserve(usize::MAX).is_err());
+        assert_eq!(file.get_size(), 5000);
+        assert_eq!(file.mcache.len(), 2);
+        assert_eq!(file.reserved.len(), 0);
+
+        let rbuffer: &mut [u8] = &mut [0; 5000];
+        let rslice = &mut UserSlice::from_slice(rbuffer);
+        assert_eq!(file.read_file(rslice, 0, 5000), Ok(5000));
+        assert_eq!(rbuffer, wbuffer);
+    }
+
+    #[test]
+    /// Reading into a slice that's shorter than the requested range is truncated.
+    fn test_read_file_short_slice() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let wbuffer: &mut [u8] = &mut [0xb; 5000];
+        assert_eq!(file.write_file(wbuffer, 5000, 0), Ok(5000));
+
+        let rbuffer: &mut [u8] = &mut [0; 4100];
+        let rslice = &mut UserSlice::from_slice(rbuffer);
+        assert_eq!(file.read_file(rslice, 0, 5000), Ok(4100));
+        assert!(rbuffer.iter().all(|b| *b == 0xb));
+    }
+
+    #[test]
+    /// Zero-filled buffers are reported as holes.
+    fn test_seek_data_hole() {
+        let mut file = File::new(FileModes::S_IRWXU.into()).unwrap();
+        let data: &[u8] = &[0xa; BASE_PAGE_SIZE];
+        // data (page 0) -- hole (page 1, 2) -- data (page 3)
+        assert_eq!(file.write_file(data, BASE_PAGE_SIZE, 0), Ok(BASE_PAGE_SIZE));
+        assert_eq!(file.write_file(data, 100, 3 * BASE_PAGE_SIZE), Ok(100));
+        let size = 3 * BASE_PAGE_SIZE + 100;
+        assert_eq!(file.get_size(), size);
+
+        assert_eq!(file.seek_data(0), Some(0));
+        assert_eq!(file.seek_hole(0), Some(BASE_PAGE_SIZE));
+        assert_eq!(file.seek_hole(10), Some(BASE_PAGE_SIZE));
+        assert_eq!(file.seek_data(BASE_PAGE_SIZE), Some(3 * BASE_PAGE_SIZE));
+        assert_eq!(file.seek_data(BASE_PAGE_SIZE + 5), Some(3 * BASE_PAGE_SIZE));
+        assert_eq!(
+            file.seek_hole(2 * BASE_PAGE_SIZE + 1),
+            Some(2 * BASE_PAGE_SIZE + 1)
+        );
+        // End of file is an implicit hole
+        assert_eq!(file.seek_hole(3 * BASE_PAGE_SIZE), Some(size));
+        assert_eq!(file.seek_data(size - 1), Some(size - 1));
+        assert_eq!(file.seek_data(size), None);
+        assert_eq!(file.seek_hole(size), None);
+    }
+
     #[test]
     /// This test checks if the file truncation works as expected.
     fn test_file_truncate() {
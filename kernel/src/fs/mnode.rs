@@ -4,7 +4,7 @@
 use alloc::string::String;
 use core::convert::TryFrom;
 
-use kpi::io::FileType;
+use kpi::io::{FileModes, FileType};
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
@@ -15,11 +15,15 @@ use super::{Mnode, Modes};
 
 /// Memnode representation, similar to Inode for a memory-fs.
 #[derive(Debug)]
+#[cfg_attr(test, derive(Clone))]
 pub struct MemNode {
     mnode_num: Mnode,
     name: String,
     node_type: FileType,
+    modes: FileModes,
     file: Option<File>,
+    /// Number of names directly inside a directory (always 0 for files).
+    entries: u64,
 }
 
 /// Required for the testing
@@ -28,7 +32,9 @@ impl PartialEq for MemNode {
         (self.mnode_num == other.mnode_num)
             && (self.name == other.name)
             && (self.node_type == other.node_type)
+            && (self.modes == other.modes)
             && (self.file == other.file)
+            && (self.entries == other.entries)
     }
 }
 
@@ -39,7 +45,9 @@ impl Default for MemNode {
             mnode_num: u64::MAX,
             name: String::new(),
             node_type: FileType::File,
+            modes: FileModes::empty(),
             file: None,
+            entries: 0,
         }
     }
 }
@@ -64,7 +72,9 @@ impl MemNode {
             mnode_num,
             name: TryString::try_from(pathname)?.into(),
             node_type,
+            modes: FileModes::from(modes),
             file,
+            entries: 0,
         })
     }
 
@@ -76,10 +86,70 @@ impl MemNode {
             return Err(KError::PermissionError);
         }
         let len: usize = buffer.len();
+        // A zero-length write doesn't touch the file (not even to extend it to `offset`).
+        if len == 0 {
+            return Ok(0);
+        }
 
         self.file.as_mut().unwrap().write_file(buffer, len, offset)
     }
 
+    /// Overwrite `expected` at `offset` with `new`, but only if the file
+    /// currently holds `expected` there.
+    ///
+    /// # Returns
+    /// `true` if the file was written, `false` if its contents didn't match.
+    pub fn compare_and_write(
+        &mut self,
+        expected: &[u8],
+        new: &[u8],
+        offset: usize,
+    ) -> Result<bool, KError> {
+        if self.node_type != FileType::File {
+            return Err(KError::PermissionError);
+        }
+        let file = self.file.as_mut().unwrap();
+        if !file.get_mode().is_readable() || !file.get_mode().is_writable() {
+            return Err(KError::PermissionError);
+        }
+        if expected.len() != new.len() {
+            return Err(KError::InvalidLength);
+        }
+
+        if !file.contains_at(expected, offset) {
+            return Ok(false);
+        }
+        file.write_file(new, new.len(), offset)?;
+        Ok(true)
+    }
+
+    /// Grow the file to `size` bytes (the new part reads as zeroes), does
+    /// nothing if the file is already at least that long.
+    ///
+    /// On error the file is left unmodified.
+    pub fn preallocate(&mut self, size: usize) -> Result<(), KError> {
+        if self.node_type != FileType::File {
+            return Err(KError::PermissionError);
+        }
+        let file = self.file.as_mut().unwrap();
+        let curr_size = file.get_size();
+        if size <= curr_size {
+            return Ok(());
+        }
+        file.increase_file_size(curr_size, size)
+    }
+
+    /// Append data (staged with `File::stage_append`) to an in-memory file.
+    pub fn commit_append(&mut self, staged: StagedAppend, buffer: &[u8]) -> Result<usize, KError> {
+        // Return if the user doesn't have write permissions for the file.
+        if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
+        {
+            return Err(KError::PermissionError);
+        }
+
+        self.file.as_mut().unwrap().commit_append(staged, buffer)
+    }
+
     /// Read from an in-memory file.
     pub fn read(&self, buffer: &mut UserSlice, offset: usize) -> Result<usize, KError> {
         // Return if the user doesn't have read permissions for the file.
@@ -110,7 +180,36 @@ impl MemNode {
         self.file
             .as_ref()
             .unwrap()
-            .read_file(&mut *buffer, offset, new_offset)
+            .read_file(buffer, offset, new_offset)
+    }
+
+    /// Checksum over the contents of the file.
+    pub fn checksum(&self) -> Result<u64, KError> {
+        if self.node_type != FileType::File {
+            return Err(KError::DirectoryError);
+        }
+        Ok(self.file.as_ref().unwrap().checksum())
+    }
+
+    /// Next data offset at or after `from_offset` (`None` for directories).
+    pub fn seek_data(&self, from_offset: usize) -> Option<usize> {
+        self.file
+            .as_ref()
+            .and_then(|file| file.seek_data(from_offset))
+    }
+
+    /// Next hole offset at or after `from_offset` (`None` for directories).
+    pub fn seek_hole(&self, from_offset: usize) -> Option<usize> {
+        self.file
+            .as_ref()
+            .and_then(|file| file.seek_hole(from_offset))
+    }
+
+    /// Prepares `len` bytes at `offset` for a read (nothing for directories).
+    pub fn read_ahead(&self, offset: usize, len: usize) -> usize {
+        self.file
+            .as_ref()
+            .map_or(0, |file| file.read_ahead(offset, len))
     }
 
     /// Get the file size
@@ -123,6 +222,32 @@ impl MemNode {
         self.node_type
     }
 
+    /// Get the modes of the mnode.
+    pub fn get_mode(&self) -> FileModes {
+        self.modes
+    }
+
+    /// Get the number of entries in a directory.
+    pub fn get_entry_count(&self) -> u64 {
+        self.entries
+    }
+
+    /// Set the number of entries in a directory (ignored for files).
+    pub fn set_entry_count(&mut self, entries: u64) {
+        if self.node_type == FileType::Directory {
+            self.entries = entries;
+        }
+    }
+
+    /// Change the modes of the mnode, later reads and writes are checked
+    /// against the new modes.
+    pub fn set_mode(&mut self, modes: Modes) {
+        self.modes = FileModes::from(modes);
+        if let Some(file) = self.file.as_mut() {
+            file.set_mode(self.modes);
+        }
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) -> Result<(), KError> {
         if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
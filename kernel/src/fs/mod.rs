@@ -3,16 +3,18 @@
 
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use kpi::io::*;
 use spin::RwLock;
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::fallible_string::TryString;
+use crate::memory::BASE_PAGE_SIZE;
 
 pub use rwlock::RwLock as NrLock;
 
@@ -48,8 +50,20 @@ pub type Offset = i64;
 
 /// Abstract definition of file-system interface operations.
 pub trait FileSystem {
-    fn create(&self, pathname: &str, modes: Modes) -> Result<u64, KError>;
+    fn create(&self, pathname: &str, modes: Modes, node_type: FileType) -> Result<u64, KError>;
+    /// Create a regular file.
+    fn create_file(&self, pathname: &str, modes: Modes) -> Result<u64, KError> {
+        self.create(pathname, modes, FileType::File)
+    }
+    fn create_with_size(&self, pathname: &str, modes: Modes, size: usize) -> Result<u64, KError>;
     fn write(&self, mnode_num: Mnode, buffer: &[u8], offset: usize) -> Result<usize, KError>;
+    fn compare_and_write(
+        &self,
+        mnode_num: Mnode,
+        expected: &[u8],
+        new: &[u8],
+        offset: usize,
+    ) -> Result<bool, KError>;
     fn read(
         &self,
         mnode_num: Mnode,
@@ -58,10 +72,17 @@ pub trait FileSystem {
     ) -> Result<usize, KError>;
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>>;
     fn file_info(&self, mnode: Mnode) -> FileInfo;
+    fn file_stat(&self, mnode: Mnode) -> FileStat;
     fn delete(&self, pathname: &str) -> Result<(), KError>;
     fn truncate(&self, pathname: &str) -> Result<(), KError>;
     fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError>;
     fn mkdir(&self, pathname: &str, modes: Modes) -> Result<(), KError>;
+    fn rmdir(&self, pathname: &str) -> Result<(), KError>;
+    fn open_by_mnode(&self, mnode: Mnode, flags: Flags) -> Result<(), KError>;
+    fn checksum(&self, mnode: Mnode) -> Result<u64, KError>;
+    fn seek_data(&self, mnode: Mnode, from_offset: usize) -> Option<usize>;
+    fn seek_hole(&self, mnode: Mnode, from_offset: usize) -> Option<usize>;
+    fn read_ahead(&self, mnode: Mnode, offset: usize, len: usize) -> usize;
 }
 
 /// Abstract definition of a file descriptor.
@@ -80,6 +101,12 @@ pub struct Fd {
     mnode: Mnode,
     flags: FileFlags,
     offset: AtomicUsize,
+    /// The `FileAdvice` for the fd (as `u64`).
+    advice: AtomicU64,
+    /// Offset at which a read continues the previous read.
+    next_read: AtomicUsize,
+    /// Did the last read continue the one before it?
+    sequential: AtomicBool,
 }
 
 impl FileDescriptor for Fd {
@@ -89,6 +116,9 @@ impl FileDescriptor for Fd {
             mnode: u64::MAX,
             flags: Default::default(),
             offset: AtomicUsize::new(0),
+            advice: AtomicU64::new(FileAdvice::Normal as u64),
+            next_read: AtomicUsize::new(0),
+            sequential: AtomicBool::new(false),
         }
     }
 
@@ -114,9 +144,86 @@ impl FileDescriptor for Fd {
     }
 }
 
+impl Fd {
+    /// Sets the hint how the fd is going to be read.
+    pub fn set_advice(&self, advice: FileAdvice) {
+        self.advice.store(advice as u64, Ordering::Relaxed);
+    }
+
+    pub fn get_advice(&self) -> FileAdvice {
+        FileAdvice::try_from(self.advice.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    /// Tracks the access pattern, call this after reading `len` bytes at
+    /// `offset`.
+    ///
+    /// Returns true if the read is sequential: it started where the previous
+    /// read on the fd ended (for a new fd, that's offset 0).
+    pub fn record_read(&self, offset: usize, len: usize) -> bool {
+        let sequential = self.next_read.swap(offset + len, Ordering::Relaxed) == offset;
+        self.sequential.store(sequential, Ordering::Relaxed);
+        sequential
+    }
+
+    /// Should the file-system read ahead after a read on the fd?
+    pub fn read_ahead(&self) -> bool {
+        match self.get_advice() {
+            FileAdvice::Normal => self.sequential.load(Ordering::Relaxed),
+            FileAdvice::Sequential => true,
+            FileAdvice::Random => false,
+        }
+    }
+}
+
 /// The mnode number assigned to the first file.
 pub const MNODE_OFFSET: usize = 2;
 
+/// How many bytes past a read the file-system prepares when reading ahead.
+pub const READ_AHEAD_SIZE: usize = 4 * BASE_PAGE_SIZE;
+
+/// The default upper bound on the size of a single file (1 TiB).
+pub const DEFAULT_MAX_FILE_SIZE: usize = 1 << 40;
+
+/// An advisory lock on a file, see `MlnrFS::flock`.
+#[derive(Debug, Eq, PartialEq)]
+enum FileLock {
+    /// Held by all these owners.
+    Shared(Vec<u64>),
+    /// Held by a single owner.
+    Exclusive(u64),
+}
+
+impl FileLock {
+    /// Does anyone other than `owner` hold the lock?
+    fn held_by_other(&self, owner: u64) -> bool {
+        match self {
+            FileLock::Shared(owners) => owners.iter().any(|o| *o != owner),
+            FileLock::Exclusive(o) => *o != owner,
+        }
+    }
+
+    /// Drops `owner` from the lock, returns true if nobody holds it anymore.
+    fn release(&mut self, owner: u64) -> bool {
+        match self {
+            FileLock::Shared(owners) => {
+                owners.retain(|o| *o != owner);
+                owners.is_empty()
+            }
+            FileLock::Exclusive(o) => *o == owner,
+        }
+    }
+}
+
+/// The directory `pathname` is in (e.g., `/a` for `/a/b` and `/` for `/a`),
+/// `None` if `pathname` has no `/` in it or is the root.
+fn parent_dir(pathname: &str) -> Option<&str> {
+    let pathname = pathname.trim_end_matches('/');
+    match pathname.rfind('/')? {
+        0 => Some("/"),
+        idx => Some(&pathname[..idx]),
+    }
+}
+
 /// The in-memory file-system representation.
 #[derive(Debug)]
 pub struct MlnrFS {
@@ -126,6 +233,22 @@ pub struct MlnrFS {
     files: RwLock<HashMap<String, Arc<Mnode>>>,
     root: (String, Mnode),
     nextmemnode: AtomicUsize,
+    /// Writes that would grow a file past this size fail with `FileTooBig`.
+    max_file_size: usize,
+    /// Advisory locks held on files (see `flock`).
+    locks: RwLock<HashMap<Mnode, FileLock>>,
+    /// Files without a name (see `create_anonymous`).
+    anonymous: RwLock<HashSet<Mnode>>,
+}
+
+/// A copy of the files (and their contents) of a `MlnrFS`, see
+/// `MlnrFS::snapshot`.
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsSnapshot {
+    mnodes: HashMap<Mnode, MemNode>,
+    files: HashMap<String, Mnode>,
+    nextmemnode: usize,
 }
 
 unsafe impl Sync for MlnrFS {}
@@ -168,19 +291,350 @@ impl Default for MlnrFS {
             files,
             root,
             nextmemnode: AtomicUsize::new(MNODE_OFFSET),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            locks: RwLock::new(HashMap::new()),
+            anonymous: RwLock::new(HashSet::new()),
         }
     }
 }
 
 impl MlnrFS {
+    /// Create a file system that limits files to `max_file_size` bytes.
+    pub fn with_max_file_size(max_file_size: usize) -> MlnrFS {
+        MlnrFS {
+            max_file_size,
+            ..Default::default()
+        }
+    }
+
+    /// The maximum size a file can grow to through `write`.
+    pub fn max_file_size(&self) -> usize {
+        self.max_file_size
+    }
+
+    /// Takes or releases an advisory lock on `mnode` for `owner` (e.g., a
+    /// process id).
+    ///
+    /// Works like `flock(2)` with `LOCK_NB`: a request that conflicts with a
+    /// lock of another owner fails with `WouldBlock` instead of waiting. An
+    /// owner can convert its lock between shared and exclusive. Releasing a
+    /// lock that isn't held does nothing.
+    pub fn flock(&self, mnode: Mnode, owner: u64, op: LockOp) -> Result<(), KError> {
+        if !self.mnodes.read().contains_key(&mnode) {
+            return Err(KError::InvalidFile);
+        }
+
+        let mut locks = self.locks.write();
+        match op {
+            LockOp::Unlock => {
+                if locks.get_mut(&mnode).map_or(false, |lock| lock.release(owner)) {
+                    locks.remove(&mnode);
+                }
+                Ok(())
+            }
+            LockOp::Exclusive => {
+                if locks.get(&mnode).map_or(false, |lock| lock.held_by_other(owner)) {
+                    return Err(KError::WouldBlock);
+                }
+                locks.try_reserve(1)?;
+                locks.insert(mnode, FileLock::Exclusive(owner));
+                Ok(())
+            }
+            LockOp::Shared => {
+                if let Some(FileLock::Shared(owners)) = locks.get_mut(&mnode) {
+                    if !owners.contains(&owner) {
+                        owners.try_reserve(1)?;
+                        owners.push(owner);
+                    }
+                    return Ok(());
+                }
+                if locks.get(&mnode).map_or(false, |lock| lock.held_by_other(owner)) {
+                    return Err(KError::WouldBlock);
+                }
+
+                // Not locked, or `owner` converts its exclusive lock
+                let mut owners = Vec::new();
+                owners.try_reserve(1)?;
+                owners.push(owner);
+                locks.try_reserve(1)?;
+                locks.insert(mnode, FileLock::Shared(owners));
+                Ok(())
+            }
+        }
+    }
+
+    /// Releases all locks held by `owner` (e.g., once a process exits).
+    pub fn release_locks(&self, owner: u64) {
+        self.locks.write().retain(|_mnode, lock| !lock.release(owner));
+    }
+
+    /// Creates a file that has no name (like `O_TMPFILE`), it can only be
+    /// reached through its mnode.
+    ///
+    /// The file stays around until `release_anonymous` removes it.
+    pub fn create_anonymous(&self, modes: Modes) -> Result<Mnode, KError> {
+        let mnode_num = self.get_next_mno() as u64;
+        let memnode = MemNode::new(mnode_num, "", modes, FileType::File)?;
+
+        let mut mnodes = self.mnodes.write();
+        let mut anonymous = self.anonymous.write();
+        mnodes.try_reserve(1)?;
+        anonymous.try_reserve(1)?;
+        mnodes.insert(mnode_num, NrLock::new(memnode));
+        anonymous.insert(mnode_num);
+
+        Ok(mnode_num)
+    }
+
+    /// Is `mnode` a file created by `create_anonymous`?
+    pub fn is_anonymous(&self, mnode: Mnode) -> bool {
+        self.anonymous.read().contains(&mnode)
+    }
+
+    /// Removes `mnode` if it's a file without a name, returns true if it
+    /// did so.
+    pub fn release_anonymous(&self, mnode: Mnode) -> bool {
+        let mut mnodes = self.mnodes.write();
+        if self.anonymous.write().remove(&mnode) {
+            mnodes.remove(&mnode);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Changes the modes of the file or directory at `pathname` (like
+    /// `chmod`).
+    pub fn set_mode(&self, pathname: &str, modes: Modes) -> Result<(), KError> {
+        let files = self.files.read();
+        let mnode = files.get(pathname).ok_or(KError::InvalidFile)?;
+        match self.mnodes.read().get(mnode) {
+            Some(memnode) => {
+                memnode.write().set_mode(modes);
+                Ok(())
+            }
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Renames `oldname` to `newname`.
+    ///
+    /// An existing `newname` is overwritten unless `flags` has
+    /// `RENAME_NOREPLACE`, then the rename fails with `AlreadyPresent`.
+    pub fn rename2(&self, oldname: &str, newname: &str, flags: RenameFlags) -> Result<(), KError> {
+        if self.is_root(oldname) || self.is_root(newname) {
+            return Err(KError::PermissionError);
+        }
+        if self.files.read().get(oldname).is_none() {
+            return Err(KError::InvalidFile);
+        }
+        let newname_key = TryString::try_from(newname)?.into();
+
+        // If the newfile exists then overwrite it with the oldfile.
+        if !flags.is_noreplace() && self.files.read().get(newname).is_some() {
+            self.delete(newname).unwrap();
+        }
+
+        // TODO: Can we optimize it somehow?
+        let mut lock_at_root = self.files.write();
+        if flags.is_noreplace() && lock_at_root.contains_key(newname) {
+            return Err(KError::AlreadyPresent);
+        }
+        let mnode = match lock_at_root.remove_entry(oldname) {
+            Some((_key, oldnmode)) => {
+                let mnode = *oldnmode;
+                match lock_at_root.insert(newname_key, oldnmode) {
+                    None => mnode,
+                    Some(_) => return Err(KError::PermissionError),
+                }
+            }
+            None => return Err(KError::InvalidFile),
+        };
+
+        let mnodes = self.mnodes.read();
+        MlnrFS::update_parent_entries(&mnodes, &lock_at_root, oldname, false);
+        MlnrFS::update_parent_entries(&mnodes, &lock_at_root, newname, true);
+        // The children of a directory keep their names, so the count of a
+        // renamed directory has to be taken again.
+        if let Some(memnode) = mnodes.get(&mnode) {
+            let entries = MlnrFS::count_entries(&lock_at_root, newname);
+            memnode.write().set_entry_count(entries);
+        }
+        Ok(())
+    }
+
+    /// Flushes every file of the file system (like `sync(2)`).
+    ///
+    /// The in-memory file system has nothing to write back, it only checks
+    /// that every name and anonymous file refers to an existing mnode. Only
+    /// takes read locks, so writes to files can proceed while it runs.
+    pub fn sync_all(&self) -> Result<(), KError> {
+        let files = self.files.read();
+        let mnodes = self.mnodes.read();
+        let named = files.values().map(|mnode| **mnode);
+        let anonymous = self.anonymous.read();
+        if named
+            .chain(anonymous.iter().copied())
+            .all(|mnode| mnodes.contains_key(&mnode))
+        {
+            Ok(())
+        } else {
+            Err(KError::InvalidFile)
+        }
+    }
+
+    /// Writes all buffers in `iovecs` back to back, starting at `offset`.
+    ///
+    /// The file stays locked for the whole operation so readers see either
+    /// none or all of the buffers. Returns the total number of bytes written.
+    pub fn writev(
+        &self,
+        mnode_num: Mnode,
+        iovecs: &[&[u8]],
+        offset: usize,
+    ) -> Result<usize, KError> {
+        let len = iovecs
+            .iter()
+            .try_fold(0usize, |len, buf| len.checked_add(buf.len()))
+            .ok_or(KError::FileTooBig)?;
+        match offset.checked_add(len) {
+            Some(end) if len == 0 || end <= self.max_file_size => {}
+            _ => return Err(KError::FileTooBig),
+        }
+
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                let mut mnode = mnode.write();
+                let mut written = 0;
+                for buf in iovecs.iter().filter(|buf| !buf.is_empty()) {
+                    written += mnode.write(buf, offset + written)?;
+                }
+                Ok(written)
+            }
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Reads into the buffers in `iovecs`, filling one after the other,
+    /// starting at `offset`.
+    ///
+    /// Returns the total number of bytes read, this stops short at the end
+    /// of the file.
+    pub fn readv(
+        &self,
+        mnode_num: Mnode,
+        iovecs: &mut [UserSlice],
+        offset: usize,
+    ) -> Result<usize, KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                let mnode = mnode.read();
+                let mut read = 0;
+                for buf in iovecs.iter_mut().filter(|buf| !buf.is_empty()) {
+                    let len = mnode.read(buf, offset + read)?;
+                    read += len;
+                    if len < buf.len() {
+                        break;
+                    }
+                }
+                Ok(read)
+            }
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Copies the current state of the file system.
+    #[cfg(test)]
+    pub fn snapshot(&self) -> FsSnapshot {
+        let files = self.files.read();
+        let mnodes = self.mnodes.read();
+        FsSnapshot {
+            mnodes: mnodes
+                .iter()
+                .map(|(num, mnode)| (*num, mnode.read().clone()))
+                .collect(),
+            files: files
+                .iter()
+                .map(|(name, mnode)| (name.clone(), **mnode))
+                .collect(),
+            nextmemnode: self.nextmemnode.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Replaces the state of the file system with `snapshot`.
+    ///
+    /// Holds the locks of the file and mnode maps, so other operations see
+    /// either the old or the restored state.
+    #[cfg(test)]
+    pub fn restore(&self, snapshot: FsSnapshot) {
+        let mut files = self.files.write();
+        let mut mnodes = self.mnodes.write();
+        *mnodes = snapshot
+            .mnodes
+            .into_iter()
+            .map(|(num, mnode)| (num, NrLock::new(mnode)))
+            .collect();
+        *files = snapshot
+            .files
+            .into_iter()
+            .map(|(name, mnode)| (name, Arc::new(mnode)))
+            .collect();
+        self.nextmemnode.store(snapshot.nextmemnode, Ordering::Relaxed);
+    }
+
     /// Get the next available memnode number.
     fn get_next_mno(&self) -> usize {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Adds (`added`) or removes one entry from the count of the directory
+    /// containing `pathname`, if that directory exists.
+    fn update_parent_entries(
+        mnodes: &HashMap<Mnode, NrLock<MemNode>>,
+        files: &HashMap<String, Arc<Mnode>>,
+        pathname: &str,
+        added: bool,
+    ) {
+        let parent = match parent_dir(pathname).and_then(|parent| files.get(parent)) {
+            Some(parent) => parent,
+            None => return,
+        };
+        if let Some(memnode) = mnodes.get(parent) {
+            let mut memnode = memnode.write();
+            let entries = memnode.get_entry_count();
+            let entries = if added {
+                entries + 1
+            } else {
+                entries.saturating_sub(1)
+            };
+            memnode.set_entry_count(entries);
+        }
+    }
+
+    /// Counts the names directly inside `dirname` (used when a directory
+    /// gets a new name, later changes are tracked incrementally).
+    fn count_entries(files: &HashMap<String, Arc<Mnode>>, dirname: &str) -> u64 {
+        files
+            .keys()
+            .filter(|name| name.as_str() != dirname && parent_dir(name) == Some(dirname))
+            .count() as u64
+    }
+
+    /// Is `pathname` the root directory (which can't be removed or renamed)?
+    fn is_root(&self, pathname: &str) -> bool {
+        pathname == self.root.0
+    }
+
+    /// Would a write at `offset` append to the file `mnode`?
+    fn is_append(&self, mnode: &NrLock<MemNode>, offset: usize) -> bool {
+        let mnode = mnode.read();
+        mnode.get_mnode_type() == FileType::File && mnode.get_file_size() == offset
+    }
 }
 
 impl FileSystem for MlnrFS {
-    fn create(&self, pathname: &str, modes: Modes) -> Result<u64, KError> {
+    /// Create a file or a directory (depending on `node_type`).
+    fn create(&self, pathname: &str, modes: Modes, node_type: FileType) -> Result<u64, KError> {
         // Check if the file with the same name already exists.
         if self.files.read().get(pathname).is_some() {
             return Err(KError::AlreadyPresent);
@@ -194,19 +648,80 @@ impl FileSystem for MlnrFS {
         let mut mnodes = self.mnodes.write();
         mnodes.try_reserve(1)?;
 
-        // TODO: For now all newly created mnode are for file. How to differentiate
-        // between a file and a directory. Take input from the user?
-        let memnode = MemNode::new(mnode_num, pathname, modes, FileType::File)?;
+        let mut memnode = MemNode::new(mnode_num, pathname, modes, node_type)?;
 
-        self.files.write().insert(pathname_string, arc_mnode_num);
+        let mut files = self.files.write();
+        if node_type == FileType::Directory {
+            memnode.set_entry_count(MlnrFS::count_entries(&files, pathname));
+        }
+        files.insert(pathname_string, arc_mnode_num);
         mnodes.insert(mnode_num, NrLock::new(memnode));
+        MlnrFS::update_parent_entries(&mnodes, &files, pathname, true);
 
         Ok(mnode_num)
     }
 
+    /// Create a file that is `size` bytes long (and reads as zeroes), all
+    /// memory for it is allocated up front.
+    ///
+    /// If that allocation fails, no file is created.
+    fn create_with_size(&self, pathname: &str, modes: Modes, size: usize) -> Result<u64, KError> {
+        if size > self.max_file_size {
+            return Err(KError::FileTooBig);
+        }
+        let mnode_num = self.create_file(pathname, modes)?;
+        let preallocated = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.write().preallocate(size),
+            None => unreachable!("create_with_size: file we just created is gone"),
+        };
+
+        if let Err(e) = preallocated {
+            self.delete(pathname).expect("Can't remove the file we just created");
+            return Err(e);
+        }
+        Ok(mnode_num)
+    }
+
     fn write(&self, mnode_num: Mnode, buffer: &[u8], offset: usize) -> Result<usize, KError> {
         match self.mnodes.read().get(&mnode_num) {
-            Some(mnode) => mnode.write().write(buffer, offset),
+            Some(mnode) => {
+                // Reject the write before the file allocates any buffers for it.
+                if !buffer.is_empty() {
+                    match offset.checked_add(buffer.len()) {
+                        Some(end) if end <= self.max_file_size => {}
+                        _ => return Err(KError::FileTooBig),
+                    }
+                }
+
+                // Appends get copied into new buffers before we take the
+                // write-lock, so readers of the existing content only have to
+                // wait for the (short) commit and never see a partial append.
+                if buffer.len() > BASE_PAGE_SIZE && self.is_append(mnode, offset) {
+                    let staged = file::File::stage_append(offset, buffer)?;
+                    match mnode.write().commit_append(staged, buffer) {
+                        Err(KError::InvalidOffset) => {
+                            // The file changed in the meantime, do a regular write
+                        }
+                        r => return r,
+                    }
+                }
+                mnode.write().write(buffer, offset)
+            }
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Replaces `expected` at `offset` with `new` if (and only if) the file
+    /// holds `expected` there, no other write can come in between.
+    fn compare_and_write(
+        &self,
+        mnode_num: Mnode,
+        expected: &[u8],
+        new: &[u8],
+        offset: usize,
+    ) -> Result<bool, KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.write().compare_and_write(expected, new, offset),
             None => Err(KError::InvalidFile),
         }
     }
@@ -228,26 +743,39 @@ impl FileSystem for MlnrFS {
     }
 
     fn file_info(&self, mnode: Mnode) -> FileInfo {
+        self.file_stat(mnode).into()
+    }
+
+    fn file_stat(&self, mnode: Mnode) -> FileStat {
         match self.mnodes.read().get(&mnode) {
-            Some(mnode) => match mnode.read().get_mnode_type() {
-                FileType::Directory => FileInfo {
-                    fsize: 0,
-                    ftype: FileType::Directory.into(),
-                },
-                FileType::File => FileInfo {
-                    fsize: mnode.read().get_file_size() as u64,
-                    ftype: FileType::File.into(),
-                },
-            },
-            None => unreachable!("file_info: shouldn't reach here"),
+            Some(mnode) => {
+                let mnode = mnode.read();
+                // For directories, `fsize` is the number of entries in it.
+                let fsize = match mnode.get_mnode_type() {
+                    FileType::Directory => mnode.get_entry_count(),
+                    FileType::File => mnode.get_file_size() as u64,
+                };
+                FileStat {
+                    ftype: mnode.get_mnode_type().into(),
+                    fsize,
+                    // We don't support hard-links, every mnode has exactly one name.
+                    nlink: 1,
+                    mode: mnode.get_mode().into(),
+                }
+            }
+            None => unreachable!("file_stat: shouldn't reach here"),
         }
     }
 
     fn delete(&self, pathname: &str) -> Result<(), KError> {
+        if self.is_root(pathname) {
+            return Err(KError::PermissionError);
+        }
         let mut files = self.files.write();
         if let Some(mnode) = files.get(pathname) {
             if Arc::strong_count(mnode) == 1 {
                 self.mnodes.write().remove(mnode);
+                self.locks.write().remove(mnode);
             } else {
                 return Err(KError::PermissionError);
             }
@@ -257,6 +785,7 @@ impl FileSystem for MlnrFS {
 
         let r = files.remove(pathname);
         assert!(r.is_some(), "Didn't remove the mnode?");
+        MlnrFS::update_parent_entries(&self.mnodes.read(), &files, pathname, false);
         Ok(())
     }
 
@@ -271,49 +800,117 @@ impl FileSystem for MlnrFS {
     }
 
     fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError> {
-        if self.files.read().get(oldname).is_none() {
-            return Err(KError::InvalidFile);
+        self.rename2(oldname, newname, RenameFlags::empty())
+    }
+
+    /// Create a directory. The implementation is quite simplistic for now, and only used
+    /// by leveldb benchmark.
+    fn mkdir(&self, pathname: &str, modes: Modes) -> Result<(), KError> {
+        self.create(pathname, modes, FileType::Directory).map(|_mnode| ())
+    }
+
+    /// Remove an empty directory. Since the file-system keeps a flat path map,
+    /// a directory is considered non-empty if any path has it as a prefix.
+    fn rmdir(&self, pathname: &str) -> Result<(), KError> {
+        if self.is_root(pathname) {
+            return Err(KError::PermissionError);
         }
-        let newname_key = TryString::try_from(newname)?.into();
+        let mut files = self.files.write();
+        let (mnode, is_shared) = match files.get(pathname) {
+            Some(mnode) => (**mnode, Arc::strong_count(mnode) != 1),
+            None => return Err(KError::InvalidFile),
+        };
 
-        // If the newfile exists then overwrite it with the oldfile.
-        if self.files.read().get(newname).is_some() {
-            self.delete(newname).unwrap();
+        let mut mnodes = self.mnodes.write();
+        match mnodes.get(&mnode) {
+            Some(memnode) => {
+                if memnode.read().get_mnode_type() != FileType::Directory {
+                    return Err(KError::NotDirectory);
+                }
+            }
+            None => return Err(KError::InvalidFile),
         }
 
-        // TODO: Can we optimize it somehow?
-        let mut lock_at_root = self.files.write();
-        match lock_at_root.remove_entry(oldname) {
-            Some((_key, oldnmode)) => match lock_at_root.insert(newname_key, oldnmode) {
-                None => Ok(()),
-                Some(_) => Err(KError::PermissionError),
-            },
+        let has_children = files.keys().any(|name| {
+            name.len() > pathname.len()
+                && name.starts_with(pathname)
+                && (pathname.ends_with('/') || name.as_bytes()[pathname.len()] == b'/')
+        });
+        if has_children {
+            return Err(KError::DirectoryNotEmpty);
+        }
+        if is_shared {
+            return Err(KError::PermissionError);
+        }
+
+        mnodes.remove(&mnode);
+        self.locks.write().remove(&mnode);
+        let r = files.remove(pathname);
+        assert!(r.is_some(), "Didn't remove the mnode?");
+        MlnrFS::update_parent_entries(&mnodes, &files, pathname, false);
+        Ok(())
+    }
+
+    /// Re-open a file by its mnode number (e.g., from an earlier lookup)
+    /// without resolving the path again.
+    ///
+    /// Fails with `InvalidFile` if the mnode no longer exists, and with
+    /// `DirectoryError` if a directory is opened for writing (only read-only
+    /// opens of directories are allowed).
+    fn open_by_mnode(&self, mnode: Mnode, flags: Flags) -> Result<(), KError> {
+        let flags = FileFlags::from(flags);
+        match self.mnodes.read().get(&mnode) {
+            Some(memnode) => {
+                let is_directory = memnode.read().get_mnode_type() == FileType::Directory;
+                if flags.is_directory() && !is_directory {
+                    return Err(KError::NotDirectory);
+                }
+                if is_directory && (flags.is_write() || flags.is_create() || flags.is_truncate()) {
+                    return Err(KError::DirectoryError);
+                }
+                if flags.is_truncate() {
+                    memnode.write().file_truncate()?;
+                }
+                Ok(())
+            }
             None => Err(KError::InvalidFile),
         }
     }
 
-    /// Create a directory. The implementation is quite simplistic for now, and only used
-    /// by leveldb benchmark.
-    fn mkdir(&self, pathname: &str, modes: Modes) -> Result<(), KError> {
-        // Check if the file with the same name already exists.
-        if self.files.read().get(pathname).is_some() {
-            return Err(KError::AlreadyPresent);
+    /// Checksum over the contents of a file, e.g., to compare it with
+    /// a copy without transferring the data.
+    fn checksum(&self, mnode: Mnode) -> Result<u64, KError> {
+        match self.mnodes.read().get(&mnode) {
+            Some(memnode) => memnode.read().checksum(),
+            None => Err(KError::InvalidFile),
         }
+    }
 
-        let pathname_key = TryString::try_from(pathname)?.into();
-        let mnode_num = self.get_next_mno() as u64;
-        // TODO(error-handling): Should we decrease mnode-num or ignore?
-        let arc_mnode_num = Arc::try_new(mnode_num)?;
-        let mut mnodes = self.mnodes.write();
-        mnodes.try_reserve(1)?;
+    /// Finds the next data region (at or after `from_offset`), lets a copy
+    /// skip holes.
+    fn seek_data(&self, mnode: Mnode, from_offset: usize) -> Option<usize> {
+        self.mnodes
+            .read()
+            .get(&mnode)
+            .and_then(|memnode| memnode.read().seek_data(from_offset))
+    }
 
-        let memnode = match MemNode::new(mnode_num, pathname, modes, FileType::Directory) {
-            Ok(memnode) => memnode,
-            Err(e) => return Err(e),
-        };
-        self.files.write().insert(pathname_key, arc_mnode_num);
-        mnodes.insert(mnode_num, NrLock::new(memnode));
+    /// Finds the next hole (at or after `from_offset`).
+    fn seek_hole(&self, mnode: Mnode, from_offset: usize) -> Option<usize> {
+        self.mnodes
+            .read()
+            .get(&mnode)
+            .and_then(|memnode| memnode.read().seek_hole(from_offset))
+    }
 
-        Ok(())
+    /// Hint that `len` bytes at `offset` are read soon.
+    ///
+    /// Our files are in memory, so this only touches their buffers. A
+    /// file-system with a backing store would start fetching the data here.
+    fn read_ahead(&self, mnode: Mnode, offset: usize, len: usize) -> usize {
+        self.mnodes
+            .read()
+            .get(&mnode)
+            .map_or(0, |memnode| memnode.read().read_ahead(offset, len))
     }
 }
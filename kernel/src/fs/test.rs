@@ -132,7 +132,8 @@ impl ModelFS {
 
 impl FileSystem for ModelFS {
     // Create just puts the file in the oplop and increases mnode counter.
-    fn create(&self, pathname: &str, mode: Modes) -> Result<u64, KError> {
+    /// The model doesn't distinguish between files and directories.
+    fn create(&self, pathname: &str, mode: Modes, _node_type: FileType) -> Result<u64, KError> {
         let path = String::from(pathname);
         if self.file_exists(&path) {
             Err(KError::AlreadyPresent)
@@ -147,6 +148,11 @@ impl FileSystem for ModelFS {
         }
     }
 
+    /// Return a `dummy` response (the model doesn't track file sizes).
+    fn create_with_size(&self, pathname: &str, mode: Modes, _size: usize) -> Result<u64, KError> {
+        self.create_file(pathname, mode)
+    }
+
     /// Write just logs the write to the oplog.
     ///
     /// Our model assumes that the buffer repeats the first byte for its entire length.
@@ -293,6 +299,11 @@ impl FileSystem for ModelFS {
         FileInfo { ftype: 0, fsize: 0 }
     }
 
+    /// Returns a `dummy` file-stat.
+    fn file_stat(&self, _mnode: Mnode) -> FileStat {
+        Default::default()
+    }
+
     /// Return a `dummy` response as this function is only used for open with O_TRUNC flag.
     fn truncate(&self, _pathname: &str) -> Result<(), KError> {
         Ok(())
@@ -306,6 +317,52 @@ impl FileSystem for ModelFS {
     fn mkdir(&self, _pathname: &str, _mode: Modes) -> Result<(), KError> {
         Ok(())
     }
+
+    fn rmdir(&self, _pathname: &str) -> Result<(), KError> {
+        Ok(())
+    }
+
+    /// Return a `dummy` checksum.
+    fn checksum(&self, _mnode: Mnode) -> Result<u64, KError> {
+        Ok(0)
+    }
+
+    /// Return a `dummy` response (the model doesn't compare contents).
+    fn compare_and_write(
+        &self,
+        _mnode: Mnode,
+        _expected: &[u8],
+        _new: &[u8],
+        _offset: usize,
+    ) -> Result<bool, KError> {
+        Ok(false)
+    }
+
+    /// Return a `dummy` response (the model has no holes).
+    fn seek_data(&self, _mnode: Mnode, from_offset: usize) -> Option<usize> {
+        Some(from_offset)
+    }
+
+    /// Return a `dummy` response (the model has no holes).
+    fn seek_hole(&self, _mnode: Mnode, _from_offset: usize) -> Option<usize> {
+        None
+    }
+
+    /// Return a `dummy` response (the model has no data to prepare).
+    fn read_ahead(&self, _mnode: Mnode, _offset: usize, _len: usize) -> usize {
+        0
+    }
+
+    /// Only the root is a directory in the model.
+    fn open_by_mnode(&self, mnode: Mnode, flags: Flags) -> Result<(), KError> {
+        if !self.mnode_exists(mnode) {
+            Err(KError::InvalidFile)
+        } else if FileFlags::from(flags).is_directory() && mnode != 1 {
+            Err(KError::NotDirectory)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// Two writes/reads at different offsets should return
@@ -313,7 +370,7 @@ impl FileSystem for ModelFS {
 #[test]
 fn model_read() {
     let mfs: ModelFS = Default::default();
-    assert!(mfs.create("/bla", FileModes::S_IRWXU.into()).is_ok());
+    assert!(mfs.create_file("/bla", FileModes::S_IRWXU.into()).is_ok());
     let mnode = mfs.lookup("/bla").unwrap();
 
     let mut wdata1 = [1, 1];
@@ -345,7 +402,7 @@ fn model_read() {
 #[test]
 fn model_overlapping_writes() {
     let mfs: ModelFS = Default::default();
-    assert!(mfs.create("/bla", FileModes::S_IRWXU.into()).is_ok());
+    assert!(mfs.create_file("/bla", FileModes::S_IRWXU.into()).is_ok());
     let mnode = mfs.lookup("/bla").unwrap();
 
     let mut data = [1, 1, 1];
@@ -374,6 +431,7 @@ enum TestAction {
     Create(Vec<String>, Modes),
     Delete(Vec<String>),
     Lookup(Vec<String>),
+    OpenByMnode(Mnode, bool),
 }
 
 /// Generates one `TestAction` entry randomly.
@@ -391,6 +449,7 @@ fn action() -> impl Strategy<Value = TestAction> {
         (path(), mode_gen(0xfff)).prop_map(|(a, b)| TestAction::Create(a, b)),
         path().prop_map(TestAction::Delete),
         path().prop_map(TestAction::Lookup),
+        (mnode_gen(0x1000), any::<bool>()).prop_map(|(a, b)| TestAction::OpenByMnode(a, b)),
     ]
 }
 
@@ -454,62 +513,147 @@ fn path() -> impl Strategy<Value = Vec<String>> {
     proptest::collection::vec(path_names(), 4)
 }
 
-proptest! {
-    // Verify that our FS implementation behaves according to the `ModelFileSystem`.
-    #[test]
-    fn model_equivalence(ops in actions()) {
-        let model: ModelFS = Default::default();
-        let totest: MlnrFS = Default::default();
+/// Checks that our FS implementation behaves according to the `ModelFileSystem`
+/// for the given list of actions.
+fn check_model_equivalence(ops: Vec<TestAction>) {
+    let model: ModelFS = Default::default();
+    let totest: MlnrFS = Default::default();
+
+    use TestAction::*;
+    for action in ops {
+        match action {
+            Read(mnode, offset, len) => {
+                let mut buffer1: Vec<u8> = Vec::with_capacity(len);
+                let mut buffer2: Vec<u8> = Vec::with_capacity(len);
+
+                let mut slice1 = UserSlice::from_slice(buffer1.as_mut_slice());
+                let mut slice2 = UserSlice::from_slice(buffer2.as_mut_slice());
+                let rmodel = model.read(mnode, &mut slice1, offset);
+                let rtotest = totest.read(mnode, &mut slice2, offset);
+                assert_eq!(rmodel, rtotest);
+                assert_eq!(buffer1, buffer2);
+            }
+            Write(mnode, offset, pattern, len) => {
+                let mut buffer: Vec<u8> = Vec::with_capacity(len);
+                for _i in 0..len {
+                    buffer.push(pattern as u8);
+                }
 
-        use TestAction::*;
-        for action in ops {
-            match action {
-                Read(mnode, offset, len) => {
+                let rmodel =
+                    model.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
+                let rtotest =
+                    totest.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
+                assert_eq!(rmodel, rtotest);
+            }
+            Create(path, mode) => {
+                let path_str = path.join("/");
 
-                    let mut buffer1: Vec<u8> = Vec::with_capacity(len);
-                    let mut buffer2: Vec<u8> = Vec::with_capacity(len);
+                let rmodel = model.create_file(path_str.as_str(), mode);
+                let rtotest = totest.create_file(path_str.as_str(), mode);
+                assert_eq!(rmodel, rtotest);
+            }
+            Delete(path) => {
+                let path_str = path.join("/");
 
-                    let rmodel = model.read(mnode, &mut UserSlice::from_slice(buffer1.as_mut_slice()), offset);
-                    let rtotest = totest.read(mnode, &mut UserSlice::from_slice(buffer2.as_mut_slice()), offset);
-                    assert_eq!(rmodel, rtotest);
-                    assert_eq!(buffer1, buffer2);
-                }
-                Write(mnode, offset, pattern, len) => {
-                    let mut buffer: Vec<u8> = Vec::with_capacity(len);
-                    for _i in 0..len {
-                        buffer.push(pattern as u8);
-                    }
+                let rmodel = model.delete(path_str.as_str());
+                let rtotest = totest.delete(path_str.as_str());
+                assert_eq!(rmodel, rtotest);
+            }
+            Lookup(path) => {
+                let path_str = path.join("/");
 
-                    let rmodel = model.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
-                    let rtotest = totest.write(mnode, &mut UserSlice::from_slice(buffer.as_mut_slice()), offset);
-                    assert_eq!(rmodel, rtotest);
+                let rmodel = model.lookup(path_str.as_str());
+                let rtotest = totest.lookup(path_str.as_str());
+                assert_eq!(rmodel, rtotest);
+            }
+            OpenByMnode(mnode, directory) => {
+                let mut flags = FileFlags::O_RDONLY;
+                if directory {
+                    flags |= FileFlags::O_DIRECTORY;
                 }
-                Create(path, mode) => {
-                    let path_str = path.join("/");
 
-                    let rmodel = model.create(path_str.as_str(), mode);
-                    let rtotest = totest.create(path_str.as_str(), mode);
-                    assert_eq!(rmodel, rtotest);
-                }
-                Delete(path) => {
-                    let path_str = path.join("/");
+                let rmodel = model.open_by_mnode(mnode, flags.into());
+                let rtotest = totest.open_by_mnode(mnode, flags.into());
+                assert_eq!(rmodel, rtotest);
+            }
+        }
+    }
+}
 
-                    let rmodel = model.delete(path_str.as_str());
-                    let rtotest = totest.delete(path_str.as_str());
-                    assert_eq!(rmodel, rtotest);
-                }
-                Lookup(path) => {
-                    let path_str = path.join("/");
+/// Environment variable to replay `model_equivalence` with a given seed.
+const PROPTEST_SEED_ENV: &str = "NRK_FS_PROPTEST_SEED";
+
+/// A proptest runner that derives all its random choices from `seed`.
+fn seeded_runner(seed: u64) -> proptest::test_runner::TestRunner {
+    use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+
+    let mut seed_bytes = [0u8; 32];
+    seed_bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    let config = Config {
+        // We store failing cases ourselves (see `model_equivalence_seeded`)
+        failure_persistence: None,
+        ..Config::default()
+    };
+    TestRunner::new_with_rng(config, TestRng::from_seed(RngAlgorithm::ChaCha, &seed_bytes))
+}
 
-                    let rmodel = model.lookup(path_str.as_str());
-                    let rtotest = totest.lookup(path_str.as_str());
-                    assert_eq!(rmodel, rtotest);
-                }
+/// Runs the model equivalence check for the actions generated from `seed`.
+///
+/// In case of a failure the (shrunk) list of actions is written to
+/// `nrk-fs-proptest-<seed>.txt` in the temp directory.
+fn model_equivalence_seeded(seed: u64) {
+    let mut runner = seeded_runner(seed);
+    let result = runner.run(&actions(), |ops| {
+        check_model_equivalence(ops);
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        let path = std::env::temp_dir().join(format!("nrk-fs-proptest-{}.txt", seed));
+        if let proptest::test_runner::TestError::Fail(_reason, ops) = &e {
+            if let Err(io_err) = std::fs::write(&path, format!("{:#?}\n", ops)) {
+                log::error!("Can't store failing actions in {:?}: {}", path, io_err);
             }
         }
+        panic!(
+            "model_equivalence failed, replay with {}={} (actions in {:?}): {}",
+            PROPTEST_SEED_ENV, seed, path, e
+        );
     }
 }
 
+/// Verify that our FS implementation behaves according to the `ModelFileSystem`.
+///
+/// Uses the seed in `NRK_FS_PROPTEST_SEED` if it's set, a new one otherwise.
+#[test]
+fn model_equivalence() {
+    let seed = std::env::var(PROPTEST_SEED_ENV)
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |t| t.as_nanos() as u64)
+        });
+    model_equivalence_seeded(seed);
+}
+
+/// The same seed always generates the same list of actions.
+#[test]
+fn proptest_seed_is_deterministic() {
+    use proptest::strategy::ValueTree;
+
+    let generate = |seed| {
+        actions()
+            .new_tree(&mut seeded_runner(seed))
+            .expect("Can't generate actions")
+            .current()
+    };
+    assert_eq!(generate(42), generate(42));
+    assert_eq!(generate(0xdead_beef), generate(0xdead_beef));
+    assert_ne!(generate(1), generate(2));
+}
+
 /// Initialize and update file descriptor mnode number and permission flags.
 #[test]
 fn test_file_descriptor() {
@@ -523,6 +667,23 @@ fn test_file_descriptor() {
     assert_eq!(fd.get_flags(), FileFlags::O_RDWR);
 }
 
+/// Closing all file descriptors at once.
+#[test]
+fn test_close_all_fds() {
+    use crate::fs::fd::FileDesc;
+    let mut fdesc: FileDesc = Default::default();
+    for _i in 0..4 {
+        assert!(fdesc.allocate_fd().is_some());
+    }
+    assert_eq!(fdesc.deallocate_fd(1), Ok(1));
+    assert_eq!(fdesc.open_count(), 3);
+
+    assert_eq!(fdesc.close_all(), 3);
+    assert_eq!(fdesc.open_count(), 0);
+    assert_eq!(fdesc.close_all(), 0);
+    assert_eq!(fdesc.deallocate_fd(0), Err(KError::InvalidFileDescriptor));
+}
+
 /// Initialize memfs for root and verify the values.
 #[test]
 fn test_memfs_init() {
@@ -542,7 +703,7 @@ fn test_memfs_init() {
 fn test_file_create() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -551,6 +712,29 @@ fn test_file_create() {
     );
 }
 
+/// `create` makes files and directories depending on the node type,
+/// `mkdir` goes through the same path.
+#[test]
+fn test_create_node_types() {
+    let memfs: MlnrFS = Default::default();
+    let modes = FileModes::S_IRWXU.into();
+    let file = memfs.create("/file", modes, FileType::File).unwrap();
+    let dir = memfs.create("/dir", modes, FileType::Directory).unwrap();
+    assert_eq!(memfs.file_info(file).ftype, FileType::File.into());
+    assert_eq!(memfs.file_info(dir).ftype, FileType::Directory.into());
+
+    // The directory can be removed, the file can't
+    assert_eq!(memfs.rmdir("/file"), Err(KError::NotDirectory));
+    assert_eq!(memfs.rmdir("/dir"), Ok(()));
+
+    assert_eq!(
+        memfs.create("/file", modes, FileType::Directory),
+        Err(KError::AlreadyPresent)
+    );
+    assert_eq!(memfs.mkdir("/file", modes), Err(KError::AlreadyPresent));
+    assert!(memfs.mkdir("/dir", modes).is_ok());
+}
+
 /// Create a file with non-read permission and try to read it.
 #[test]
 
@@ -558,7 +742,7 @@ fn test_file_read_permission_error() {
     let buffer = &[0; 10];
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IWUSR.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IWUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -580,7 +764,7 @@ fn test_file_write_permission_error() {
     let buffer = &[0; 10];
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -600,7 +784,7 @@ fn test_file_write() {
     let buffer = &[0; 10];
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -624,7 +808,7 @@ fn test_file_read() {
 
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -652,7 +836,7 @@ fn test_file_read() {
 fn test_file_lookup() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -668,7 +852,7 @@ fn test_file_lookup() {
 fn test_file_fake_lookup() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -684,7 +868,7 @@ fn test_file_fake_lookup() {
 fn test_file_duplicate_create() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -692,7 +876,7 @@ fn test_file_duplicate_create() {
         Some(&Arc::new(2))
     );
     assert_eq!(
-        memfs.create(filename, FileModes::S_IRWXU.into()),
+        memfs.create_file(filename, FileModes::S_IRWXU.into()),
         Err(KError::AlreadyPresent)
     );
 }
@@ -702,7 +886,7 @@ fn test_file_duplicate_create() {
 fn test_file_info() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
     assert_eq!(
@@ -712,6 +896,62 @@ fn test_file_info() {
     assert_eq!(memfs.file_info(2), FileInfo { ftype: 2, fsize: 0 });
 }
 
+/// Test file_stat reports the modes a file was created with.
+#[test]
+fn test_file_stat() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("file.txt", FileModes::S_IRUSR.into()).unwrap();
+    assert_eq!(
+        memfs.file_stat(mnode),
+        FileStat {
+            ftype: FileType::File.into(),
+            fsize: 0,
+            nlink: 1,
+            mode: FileModes::S_IRUSR.into(),
+        }
+    );
+    assert_eq!(memfs.file_info(mnode), memfs.file_stat(mnode).into());
+
+    memfs.mkdir("dir", FileModes::S_IRWXU.into()).unwrap();
+    let dir = memfs.lookup("dir").unwrap();
+    assert_eq!(
+        memfs.file_stat(*dir),
+        FileStat {
+            ftype: FileType::Directory.into(),
+            fsize: 0,
+            nlink: 1,
+            mode: FileModes::S_IRWXU.into(),
+        }
+    );
+}
+
+/// Test the `fsize` of a directory is the number of entries in it.
+#[test]
+fn test_directory_entry_count() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    let dir = *memfs.lookup("/dir").unwrap();
+    assert_eq!(memfs.file_info(dir).fsize, 0);
+
+    let n = 5;
+    for i in 0..n {
+        let name = format!("/dir/file{}", i);
+        memfs.create_file(&name, FileModes::S_IRWXU.into()).unwrap();
+    }
+    // Files further down don't count.
+    memfs.mkdir("/dir/sub", FileModes::S_IRWXU.into()).unwrap();
+    memfs.create_file("/dir/sub/file", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.file_info(dir).fsize, n + 1);
+    assert_eq!(memfs.file_info(*memfs.lookup("/dir/sub").unwrap()).fsize, 1);
+    assert_eq!(memfs.file_info(1).fsize, 1);
+
+    memfs.delete("/dir/file0").unwrap();
+    assert_eq!(memfs.file_info(dir).fsize, n);
+    memfs.rename("/dir/file1", "/file1").unwrap();
+    assert_eq!(memfs.file_info(dir).fsize, n - 1);
+    assert_eq!(memfs.file_info(1).fsize, 2);
+}
+
 /// Test file deletion.
 #[test]
 fn test_file_delete() {
@@ -719,7 +959,7 @@ fn test_file_delete() {
     let filename = "file.txt";
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
 
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.delete(filename), Ok(()));
     assert_eq!(memfs.delete(filename).is_err(), true);
@@ -734,12 +974,87 @@ fn test_file_delete() {
     );
 }
 
+/// Zero-length reads/writes succeed on a valid file and leave it unchanged.
+#[test]
+fn test_zero_length_io() {
+    let memfs: MlnrFS = Default::default();
+    let model: ModelFS = Default::default();
+    let mnode = memfs.create_file("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    let mmnode = model.create_file("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(mnode, mmnode);
+
+    let buffer: &mut [u8; 0] = &mut [];
+    assert_eq!(memfs.write(mnode, buffer, 100), Ok(0));
+    assert_eq!(model.write(mnode, buffer, 100), Ok(0));
+    assert_eq!(memfs.file_info(mnode).fsize, 0);
+
+    assert_eq!(
+        memfs.read(mnode, &mut UserSlice::from_slice(buffer), 0),
+        Ok(0)
+    );
+    assert_eq!(
+        model.read(mnode, &mut UserSlice::from_slice(buffer), 0),
+        Ok(0)
+    );
+}
+
+/// Writes up to the maximum file size succeed, anything past it fails
+/// without growing the file.
+#[test]
+fn test_max_file_size() {
+    let limit = 2 * crate::memory::BASE_PAGE_SIZE + 10;
+    let memfs = MlnrFS::with_max_file_size(limit);
+    assert_eq!(memfs.max_file_size(), limit);
+    let mnode = memfs.create_file("file.txt", FileModes::S_IRWXU.into()).unwrap();
+
+    let buffer = [0xaa; 10];
+    assert_eq!(memfs.write(mnode, &buffer, limit - 10), Ok(10));
+    assert_eq!(memfs.file_info(mnode).fsize, limit as u64);
+
+    assert_eq!(
+        memfs.write(mnode, &buffer[..1], limit),
+        Err(KError::FileTooBig)
+    );
+    assert_eq!(
+        memfs.write(mnode, &buffer, limit - 5),
+        Err(KError::FileTooBig)
+    );
+    assert_eq!(
+        memfs.write(mnode, &buffer, usize::MAX - 5),
+        Err(KError::FileTooBig)
+    );
+    assert_eq!(memfs.file_info(mnode).fsize, limit as u64);
+
+    // The default limit doesn't get in the way of regular writes.
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(memfs.max_file_size(), DEFAULT_MAX_FILE_SIZE);
+}
+
+/// Zero-length reads/writes still fail on an invalid file.
+#[test]
+fn test_zero_length_io_invalid_file() {
+    let memfs: MlnrFS = Default::default();
+    let model: ModelFS = Default::default();
+
+    let buffer: &mut [u8; 0] = &mut [];
+    assert_eq!(memfs.write(10, buffer, 0), Err(KError::InvalidFile));
+    assert_eq!(model.write(10, buffer, 0), Err(KError::InvalidFile));
+    assert_eq!(
+        memfs.read(10, &mut UserSlice::from_slice(buffer), 0),
+        Err(KError::InvalidFile)
+    );
+    assert_eq!(
+        model.read(10, &mut UserSlice::from_slice(buffer), 0),
+        Err(KError::InvalidFile)
+    );
+}
+
 #[test]
 fn test_file_rename() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let oldmnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert!(memfs.rename(filename, newname).is_ok());
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(oldmnode, *mnode);
@@ -750,7 +1065,7 @@ fn test_file_rename_and_read() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
 
     let buffer: &mut [u8; 10] = &mut [0xb; 10];
     assert_eq!(
@@ -774,7 +1089,7 @@ fn test_file_rename_and_write() {
     let memfs: MlnrFS = Default::default();
     let filename = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
+    let oldmnode = memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
     assert!(memfs.rename(filename, newname).is_ok());
     let mnode = memfs.lookup(newname).unwrap();
     assert_eq!(oldmnode, *mnode);
@@ -803,8 +1118,8 @@ fn test_file_rename_to_existent_file() {
     let memfs: MlnrFS = Default::default();
     let oldname = "file.txt";
     let newname = "filenew.txt";
-    let oldmnode = memfs.create(oldname, FileModes::S_IRWXU.into()).unwrap();
-    let newmnode = memfs.create(newname, FileModes::S_IRWXU.into()).unwrap();
+    let oldmnode = memfs.create_file(oldname, FileModes::S_IRWXU.into()).unwrap();
+    let newmnode = memfs.create_file(newname, FileModes::S_IRWXU.into()).unwrap();
     assert_ne!(oldmnode, newmnode);
     assert_eq!(memfs.rename(oldname, newname), Ok(()));
 
@@ -813,3 +1128,577 @@ fn test_file_rename_to_existent_file() {
     // New file points to old mnode.
     assert_eq!(*memfs.lookup(newname).unwrap(), oldmnode);
 }
+
+/// `RENAME_NOREPLACE` refuses to overwrite an existing destination.
+#[test]
+fn test_file_rename_noreplace() {
+    let memfs: MlnrFS = Default::default();
+    let oldmnode = memfs.create_file("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    let newmnode = memfs.create_file("filenew.txt", FileModes::S_IRWXU.into()).unwrap();
+
+    assert_eq!(
+        memfs.rename2("file.txt", "filenew.txt", RenameFlags::RENAME_NOREPLACE),
+        Err(KError::AlreadyPresent)
+    );
+    // Nothing changed
+    assert_eq!(*memfs.lookup("file.txt").unwrap(), oldmnode);
+    assert_eq!(*memfs.lookup("filenew.txt").unwrap(), newmnode);
+
+    assert_eq!(
+        memfs.rename2("file.txt", "other.txt", RenameFlags::RENAME_NOREPLACE),
+        Ok(())
+    );
+    assert_eq!(memfs.lookup("file.txt"), None);
+    assert_eq!(*memfs.lookup("other.txt").unwrap(), oldmnode);
+}
+
+/// Test removing an empty directory.
+#[test]
+fn test_rmdir_empty() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.rmdir("/dir"), Ok(()));
+    assert_eq!(memfs.lookup("/dir"), None);
+    assert_eq!(memfs.rmdir("/dir"), Err(KError::InvalidFile));
+}
+
+/// Test that a directory with children can't be removed.
+#[test]
+fn test_rmdir_not_empty() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    memfs
+        .create_file("/dir/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    memfs
+        .create_file("/dirfile.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(memfs.rmdir("/dir"), Err(KError::DirectoryNotEmpty));
+    assert!(memfs.lookup("/dir").is_some());
+
+    assert_eq!(memfs.delete("/dir/file.txt"), Ok(()));
+    assert_eq!(memfs.rmdir("/dir"), Ok(()));
+}
+
+/// Test that rmdir refuses to remove a regular file.
+#[test]
+fn test_rmdir_on_file() {
+    let memfs: MlnrFS = Default::default();
+    memfs.create_file("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.rmdir("file.txt"), Err(KError::NotDirectory));
+    assert!(memfs.lookup("file.txt").is_some());
+}
+
+/// Re-open a file by mnode, until it got deleted.
+#[test]
+fn test_open_by_mnode() {
+    let memfs: MlnrFS = Default::default();
+    let filename = "/dir/file.txt";
+    memfs.create_file(filename, FileModes::S_IRWXU.into()).unwrap();
+    let mnode = *memfs.lookup(filename).unwrap();
+
+    let buffer: &mut [u8; 10] = &mut [0xb; 10];
+    assert_eq!(memfs.write(mnode, buffer, 0), Ok(10));
+    assert_eq!(memfs.open_by_mnode(mnode, FileFlags::O_RDWR.into()), Ok(()));
+    assert_eq!(memfs.file_info(mnode).fsize, 10);
+
+    // O_TRUNC works the same as when opening by path
+    let flags = FileFlags::O_RDWR | FileFlags::O_TRUNC;
+    assert_eq!(memfs.open_by_mnode(mnode, flags.into()), Ok(()));
+    assert_eq!(memfs.file_info(mnode).fsize, 0);
+
+    // A stale mnode fails cleanly
+    assert_eq!(memfs.delete(filename), Ok(()));
+    assert_eq!(
+        memfs.open_by_mnode(mnode, FileFlags::O_RDWR.into()),
+        Err(KError::InvalidFile)
+    );
+}
+
+/// Directories can only be opened read-only.
+#[test]
+fn test_open_directory() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    let mnode = *memfs.lookup("/dir").unwrap();
+
+    assert_eq!(
+        memfs.open_by_mnode(mnode, FileFlags::O_RDONLY.into()),
+        Ok(())
+    );
+    assert_eq!(
+        memfs.open_by_mnode(mnode, FileFlags::O_RDWR.into()),
+        Err(KError::DirectoryError)
+    );
+    assert_eq!(
+        memfs.open_by_mnode(mnode, FileFlags::O_WRONLY.into()),
+        Err(KError::DirectoryError)
+    );
+    let flags = FileFlags::O_RDONLY | FileFlags::O_CREAT;
+    assert_eq!(
+        memfs.open_by_mnode(mnode, flags.into()),
+        Err(KError::DirectoryError)
+    );
+}
+
+/// Opening with O_DIRECTORY only succeeds for directories.
+#[test]
+fn test_open_expecting_directory() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    let dir = *memfs.lookup("/dir").unwrap();
+    let file = memfs.create_file("/file", FileModes::S_IRWXU.into()).unwrap();
+
+    let flags = FileFlags::O_RDONLY | FileFlags::O_DIRECTORY;
+    assert_eq!(memfs.open_by_mnode(dir, flags.into()), Ok(()));
+    assert_eq!(
+        memfs.open_by_mnode(file, flags.into()),
+        Err(KError::NotDirectory)
+    );
+    assert_eq!(
+        memfs.open_by_mnode(file, FileFlags::O_RDONLY.into()),
+        Ok(())
+    );
+}
+
+/// The checksum doesn't depend on how the file was written.
+#[test]
+fn test_checksum_chunking() {
+    let memfs: MlnrFS = Default::default();
+    let content: Vec<u8> = (0..3 * crate::memory::BASE_PAGE_SIZE + 17)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    let m1 = memfs.create_file("one", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(m1, &content, 0), Ok(content.len()));
+
+    // Same content, written backwards in odd-sized chunks
+    let m2 = memfs.create_file("two", FileModes::S_IRWXU.into()).unwrap();
+    let mut end = content.len();
+    while end > 0 {
+        let start = end.saturating_sub(1000);
+        assert_eq!(
+            memfs.write(m2, &content[start..end], start),
+            Ok(end - start)
+        );
+        end = start;
+    }
+
+    assert_eq!(memfs.checksum(m1), memfs.checksum(m2));
+
+    // Holes count as zeroes
+    let m3 = memfs.create_file("three", FileModes::S_IRWXU.into()).unwrap();
+    let m4 = memfs.create_file("four", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(m3, &[0; 10], 0), Ok(10));
+    assert_eq!(memfs.write(m4, &[0; 1], 9), Ok(1));
+    assert_eq!(memfs.checksum(m3), memfs.checksum(m4));
+}
+
+/// A single differing byte changes the checksum.
+#[test]
+fn test_checksum_differs() {
+    let memfs: MlnrFS = Default::default();
+    let mut content: Vec<u8> = (0..2 * crate::memory::BASE_PAGE_SIZE)
+        .map(|i| (i % 13) as u8)
+        .collect();
+
+    let m1 = memfs.create_file("one", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(m1, &content, 0), Ok(content.len()));
+
+    content[crate::memory::BASE_PAGE_SIZE + 1] ^= 0x1;
+    let m2 = memfs.create_file("two", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(m2, &content, 0), Ok(content.len()));
+
+    assert_ne!(memfs.checksum(m1).unwrap(), memfs.checksum(m2).unwrap());
+    assert_eq!(memfs.checksum(0xdead), Err(KError::InvalidFile));
+}
+
+/// Copying into a user-slice that's shorter than the source is truncated.
+#[test]
+fn test_user_slice_copy_from() {
+    let src: &[u8] = &[0xa; 32];
+    let mut dst = [0u8; 10];
+    assert_eq!(UserSlice::from_slice(&mut dst).copy_from(src), Ok(10));
+    assert_eq!(dst, [0xa; 10]);
+
+    let mut dst = [0u8; 64];
+    assert_eq!(UserSlice::from_slice(&mut dst).copy_from(src), Ok(32));
+    assert_eq!(&dst[..32], src);
+    assert!(dst[32..].iter().all(|b| *b == 0));
+}
+
+/// Copying at an offset only writes the tail of the user-slice.
+#[test]
+fn test_user_slice_copy_from_at() {
+    let src: &[u8] = &[0xa; 32];
+    let mut dst = [0u8; 40];
+    assert_eq!(UserSlice::from_slice(&mut dst).copy_from_at(30, src), Ok(10));
+    assert!(dst[..30].iter().all(|b| *b == 0));
+    assert_eq!(dst[30..], [0xa; 10]);
+
+    assert_eq!(UserSlice::from_slice(&mut dst).copy_from_at(40, src), Ok(0));
+    assert_eq!(
+        UserSlice::from_slice(&mut dst).copy_from_at(41, src),
+        Err(KError::InvalidOffset)
+    );
+}
+
+/// `LowestFree` reuses a closed fd right away.
+#[test]
+fn test_fd_policy_lowest_free() {
+    use super::fd::{FdAllocPolicy, FileDesc};
+
+    let mut fds: FileDesc = Default::default();
+    assert_eq!(fds.policy(), FdAllocPolicy::LowestFree);
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(0));
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(1));
+    assert_eq!(fds.deallocate_fd(0), Ok(0));
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(0));
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(2));
+}
+
+/// `Monotonic` keeps advancing and only wraps around at the end of the table.
+#[test]
+fn test_fd_policy_monotonic() {
+    use super::fd::{FdAllocPolicy, FileDesc};
+
+    let mut fds = FileDesc::with_policy(FdAllocPolicy::Monotonic);
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(0));
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(1));
+    assert_eq!(fds.deallocate_fd(0), Ok(0));
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(2));
+
+    // Fill up the rest of the table, then we have to wrap around to 0
+    for expected in 3..MAX_FILES_PER_PROCESS {
+        assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(expected as u64));
+    }
+    assert_eq!(fds.allocate_fd().map(|(fd, _)| fd), Some(0));
+    assert!(fds.allocate_fd().is_none());
+}
+
+/// Readers running concurrently with appends see the old data unchanged and
+/// appends either entirely or not at all.
+#[test]
+fn test_read_during_append() {
+    extern crate std;
+    use alloc::sync::Arc;
+    use std::thread;
+
+    const CHUNK: usize = 2 * crate::memory::BASE_PAGE_SIZE;
+    const APPENDS: usize = 64;
+    let head = crate::memory::BASE_PAGE_SIZE;
+
+    let memfs = Arc::new(MlnrFS::default());
+    let mnode = memfs.create_file("log", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(mnode, &[0xa; 4096], 0), Ok(head));
+
+    let writer = {
+        let memfs = memfs.clone();
+        thread::spawn(move || {
+            for i in 0..APPENDS {
+                let chunk = [(i % 200) as u8 + 1; CHUNK];
+                assert_eq!(memfs.write(mnode, &chunk, head + i * CHUNK), Ok(CHUNK));
+            }
+        })
+    };
+
+    let mut rbuffer: Vec<u8> = alloc::vec![0; head + APPENDS * CHUNK];
+    loop {
+        let mut first = [0u8; 4096];
+        assert_eq!(
+            memfs.read(mnode, &mut UserSlice::from_slice(&mut first), 0),
+            Ok(head)
+        );
+        assert!(first.iter().all(|b| *b == 0xa));
+
+        let read = memfs
+            .read(mnode, &mut UserSlice::from_slice(rbuffer.as_mut_slice()), 0)
+            .unwrap();
+        assert_eq!((read - head) % CHUNK, 0, "Saw a partial append");
+        for (i, chunk) in rbuffer[head..read].chunks(CHUNK).enumerate() {
+            assert!(chunk.iter().all(|b| *b == (i % 200) as u8 + 1));
+        }
+
+        if read == rbuffer.len() {
+            break;
+        }
+    }
+
+    writer.join().unwrap();
+}
+
+/// A compare-and-write only modifies the file if the contents match.
+#[test]
+fn test_compare_and_write() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("cas", FileModes::S_IRWXU.into()).unwrap();
+    let mut content = [0xa; 8192];
+    assert_eq!(memfs.write(mnode, &content, 0), Ok(8192));
+
+    // Range crosses a buffer boundary
+    assert_eq!(
+        memfs.compare_and_write(mnode, &[0xa; 16], &[0xb; 16], 4088),
+        Ok(true)
+    );
+    content[4088..4104].copy_from_slice(&[0xb; 16]);
+
+    // Contents changed in the meantime
+    assert_eq!(
+        memfs.compare_and_write(mnode, &[0xa; 16], &[0xc; 16], 4088),
+        Ok(false)
+    );
+    // Past the end of the file
+    assert_eq!(
+        memfs.compare_and_write(mnode, &[0xa; 16], &[0xc; 16], 8184),
+        Ok(false)
+    );
+    assert_eq!(
+        memfs.compare_and_write(mnode, &[0xa; 16], &[0xc; 8], 0),
+        Err(KError::InvalidLength)
+    );
+
+    let mut rbuffer = [0u8; 8192];
+    assert_eq!(
+        memfs.read(mnode, &mut UserSlice::from_slice(&mut rbuffer), 0),
+        Ok(8192)
+    );
+    assert_eq!(&rbuffer[..], &content[..]);
+}
+
+/// Writes to a read-only file succeed once `set_mode` made it writable.
+#[test]
+fn test_set_mode() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("file", FileModes::S_IRUSR.into()).unwrap();
+    assert_eq!(memfs.write(mnode, &[0xa; 10], 0), Err(KError::PermissionError));
+
+    assert_eq!(memfs.set_mode("file", FileModes::S_IRWXU.into()), Ok(()));
+    assert_eq!(memfs.file_stat(mnode).mode, u64::from(FileModes::S_IRWXU));
+    assert_eq!(memfs.write(mnode, &[0xa; 10], 0), Ok(10));
+
+    assert_eq!(memfs.set_mode("file", FileModes::S_IWUSR.into()), Ok(()));
+    let mut rdata = [0u8; 10];
+    let mut rbuffer = UserSlice::from_slice(&mut rdata);
+    assert_eq!(memfs.read(mnode, &mut rbuffer, 0), Err(KError::PermissionError));
+
+    assert_eq!(
+        memfs.set_mode("missing", FileModes::S_IRWXU.into()),
+        Err(KError::InvalidFile)
+    );
+}
+
+/// `sync_all` succeeds on a populated file system and doesn't change it,
+/// a name that points to a missing mnode is reported.
+#[test]
+fn test_sync_all() {
+    let memfs: MlnrFS = Default::default();
+    let a = memfs.create_file("a", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(a, &[0xa; 5000], 0), Ok(5000));
+    memfs.mkdir("dir", FileModes::S_IRWXU.into()).unwrap();
+    let b = memfs.create_file("dir/b", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(b, &[0xb; 10], 0), Ok(10));
+    memfs.create_anonymous(FileModes::S_IRWXU.into()).unwrap();
+
+    let snapshot = memfs.snapshot();
+    assert_eq!(memfs.sync_all(), Ok(()));
+    assert_eq!(memfs.snapshot(), snapshot);
+
+    memfs.files.write().insert(String::from("dangling"), Arc::new(0xdead));
+    assert_eq!(memfs.sync_all(), Err(KError::InvalidFile));
+}
+
+/// Restoring a snapshot undoes everything that happened after it was taken.
+#[test]
+fn test_snapshot_restore() {
+    let memfs: MlnrFS = Default::default();
+    let a = memfs.create_file("a", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(a, &[0xa; 5000], 0), Ok(5000));
+    memfs.mkdir("dir", FileModes::S_IRWXU.into()).unwrap();
+
+    let snapshot = memfs.snapshot();
+
+    assert_eq!(memfs.write(a, &[0xb; 100], 10), Ok(100));
+    let b = memfs.create_file("b", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(b, &[0xc; 10], 0), Ok(10));
+    memfs.delete("dir").unwrap();
+    assert_ne!(memfs.snapshot(), snapshot);
+
+    memfs.restore(snapshot.clone());
+    assert_eq!(memfs.snapshot(), snapshot);
+    assert!(memfs.lookup("b").is_none());
+    assert!(memfs.lookup("dir").is_some());
+
+    let mut rbuffer = [0u8; 5000];
+    assert_eq!(
+        memfs.read(a, &mut UserSlice::from_slice(&mut rbuffer), 0),
+        Ok(5000)
+    );
+    assert!(rbuffer.iter().all(|b| *b == 0xa));
+
+    // The file system keeps working (and hands out the same mnodes again)
+    assert_eq!(memfs.create_file("b", FileModes::S_IRWXU.into()), Ok(b));
+}
+
+/// The root directory can't be deleted, removed or renamed.
+#[test]
+fn test_root_is_protected() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(memfs.delete("/"), Err(KError::PermissionError));
+    assert_eq!(memfs.rmdir("/"), Err(KError::PermissionError));
+    assert_eq!(memfs.rename("/", "/root"), Err(KError::PermissionError));
+
+    memfs.create_file("file", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.rename("file", "/"), Err(KError::PermissionError));
+
+    // The root is still there and usable
+    assert_eq!(memfs.lookup("/").map(|m| *m), Some(1));
+    assert!(memfs.lookup("/root").is_none());
+    assert!(memfs.lookup("file").is_some());
+    assert!(memfs.create_file("/a", FileModes::S_IRWXU.into()).is_ok());
+    assert_eq!(memfs.file_info(1).ftype, FileType::Directory.into());
+}
+
+/// Files created with a size read as zeroes up to that size.
+#[test]
+fn test_create_with_size() {
+    const SIZE: usize = 1024 * 1024;
+    let memfs = MlnrFS::with_max_file_size(2 * SIZE);
+
+    let mnode = memfs
+        .create_with_size("file", FileModes::S_IRWXU.into(), SIZE)
+        .unwrap();
+    assert_eq!(memfs.file_info(mnode).fsize, SIZE as u64);
+
+    let mut rbuffer: Vec<u8> = alloc::vec![0xff; SIZE];
+    assert_eq!(
+        memfs.read(mnode, &mut UserSlice::from_slice(&mut rbuffer), 0),
+        Ok(SIZE)
+    );
+    assert!(rbuffer.iter().all(|b| *b == 0));
+
+    // Writing into the preallocated part doesn't change the size
+    assert_eq!(memfs.write(mnode, &[0xa; 10], SIZE - 10), Ok(10));
+    assert_eq!(memfs.file_info(mnode).fsize, SIZE as u64);
+
+    // Too big: no file is created
+    assert_eq!(
+        memfs.create_with_size("big", FileModes::S_IRWXU.into(), 2 * SIZE + 1),
+        Err(KError::FileTooBig)
+    );
+    assert!(memfs.lookup("big").is_none());
+}
+
+/// Conflicting advisory locks of different owners are refused.
+#[test]
+fn test_flock() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("file", FileModes::S_IRWXU.into()).unwrap();
+    let (first, second) = (1, 2);
+
+    assert_eq!(memfs.flock(mnode, first, LockOp::Exclusive), Ok(()));
+    assert_eq!(
+        memfs.flock(mnode, second, LockOp::Exclusive),
+        Err(KError::WouldBlock)
+    );
+    assert_eq!(
+        memfs.flock(mnode, second, LockOp::Shared),
+        Err(KError::WouldBlock)
+    );
+    // Unlocking someone else's lock does nothing
+    assert_eq!(memfs.flock(mnode, second, LockOp::Unlock), Ok(()));
+    assert_eq!(
+        memfs.flock(mnode, second, LockOp::Exclusive),
+        Err(KError::WouldBlock)
+    );
+
+    // Downgrade, then both can share the lock but neither can upgrade
+    assert_eq!(memfs.flock(mnode, first, LockOp::Shared), Ok(()));
+    assert_eq!(memfs.flock(mnode, second, LockOp::Shared), Ok(()));
+    assert_eq!(
+        memfs.flock(mnode, first, LockOp::Exclusive),
+        Err(KError::WouldBlock)
+    );
+    assert_eq!(memfs.flock(mnode, second, LockOp::Unlock), Ok(()));
+    assert_eq!(memfs.flock(mnode, first, LockOp::Exclusive), Ok(()));
+
+    // Locks go away with their owner
+    memfs.release_locks(first);
+    assert_eq!(memfs.flock(mnode, second, LockOp::Exclusive), Ok(()));
+
+    assert_eq!(
+        memfs.flock(0xdead, first, LockOp::Shared),
+        Err(KError::InvalidFile)
+    );
+}
+
+/// Buffers written with `writev` end up back to back in the file, `readv`
+/// splits the content up again.
+#[test]
+fn test_writev_readv() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("file", FileModes::S_IRWXU.into()).unwrap();
+
+    let iovecs: [&[u8]; 3] = [&[1, 1], &[], &[2, 2, 2]];
+    assert_eq!(memfs.writev(mnode, &iovecs, 0), Ok(5));
+    assert_eq!(memfs.writev(mnode, &[&[3]], 5), Ok(1));
+
+    let mut rdata = [0u8; 8];
+    let mut rbuffer = UserSlice::from_slice(&mut rdata);
+    assert_eq!(memfs.read(mnode, &mut rbuffer, 0), Ok(6));
+    assert_eq!(rdata, [1, 1, 2, 2, 2, 3, 0, 0]);
+
+    let (mut first, mut second, mut third) = ([0u8; 1], [0u8; 4], [0u8; 4]);
+    {
+        let mut iovecs = [
+            UserSlice::from_slice(&mut first),
+            UserSlice::from_slice(&mut second),
+            UserSlice::from_slice(&mut third),
+        ];
+        // Stops at the end of the file
+        assert_eq!(memfs.readv(mnode, &mut iovecs, 0), Ok(6));
+    }
+    assert_eq!((first, second, third), ([1], [1, 2, 2, 2], [3, 0, 0, 0]));
+
+    let max = memfs.max_file_size();
+    assert_eq!(
+        memfs.writev(mnode, &[&[1], &[2]], max - 1),
+        Err(KError::FileTooBig)
+    );
+    assert_eq!(memfs.writev(0xdead, &[&[1]], 0), Err(KError::InvalidFile));
+}
+
+/// Reads that continue where the previous one stopped turn on read-ahead
+/// (with the default advice), a jump turns it off again.
+#[test]
+fn test_read_ahead_detection() {
+    use crate::fs::{Fd, FileDescriptor};
+    let fd = Fd::init_fd();
+    assert_eq!(fd.get_advice(), FileAdvice::Normal);
+    assert!(!fd.read_ahead());
+
+    assert!(fd.record_read(0, 100));
+    assert!(fd.read_ahead());
+    assert!(fd.record_read(100, 50));
+    assert!(fd.read_ahead());
+
+    // Random access
+    assert!(!fd.record_read(4096, 10));
+    assert!(!fd.read_ahead());
+    assert!(fd.record_read(4106, 10));
+    assert!(fd.read_ahead());
+
+    fd.set_advice(FileAdvice::Random);
+    assert!(!fd.read_ahead());
+    fd.set_advice(FileAdvice::Sequential);
+    assert!(!fd.record_read(0, 10));
+    assert!(fd.read_ahead());
+
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create_file("file", FileModes::S_IRWXU.into()).unwrap();
+    let size = 3 * BASE_PAGE_SIZE;
+    assert_eq!(memfs.write(mnode, &[1; 3 * BASE_PAGE_SIZE], 0), Ok(size));
+    // Read-ahead stops at the end of the file
+    assert_eq!(memfs.read_ahead(mnode, 10, READ_AHEAD_SIZE), size - 10);
+    assert_eq!(memfs.read_ahead(mnode, size, READ_AHEAD_SIZE), 0);
+    assert_eq!(memfs.read_ahead(0xdead, 0, READ_AHEAD_SIZE), 0);
+}
@@ -144,6 +144,65 @@ pub fn xmain() -> Result<(), crate::error::KError> {
     arch::debug::shutdown(ExitReason::Ok);
 }
 
+/// Compares allocating and freeing fixed-size objects from an `ObjectPool`
+/// with going through the global allocator.
+#[cfg(all(feature = "integration-test", feature = "test-objectpool"))]
+pub fn xmain() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::ptr::NonNull;
+    use log::info;
+
+    use crate::memory::mcache::TCache;
+    use crate::memory::pool::ObjectPool;
+    use crate::memory::PhysicalPageProvider;
+
+    type Object = [u64; 8];
+    const BATCH: usize = 64;
+    const ITERATIONS: usize = 10_000;
+
+    let frame = kcb::get_kcb()
+        .mem_manager()
+        .allocate_large_page()
+        .expect("Can't allocate a large page");
+    let mut tcache = TCache::new_with_frame(frame.affinity, frame);
+    let mut pool: ObjectPool<Object> = ObjectPool::new();
+
+    let mut objs: Vec<NonNull<Object>> = Vec::with_capacity(BATCH);
+    let start = unsafe { x86::time::rdtsc() };
+    for i in 0..ITERATIONS {
+        for _ in 0..BATCH {
+            let obj = pool.alloc(&mut tcache, [i as u64; 8]).expect("Can't allocate");
+            objs.push(obj);
+        }
+        for obj in objs.drain(..) {
+            unsafe { pool.free(obj) };
+        }
+    }
+    let pool_cycles = unsafe { x86::time::rdtsc() } - start;
+
+    let mut boxes: Vec<Box<Object>> = Vec::with_capacity(BATCH);
+    let mut sum = 0;
+    let start = unsafe { x86::time::rdtsc() };
+    for i in 0..ITERATIONS {
+        for _ in 0..BATCH {
+            boxes.push(Box::new([i as u64; 8]));
+        }
+        // Use the objects so the allocations can't be elided
+        sum += boxes.iter().map(|b| b[7]).sum::<u64>();
+        boxes.clear();
+    }
+    let alloc_cycles = unsafe { x86::time::rdtsc() } - start;
+    assert_eq!(sum, (0..ITERATIONS as u64).sum::<u64>() * BATCH as u64);
+
+    let ops = (ITERATIONS * BATCH) as u64;
+    info!("ObjectPool alloc+free: {} cycles", pool_cycles / ops);
+    info!("Global allocator alloc+free: {} cycles", alloc_cycles / ops);
+    pool.release_frames(&mut tcache).expect("Can't release pool frames");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Checks that we can initialize ACPI, query the ACPI tables,
 /// and parse the topology. The test ensures things work in case we
 /// have no numa nodes.
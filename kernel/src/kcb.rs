@@ -24,10 +24,13 @@ use crate::arch::process::PROCESS_TABLE;
 use crate::memory::emem::EmergencyAllocator;
 use crate::memory::mcache::TCache;
 use crate::memory::mcache::TCacheSp;
-use crate::memory::{AllocatorStatistics, GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider};
+use crate::memory::pool::TableFramePool;
+use crate::memory::{
+    AllocatorStatistics, Frame, GlobalMemory, GrowBackend, OomPolicy, PAddr, PhysicalPageProvider,
+};
 use crate::nr::KernelNode;
 use crate::nrproc::NrProcess;
-use crate::process::{Pid, Process, MAX_PROCESSES};
+use crate::process::{ElfLoading, Pid, Process, MAX_PROCESSES};
 
 pub use crate::arch::kcb::{get_kcb, try_get_kcb};
 
@@ -56,6 +59,14 @@ enum CmdToken {
     #[token("appcmd")]
     AppArgs,
 
+    /// What the kernel allocator does once it runs out of memory.
+    #[token("oom")]
+    Oom,
+
+    /// How read-only ELF segments of processes get loaded.
+    #[token("elfload")]
+    ElfLoad,
+
     #[regex("[a-zA-Z0-9\\._-]*")]
     Ident,
 
@@ -80,6 +91,8 @@ pub struct BootloaderArguments {
     pub init_binary: &'static str,
     pub init_args: &'static str,
     pub app_args: &'static str,
+    pub oom_policy: OomPolicy,
+    pub elf_loading: ElfLoading,
 }
 
 impl Default for BootloaderArguments {
@@ -89,6 +102,8 @@ impl Default for BootloaderArguments {
             init_binary: "init",
             init_args: "",
             app_args: "",
+            oom_policy: OomPolicy::ReturnNull,
+            elf_loading: ElfLoading::Eager,
         }
     }
 }
@@ -105,6 +120,8 @@ impl BootloaderArguments {
             init_binary,
             init_args,
             app_args,
+            oom_policy: OomPolicy::ReturnNull,
+            elf_loading: ElfLoading::Eager,
         }
     }
 
@@ -131,7 +148,12 @@ impl BootloaderArguments {
                 CmdToken::KernelBinary => {
                     //assert_eq!(slice, "./kernel");
                 }
-                CmdToken::Log | CmdToken::InitBinary | CmdToken::InitArgs | CmdToken::AppArgs => {
+                CmdToken::Log
+                | CmdToken::InitBinary
+                | CmdToken::InitArgs
+                | CmdToken::AppArgs
+                | CmdToken::Oom
+                | CmdToken::ElfLoad => {
                     prev = token;
                 }
                 CmdToken::Ident => match prev {
@@ -151,6 +173,20 @@ impl BootloaderArguments {
                         parsed_args.app_args = slice;
                         prev = CmdToken::Error;
                     }
+                    CmdToken::Oom => {
+                        match slice.parse() {
+                            Ok(policy) => parsed_args.oom_policy = policy,
+                            Err(_) => error!("Unknown oom policy '{}' in {}", slice, args),
+                        }
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::ElfLoad => {
+                        match slice.parse() {
+                            Ok(loading) => parsed_args.elf_loading = loading,
+                            Err(_) => error!("Unknown elf loading '{}' in {}", slice, args),
+                        }
+                        prev = CmdToken::Error;
+                    }
                     _ => {
                         error!("Invalid cmd arguments: {} (skipped {})", args, slice);
                         continue;
@@ -161,6 +197,8 @@ impl BootloaderArguments {
                         && prev != CmdToken::InitBinary
                         && prev != CmdToken::InitArgs
                         && prev != CmdToken::AppArgs
+                        && prev != CmdToken::Oom
+                        && prev != CmdToken::ElfLoad
                     {
                         error!("Malformed args (unexpected equal sign) in {}", args);
                         continue;
@@ -264,6 +302,9 @@ where
     /// A handle to a bump-style emergency Allocator.
     pub ezone_allocator: RefCell<EmergencyAllocator>,
 
+    /// Zeroed base-pages that are kept around for page-tables.
+    pub table_frames: RefCell<TableFramePool>,
+
     /// Related meta-data to manage physical memory for a given NUMA node.
     pub physical_memory: PhysicalMemoryArena,
 
@@ -305,6 +346,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             kernel_binary,
             emanager: RefCell::new(emanager),
             ezone_allocator: RefCell::new(EmergencyAllocator::empty()),
+            table_frames: RefCell::new(TableFramePool::new()),
             node,
             memory_arenas: [DEFAULT_PHYSICAL_MEMORY_ARENA; MAX_NUMA_NODES],
             // Can't initialize these yet, we need basic Kcb first for
@@ -442,6 +484,23 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         )
     }
 
+    /// Get a (zeroed) base-page for a page-table.
+    ///
+    /// Uses a frame from `table_frames` if there is one and falls back to
+    /// the memory manager otherwise.
+    pub fn alloc_table_frame(&self) -> Result<Frame, KError> {
+        let mut pool = self.table_frames.borrow_mut();
+        let mut pager = self.mem_manager();
+        pool.alloc_table_frame(&mut *pager)
+    }
+
+    /// Give a page-table frame back (to `table_frames`).
+    pub fn free_table_frame(&self, frame: Frame) -> Result<(), KError> {
+        let mut pool = self.table_frames.borrow_mut();
+        let mut pager = self.mem_manager();
+        pool.free_table_frame(&mut *pager, frame)
+    }
+
     pub fn kernel_binary(&self) -> &'static [u8] {
         self.kernel_binary
     }
@@ -471,6 +530,8 @@ pub trait ArchSpecificKcb {
 #[cfg(test)]
 mod test {
     use super::BootloaderArguments;
+    use crate::memory::OomPolicy;
+    use crate::process::ElfLoading;
 
     #[test]
     fn parse_args_empty() {
@@ -512,6 +573,32 @@ mod test {
         assert_eq!(ba.init_args, "");
     }
 
+    #[test]
+    fn parse_args_oom() {
+        let ba = BootloaderArguments::from_str("./kernel oom=panic log=trace");
+        assert_eq!(ba.oom_policy, OomPolicy::Panic);
+        assert_eq!(ba.log_filter, "trace");
+        let ba = BootloaderArguments::from_str("./kernel oom=null");
+        assert_eq!(ba.oom_policy, OomPolicy::ReturnNull);
+        let ba = BootloaderArguments::from_str("./kernel oom=maybe");
+        assert_eq!(ba.oom_policy, OomPolicy::ReturnNull);
+    }
+
+    #[test]
+    fn parse_args_elfload() {
+        let ba = BootloaderArguments::from_str("./kernel log=trace");
+        assert_eq!(ba.elf_loading, ElfLoading::Eager);
+        let ba = BootloaderArguments::from_str("./kernel elfload=lazy log=trace");
+        assert_eq!(ba.elf_loading, ElfLoading::Lazy);
+        assert_eq!(ba.log_filter, "trace");
+        let ba = BootloaderArguments::from_str("./kernel elfload=eager");
+        assert_eq!(ba.elf_loading, ElfLoading::Eager);
+        let ba = BootloaderArguments::from_str("./kernel elfload=shared");
+        assert_eq!(ba.elf_loading, ElfLoading::Shared);
+        let ba = BootloaderArguments::from_str("./kernel elfload=sometimes");
+        assert_eq!(ba.elf_loading, ElfLoading::Eager);
+    }
+
     #[test]
     fn parse_args_initargs() {
         let ba = BootloaderArguments::from_str("./kernel initargs=0");
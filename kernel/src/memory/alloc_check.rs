@@ -0,0 +1,234 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Remembers the `Layout` of live allocations.
+//!
+//! Used by the `KernelAllocator` (with the `alloc-check` feature) to catch
+//! callers that free memory with a different layout than the one they
+//! allocated it with (which can make us give memory back to the wrong
+//! allocator).
+
+use core::alloc::Layout;
+
+use spin::Mutex;
+
+/// How many live allocations we can keep track of.
+///
+/// Allocations that don't fit anymore are not checked.
+pub const ALLOC_CHECK_ENTRIES: usize = 8192;
+
+/// Marks a slot that was never used.
+const EMPTY: u64 = 0;
+/// Marks a slot that was used but is free again (we still have to probe
+/// past it).
+const TOMBSTONE: u64 = 1;
+
+/// A deallocation that didn't match its allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutMismatch {
+    pub ptr: u64,
+    /// Layout the memory was allocated with.
+    pub allocated: Layout,
+    /// Layout the memory was freed with.
+    pub freed: Layout,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ptr: u64,
+    size: usize,
+    align: usize,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        ptr: EMPTY,
+        size: 0,
+        align: 0,
+    };
+}
+
+/// A fixed-size hash-table from pointer to `Layout`.
+///
+/// Doesn't allocate (it sits underneath the allocator after all).
+pub struct LayoutTable {
+    entries: Mutex<[Entry; ALLOC_CHECK_ENTRIES]>,
+}
+
+impl LayoutTable {
+    pub const fn new() -> LayoutTable {
+        LayoutTable {
+            entries: Mutex::new([Entry::EMPTY; ALLOC_CHECK_ENTRIES]),
+        }
+    }
+
+    /// Slots to look at for `ptr`, in probing order.
+    fn probe(ptr: u64) -> impl Iterator<Item = usize> {
+        // Allocations are at least 8 byte aligned, fibonacci hashing for the rest
+        let start = ((ptr >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize;
+        (0..ALLOC_CHECK_ENTRIES).map(move |i| (start + i) % ALLOC_CHECK_ENTRIES)
+    }
+
+    /// Remember that `ptr` was allocated with `layout`.
+    pub fn insert(&self, ptr: *const u8, layout: Layout) {
+        let ptr = ptr as u64;
+        debug_assert!(ptr != EMPTY && ptr != TOMBSTONE);
+
+        let mut entries = self.entries.lock();
+        for idx in LayoutTable::probe(ptr) {
+            if entries[idx].ptr == EMPTY || entries[idx].ptr == TOMBSTONE {
+                entries[idx] = Entry {
+                    ptr,
+                    size: layout.size(),
+                    align: layout.align(),
+                };
+                return;
+            }
+        }
+        // Table is full, this allocation won't be checked
+    }
+
+    /// Forget about `ptr`, which is freed with `layout`.
+    ///
+    /// Returns an error in case `ptr` was allocated with a different layout.
+    /// Pointers we don't know about are ignored.
+    pub fn remove(&self, ptr: *const u8, layout: Layout) -> Result<(), LayoutMismatch> {
+        let ptr = ptr as u64;
+        let mut entries = self.entries.lock();
+        for idx in LayoutTable::probe(ptr) {
+            if entries[idx].ptr == EMPTY {
+                break;
+            }
+            if entries[idx].ptr == ptr {
+                let entry = entries[idx];
+                entries[idx] = Entry {
+                    ptr: TOMBSTONE,
+                    ..Entry::EMPTY
+                };
+
+                if entry.size != layout.size() || entry.align != layout.align() {
+                    return Err(LayoutMismatch {
+                        ptr,
+                        // Safety: Was a valid layout when we inserted it
+                        allocated: unsafe {
+                            Layout::from_size_align_unchecked(entry.size, entry.align)
+                        },
+                        freed: layout,
+                    });
+                }
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `ptr` (allocated with `layout`) now has `new_size` bytes, it was
+    /// reallocated in place.
+    ///
+    /// Returns an error in case `ptr` was allocated with a different layout.
+    /// Pointers we don't know about are ignored.
+    pub fn resize(
+        &self,
+        ptr: *const u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<(), LayoutMismatch> {
+        let ptr = ptr as u64;
+        let mut entries = self.entries.lock();
+        for idx in LayoutTable::probe(ptr) {
+            if entries[idx].ptr == EMPTY {
+                break;
+            }
+            if entries[idx].ptr == ptr {
+                let entry = &mut entries[idx];
+                if entry.size != layout.size() || entry.align != layout.align() {
+                    return Err(LayoutMismatch {
+                        ptr,
+                        // Safety: Was a valid layout when we inserted it
+                        allocated: unsafe {
+                            Layout::from_size_align_unchecked(entry.size, entry.align)
+                        },
+                        freed: layout,
+                    });
+                }
+                entry.size = new_size;
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for LayoutTable {
+    fn default() -> LayoutTable {
+        LayoutTable::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Freeing with a different layout is detected.
+    #[test]
+    fn layout_table_mismatch() {
+        let table = LayoutTable::new();
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let wrong = Layout::from_size_align(128, 8).unwrap();
+
+        table.insert(0x1000 as *const u8, small);
+        table.insert(0x2000 as *const u8, small);
+        assert_eq!(
+            table.remove(0x1000 as *const u8, wrong),
+            Err(LayoutMismatch {
+                ptr: 0x1000,
+                allocated: small,
+                freed: wrong,
+            })
+        );
+        assert_eq!(table.remove(0x2000 as *const u8, small), Ok(()));
+
+        // Removed (or never inserted) pointers aren't checked
+        assert_eq!(table.remove(0x1000 as *const u8, wrong), Ok(()));
+        assert_eq!(table.remove(0x3000 as *const u8, wrong), Ok(()));
+    }
+
+    /// A resized entry is freed with its new size.
+    #[test]
+    fn layout_table_resize() {
+        let table = LayoutTable::new();
+        let small = Layout::from_size_align(20, 8).unwrap();
+        let grown = Layout::from_size_align(30, 8).unwrap();
+
+        table.insert(0x1000 as *const u8, small);
+        assert_eq!(table.resize(0x1000 as *const u8, small, 30), Ok(()));
+        assert_eq!(table.remove(0x1000 as *const u8, grown), Ok(()));
+
+        table.insert(0x1000 as *const u8, small);
+        assert!(table.resize(0x1000 as *const u8, grown, 40).is_err());
+    }
+
+    /// Entries are found again after colliding ones got removed.
+    #[test]
+    fn layout_table_probing() {
+        let table = LayoutTable::new();
+        let layout = |i: usize| Layout::from_size_align(8 * (i + 1), 8).unwrap();
+
+        for i in 0..ALLOC_CHECK_ENTRIES {
+            table.insert((0x10_0000 + i * 8) as *const u8, layout(i));
+        }
+        for i in (0..ALLOC_CHECK_ENTRIES).step_by(2) {
+            assert_eq!(
+                table.remove((0x10_0000 + i * 8) as *const u8, layout(i)),
+                Ok(())
+            );
+        }
+        for i in (1..ALLOC_CHECK_ENTRIES).step_by(2) {
+            assert!(table
+                .remove((0x10_0000 + i * 8) as *const u8, layout(i + 1))
+                .is_err());
+        }
+    }
+}
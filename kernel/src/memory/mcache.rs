@@ -23,12 +23,12 @@ use static_assertions as sa;
 use super::*;
 
 /// A big cache of base and large pages, fits on a 2 MiB page.
-pub type NCache = MCache<131071, 131070>;
+pub type NCache = MCache<131070, 131070>;
 sa::assert_eq_size!(NCache, [u8; LARGE_PAGE_SIZE]);
 sa::const_assert!(core::mem::align_of::<NCache>() <= super::BASE_PAGE_SIZE);
 
 /// A small cache of 4 KiB and 2 MiB pages, fits on a 4K page.
-pub type TCache = MCache<381, 128>;
+pub type TCache = MCache<380, 128>;
 sa::assert_eq_size!(TCache, [u8; BASE_PAGE_SIZE]);
 sa::const_assert!(core::mem::align_of::<TCache>() <= super::BASE_PAGE_SIZE);
 
@@ -48,6 +48,12 @@ pub struct MCache<const BP: usize, const LP: usize> {
     base_page_addresses: arrayvec::ArrayVec<PAddr, BP>,
     /// A vector of free, cached large-page addresses
     large_page_addresses: arrayvec::ArrayVec<PAddr, LP>,
+    /// How many entries at the bottom of `base_page_addresses` are
+    /// already zeroed (see `scrub_one`).
+    clean_base_pages: usize,
+    /// Were base-pages released since the last `compact`? Otherwise there
+    /// is nothing new that could form a large-page.
+    base_pages_released: bool,
 }
 
 impl<const BP: usize, const LP: usize> crate::kcb::MemManager for MCache<BP, LP> {}
@@ -58,6 +64,8 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
             node,
             base_page_addresses: arrayvec::ArrayVec::new_const(),
             large_page_addresses: arrayvec::ArrayVec::new_const(),
+            clean_base_pages: 0,
+            base_pages_released: false,
         }
     }
 
@@ -97,6 +105,22 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
         self.populate(frame, how_many_large_pages)
     }
 
+    /// Populate the MCache with at most the first `max_bytes` of `frame`
+    /// (using the same policy as `populate_2m_first`).
+    ///
+    /// This can be used to keep part of a node's memory out of the cache.
+    ///
+    /// # Returns
+    /// The part of `frame` that was not added (can be empty).
+    pub fn populate_limited(&mut self, frame: Frame, max_bytes: usize) -> Frame {
+        let limit = (max_bytes / BASE_PAGE_SIZE) * BASE_PAGE_SIZE;
+        let (absorbed, leftover) = frame.split_at(core::cmp::min(limit, frame.size()));
+        if absorbed.size() > 0 {
+            self.populate_2m_first(absorbed);
+        }
+        leftover
+    }
+
     fn populate(&mut self, frame: Frame, mut how_many_large_pages: usize) {
         let base_count_before_populate = self.base_page_addresses.len();
         let large_count_before_populate = self.large_page_addresses.len();
@@ -168,10 +192,24 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
             (*(ncache.as_mut_ptr())).node = node;
             (*(ncache.as_mut_ptr())).base_page_addresses = arrayvec::ArrayVec::new_const();
             (*(ncache.as_mut_ptr())).large_page_addresses = arrayvec::ArrayVec::new_const();
+            (*(ncache.as_mut_ptr())).clean_base_pages = 0;
             ncache.assume_init_mut()
         }
     }
 
+    /// Pop a base-page from the stack (while keeping track of clean pages).
+    fn pop_base_page(&mut self) -> Option<PAddr> {
+        let paddr = self.base_page_addresses.pop();
+        self.clean_base_pages =
+            core::cmp::min(self.clean_base_pages, self.base_page_addresses.len());
+        paddr
+    }
+
+    /// How many free base-pages are already zeroed.
+    pub fn clean_base_pages(&self) -> usize {
+        self.clean_base_pages
+    }
+
     fn paddr_to_base_page(&self, pa: PAddr) -> Frame {
         Frame::new(pa, BASE_PAGE_SIZE, self.node)
     }
@@ -191,6 +229,186 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
         self.base_page_addresses.len() * BASE_PAGE_SIZE
             + self.large_page_addresses.len() * LARGE_PAGE_SIZE
     }
+
+    /// Release a large-page, breaking it up into base-pages if the
+    /// large-page stack is already full.
+    ///
+    /// Fails with `CacheFull` (and keeps none of the memory) if the
+    /// base-page stack can't hold all base-pages of the frame either.
+    pub fn fragment_large_page(&mut self, frame: Frame) -> Result<(), KError> {
+        assert_eq!(frame.size(), LARGE_PAGE_SIZE);
+        assert_eq!(frame.base % LARGE_PAGE_SIZE, 0);
+        assert_eq!(frame.affinity, self.node);
+
+        if !self.large_page_addresses.is_full() {
+            self.large_page_addresses.push(frame.base);
+            return Ok(());
+        }
+
+        let spare = self.base_page_addresses.capacity() - self.base_page_addresses.len();
+        if spare < frame.base_pages() {
+            return Err(KError::CacheFull);
+        }
+        for base_page in frame {
+            self.base_page_addresses.push(base_page.base);
+        }
+        Ok(())
+    }
+
+    /// Promotes free base-pages that together form a large-page back to
+    /// large-pages (e.g., after large-pages were split up with
+    /// `fragment_large_page`).
+    ///
+    /// If a large-page is recovered this sorts the base-page stack, so we
+    /// forget which base-pages were already zeroed (see `scrub_one`).
+    ///
+    /// # Returns
+    /// How many large-pages were recovered.
+    pub fn compact(&mut self) -> usize {
+        self.base_pages_released = false;
+        let pages_per_large_page = LARGE_PAGE_SIZE / BASE_PAGE_SIZE;
+        let len = self.base_page_addresses.len();
+        if len < pages_per_large_page || self.large_page_addresses.is_full() {
+            return 0;
+        }
+
+        // Sort clean and dirty pages on their own first, that way we still
+        // know which ones are clean if nothing can be promoted
+        let (clean, dirty) = self
+            .base_page_addresses
+            .split_at_mut(self.clean_base_pages);
+        clean.sort_unstable();
+        dirty.sort_unstable();
+        if !Self::has_large_page(clean, dirty) {
+            return 0;
+        }
+
+        self.base_page_addresses.sort_unstable();
+        self.clean_base_pages = 0;
+
+        let mut recovered = 0;
+        let mut kept = 0;
+        let mut i = 0;
+        while i < len {
+            let paddr = self.base_page_addresses[i];
+            // The stack is sorted and has no duplicates so if the last page
+            // is where we expect it, all pages in-between are present too
+            let last = i + pages_per_large_page - 1;
+            if paddr % LARGE_PAGE_SIZE == 0
+                && last < len
+                && self.base_page_addresses[last] == paddr + (LARGE_PAGE_SIZE - BASE_PAGE_SIZE)
+                && !self.large_page_addresses.is_full()
+            {
+                self.large_page_addresses.push(paddr);
+                recovered += 1;
+                i += pages_per_large_page;
+            } else {
+                self.base_page_addresses[kept] = paddr;
+                kept += 1;
+                i += 1;
+            }
+        }
+        self.base_page_addresses.truncate(kept);
+
+        if recovered > 0 {
+            debug!("MCache#{} recovered {} large-pages.", self.node, recovered);
+        }
+        recovered
+    }
+
+    /// Do the (sorted, without duplicates) base-pages in `a` and `b` contain
+    /// all base-pages of a large-page?
+    fn has_large_page(a: &[PAddr], b: &[PAddr]) -> bool {
+        let pages_per_large_page = LARGE_PAGE_SIZE / BASE_PAGE_SIZE;
+        let (mut i, mut j) = (0, 0);
+        let mut prev = PAddr::zero();
+        let mut run = 0;
+        // Walk both in order, counting how long the current run of
+        // contiguous pages (starting at a large-page boundary) is
+        while i < a.len() || j < b.len() {
+            let paddr = if j == b.len() || (i < a.len() && a[i] < b[j]) {
+                i += 1;
+                a[i - 1]
+            } else {
+                j += 1;
+                b[j - 1]
+            };
+
+            if run > 0 && paddr == prev + BASE_PAGE_SIZE {
+                run += 1;
+            } else if paddr % LARGE_PAGE_SIZE == 0 {
+                run = 1;
+            } else {
+                run = 0;
+            }
+            if run == pages_per_large_page {
+                return true;
+            }
+            prev = paddr;
+        }
+
+        false
+    }
+}
+
+impl TCache {
+    /// How many pages we move at once in `refill_from`.
+    const REFILL_BATCH_SIZE: usize = 32;
+
+    /// Refill the TCache with up to `base_pages` base-pages and `large_pages`
+    /// large-pages from `ncache`.
+    ///
+    /// Pages are moved in batches (using the `ReapBackend` and `GrowBackend`
+    /// interfaces) so a caller only has to hold the NCache lock once. We never
+    /// take more than what fits in the TCache, or what the NCache has.
+    ///
+    /// Returns how many base and large-pages were moved.
+    pub fn refill_from(
+        &mut self,
+        ncache: &mut NCache,
+        base_pages: usize,
+        large_pages: usize,
+    ) -> (usize, usize) {
+        debug_assert_eq!(self.node, ncache.node, "Refill from a different node?");
+        let base_pages = core::cmp::min(self.spare_base_page_capacity(), base_pages);
+        let large_pages = core::cmp::min(self.spare_large_page_capacity(), large_pages);
+
+        let mut moved_base_pages = 0;
+        while moved_base_pages < base_pages {
+            let mut batch = [None; TCache::REFILL_BATCH_SIZE];
+            let count = core::cmp::min(batch.len(), base_pages - moved_base_pages);
+            ncache.reap_base_pages(&mut batch[..count]);
+
+            let frames: ArrayVec<Frame, { TCache::REFILL_BATCH_SIZE }> =
+                batch.iter().flatten().copied().collect();
+            self.grow_base_pages(&frames)
+                .expect("We ensure to not overfill the TCache above.");
+            moved_base_pages += frames.len();
+            if frames.len() < count {
+                // NCache ran out of base-pages
+                break;
+            }
+        }
+
+        let mut moved_large_pages = 0;
+        while moved_large_pages < large_pages {
+            let mut batch = [None; TCache::REFILL_BATCH_SIZE];
+            let count = core::cmp::min(batch.len(), large_pages - moved_large_pages);
+            ncache.reap_large_pages(&mut batch[..count]);
+
+            let frames: ArrayVec<Frame, { TCache::REFILL_BATCH_SIZE }> =
+                batch.iter().flatten().copied().collect();
+            self.grow_large_pages(&frames)
+                .expect("We ensure to not overfill the TCache above.");
+            moved_large_pages += frames.len();
+            if frames.len() < count {
+                // NCache ran out of large-pages
+                break;
+            }
+        }
+
+        (moved_base_pages, moved_large_pages)
+    }
 }
 
 impl<const BP: usize, const LP: usize> fmt::Debug for MCache<BP, LP> {
@@ -244,13 +462,36 @@ impl<const BP: usize, const LP: usize> AllocatorStatistics for MCache<BP, LP> {
 
 impl<const BP: usize, const LP: usize> PhysicalPageProvider for MCache<BP, LP> {
     fn allocate_base_page(&mut self) -> Result<Frame, KError> {
-        let paddr = self
-            .base_page_addresses
-            .pop()
-            .ok_or(KError::CacheExhausted)?;
+        let paddr = self.pop_base_page().ok_or(KError::CacheExhausted)?;
         Ok(self.paddr_to_base_page(paddr))
     }
 
+    fn allocate_clean_base_page(&mut self) -> Result<Frame, KError> {
+        if self.clean_base_pages > 0 {
+            // The top-most clean page, whatever is on top of the stack takes
+            // its place (which is no longer counted as clean)
+            self.clean_base_pages -= 1;
+            let paddr = self.base_page_addresses.swap_remove(self.clean_base_pages);
+            Ok(self.paddr_to_base_page(paddr))
+        } else {
+            let mut frame = self.allocate_base_page()?;
+            unsafe { frame.zero() };
+            Ok(frame)
+        }
+    }
+
+    fn scrub_one(&mut self) -> bool {
+        if self.clean_base_pages < self.base_page_addresses.len() {
+            let paddr = self.base_page_addresses[self.clean_base_pages];
+            let mut frame = self.paddr_to_base_page(paddr);
+            unsafe { frame.zero() };
+            self.clean_base_pages += 1;
+            true
+        } else {
+            false
+        }
+    }
+
     fn release_base_page(&mut self, frame: Frame) -> Result<(), KError> {
         assert_eq!(frame.size(), BASE_PAGE_SIZE);
         assert_eq!(frame.base % BASE_PAGE_SIZE, 0);
@@ -258,10 +499,19 @@ impl<const BP: usize, const LP: usize> PhysicalPageProvider for MCache<BP, LP> {
 
         self.base_page_addresses
             .try_push(frame.base)
-            .map_err(|_e| KError::CacheFull)
+            .map_err(|_e| KError::CacheFull)?;
+        self.base_pages_released = true;
+        Ok(())
     }
 
     fn allocate_large_page(&mut self) -> Result<Frame, KError> {
+        if self.large_page_addresses.is_empty()
+            && self.base_pages_released
+            && self.base_page_addresses.len() >= LARGE_PAGE_SIZE / BASE_PAGE_SIZE
+        {
+            // Maybe some of the released base-pages can form a large-page again
+            self.compact();
+        }
         let paddr = self
             .large_page_addresses
             .pop()
@@ -278,6 +528,43 @@ impl<const BP: usize, const LP: usize> PhysicalPageProvider for MCache<BP, LP> {
             .try_push(frame.base)
             .map_err(|_e| KError::CacheFull)
     }
+
+    fn release_base_pages(&mut self, frames: &[Frame]) -> Result<(), KError> {
+        let spare = self.base_page_addresses.capacity() - self.base_page_addresses.len();
+        let count = core::cmp::min(spare, frames.len());
+
+        for frame in &frames[..count] {
+            assert_eq!(frame.size(), BASE_PAGE_SIZE);
+            assert_eq!(frame.base % BASE_PAGE_SIZE, 0);
+            assert_eq!(frame.affinity, self.node);
+            self.base_page_addresses.push(frame.base);
+        }
+        self.base_pages_released |= count > 0;
+
+        if count < frames.len() {
+            Err(KError::CantGrowFurther { count })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn release_large_pages(&mut self, frames: &[Frame]) -> Result<(), KError> {
+        let spare = self.large_page_addresses.capacity() - self.large_page_addresses.len();
+        let count = core::cmp::min(spare, frames.len());
+
+        for frame in &frames[..count] {
+            assert_eq!(frame.size(), LARGE_PAGE_SIZE);
+            assert_eq!(frame.base % LARGE_PAGE_SIZE, 0);
+            assert_eq!(frame.affinity, self.node);
+            self.large_page_addresses.push(frame.base);
+        }
+
+        if count < frames.len() {
+            Err(KError::CantGrowFurther { count })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<const BP: usize, const LP: usize> GrowBackend for MCache<BP, LP> {
@@ -294,6 +581,7 @@ impl<const BP: usize, const LP: usize> GrowBackend for MCache<BP, LP> {
             self.base_page_addresses
                 .try_push(frame.base)
                 .map_err(|_e| KError::CacheFull)?;
+            self.base_pages_released = true;
         }
         Ok(())
     }
@@ -320,7 +608,7 @@ impl<const BP: usize, const LP: usize> ReapBackend for MCache<BP, LP> {
     /// Give base-pages back.
     fn reap_base_pages(&mut self, free_list: &mut [Option<Frame>]) {
         for insert in free_list.iter_mut() {
-            if let Some(paddr) = self.base_page_addresses.pop() {
+            if let Some(paddr) = self.pop_base_page() {
                 *insert = Some(self.paddr_to_base_page(paddr));
             } else {
                 // We don't have anything left in our cache
@@ -347,6 +635,9 @@ mod test {
     use super::*;
     extern crate std;
 
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+
     /// A hack to get an MCache without overflowing our stack in the tests :/
     ///
     /// A stack overflow results when trying allocate this the normal way
@@ -360,6 +651,20 @@ mod test {
         }
     }
 
+    /// Frames allocated with a tag carry it.
+    #[cfg(feature = "frame-tags")]
+    #[test]
+    fn tcache_allocate_tagged() {
+        let mut tcache = TCache::new(4);
+        tcache
+            .release_base_page(Frame::new(PAddr::from(0x2000), 0x1000, 4))
+            .expect("release");
+
+        let frame = tcache.allocate_base_page_tagged(0xbeef).expect("allocate");
+        assert_eq!(frame.base, PAddr::from(0x2000));
+        assert_eq!(frame.tag(), 0xbeef);
+    }
+
     /// Can't add wrong size.
     #[test]
     #[should_panic]
@@ -436,6 +741,46 @@ mod test {
         assert_eq!(free_list[1].unwrap().affinity, 4);
     }
 
+    /// Scrubbed base-pages are handed out by `allocate_clean_base_page`
+    /// without zeroing them again.
+    #[test]
+    fn tcache_scrub_and_allocate_clean() {
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let mut tcache = TCache::new(0);
+        for _i in 0..3 {
+            let mut frame = mm.allocate_frame(BASE_PAGE_SIZE).unwrap();
+            unsafe { frame.fill(0xffu8) };
+            tcache.release_base_page(frame).expect("release");
+        }
+
+        assert_eq!(tcache.clean_base_pages(), 0);
+        assert!(tcache.scrub_one());
+        assert!(tcache.scrub_one());
+        assert_eq!(tcache.clean_base_pages(), 2);
+
+        // Dirty the bottom (clean) page behind the allocator's back, this lets
+        // us tell if `allocate_clean_base_page` zeroes it again
+        let mut bottom = tcache.paddr_to_base_page(tcache.base_page_addresses[0]);
+        unsafe { bottom.fill(0xaau8) };
+
+        let f1 = tcache.allocate_clean_base_page().expect("allocate");
+        assert_eq!(tcache.clean_base_pages(), 1);
+        let f2 = tcache.allocate_clean_base_page().expect("allocate");
+        assert_eq!(tcache.clean_base_pages(), 0);
+        assert_eq!(f2.base, bottom.base);
+        unsafe {
+            assert!(f1.as_slice::<u8>().unwrap().iter().all(|b| *b == 0));
+            assert!(f2.as_slice::<u8>().unwrap().iter().all(|b| *b == 0xaa));
+        }
+
+        // No clean pages left, falls back to zeroing on allocation
+        let f3 = tcache.allocate_clean_base_page().expect("allocate");
+        unsafe {
+            assert!(f3.as_slice::<u8>().unwrap().iter().all(|b| *b == 0));
+        }
+        assert!(!tcache.scrub_one());
+    }
+
     /// Test that release and allocate works as expected.
     /// Also verify free memory reporting along the way.
     #[test]
@@ -497,6 +842,210 @@ mod test {
             .expect_err("Can't allocate more than we gave it");
     }
 
+    /// Test that we can release a batch of frames in one go.
+    #[test]
+    fn ncache_release_many() {
+        let mut ncache = get_an_ncache::<131070, 131070>();
+        ncache.node = 1;
+
+        let frames: std::vec::Vec<Frame> = (1..=100)
+            .map(|i| Frame::new(PAddr::from(i * BASE_PAGE_SIZE), BASE_PAGE_SIZE, 1))
+            .collect();
+        let free_before = ncache.free_base_pages();
+        ncache.release_base_pages(&frames).expect("release");
+        assert_eq!(ncache.free_base_pages(), free_before + 100);
+
+        let frames: std::vec::Vec<Frame> = (1..=4)
+            .map(|i| Frame::new(PAddr::from(i * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 1))
+            .collect();
+        ncache.release_large_pages(&frames).expect("release");
+        assert_eq!(ncache.free_large_pages(), 4);
+    }
+
+    /// Only the allowed amount of memory ends up in the cache.
+    #[test]
+    fn ncache_populate_limited() {
+        let ncache = get_an_ncache::<131070, 131070>();
+        let frame = Frame::new(PAddr::from(0x4000_0000), 8 * LARGE_PAGE_SIZE, 0);
+
+        // Gets rounded down to a multiple of the base-page size
+        let cap = 2 * LARGE_PAGE_SIZE + BASE_PAGE_SIZE + 100;
+        let leftover = ncache.populate_limited(frame, cap);
+        assert_eq!(
+            leftover.base,
+            frame.base + 2 * LARGE_PAGE_SIZE + BASE_PAGE_SIZE
+        );
+        assert_eq!(leftover.size(), 6 * LARGE_PAGE_SIZE - BASE_PAGE_SIZE);
+        assert_eq!(ncache.free(), 2 * LARGE_PAGE_SIZE + BASE_PAGE_SIZE);
+
+        // A cap bigger than the frame takes all of it
+        let leftover = ncache.populate_limited(leftover, usize::MAX);
+        assert_eq!(leftover.size(), 0);
+        assert_eq!(ncache.free(), frame.size());
+    }
+
+    /// A large-page that doesn't fit on the large-page stack ends up as
+    /// base-pages.
+    #[test]
+    fn mcache_fragment_large_page() {
+        let mut cache = MCache::<600, 2>::new(0);
+        for i in 1..=2 {
+            let frame = Frame::new(PAddr::from(i * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+            cache.release_large_page(frame).expect("release");
+        }
+        let frame = Frame::new(PAddr::from(3 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        assert_eq!(cache.release_large_page(frame), Err(KError::CacheFull));
+
+        assert_eq!(cache.fragment_large_page(frame), Ok(()));
+        assert_eq!(cache.free_large_pages(), 2);
+        assert_eq!(cache.free_base_pages(), LARGE_PAGE_SIZE / BASE_PAGE_SIZE);
+
+        // Not enough space for another 512 base-pages
+        let other = Frame::new(PAddr::from(4 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        assert_eq!(cache.fragment_large_page(other), Err(KError::CacheFull));
+        assert_eq!(cache.free_base_pages(), LARGE_PAGE_SIZE / BASE_PAGE_SIZE);
+
+        // We get back every base-page of the large-page
+        let mut bases: std::vec::Vec<PAddr> = (0..LARGE_PAGE_SIZE / BASE_PAGE_SIZE)
+            .map(|_| cache.allocate_base_page().expect("allocate").base)
+            .collect();
+        assert!(cache.allocate_base_page().is_err());
+        bases.sort_unstable();
+        for (i, base) in bases.iter().enumerate() {
+            assert_eq!(*base, frame.base + i * BASE_PAGE_SIZE);
+        }
+
+        // With space on the large-page stack it's kept as a large-page
+        let large = cache.allocate_large_page().expect("allocate");
+        assert_eq!(cache.fragment_large_page(large), Ok(()));
+        assert_eq!(cache.free_large_pages(), 2);
+        assert_eq!(cache.free_base_pages(), 0);
+    }
+
+    /// Base-pages of a fragmented large-page are turned back into a
+    /// large-page by `compact`.
+    #[test]
+    fn mcache_compact() {
+        let mut cache = MCache::<1200, 2>::new(0);
+        let frame = Frame::new(PAddr::from(2 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        // Release the base-pages out of order with some unrelated ones in-between
+        let pages: Vec<Frame> = frame.into_iter().collect();
+        for (i, base_page) in pages.iter().rev().enumerate() {
+            cache.release_base_page(*base_page).expect("release");
+            if i % 128 == 0 {
+                let other = PAddr::from(8 * LARGE_PAGE_SIZE + i * BASE_PAGE_SIZE);
+                cache
+                    .release_base_page(Frame::new(other, BASE_PAGE_SIZE, 0))
+                    .expect("release");
+            }
+        }
+        // An unaligned run of 512 base-pages isn't a large-page
+        let unaligned = Frame::new(
+            PAddr::from(4 * LARGE_PAGE_SIZE + BASE_PAGE_SIZE),
+            LARGE_PAGE_SIZE,
+            0,
+        );
+        for base_page in unaligned {
+            cache.release_base_page(base_page).expect("release");
+        }
+        let base_pages = cache.free_base_pages();
+        assert_eq!(cache.free_large_pages(), 0);
+
+        assert_eq!(cache.compact(), 1);
+        assert_eq!(cache.free_large_pages(), 1);
+        assert_eq!(
+            cache.free_base_pages(),
+            base_pages - LARGE_PAGE_SIZE / BASE_PAGE_SIZE
+        );
+        assert_eq!(cache.allocate_large_page(), Ok(frame));
+        assert_eq!(cache.compact(), 0);
+    }
+
+    /// A large-page is recovered on demand once the large-page stack is empty.
+    #[test]
+    fn mcache_allocate_large_page_compacts() {
+        let mut cache = MCache::<600, 2>::new(0);
+        let frame = Frame::new(PAddr::from(2 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        for base_page in frame {
+            cache.release_base_page(base_page).expect("release");
+        }
+        assert_eq!(cache.allocate_large_page(), Ok(frame));
+        assert_eq!(cache.free_base_pages(), 0);
+        assert_eq!(cache.allocate_large_page(), Err(KError::CacheExhausted));
+    }
+
+    /// If no large-page can be recovered, `compact` doesn't forget which
+    /// base-pages are clean.
+    #[test]
+    fn mcache_compact_keeps_clean_pages() {
+        let mut cache = MCache::<600, 2>::new(0);
+        // One page short of a large-page
+        let frame = Frame::new(PAddr::from(2 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        for base_page in frame.into_iter().skip(1) {
+            cache.release_base_page(base_page).expect("release");
+        }
+        let other = Frame::new(PAddr::from(8 * LARGE_PAGE_SIZE), BASE_PAGE_SIZE, 0);
+        cache.release_base_page(other).expect("release");
+        // Pretend the bottom of the stack got scrubbed (see `scrub_one`)
+        cache.clean_base_pages = 10;
+        let mut clean: Vec<PAddr> = cache.base_page_addresses[..10].to_vec();
+        clean.sort_unstable();
+
+        assert_eq!(cache.compact(), 0);
+        assert_eq!(cache.allocate_large_page(), Err(KError::CacheExhausted));
+        assert_eq!(cache.clean_base_pages(), 10);
+        let mut still_clean: Vec<PAddr> = cache.base_page_addresses[..10].to_vec();
+        still_clean.sort_unstable();
+        assert_eq!(still_clean, clean);
+
+        // With the missing page it can be promoted (and clean pages are reset)
+        let first = Frame::new(frame.base, BASE_PAGE_SIZE, 0);
+        cache.release_base_page(first).expect("release");
+        assert_eq!(cache.allocate_large_page(), Ok(frame));
+        assert_eq!(cache.clean_base_pages(), 0);
+        assert_eq!(cache.free_base_pages(), 1);
+    }
+
+    /// A failed large-page allocation only compacts again after base-pages
+    /// were released.
+    #[test]
+    fn mcache_allocate_large_page_compacts_after_release() {
+        let mut cache = MCache::<600, 2>::new(0);
+        let frame = Frame::new(PAddr::from(2 * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        let pages: Vec<Frame> = frame.into_iter().collect();
+        cache.release_base_pages(&pages[1..]).expect("release");
+        let other = Frame::new(PAddr::from(8 * LARGE_PAGE_SIZE), BASE_PAGE_SIZE, 0);
+        cache.release_base_page(other).expect("release");
+        assert!(cache.base_pages_released);
+
+        assert_eq!(cache.allocate_large_page(), Err(KError::CacheExhausted));
+        assert!(!cache.base_pages_released);
+        // Nothing was released, so the next request doesn't compact
+        assert_eq!(cache.allocate_large_page(), Err(KError::CacheExhausted));
+        assert!(!cache.base_pages_released);
+
+        cache.release_base_page(pages[0]).expect("release");
+        assert_eq!(cache.allocate_large_page(), Ok(frame));
+        assert_eq!(cache.free_base_pages(), 1);
+    }
+
+    /// A batch release that exceeds the capacity reports how many frames
+    /// were accepted.
+    #[test]
+    fn tcache_release_many_overflow() {
+        let mut tcache = TCache::new(0);
+        let spare = tcache.spare_base_page_capacity();
+
+        let frames: std::vec::Vec<Frame> = (1..=spare + 10)
+            .map(|i| Frame::new(PAddr::from(i * BASE_PAGE_SIZE), BASE_PAGE_SIZE, 0))
+            .collect();
+        assert_eq!(
+            tcache.release_base_pages(&frames),
+            Err(KError::CantGrowFurther { count: spare })
+        );
+        assert_eq!(tcache.free_base_pages(), spare);
+    }
+
     /// TCache should be fit exactly within a base-page.
     #[test]
     fn tcache_populate() {
@@ -595,6 +1144,44 @@ mod test {
         assert_eq!(free_list[1].unwrap().affinity, 4);
     }
 
+    /// Refill a TCache from an NCache in bulk.
+    #[test]
+    fn tcache_refill_from() {
+        let ncache = get_an_ncache::<131070, 131070>();
+        ncache.node = 4;
+        let mut tcache = TCache::new(4);
+
+        let frames: std::vec::Vec<Frame> = (1..=500)
+            .map(|i| Frame::new(PAddr::from(i * BASE_PAGE_SIZE), BASE_PAGE_SIZE, 4))
+            .collect();
+        ncache.grow_base_pages(&frames).expect("grow");
+        let frames = &[
+            Frame::new(PAddr::from(LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 4),
+            Frame::new(PAddr::from(LARGE_PAGE_SIZE * 2), LARGE_PAGE_SIZE, 4),
+            Frame::new(PAddr::from(LARGE_PAGE_SIZE * 3), LARGE_PAGE_SIZE, 4),
+        ];
+        ncache.grow_large_pages(frames).expect("grow");
+        let total = ncache.free();
+
+        assert_eq!(tcache.refill_from(ncache, 100, 2), (100, 2));
+        assert_eq!(tcache.free_base_pages(), 100);
+        assert_eq!(tcache.free_large_pages(), 2);
+        assert_eq!(ncache.free_base_pages(), 400);
+        assert_eq!(ncache.free_large_pages(), 1);
+        assert_eq!(tcache.free() + ncache.free(), total);
+
+        // Limited by the TCache capacity (base) and what the NCache has (large)
+        assert_eq!(tcache.refill_from(ncache, 1000, 1000), (280, 1));
+        assert_eq!(tcache.free_base_pages(), 380);
+        assert_eq!(tcache.free_large_pages(), 3);
+        assert_eq!(ncache.free_base_pages(), 120);
+        assert_eq!(ncache.free_large_pages(), 0);
+        assert_eq!(tcache.free() + ncache.free(), total);
+
+        let f = tcache.allocate_base_page().expect("Can allocate");
+        assert_eq!(f.affinity, 4);
+    }
+
     /// Test that release and allocate works as expected.
     /// Also verify free memory reporting along the way.
     #[test]
@@ -654,4 +1241,191 @@ mod test {
             .allocate_base_page()
             .expect_err("Can't allocate more than we gave it");
     }
+
+    /// Operations for the `mcache_stress` test.
+    #[derive(Clone, Debug)]
+    enum StressOp {
+        AllocBase,
+        AllocLarge,
+        /// Release the n-th (modulo) base-page we hold.
+        ReleaseBase(usize),
+        /// Release the n-th (modulo) large-page we hold.
+        ReleaseLarge(usize),
+        /// Release up to n of the base-pages we hold at once.
+        ReleaseBaseMany(usize),
+        /// Give the n-th (modulo) large-page back with `fragment_large_page`.
+        FragmentLarge(usize),
+        /// Split the n-th (modulo) large-page we hold into base-pages.
+        SplitLarge(usize),
+        /// Merge base-pages we hold that form a large-page into one.
+        MergeBase,
+        /// Promote free base-pages of the cache to large-pages.
+        Compact,
+    }
+
+    fn stress_op() -> impl Strategy<Value = StressOp> {
+        prop_oneof![
+            Just(StressOp::AllocBase),
+            Just(StressOp::AllocLarge),
+            any::<usize>().prop_map(StressOp::ReleaseBase),
+            any::<usize>().prop_map(StressOp::ReleaseLarge),
+            (0..64usize).prop_map(StressOp::ReleaseBaseMany),
+            any::<usize>().prop_map(StressOp::FragmentLarge),
+            any::<usize>().prop_map(StressOp::SplitLarge),
+            Just(StressOp::MergeBase),
+            Just(StressOp::Compact),
+        ]
+    }
+
+    /// A cache with small stacks so we run into `CacheFull` often.
+    type StressCache = MCache<600, 3>;
+
+    /// Where the memory of the `mcache_stress` test comes from.
+    const STRESS_BASE: usize = 0x4000_0000;
+    /// Large-pages initially in the cache for `mcache_stress`.
+    const STRESS_LARGE_PAGES: usize = 3;
+    /// Base-pages initially in the cache for `mcache_stress`.
+    const STRESS_BASE_PAGES: usize = 48;
+
+    /// Checks that `cache` and the pages we hold (`base`, `large`) still
+    /// account for all the memory we started with and don't overlap.
+    fn check_invariants(cache: &StressCache, base: &[Frame], large: &[Frame]) {
+        let total = STRESS_LARGE_PAGES * LARGE_PAGE_SIZE + STRESS_BASE_PAGES * BASE_PAGE_SIZE;
+        let held = base.len() * BASE_PAGE_SIZE + large.len() * LARGE_PAGE_SIZE;
+        assert_eq!(cache.free() + held, total, "bytes are conserved");
+        assert_eq!(
+            cache.free(),
+            cache.free_base_pages() * BASE_PAGE_SIZE + cache.free_large_pages() * LARGE_PAGE_SIZE
+        );
+        assert_eq!(cache.free_base_pages(), cache.base_page_addresses.len());
+        assert_eq!(cache.free_large_pages(), cache.large_page_addresses.len());
+        assert!(cache.clean_base_pages() <= cache.free_base_pages());
+
+        let mut frames: Vec<Frame> = Vec::new();
+        for pa in cache.base_page_addresses.iter() {
+            assert_eq!(*pa % BASE_PAGE_SIZE, 0);
+            frames.push(cache.paddr_to_base_page(*pa));
+        }
+        for pa in cache.large_page_addresses.iter() {
+            assert_eq!(*pa % LARGE_PAGE_SIZE, 0);
+            frames.push(cache.paddr_to_large_page(*pa));
+        }
+        for f in base {
+            assert_eq!(f.size(), BASE_PAGE_SIZE);
+            assert_eq!(f.base % BASE_PAGE_SIZE, 0);
+        }
+        for f in large {
+            assert_eq!(f.size(), LARGE_PAGE_SIZE);
+            assert!(f.is_large_page_aligned());
+        }
+        frames.extend_from_slice(base);
+        frames.extend_from_slice(large);
+
+        frames.sort_by_key(|f| f.base);
+        for pair in frames.windows(2) {
+            assert!(
+                pair[0].intersect(&pair[1]).is_none(),
+                "{:?} overlaps {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    proptest! {
+        /// Random sequences of allocate/release/split/merge operations keep
+        /// the cache's accounting consistent.
+        #[test]
+        fn mcache_stress(ops in prop::collection::vec(stress_op(), 0..256)) {
+            let mut cache = StressCache::new(0);
+            for i in 0..STRESS_LARGE_PAGES {
+                let base = PAddr::from(STRESS_BASE + i * LARGE_PAGE_SIZE);
+                cache
+                    .release_large_page(Frame::new(base, LARGE_PAGE_SIZE, 0))
+                    .expect("release");
+            }
+            for i in 0..STRESS_BASE_PAGES {
+                let base = STRESS_BASE + STRESS_LARGE_PAGES * LARGE_PAGE_SIZE + i * BASE_PAGE_SIZE;
+                cache
+                    .release_base_page(Frame::new(PAddr::from(base), BASE_PAGE_SIZE, 0))
+                    .expect("release");
+            }
+
+            let mut base: Vec<Frame> = Vec::new();
+            let mut large: Vec<Frame> = Vec::new();
+            check_invariants(&cache, &base, &large);
+
+            for op in ops {
+                match op {
+                    StressOp::AllocBase => match cache.allocate_base_page() {
+                        Ok(f) => base.push(f),
+                        Err(e) => assert_eq!(e, KError::CacheExhausted),
+                    },
+                    StressOp::AllocLarge => match cache.allocate_large_page() {
+                        Ok(f) => large.push(f),
+                        Err(e) => assert_eq!(e, KError::CacheExhausted),
+                    },
+                    StressOp::ReleaseBase(idx) if !base.is_empty() => {
+                        let f = base.swap_remove(idx % base.len());
+                        if let Err(e) = cache.release_base_page(f) {
+                            assert_eq!(e, KError::CacheFull);
+                            base.push(f);
+                        }
+                    }
+                    StressOp::ReleaseLarge(idx) if !large.is_empty() => {
+                        let f = large.swap_remove(idx % large.len());
+                        if let Err(e) = cache.release_large_page(f) {
+                            assert_eq!(e, KError::CacheFull);
+                            large.push(f);
+                        }
+                    }
+                    StressOp::ReleaseBaseMany(n) => {
+                        let n = core::cmp::min(n, base.len());
+                        let frames = base.split_off(base.len() - n);
+                        match cache.release_base_pages(&frames) {
+                            Ok(()) => {}
+                            Err(KError::CantGrowFurther { count }) => {
+                                base.extend_from_slice(&frames[count..])
+                            }
+                            Err(e) => panic!("unexpected error {:?}", e),
+                        }
+                    }
+                    StressOp::FragmentLarge(idx) if !large.is_empty() => {
+                        let f = large.swap_remove(idx % large.len());
+                        if let Err(e) = cache.fragment_large_page(f) {
+                            assert_eq!(e, KError::CacheFull);
+                            large.push(f);
+                        }
+                    }
+                    StressOp::SplitLarge(idx) if !large.is_empty() => {
+                        let f = large.swap_remove(idx % large.len());
+                        base.extend(f.into_iter());
+                    }
+                    StressOp::MergeBase => {
+                        base.sort_by_key(|f| f.base);
+                        let pages = LARGE_PAGE_SIZE / BASE_PAGE_SIZE;
+                        let start = (0..base.len()).find(|&i| {
+                            base[i].is_large_page_aligned()
+                                && i + pages <= base.len()
+                                && base[i..i + pages]
+                                    .windows(2)
+                                    .all(|w| w[0].is_contiguous_with(&w[1]))
+                        });
+                        if let Some(i) = start {
+                            let merged = Frame::new(base[i].base, LARGE_PAGE_SIZE, 0);
+                            base.drain(i..i + pages);
+                            large.push(merged);
+                        }
+                    }
+                    StressOp::Compact => {
+                        let large_pages = cache.free_large_pages();
+                        let recovered = cache.compact();
+                        assert_eq!(cache.free_large_pages(), large_pages + recovered);
+                    }
+                    _ => {}
+                }
+                check_invariants(&cache, &base, &large);
+            }
+        }
+    }
 }
@@ -16,13 +16,14 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::intrinsics::likely;
 use core::mem::transmute;
-use core::sync::atomic::AtomicU64;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use core::{fmt, ptr};
 
 use arrayvec::ArrayVec;
 use log::{debug, error, trace, warn};
 use slabmalloc::{Allocator, ZoneAllocator};
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use x86::bits64::paging;
 
 use crate::arch::MAX_NUMA_NODES;
@@ -37,9 +38,13 @@ pub use crate::arch::memory::{
 
 use vspace::MapAction;
 
+#[cfg(any(test, feature = "alloc-check"))]
+pub mod alloc_check;
 pub mod detmem;
 pub mod emem;
 pub mod mcache;
+pub mod pool;
+pub mod trace;
 pub mod vspace;
 #[cfg(test)]
 pub mod vspace_model;
@@ -47,6 +52,10 @@ pub mod vspace_model;
 /// How many initial physical memory regions we support.
 pub const MAX_PHYSICAL_REGIONS: usize = 64;
 
+/// Default amount of memory reserved per NUMA node for the early allocator
+/// (`emem`) in [`GlobalMemory::new`].
+pub const DEFAULT_EMEM_SIZE: usize = 2 * LARGE_PAGE_SIZE + 64 * BASE_PAGE_SIZE;
+
 /// The global allocator in the kernel.
 //#[cfg(not(any(test, fuzzing)))]
 #[cfg(target_os = "none")]
@@ -55,8 +64,54 @@ static MEM_PROVIDER: KernelAllocator = KernelAllocator {
     big_objects_sbrk: AtomicU64::new(
         KERNEL_BASE + (2048 * x86::bits64::paging::HUGE_PAGE_SIZE) as u64,
     ),
+    panic_on_oom: AtomicBool::new(false),
+    #[cfg(feature = "alloc-trace")]
+    trace: trace::AllocTrace::new(),
+    #[cfg(feature = "alloc-check")]
+    layouts: alloc_check::LayoutTable::new(),
 };
 
+/// Prints the most recent events of the kernel allocator.
+#[cfg(all(target_os = "none", feature = "alloc-trace"))]
+pub fn dump_alloc_trace() {
+    MEM_PROVIDER.trace.dump();
+}
+
+/// Sets what the kernel allocator does once it runs out of memory.
+#[cfg(target_os = "none")]
+pub fn set_oom_policy(policy: OomPolicy) {
+    MEM_PROVIDER.set_oom_policy(policy);
+}
+
+/// What the `KernelAllocator` does when an allocation fails.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OomPolicy {
+    /// Return a null pointer and let the caller deal with it (e.g., by
+    /// killing a process instead of the whole kernel).
+    ReturnNull,
+    /// Fail fast: panic with a description of the allocation.
+    Panic,
+}
+
+impl Default for OomPolicy {
+    fn default() -> Self {
+        OomPolicy::ReturnNull
+    }
+}
+
+/// Parses the policy from the kernel command-line (`oom=null|panic`).
+impl core::str::FromStr for OomPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<OomPolicy, ()> {
+        match policy {
+            "null" => Ok(OomPolicy::ReturnNull),
+            "panic" => Ok(OomPolicy::Panic),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Different types of allocator that the KernelAllocator can use.
 #[derive(Debug, PartialEq)]
 enum AllocatorType {
@@ -68,9 +123,82 @@ enum AllocatorType {
     MapBig,
 }
 
+/// How often `alloc` refills the caches before giving up on an allocation.
+const MAX_REFILL_TRIES: usize = 3;
+
+/// State of the allocator after an allocation still failed with freshly
+/// refilled caches (see `KernelAllocator::refill_exhausted`).
+#[derive(Debug)]
+struct RefillDiagnostic {
+    layout: Layout,
+    allocator: AllocatorType,
+    /// Pages (base, large) the zone or TCache asks for on a refill.
+    refill_amount: (usize, usize),
+    /// Free (base, large) pages in the core-local TCache, if we could look.
+    tcache: Option<(usize, usize)>,
+    /// Free (base, large) pages in the NUMA node's NCache, if we could look.
+    ncache: Option<(usize, usize)>,
+}
+
+impl RefillDiagnostic {
+    /// Gathers the state of the caches involved in allocating `layout`.
+    fn collect(layout: Layout) -> RefillDiagnostic {
+        let mut tcache = None;
+        let mut ncache = None;
+        if let Some(kcb) = kcb::try_get_kcb() {
+            if let Ok(mem_manager) = kcb.try_mem_manager() {
+                tcache = Some((mem_manager.free_base_pages(), mem_manager.free_large_pages()));
+            }
+            if let Some(gmanager) = kcb.physical_memory.gmanager {
+                let node = gmanager.lock_node(kcb.physical_memory.affinity as usize);
+                ncache = Some((node.free_base_pages(), node.free_large_pages()));
+            }
+        }
+
+        RefillDiagnostic {
+            layout,
+            allocator: KernelAllocator::allocator_for(layout),
+            refill_amount: KernelAllocator::refill_amount(layout),
+            tcache,
+            ncache,
+        }
+    }
+}
+
+/// Formatting doesn't allocate, we're printing this because we're out of
+/// memory after all.
+impl fmt::Display for RefillDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Can't allocate {:?} with {:?} after {} refills (refill needs {} bp {} lp)",
+            self.layout,
+            self.allocator,
+            MAX_REFILL_TRIES,
+            self.refill_amount.0,
+            self.refill_amount.1
+        )?;
+        for (name, free) in &[("TCache", self.tcache), ("NCache", self.ncache)] {
+            match free {
+                Some((bp, lp)) => write!(f, ", free in {}: {} bp {} lp", name, bp, lp)?,
+                None => write!(f, ", free in {}: unavailable", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Implements the kernel memory allocation strategy.
 pub struct KernelAllocator {
     big_objects_sbrk: AtomicU64,
+    /// Set if the `OomPolicy` is `Panic`.
+    panic_on_oom: AtomicBool,
+    /// Recent allocation events (for post-mortem debugging).
+    #[cfg(feature = "alloc-trace")]
+    trace: trace::AllocTrace,
+    /// Layouts of live allocations (to catch frees with a wrong layout).
+    #[cfg(feature = "alloc-check")]
+    layouts: alloc_check::LayoutTable,
 }
 
 /// Calculate how many base and large pages we need to fit a given size.
@@ -95,6 +223,9 @@ pub fn size_to_pages(size: usize) -> (usize, usize) {
 
 impl KernelAllocator {
     /// Try to allocate a piece of memory.
+    ///
+    /// The memory is not zeroed (on any of the paths), `alloc_zeroed` relies
+    /// on the default `GlobalAlloc` implementation to clear it.
     fn try_alloc(&self, layout: Layout) -> Result<ptr::NonNull<u8>, KError> {
         let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
         match KernelAllocator::allocator_for(layout) {
@@ -110,7 +241,11 @@ impl KernelAllocator {
             }
             AllocatorType::MemManager if layout.size() <= LARGE_PAGE_SIZE => {
                 let mut pmanager = kcb.try_mem_manager()?;
-                let f = pmanager.allocate_large_page()?;
+                let f = if KernelAllocator::page_size_for(layout) == BASE_PAGE_SIZE {
+                    pmanager.allocate_base_page()?
+                } else {
+                    pmanager.allocate_large_page()?
+                };
                 unsafe { Ok(ptr::NonNull::new_unchecked(f.kernel_vaddr().as_mut_ptr())) }
             }
             AllocatorType::MapBig => {
@@ -207,8 +342,20 @@ impl KernelAllocator {
     }
 
     /// Determines which Allocator to use for a given Layout.
+    ///
+    /// Page-aligned requests (up to a large-page) are served by handing out
+    /// an entire frame: the ZoneAllocator would have to skip over most of a
+    /// slab to satisfy the alignment (and can't do it at all for alignments
+    /// above a base-page).
     fn allocator_for(layout: Layout) -> AllocatorType {
         const MAX_ALLOC_PLUS_ONE: usize = ZoneAllocator::MAX_ALLOC_SIZE + 1;
+        if layout.align() >= BASE_PAGE_SIZE
+            && layout.align() <= LARGE_PAGE_SIZE
+            && layout.size() <= LARGE_PAGE_SIZE
+        {
+            return AllocatorType::MemManager;
+        }
+
         match layout.size() {
             0..=ZoneAllocator::MAX_ALLOC_SIZE => AllocatorType::Zone,
             MAX_ALLOC_PLUS_ONE..=LARGE_PAGE_SIZE => AllocatorType::MemManager,
@@ -216,6 +363,33 @@ impl KernelAllocator {
         }
     }
 
+    /// The frame size a `AllocatorType::MemManager` allocation for `layout`
+    /// ends up using.
+    fn page_size_for(layout: Layout) -> usize {
+        if layout.size() <= BASE_PAGE_SIZE && layout.align() <= BASE_PAGE_SIZE {
+            BASE_PAGE_SIZE
+        } else {
+            LARGE_PAGE_SIZE
+        }
+    }
+
+    /// Can `ptr` be the start of the frame that backs a
+    /// `AllocatorType::MemManager` allocation for `layout`?
+    ///
+    /// Logs a warning if not, `dealloc` then leaks the memory instead of
+    /// panicking in `Frame::new` (which is worse, e.g., during teardown).
+    fn is_frame_aligned(ptr: *mut u8, layout: Layout) -> bool {
+        let page_size = KernelAllocator::page_size_for(layout);
+        let aligned = (ptr as usize) % page_size == 0;
+        if !aligned {
+            warn!(
+                "Leaking {:p} {:?}, it's not aligned to a {:#x} frame",
+                ptr, layout, page_size
+            );
+        }
+        aligned
+    }
+
     /// Try to refill our core-local zone allocator.
     ///
     /// We come here if a previous allocation failed.
@@ -258,7 +432,7 @@ impl KernelAllocator {
                 }
             }
             AllocatorType::MemManager => {
-                if layout.size() <= BASE_PAGE_SIZE {
+                if KernelAllocator::page_size_for(layout) == BASE_PAGE_SIZE {
                     (1, 0)
                 } else {
                     (0, 1)
@@ -280,7 +454,7 @@ impl KernelAllocator {
         }
 
         let gmanager = kcb.physical_memory.gmanager.unwrap(); // Ok because of check above.
-        let mut ncache = gmanager.node_caches[kcb.physical_memory.affinity as usize].lock();
+        let mut ncache = gmanager.lock_node(kcb.physical_memory.affinity as usize);
         let mut mem_manager = kcb.try_mem_manager()?;
         // Make sure we don't overflow the TCache
         let needed_base_pages =
@@ -400,7 +574,84 @@ impl KernelAllocator {
 /// allocators.
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        for _tries in 0..3 {
+        let ptr = self.alloc_inner(layout);
+        #[cfg(feature = "alloc-trace")]
+        self.trace.record(layout, ptr, trace::AllocEventKind::Alloc);
+        #[cfg(feature = "alloc-check")]
+        if !ptr.is_null() {
+            self.layouts.insert(ptr, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-trace")]
+        self.trace
+            .record(layout, ptr, trace::AllocEventKind::Dealloc);
+        #[cfg(feature = "alloc-check")]
+        if let Err(e) = self.layouts.remove(ptr, layout) {
+            panic!(
+                "Freeing {:#x} with {:?} but it was allocated with {:?}",
+                e.ptr, e.freed, e.allocated
+            );
+        }
+        self.dealloc_inner(ptr, layout)
+    }
+}
+
+impl KernelAllocator {
+    /// Can the allocation at `ptr` grow (or shrink) to `new_size` without
+    /// moving it?
+    ///
+    /// Don't do a re-allocation if we're in a big enough size-class in the
+    /// ZoneAllocator. With `alloc-check` the recorded layout is updated, so
+    /// the later `dealloc` with the new size matches.
+    #[cfg_attr(not(feature = "alloc-check"), allow(unused_variables))]
+    unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let in_place = KernelAllocator::allocator_for(layout) == AllocatorType::Zone
+            && layout.size() != BASE_PAGE_SIZE
+            && new_size <= ZoneAllocator::get_max_size(layout.size()).unwrap_or(0x0);
+
+        #[cfg(feature = "alloc-check")]
+        if in_place {
+            if let Err(e) = self.layouts.resize(ptr, layout, new_size) {
+                panic!(
+                    "Reallocating {:#x} with {:?} but it was allocated with {:?}",
+                    e.ptr, e.freed, e.allocated
+                );
+            }
+        }
+        in_place
+    }
+
+    pub fn set_oom_policy(&self, policy: OomPolicy) {
+        self.panic_on_oom
+            .store(policy == OomPolicy::Panic, Ordering::Relaxed);
+    }
+
+    pub fn oom_policy(&self) -> OomPolicy {
+        if self.panic_on_oom.load(Ordering::Relaxed) {
+            OomPolicy::Panic
+        } else {
+            OomPolicy::ReturnNull
+        }
+    }
+
+    /// Handles an allocation for `layout` that failed with `err`, according
+    /// to the `OomPolicy`.
+    fn out_of_memory(&self, layout: Layout, err: KError) -> *mut u8 {
+        match self.oom_policy() {
+            OomPolicy::ReturnNull => ptr::null_mut(),
+            OomPolicy::Panic => panic!("Out of memory: can't allocate {:?} ({})", layout, err),
+        }
+    }
+
+    /// Allocate memory for `layout`, refilling the caches if necessary.
+    ///
+    /// What happens if we're out of memory depends on the `OomPolicy`.
+    unsafe fn alloc_inner(&self, layout: Layout) -> *mut u8 {
+        let mut refills = 0;
+        for _tries in 0..MAX_REFILL_TRIES {
             let res = self.try_alloc(layout);
             match res {
                 // Allocation worked
@@ -423,6 +674,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
                     match self.try_refill(layout, e) {
                         Ok(_) => {
                             // Refilling worked, re-try allocation
+                            refills += 1;
                             continue;
                         }
                         Err(KError::KcbUnavailable) => {
@@ -433,25 +685,38 @@ unsafe impl GlobalAlloc for KernelAllocator {
                             error!("ManagerAlreadyBorrowed trying to get mem manager 2x during `try_refill` {:?}", layout);
                             break;
                         }
-                        Err(_e) => {
-                            // Refilling failed, re-try allocation
-                            return ptr::null_mut();
+                        Err(e) => {
+                            // Refilling failed, we're out of memory
+                            return self.out_of_memory(layout, e);
                         }
                     }
                 }
             }
         }
 
-        ptr::null_mut()
+        if refills == MAX_REFILL_TRIES {
+            return self.refill_exhausted(layout);
+        }
+        self.out_of_memory(layout, KError::OutOfMemory)
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Handles an allocation for `layout` that kept failing even though the
+    /// caches were refilled every time (e.g., because memory is too
+    /// fragmented). Logs the state of the caches before applying the
+    /// `OomPolicy`.
+    fn refill_exhausted(&self, layout: Layout) -> *mut u8 {
+        error!("{}", RefillDiagnostic::collect(layout));
+        self.out_of_memory(layout, KError::OutOfMemory)
+    }
+
+    /// Give the memory at `ptr` back to the allocator it came from.
+    unsafe fn dealloc_inner(&self, ptr: *mut u8, layout: Layout) {
         crate::kcb::try_get_kcb().map_or_else(
             || {
                 unreachable!("Trying to deallocate {:p} {:?} without a KCB.", ptr, layout);
             },
             |kcb| {
-                if layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE {
+                if KernelAllocator::allocator_for(layout) == AllocatorType::Zone {
                     // TODO(rust): Silly code duplication follows if/else
                     if core::intrinsics::unlikely(kcb.in_panic_mode) {
                         let mut zone_allocator = kcb
@@ -476,11 +741,13 @@ unsafe impl GlobalAlloc for KernelAllocator {
                         }
                     }
                 } else {
+                    if !KernelAllocator::is_frame_aligned(ptr, layout) {
+                        return;
+                    }
                     let kcb = kcb::get_kcb();
                     let mut fmanager = kcb.mem_manager();
 
-                    if layout.size() <= BASE_PAGE_SIZE {
-                        assert!(layout.align() <= BASE_PAGE_SIZE);
+                    if KernelAllocator::page_size_for(layout) == BASE_PAGE_SIZE {
                         let frame = Frame::new(
                             kernel_vaddr_to_paddr(VAddr::from_u64(ptr as u64)),
                             BASE_PAGE_SIZE,
@@ -496,8 +763,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
                             Err(_e) => match kcb.physical_memory.gmanager {
                                 // Try adding frame to ncache.
                                 Some(gmanager) => {
-                                    let mut ncache =
-                                        gmanager.node_caches[frame.affinity as usize].lock();
+                                    let mut ncache = gmanager.lock_node(frame.affinity as usize);
                                     ncache
                                         .release_base_page(frame)
                                         .expect("Can't deallocate frame");
@@ -517,9 +783,20 @@ unsafe impl GlobalAlloc for KernelAllocator {
                             kcb.physical_memory.affinity,
                         );
 
-                        fmanager
-                            .release_large_page(frame)
-                            .expect("Can't deallocate frame");
+                        match fmanager.release_large_page(frame) {
+                            Ok(_) => { /* Frame addition to tcache as successful.*/ }
+                            Err(_e) => match kcb.physical_memory.gmanager {
+                                // Try adding frame to ncache (as base-pages if
+                                // it has no room for more large-pages).
+                                Some(gmanager) => {
+                                    let mut ncache = gmanager.lock_node(frame.affinity as usize);
+                                    ncache
+                                        .fragment_large_page(frame)
+                                        .expect("Can't deallocate frame");
+                                }
+                                None => unreachable!("Unable to access global memory manager"),
+                            },
+                        }
                     } else {
                         error!("Loosing large memory region. Oh well.")
                     }
@@ -534,13 +811,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
                 unreachable!("Trying to reallocate {:p} {:?} without a KCB.", ptr, layout);
             },
             |kcb| {
-                if !kcb.in_panic_mode
-                    && layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE
-                    && layout.size() != BASE_PAGE_SIZE
-                    && new_size <= ZoneAllocator::get_max_size(layout.size()).unwrap_or(0x0)
-                {
-                    // Don't do a re-allocation if we're in a big enough size-class
-                    // in the ZoneAllocator
+                if !kcb.in_panic_mode && self.realloc_in_place(ptr, layout, new_size) {
                     ptr
                 } else {
                     // Slow path, allocate a bigger region and de-allocate the old one
@@ -616,6 +887,10 @@ impl fmt::Display for DataSize {
 /// `node_caches` and and `emem` can be accessed concurrently and are protected
 /// by a simple spin-lock (for reclamation and allocation).
 /// TODO(perf): This may need a more elaborate scheme in the future.
+///
+/// To read the free memory of the node-caches without taking these locks
+/// (see `GlobalMemory::stats`), every node-cache locked through `lock_node`
+/// publishes its free memory to a set of atomic counters when it is unlocked.
 #[derive(Default)]
 pub struct GlobalMemory {
     /// Holds a small amount of memory for every NUMA node.
@@ -626,6 +901,112 @@ pub struct GlobalMemory {
     /// All node-caches in the system (one for every NUMA node).
     pub(crate) node_caches:
         ArrayVec<CachePadded<Mutex<&'static mut mcache::NCache>>, MAX_NUMA_NODES>,
+
+    /// Free-memory watermarks for every node-cache.
+    watermarks: ArrayVec<Mutex<Watermarks>, MAX_NUMA_NODES>,
+
+    /// The free memory of every node-cache as of its last unlock.
+    free_counters: ArrayVec<CachePadded<FreeCounters>, MAX_NUMA_NODES>,
+
+    /// Invoked by `check_watermarks` for every node below its low watermark.
+    low_memory_callback: Mutex<Option<fn(atopology::NodeId)>>,
+}
+
+/// Cuts a large-page off the top of the first frame in `frames` that passes
+/// `filter` and is big enough. The frame keeps the memory below it.
+///
+/// Only frames that end at a large-page boundary are considered, so no memory
+/// above the large-page gets lost.
+fn carve_large_page(frames: &mut [Frame], filter: impl Fn(&Frame) -> bool) -> Option<Frame> {
+    let frame = frames.iter_mut().find(|f| {
+        filter(f) && f.size() >= LARGE_PAGE_SIZE && f.end() % LARGE_PAGE_SIZE == 0
+    })?;
+    let (rest, large_page) = frame.split_at(frame.size() - LARGE_PAGE_SIZE);
+    *frame = rest;
+    Some(large_page)
+}
+
+/// Free-memory thresholds (in bytes) of a NUMA node.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Watermarks {
+    /// Below this the node is low on memory.
+    pub low: usize,
+    /// At or above this the node has plenty of memory.
+    pub high: usize,
+}
+
+/// Free memory of a node-cache, see `GlobalMemory::stats`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct NodeStats {
+    pub free_base_pages: usize,
+    pub free_large_pages: usize,
+}
+
+impl NodeStats {
+    /// Free memory (in bytes).
+    pub fn free(&self) -> usize {
+        self.free_base_pages * BASE_PAGE_SIZE + self.free_large_pages * LARGE_PAGE_SIZE
+    }
+}
+
+/// Lock-free copy of the free memory of a node-cache.
+#[derive(Debug, Default)]
+struct FreeCounters {
+    base_pages: AtomicUsize,
+    large_pages: AtomicUsize,
+}
+
+impl FreeCounters {
+    fn publish(&self, ncache: &mcache::NCache) {
+        self.base_pages.store(ncache.free_base_pages(), Ordering::Relaxed);
+        self.large_pages.store(ncache.free_large_pages(), Ordering::Relaxed);
+    }
+
+    fn read(&self) -> NodeStats {
+        NodeStats {
+            free_base_pages: self.base_pages.load(Ordering::Relaxed),
+            free_large_pages: self.large_pages.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A locked node-cache (see `GlobalMemory::lock_node`).
+///
+/// Updates the node's `FreeCounters` when it is unlocked.
+pub(crate) struct NodeCacheGuard<'a> {
+    ncache: MutexGuard<'a, &'static mut mcache::NCache>,
+    counters: &'a FreeCounters,
+}
+
+impl<'a> Deref for NodeCacheGuard<'a> {
+    type Target = mcache::NCache;
+
+    fn deref(&self) -> &mcache::NCache {
+        &**self.ncache
+    }
+}
+
+impl<'a> DerefMut for NodeCacheGuard<'a> {
+    fn deref_mut(&mut self) -> &mut mcache::NCache {
+        &mut **self.ncache
+    }
+}
+
+impl<'a> Drop for NodeCacheGuard<'a> {
+    fn drop(&mut self) {
+        self.counters.publish(&**self.ncache);
+    }
+}
+
+/// Where the free memory of a node is with respect to its `Watermarks`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WatermarkState {
+    /// Free memory is below the low watermark.
+    Low,
+    /// Free memory is between the low and high watermark.
+    Normal,
+    /// Free memory is at or above the high watermark.
+    High,
 }
 
 impl GlobalMemory {
@@ -646,10 +1027,37 @@ impl GlobalMemory {
     /// A client needs to ensure that our frames are valid memory, and not yet
     /// being used anywhere yet.
     /// The good news is that we only invoke this once during bootstrap.
-    pub unsafe fn new(
-        mut memory: ArrayVec<Frame, MAX_PHYSICAL_REGIONS>,
+    ///
+    /// # Errors
+    /// Returns `TooManyRegions` if `memory` has more than `MAX_PHYSICAL_REGIONS`
+    /// frames and `TooManyNodes` if the frames span more than `MAX_NUMA_NODES`
+    /// NUMA nodes.
+    pub unsafe fn new(memory: &[Frame]) -> Result<GlobalMemory, KError> {
+        GlobalMemory::new_with_emem_size(memory, DEFAULT_EMEM_SIZE)
+    }
+
+    /// Same as [`GlobalMemory::new`] but reserves `emem_size` bytes per NUMA
+    /// node for the early allocator instead of [`DEFAULT_EMEM_SIZE`].
+    ///
+    /// `emem_size` has to be a multiple of `BASE_PAGE_SIZE` and hold at least
+    /// one large-page, which is needed to construct the node's NCache.
+    ///
+    /// # Safety
+    /// See [`GlobalMemory::new`].
+    pub unsafe fn new_with_emem_size(
+        regions: &[Frame],
+        emem_size: usize,
     ) -> Result<GlobalMemory, KError> {
-        debug_assert!(!memory.is_empty());
+        debug_assert!(!regions.is_empty());
+        if emem_size < LARGE_PAGE_SIZE || emem_size % BASE_PAGE_SIZE != 0 {
+            return Err(KError::InvalidLength);
+        }
+        let mut memory: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        memory
+            .try_extend_from_slice(regions)
+            .map_err(|_| KError::TooManyRegions {
+                count: regions.len(),
+            })?;
         let mut gm = GlobalMemory::default();
 
         // How many NUMA nodes are there in the system
@@ -659,21 +1067,25 @@ impl GlobalMemory {
             .max()
             .expect("Need at least some frames")
             + 1;
+        if max_affinity > MAX_NUMA_NODES {
+            return Err(KError::TooManyNodes {
+                count: max_affinity,
+            });
+        }
 
         // Construct the `emem`'s for all NUMA nodes:
         let mut cur_affinity = 0;
         // Top of the frames that we didn't end up using for the `emem` construction
         let mut leftovers: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
         for frame in memory.iter_mut() {
-            const EMEM_SIZE: usize = 2 * LARGE_PAGE_SIZE + 64 * BASE_PAGE_SIZE;
-            if frame.affinity == cur_affinity && frame.size() > EMEM_SIZE {
+            if frame.affinity == cur_affinity && frame.size() > emem_size {
                 // Let's make sure we have a frame that starts at a 2 MiB boundary which makes it easier
                 // to populate the TCache
                 let (low, large_page_aligned_frame) = frame.split_at_nearest_large_page_boundary();
                 *frame = low;
 
                 // Cut-away the top memory if the frame we got is too big
-                let (emem, leftover_mem) = large_page_aligned_frame.split_at(EMEM_SIZE);
+                let (emem, leftover_mem) = large_page_aligned_frame.split_at(emem_size);
                 if leftover_mem != Frame::empty() {
                     // And safe it for later processing
                     leftovers.push(leftover_mem);
@@ -696,7 +1108,8 @@ impl GlobalMemory {
 
         // Construct an NCache for all nodes
         for affinity in 0..max_affinity {
-            let mut ncache_memory = gm.emem[affinity].lock().allocate_large_page()?;
+            let mut ncache_memory =
+                gm.allocate_ncache_memory(affinity, &mut memory, &mut leftovers)?;
             let ncache_memory_addr: PAddr = ncache_memory.base;
             assert!(ncache_memory_addr != PAddr::zero());
             ncache_memory.zero(); // TODO(perf) this happens twice atm?
@@ -710,21 +1123,23 @@ impl GlobalMemory {
             );
 
             gm.node_caches.push(CachePadded::new(Mutex::new(ncache)));
+            gm.watermarks.push(Mutex::new(Watermarks::default()));
+            gm.free_counters.push(CachePadded::new(FreeCounters::default()));
         }
 
         // Populate the NCaches with all remaining memory
         // Ideally we fully exhaust all frames and put everything in the NCache
-        for (ncache_affinity, ncache) in gm.node_caches.iter().enumerate() {
-            let mut ncache_locked = ncache.lock();
+        let all_nodes: ArrayVec<atopology::NodeId, MAX_NUMA_NODES> = (0..max_affinity).collect();
+        for (ncache_affinity, mut ncache_locked) in gm.lock_nodes_ordered(&all_nodes)? {
             for frame in memory.iter() {
                 if frame.affinity == ncache_affinity {
-                    trace!("Trying to add {:?} frame to {:?}", frame, ncache_locked);
+                    trace!("Trying to add {:?} frame to {:?}", frame, &*ncache_locked);
                     ncache_locked.populate_2m_first(*frame);
                 }
             }
             for frame in leftovers.iter() {
                 if frame.affinity == ncache_affinity {
-                    trace!("Trying to add {:?} frame to {:?}", frame, ncache_locked);
+                    trace!("Trying to add {:?} frame to {:?}", frame, &*ncache_locked);
                     ncache_locked.populate_2m_first(*frame);
                 }
             }
@@ -732,6 +1147,219 @@ impl GlobalMemory {
 
         Ok(gm)
     }
+
+    /// Allocates the (large-page) memory for the NCache of `node`.
+    ///
+    /// This comes from the early memory of `node` if possible. If that has no
+    /// large-page left (e.g., because only a small part of the node's memory
+    /// was large-page aligned) we fall back to, in this order:
+    ///
+    /// - a large-page cut out of the node's remaining `memory`/`leftovers`,
+    /// - the early memory of the other nodes,
+    /// - a large-page cut out of the remaining memory of the other nodes.
+    ///
+    /// In the last two cases the NCache isn't local to its node but we can
+    /// still boot.
+    fn allocate_ncache_memory(
+        &self,
+        node: atopology::NodeId,
+        memory: &mut [Frame],
+        leftovers: &mut [Frame],
+    ) -> Result<Frame, KError> {
+        let err = match self.emem[node].lock().allocate_large_page() {
+            Ok(frame) => return Ok(frame),
+            Err(e) => e,
+        };
+
+        let local = |f: &Frame| f.affinity == node;
+        if let Some(frame) =
+            carve_large_page(memory, local).or_else(|| carve_large_page(leftovers, local))
+        {
+            return Ok(frame);
+        }
+
+        for (other, emem) in self.emem.iter().enumerate().filter(|(n, _)| *n != node) {
+            if let Ok(frame) = emem.lock().allocate_large_page() {
+                warn!("Not enough memory on node {}, its NCache is on node {}.", node, other);
+                return Ok(frame);
+            }
+        }
+
+        let remote = |f: &Frame| f.affinity != node;
+        carve_large_page(memory, remote)
+            .or_else(|| carve_large_page(leftovers, remote))
+            .map(|frame| {
+                warn!(
+                    "Not enough memory on node {}, its NCache is on node {}.",
+                    node, frame.affinity
+                );
+                frame
+            })
+            .ok_or(err)
+    }
+
+    /// Locks the node-cache of `node`.
+    ///
+    /// # Panics
+    /// If `node` doesn't exist.
+    pub(crate) fn lock_node(&self, node: atopology::NodeId) -> NodeCacheGuard<'_> {
+        NodeCacheGuard {
+            ncache: self.node_caches[node].lock(),
+            counters: &self.free_counters[node],
+        }
+    }
+
+    /// Returns the free memory of every node-cache without taking any locks.
+    ///
+    /// The numbers are published whenever a node-cache that was locked with
+    /// `lock_node` (or `lock_nodes_ordered`) is unlocked, so they can be
+    /// slightly stale: they miss whatever happens in a node-cache that is
+    /// currently locked (or was modified by locking `node_caches` directly).
+    /// Use `stats_precise` if that matters.
+    pub fn stats(&self) -> ArrayVec<NodeStats, MAX_NUMA_NODES> {
+        self.free_counters.iter().map(|c| c.read()).collect()
+    }
+
+    /// Returns the free memory of every node-cache, this locks every
+    /// node-cache (one after the other) so it may disturb allocations.
+    pub fn stats_precise(&self) -> ArrayVec<NodeStats, MAX_NUMA_NODES> {
+        (0..self.node_caches.len())
+            .map(|node| {
+                let ncache = self.lock_node(node);
+                NodeStats {
+                    free_base_pages: ncache.free_base_pages(),
+                    free_large_pages: ncache.free_large_pages(),
+                }
+            })
+            .collect()
+    }
+
+    /// Locks the node-caches of all `nodes` (duplicates are ignored).
+    ///
+    /// The locks are always acquired in ascending node order, no matter how
+    /// `nodes` is ordered. Anything that holds more than one node-cache lock
+    /// at a time has to go through this to avoid deadlocks.
+    pub(crate) fn lock_nodes_ordered(
+        &self,
+        nodes: &[atopology::NodeId],
+    ) -> Result<ArrayVec<(atopology::NodeId, NodeCacheGuard<'_>), MAX_NUMA_NODES>, KError> {
+        let mut ordered: ArrayVec<atopology::NodeId, MAX_NUMA_NODES> = ArrayVec::new();
+        for node in nodes {
+            if *node >= self.node_caches.len() {
+                return Err(KError::InvalidAffinityId);
+            }
+            if !ordered.contains(node) {
+                ordered.push(*node);
+            }
+        }
+        ordered.sort_unstable();
+
+        Ok(ordered
+            .iter()
+            .map(|node| (*node, self.lock_node(*node)))
+            .collect())
+    }
+
+    /// Moves up to `count` free base-pages from the node-cache of `from` to
+    /// the one of `to` (e.g., to rebalance memory between nodes).
+    ///
+    /// The frames get the affinity of `to` as they move. Returns how many
+    /// base-pages were moved.
+    pub fn move_base_pages(
+        &self,
+        from: atopology::NodeId,
+        to: atopology::NodeId,
+        count: usize,
+    ) -> Result<usize, KError> {
+        const BATCH_SIZE: usize = 32;
+        let mut locked = self.lock_nodes_ordered(&[from, to])?;
+        if from == to {
+            return Ok(0);
+        }
+        let (low, high) = locked.split_at_mut(1);
+        let (src, dst) = if from < to {
+            (&mut low[0].1, &mut high[0].1)
+        } else {
+            (&mut high[0].1, &mut low[0].1)
+        };
+
+        let count = core::cmp::min(count, dst.spare_base_page_capacity());
+        let mut moved = 0;
+        while moved < count {
+            let mut batch = [None; BATCH_SIZE];
+            let n = core::cmp::min(batch.len(), count - moved);
+            src.reap_base_pages(&mut batch[..n]);
+
+            let frames: ArrayVec<Frame, BATCH_SIZE> = batch
+                .iter()
+                .flatten()
+                .map(|frame| frame.with_affinity(to))
+                .collect();
+            dst.grow_base_pages(&frames)
+                .expect("We ensure to not overfill the NCache above.");
+            moved += frames.len();
+            if frames.len() < n {
+                // `from` ran out of base-pages
+                break;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Sets the free-memory watermarks (in bytes) of `node`.
+    pub fn set_watermarks(
+        &self,
+        node: atopology::NodeId,
+        low: usize,
+        high: usize,
+    ) -> Result<(), KError> {
+        if low > high {
+            return Err(KError::InvalidLength);
+        }
+        let watermarks = self.watermarks.get(node).ok_or(KError::InvalidAffinityId)?;
+        *watermarks.lock() = Watermarks { low, high };
+        Ok(())
+    }
+
+    /// Registers a function that is called (by `check_watermarks`) for every
+    /// node that is below its low watermark, e.g., to start reclaiming memory.
+    pub fn set_low_memory_callback(&self, callback: fn(atopology::NodeId)) {
+        *self.low_memory_callback.lock() = Some(callback);
+    }
+
+    /// Compares the free memory in every node-cache against its watermarks.
+    ///
+    /// Invokes the low-memory callback for all nodes that are `Low`. This
+    /// doesn't allocate since it's meant to be called when memory is scarce.
+    pub fn check_watermarks(
+        &self,
+    ) -> ArrayVec<(atopology::NodeId, WatermarkState), MAX_NUMA_NODES> {
+        let mut states = ArrayVec::new();
+        for (node, ncache) in self.node_caches.iter().enumerate() {
+            let free = AllocatorStatistics::free(&**ncache.lock());
+            let watermarks = *self.watermarks[node].lock();
+            let state = if free < watermarks.low {
+                WatermarkState::Low
+            } else if free < watermarks.high {
+                WatermarkState::Normal
+            } else {
+                WatermarkState::High
+            };
+            states.push((node, state));
+        }
+
+        let callback = *self.low_memory_callback.lock();
+        if let Some(callback) = callback {
+            for (node, state) in states.iter() {
+                if *state == WatermarkState::Low {
+                    callback(*node);
+                }
+            }
+        }
+
+        states
+    }
 }
 
 impl fmt::Debug for GlobalMemory {
@@ -758,10 +1386,59 @@ pub trait PhysicalPageProvider {
     /// Release a `BASE_PAGE_SIZE` for the given architecture back to the allocator.
     fn release_base_page(&mut self, f: Frame) -> Result<(), KError>;
 
+    /// Allocate a `BASE_PAGE_SIZE` and tag it with `tag` to record who
+    /// allocated it (see `Frame::with_tag`).
+    fn allocate_base_page_tagged(&mut self, tag: u16) -> Result<Frame, KError> {
+        self.allocate_base_page().map(|f| f.with_tag(tag))
+    }
+
+    /// Allocate a zeroed `BASE_PAGE_SIZE`.
+    ///
+    /// Allocators that scrub free pages ahead of time (see `scrub_one`) can
+    /// hand out a clean page here without zeroing it on the allocation path.
+    fn allocate_clean_base_page(&mut self) -> Result<Frame, KError> {
+        let mut frame = self.allocate_base_page()?;
+        unsafe { frame.zero() };
+        Ok(frame)
+    }
+
+    /// Zero one free base-page (e.g., from a background task when the core
+    /// is idle) so a later `allocate_clean_base_page` doesn't have to.
+    ///
+    /// Returns false if there was no page left to scrub.
+    fn scrub_one(&mut self) -> bool {
+        false
+    }
+
     /// Allocate a `LARGE_PAGE_SIZE` for the given architecture from the allocator.
     fn allocate_large_page(&mut self) -> Result<Frame, KError>;
     /// Release a `LARGE_PAGE_SIZE` for the given architecture back to the allocator.
     fn release_large_page(&mut self, f: Frame) -> Result<(), KError>;
+
+    /// Release a batch of `BASE_PAGE_SIZE` frames back to the allocator.
+    ///
+    /// Lets a client return many frames while holding the allocator (lock)
+    /// only once. Returns `CantGrowFurther` with the number of frames that
+    /// were accepted in case the allocator ran out of space.
+    fn release_base_pages(&mut self, frames: &[Frame]) -> Result<(), KError> {
+        for (count, frame) in frames.iter().enumerate() {
+            self.release_base_page(*frame)
+                .map_err(|_e| KError::CantGrowFurther { count })?;
+        }
+        Ok(())
+    }
+
+    /// Release a batch of `LARGE_PAGE_SIZE` frames back to the allocator.
+    ///
+    /// Returns `CantGrowFurther` with the number of frames that were accepted
+    /// in case the allocator ran out of space.
+    fn release_large_pages(&mut self, frames: &[Frame]) -> Result<(), KError> {
+        for (count, frame) in frames.iter().enumerate() {
+            self.release_large_page(*frame)
+                .map_err(|_e| KError::CantGrowFurther { count })?;
+        }
+        Ok(())
+    }
 }
 
 /// The backend implementation necessary to implement if we want a client to be
@@ -875,6 +1552,9 @@ pub struct Frame {
     pub base: PAddr,
     pub size: usize,
     pub affinity: atopology::NodeId,
+    /// Who allocated the frame (for debugging leaks), 0 if unknown.
+    #[cfg(feature = "frame-tags")]
+    pub tag: u16,
 }
 
 impl Frame {
@@ -888,6 +1568,8 @@ impl Frame {
             base,
             size,
             affinity: node,
+            #[cfg(feature = "frame-tags")]
+            tag: 0,
         }
     }
 
@@ -901,6 +1583,8 @@ impl Frame {
             base: range.0,
             size: (range.1 - range.0).into(),
             affinity: node,
+            #[cfg(feature = "frame-tags")]
+            tag: 0,
         }
     }
 
@@ -913,6 +1597,8 @@ impl Frame {
             base,
             size,
             affinity: node,
+            #[cfg(feature = "frame-tags")]
+            tag: 0,
         }
     }
 
@@ -922,9 +1608,39 @@ impl Frame {
             base: PAddr::zero(),
             size: 0,
             affinity: 0,
+            #[cfg(feature = "frame-tags")]
+            tag: 0,
         }
     }
 
+    /// Returns the frame re-labeled as memory of NUMA node `node`.
+    ///
+    /// Needs to be used when memory moves from one node-cache to another.
+    pub fn with_affinity(mut self, node: atopology::NodeId) -> Frame {
+        self.affinity = node;
+        self
+    }
+
+    /// Tags the frame with `tag` to record where it was allocated.
+    #[cfg(feature = "frame-tags")]
+    pub fn with_tag(mut self, tag: u16) -> Frame {
+        self.tag = tag;
+        self
+    }
+
+    /// Frames don't carry a tag without the `frame-tags` feature.
+    #[cfg(not(feature = "frame-tags"))]
+    #[inline(always)]
+    pub fn with_tag(self, _tag: u16) -> Frame {
+        self
+    }
+
+    /// Returns the allocation tag of the frame (0 if it was never tagged).
+    #[cfg(feature = "frame-tags")]
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
     /// Represent the Frame as a mutable slice of `T`.
     ///
     /// TODO: Bug (should we panic if we don't fit
@@ -947,10 +1663,24 @@ impl Frame {
     /// - `low` will be everything below alignment or Frame::empty() if `self`
     ///    is already aligned to `LARGE_PAGE_SIZE`
     fn split_at_nearest_large_page_boundary(self) -> (Frame, Frame) {
-        if self.base % LARGE_PAGE_SIZE == 0 {
+        self.split_at_nearest_boundary(LARGE_PAGE_SIZE)
+    }
+
+    /// Splits a given Frame into two (`low`, `high`).
+    ///
+    /// - `high` will be aligned to `align` or Frame::empty() if the frame
+    ///    can not be aligned to `align` within its size.
+    /// - `low` will be everything below alignment or Frame::empty() if `self`
+    ///    is already aligned to `align`
+    ///
+    /// # Panics
+    /// If `align` is not a power of two or not a multiple of the base
+    /// page-size.
+    pub fn split_at_nearest_boundary(self, align: usize) -> (Frame, Frame) {
+        if self.is_aligned_to(align) {
             (Frame::empty(), self)
         } else {
-            let new_high_base = PAddr::from(round_up!(self.base.as_usize(), LARGE_PAGE_SIZE));
+            let new_high_base = PAddr::from(round_up!(self.base.as_usize(), align));
             let split_at = new_high_base - self.base;
 
             self.split_at(split_at.as_usize())
@@ -1021,7 +1751,17 @@ impl Frame {
     }
 
     pub fn is_large_page_aligned(&self) -> bool {
-        self.base % LARGE_PAGE_SIZE == 0
+        self.is_aligned_to(LARGE_PAGE_SIZE)
+    }
+
+    /// Is the frame's base aligned to `align`?
+    ///
+    /// # Panics
+    /// If `align` is not a power of two or not a multiple of the base
+    /// page-size.
+    pub fn is_aligned_to(&self, align: usize) -> bool {
+        assert!(align.is_power_of_two() && align % BASE_PAGE_SIZE == 0);
+        self.base % align == 0
     }
 
     /// Size of the region (in bytes).
@@ -1033,6 +1773,26 @@ impl Frame {
         self.base + self.size
     }
 
+    /// Is `next` physically adjacent to (i.e., starts right after) `self`?
+    ///
+    /// This is directional, `next` has to come after `self`. Affinity is not
+    /// considered.
+    pub fn is_contiguous_with(&self, next: &Frame) -> bool {
+        self.end() == next.base
+    }
+
+    /// Returns the part of `self` that overlaps with `other` (with the
+    /// affinity of `self`), or `None` if the two frames are disjoint.
+    pub fn intersect(&self, other: &Frame) -> Option<Frame> {
+        let base = core::cmp::max(self.base, other.base);
+        let end = core::cmp::min(self.end(), other.end());
+        if base < end {
+            Some(Frame::new(base, (end - base).as_usize(), self.affinity))
+        } else {
+            None
+        }
+    }
+
     /// Zero the frame using `memset`.
     pub unsafe fn zero(&mut self) {
         self.fill(0);
@@ -1044,6 +1804,86 @@ impl Frame {
     }
 }
 
+/// Wire representation of a [`Frame`] (e.g., to hand out regions over RPC).
+///
+/// The allocation tag is not carried over.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FrameDesc {
+    pub base: u64,
+    pub size: usize,
+    pub affinity: u64,
+}
+
+impl FrameDesc {
+    /// Size of an encoded descriptor (in bytes).
+    pub const ENCODED_SIZE: usize = 24;
+
+    /// Encodes the descriptor (little-endian base, size, affinity).
+    pub fn encode(&self) -> [u8; FrameDesc::ENCODED_SIZE] {
+        let mut buf = [0u8; FrameDesc::ENCODED_SIZE];
+        buf[0..8].copy_from_slice(&self.base.to_le_bytes());
+        buf[8..16].copy_from_slice(&(self.size as u64).to_le_bytes());
+        buf[16..24].copy_from_slice(&self.affinity.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a descriptor produced by `encode`.
+    ///
+    /// This does not validate the region, convert it into a `Frame` for that.
+    pub fn decode(buf: &[u8]) -> Result<FrameDesc, KError> {
+        if buf.len() < FrameDesc::ENCODED_SIZE {
+            return Err(KError::InvalidLength);
+        }
+        let field = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[i * 8..(i + 1) * 8]);
+            u64::from_le_bytes(bytes)
+        };
+
+        Ok(FrameDesc {
+            base: field(0),
+            size: field(1) as usize,
+            affinity: field(2),
+        })
+    }
+}
+
+impl From<Frame> for FrameDesc {
+    fn from(frame: Frame) -> FrameDesc {
+        FrameDesc {
+            base: frame.base.as_u64(),
+            size: frame.size,
+            affinity: frame.affinity as u64,
+        }
+    }
+}
+
+impl core::convert::TryFrom<FrameDesc> for Frame {
+    type Error = KError;
+
+    /// Turns a (possibly untrusted) descriptor back into a Frame.
+    fn try_from(desc: FrameDesc) -> Result<Frame, KError> {
+        if desc.base % BASE_PAGE_SIZE as u64 != 0 {
+            return Err(KError::InvalidBase);
+        }
+        if desc.size % BASE_PAGE_SIZE != 0 {
+            return Err(KError::InvalidLength);
+        }
+        if desc.base.checked_add(desc.size as u64).is_none() {
+            return Err(KError::InvalidLength);
+        }
+        if desc.affinity >= MAX_NUMA_NODES as u64 {
+            return Err(KError::InvalidAffinityId);
+        }
+
+        Ok(Frame::new(
+            PAddr::from(desc.base),
+            desc.size,
+            desc.affinity as atopology::NodeId,
+        ))
+    }
+}
+
 pub struct IntoBasePageIter {
     frame: Frame,
 }
@@ -1102,11 +1942,23 @@ impl fmt::Debug for Frame {
 }
 
 pub trait PageTableProvider<'a> {
-    fn allocate_pml4<'b>(&mut self) -> Option<&'b mut paging::PML4>;
-    fn new_pdpt(&mut self) -> Option<paging::PML4Entry>;
-    fn new_pd(&mut self) -> Option<paging::PDPTEntry>;
-    fn new_pt(&mut self) -> Option<paging::PDEntry>;
-    fn new_page(&mut self) -> Option<paging::PTEntry>;
+    fn allocate_pml4<'b>(&mut self) -> Result<&'b mut paging::PML4, KError>;
+    fn new_pdpt(&mut self) -> Result<paging::PML4Entry, KError>;
+    fn new_pd(&mut self) -> Result<paging::PDPTEntry, KError>;
+    fn new_pt(&mut self) -> Result<paging::PDEntry, KError>;
+    fn new_page(&mut self) -> Result<paging::PTEntry, KError>;
+}
+
+/// Returns the entry that `entry` builds for a freshly allocated page-table
+/// `frame` (given the address of the page).
+///
+/// Errors from the allocator (e.g., `KError::CacheExhausted`) are passed
+/// on to the caller.
+fn new_table_entry<E>(
+    frame: Result<Frame, KError>,
+    entry: impl FnOnce(PAddr) -> E,
+) -> Result<E, KError> {
+    frame.map(|frame| entry(frame.base))
 }
 
 #[allow(dead_code)]
@@ -1121,83 +1973,56 @@ impl NRKPageTableProvider {
 
 impl<'a> PageTableProvider<'a> for NRKPageTableProvider {
     /// Allocate a PML4 table.
-    fn allocate_pml4<'b>(&mut self) -> Option<&'b mut paging::PML4> {
+    fn allocate_pml4<'b>(&mut self) -> Result<&'b mut paging::PML4, KError> {
         let kcb = kcb::get_kcb();
-        let mut fmanager = kcb.mem_manager();
-        unsafe {
-            fmanager
-                .allocate_base_page()
-                .map(|frame| {
-                    let pml4: &'b mut [paging::PML4Entry; 512] =
-                        transmute(paddr_to_kernel_vaddr(frame.base));
-                    pml4
-                })
-                .ok()
-        }
+        new_table_entry(kcb.alloc_table_frame(), |base| unsafe {
+            let pml4: &'b mut [paging::PML4Entry; 512] = transmute(paddr_to_kernel_vaddr(base));
+            pml4
+        })
     }
 
     /// Allocate a new page directory and return a PML4 entry for it.
-    fn new_pdpt(&mut self) -> Option<paging::PML4Entry> {
+    fn new_pdpt(&mut self) -> Result<paging::PML4Entry, KError> {
         let kcb = kcb::get_kcb();
-        let mut fmanager = kcb.mem_manager();
-
-        fmanager
-            .allocate_base_page()
-            .map(|frame| {
-                paging::PML4Entry::new(
-                    frame.base,
-                    paging::PML4Flags::P | paging::PML4Flags::RW | paging::PML4Flags::US,
-                )
-            })
-            .ok()
+        new_table_entry(kcb.alloc_table_frame(), |base| {
+            paging::PML4Entry::new(
+                base,
+                paging::PML4Flags::P | paging::PML4Flags::RW | paging::PML4Flags::US,
+            )
+        })
     }
 
     /// Allocate a new page directory and return a pdpt entry for it.
-    fn new_pd(&mut self) -> Option<paging::PDPTEntry> {
+    fn new_pd(&mut self) -> Result<paging::PDPTEntry, KError> {
         let kcb = kcb::get_kcb();
-        let mut fmanager = kcb.mem_manager();
-
-        fmanager
-            .allocate_base_page()
-            .map(|frame| {
-                paging::PDPTEntry::new(
-                    frame.base,
-                    paging::PDPTFlags::P | paging::PDPTFlags::RW | paging::PDPTFlags::US,
-                )
-            })
-            .ok()
+        new_table_entry(kcb.alloc_table_frame(), |base| {
+            paging::PDPTEntry::new(
+                base,
+                paging::PDPTFlags::P | paging::PDPTFlags::RW | paging::PDPTFlags::US,
+            )
+        })
     }
 
     /// Allocate a new page-directory and return a page directory entry for it.
-    fn new_pt(&mut self) -> Option<paging::PDEntry> {
+    fn new_pt(&mut self) -> Result<paging::PDEntry, KError> {
         let kcb = kcb::get_kcb();
-        let mut fmanager = kcb.mem_manager();
-
-        fmanager
-            .allocate_base_page()
-            .map(|frame| {
-                paging::PDEntry::new(
-                    frame.base,
-                    paging::PDFlags::P | paging::PDFlags::RW | paging::PDFlags::US,
-                )
-            })
-            .ok()
+        new_table_entry(kcb.alloc_table_frame(), |base| {
+            paging::PDEntry::new(
+                base,
+                paging::PDFlags::P | paging::PDFlags::RW | paging::PDFlags::US,
+            )
+        })
     }
 
     /// Allocate a new (4KiB) page and map it.
-    fn new_page(&mut self) -> Option<paging::PTEntry> {
+    fn new_page(&mut self) -> Result<paging::PTEntry, KError> {
         let kcb = kcb::get_kcb();
-        let mut fmanager = kcb.mem_manager();
-
-        fmanager
-            .allocate_base_page()
-            .map(|frame| {
-                paging::PTEntry::new(
-                    frame.base,
-                    paging::PTFlags::P | paging::PTFlags::RW | paging::PTFlags::US,
-                )
-            })
-            .ok()
+        new_table_entry(kcb.alloc_table_frame(), |base| {
+            paging::PTEntry::new(
+                base,
+                paging::PTFlags::P | paging::PTFlags::RW | paging::PTFlags::US,
+            )
+        })
     }
 }
 
@@ -1252,6 +2077,73 @@ mod tests {
         );
     }
 
+    #[test]
+    fn frame_split_at_nearest_boundary() {
+        const HUGE_PAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+        // 4 KiB: frames are always aligned
+        let f = Frame::new(PAddr::from(3 * BASE_PAGE_SIZE), 4096 * 2, 0);
+        assert!(f.is_aligned_to(BASE_PAGE_SIZE));
+        assert_eq!(
+            f.split_at_nearest_boundary(BASE_PAGE_SIZE),
+            (Frame::empty(), f)
+        );
+
+        // 2 MiB: same as `split_at_nearest_large_page_boundary`
+        let f = Frame::new(PAddr::from(LARGE_PAGE_SIZE - 5 * 4096), 4096 * 10, 0);
+        assert!(!f.is_aligned_to(LARGE_PAGE_SIZE));
+        assert_eq!(
+            f.split_at_nearest_boundary(LARGE_PAGE_SIZE),
+            f.split_at_nearest_large_page_boundary()
+        );
+
+        // 1 GiB
+        let f = Frame::new(
+            PAddr::from(HUGE_PAGE_SIZE - LARGE_PAGE_SIZE),
+            2 * LARGE_PAGE_SIZE,
+            0,
+        );
+        let low = Frame::new(f.base, LARGE_PAGE_SIZE, 0);
+        let high = Frame::new(PAddr::from(HUGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0);
+        assert!(f.is_aligned_to(LARGE_PAGE_SIZE));
+        assert!(!f.is_aligned_to(HUGE_PAGE_SIZE));
+        assert!(high.is_aligned_to(HUGE_PAGE_SIZE));
+        assert_eq!(f.split_at_nearest_boundary(HUGE_PAGE_SIZE), (low, high));
+        assert_eq!(
+            low.split_at_nearest_boundary(HUGE_PAGE_SIZE),
+            (low, Frame::empty())
+        );
+    }
+
+    /// Alignments have to be a power of two.
+    #[test]
+    #[should_panic]
+    fn frame_is_aligned_to_invalid() {
+        let f = Frame::new(PAddr::from(3 * BASE_PAGE_SIZE), 4096, 0);
+        f.is_aligned_to(3 * BASE_PAGE_SIZE);
+    }
+
+    #[test]
+    fn frame_desc_roundtrip() {
+        use core::convert::TryFrom;
+
+        let f = Frame::new(PAddr::from(8 * 1024 * 1024), 4096 * 10, 1);
+        let desc = FrameDesc::decode(&FrameDesc::from(f).encode()).expect("Can't decode");
+        assert_eq!(Frame::try_from(desc), Ok(f));
+
+        let misaligned = FrameDesc {
+            base: 8 * 1024 * 1024 + 12,
+            ..desc
+        };
+        let decoded = FrameDesc::decode(&misaligned.encode()).expect("Can't decode");
+        assert_eq!(Frame::try_from(decoded), Err(KError::InvalidBase));
+
+        let odd_size = FrameDesc { size: 100, ..desc };
+        assert_eq!(Frame::try_from(odd_size), Err(KError::InvalidLength));
+
+        assert_eq!(FrameDesc::decode(&[0u8; 8]), Err(KError::InvalidLength));
+    }
+
     #[test]
     fn frame_large_page_aligned() {
         let f = Frame::new(PAddr::from(0xf000), 4096 * 10, 0);
@@ -1291,6 +2183,435 @@ mod tests {
         assert_eq!(f.end(), PAddr::from(4096 * 10 + 0x1000));
     }
 
+    #[test]
+    fn frame_is_contiguous_with() {
+        let a = Frame::new(PAddr::from(0x1000), 4096 * 2, 0);
+        let b = Frame::new(PAddr::from(0x3000), 4096, 0);
+        let c = Frame::new(PAddr::from(0x5000), 4096, 0);
+
+        // Adjacent
+        assert!(a.is_contiguous_with(&b));
+        // Gap in between
+        assert!(!b.is_contiguous_with(&c));
+        assert!(!a.is_contiguous_with(&c));
+        // Reversed order
+        assert!(!b.is_contiguous_with(&a));
+    }
+
+    #[test]
+    fn frame_intersect() {
+        let a = Frame::new(PAddr::from(0x1000), 4096 * 4, 0);
+        let b = Frame::new(PAddr::from(0x3000), 4096 * 4, 1);
+        let c = Frame::new(PAddr::from(0x2000), 4096, 1);
+        let d = Frame::new(PAddr::from(0x5000), 4096, 1);
+
+        // Partial overlap
+        assert_eq!(a.intersect(&b), Some(Frame::new(PAddr::from(0x3000), 4096 * 2, 0)));
+        assert_eq!(b.intersect(&a), Some(Frame::new(PAddr::from(0x3000), 4096 * 2, 1)));
+        // Containment
+        assert_eq!(a.intersect(&c), Some(Frame::new(PAddr::from(0x2000), 4096, 0)));
+        assert_eq!(c.intersect(&a), Some(c));
+        // Disjoint (and just touching)
+        assert_eq!(a.intersect(&d), None);
+        assert_eq!(a.intersect(&Frame::new(PAddr::from(0x6000), 4096, 0)), None);
+        assert_eq!(c.intersect(&d), None);
+    }
+
+    /// Page-table entries point to the page we got from the allocator.
+    #[test]
+    fn new_table_entry_allocates() {
+        let mut tcache = mcache::TCache::new(0);
+        for base in &[0x2000, 0x3000, 0x4000, 0x5000] {
+            tcache
+                .release_base_page(Frame::new(PAddr::from(*base), BASE_PAGE_SIZE, 0))
+                .expect("release");
+        }
+
+        let pdpt = new_table_entry(tcache.allocate_base_page(), |base| {
+            paging::PML4Entry::new(base, paging::PML4Flags::P | paging::PML4Flags::RW)
+        })
+        .expect("pml4 entry");
+        assert_eq!(pdpt.address(), PAddr::from(0x5000));
+        assert!(pdpt.is_present());
+
+        let pd = new_table_entry(tcache.allocate_base_page(), |base| {
+            paging::PDPTEntry::new(base, paging::PDPTFlags::P | paging::PDPTFlags::RW)
+        })
+        .expect("pdpt entry");
+        assert_eq!(pd.address(), PAddr::from(0x4000));
+
+        let pt = new_table_entry(tcache.allocate_base_page(), |base| {
+            paging::PDEntry::new(base, paging::PDFlags::P | paging::PDFlags::RW)
+        })
+        .expect("pd entry");
+        assert_eq!(pt.address(), PAddr::from(0x3000));
+
+        let page = new_table_entry(tcache.allocate_base_page(), |base| {
+            paging::PTEntry::new(base, paging::PTFlags::P | paging::PTFlags::RW)
+        })
+        .expect("pt entry");
+        assert_eq!(page.address(), PAddr::from(0x2000));
+    }
+
+    /// Running out of memory is reported (for every level).
+    #[test]
+    fn new_table_entry_oom() {
+        let mut tcache = mcache::TCache::new(0);
+
+        assert_eq!(
+            new_table_entry(tcache.allocate_base_page(), |base| {
+                paging::PML4Entry::new(base, paging::PML4Flags::P)
+            }),
+            Err(KError::CacheExhausted)
+        );
+        assert_eq!(
+            new_table_entry(tcache.allocate_base_page(), |base| {
+                paging::PDPTEntry::new(base, paging::PDPTFlags::P)
+            }),
+            Err(KError::CacheExhausted)
+        );
+        assert_eq!(
+            new_table_entry(tcache.allocate_base_page(), |base| {
+                paging::PDEntry::new(base, paging::PDFlags::P)
+            }),
+            Err(KError::CacheExhausted)
+        );
+        assert_eq!(
+            new_table_entry(tcache.allocate_base_page(), |base| {
+                paging::PTEntry::new(base, paging::PTFlags::P)
+            }),
+            Err(KError::CacheExhausted)
+        );
+    }
+
+    /// Page-aligned layouts get routed to the physical memory manager.
+    #[test]
+    fn allocator_for_alignment() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(64, 8)),
+            AllocatorType::Zone
+        );
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(BASE_PAGE_SIZE, 8)),
+            AllocatorType::Zone
+        );
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(64, BASE_PAGE_SIZE)),
+            AllocatorType::MemManager
+        );
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(BASE_PAGE_SIZE, BASE_PAGE_SIZE)),
+            AllocatorType::MemManager
+        );
+        assert_eq!(
+            KernelAllocator::page_size_for(layout(BASE_PAGE_SIZE, BASE_PAGE_SIZE)),
+            BASE_PAGE_SIZE
+        );
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(64, 2 * BASE_PAGE_SIZE)),
+            AllocatorType::MemManager
+        );
+        assert_eq!(
+            KernelAllocator::page_size_for(layout(64, 2 * BASE_PAGE_SIZE)),
+            LARGE_PAGE_SIZE
+        );
+        assert_eq!(
+            KernelAllocator::allocator_for(layout(2 * LARGE_PAGE_SIZE, BASE_PAGE_SIZE)),
+            AllocatorType::MapBig
+        );
+    }
+
+    /// A base-page sized and aligned allocation returns aligned memory.
+    #[test]
+    fn kernel_allocator_page_aligned() {
+        let frame = crate::arch::memory::MemoryMapper::default()
+            .allocate_frame(BASE_PAGE_SIZE)
+            .expect("Can't allocate frame");
+        kcb::get_kcb()
+            .mem_manager()
+            .release_base_page(frame)
+            .expect("Can't add frame to TCache");
+
+        let allocator = test_allocator();
+        let layout = Layout::from_size_align(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % BASE_PAGE_SIZE, 0);
+            allocator.dealloc(ptr, layout);
+        }
+    }
+
+    /// A node with less memory than `DEFAULT_EMEM_SIZE` can still be used
+    /// with a smaller early-memory reserve.
+    #[test]
+    fn global_memory_custom_emem_size() {
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let node0 = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+        let small = mm.allocate_frame(2 * LARGE_PAGE_SIZE).unwrap();
+        let node1 = Frame::new(small.base, small.size(), 1);
+        assert!(node1.size() < DEFAULT_EMEM_SIZE);
+
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        regions.push(node0);
+        regions.push(node1);
+
+        let gm = unsafe {
+            GlobalMemory::new_with_emem_size(&regions, LARGE_PAGE_SIZE + 64 * BASE_PAGE_SIZE)
+        }
+        .expect("Can construct GlobalMemory");
+        assert_eq!(gm.emem.len(), 2);
+        assert_eq!(gm.node_caches.len(), 2);
+
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        regions.push(node0);
+        assert_eq!(
+            unsafe { GlobalMemory::new_with_emem_size(&regions, BASE_PAGE_SIZE) }.err(),
+            Some(KError::InvalidLength)
+        );
+    }
+
+    /// A node whose early memory has no large-page for its NCache gets one
+    /// from another node.
+    #[test]
+    fn global_memory_ncache_fallback() {
+        const ONE_MIB: usize = 1024 * 1024;
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let node0 = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+        // Only 1.5 MiB of node 1 is large-page aligned, that's all the early
+        // memory it gets (and it's not enough for a large-page).
+        let small = mm.allocate_frame(2 * LARGE_PAGE_SIZE).unwrap();
+        let node1 = Frame::new(small.base + ONE_MIB, LARGE_PAGE_SIZE + ONE_MIB / 2, 1);
+
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        regions.push(node0);
+        regions.push(node1);
+
+        let gm = unsafe {
+            GlobalMemory::new_with_emem_size(&regions, LARGE_PAGE_SIZE + 64 * BASE_PAGE_SIZE)
+        }
+        .expect("Boots with the fallback");
+        assert_eq!(gm.node_caches.len(), 2);
+
+        // The NCache of node 1 lives in the memory of node 0
+        let ncache_addr = &**gm.lock_node(1) as *const mcache::NCache as u64;
+        let node0_start = paddr_to_kernel_vaddr(node0.base).as_u64();
+        assert!(ncache_addr >= node0_start && ncache_addr < node0_start + node0.size() as u64);
+        // But it still hands out memory of node 1
+        let frame = gm.lock_node(1).allocate_base_page().expect("Has memory");
+        assert_eq!(frame.affinity, 1);
+    }
+
+    /// Too many memory regions or NUMA nodes are reported as an error
+    /// instead of overflowing our fixed-size data-structures.
+    #[test]
+    fn global_memory_too_many_regions() {
+        // The frames are never touched, the check happens before
+        let regions: Vec<Frame> = (0..MAX_PHYSICAL_REGIONS + 1)
+            .map(|i| Frame::new(PAddr::from(i * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, 0))
+            .collect();
+        assert_eq!(
+            unsafe { GlobalMemory::new(&regions) }.err(),
+            Some(KError::TooManyRegions {
+                count: MAX_PHYSICAL_REGIONS + 1
+            })
+        );
+
+        let regions: Vec<Frame> = (0..MAX_NUMA_NODES + 1)
+            .map(|i| Frame::new(PAddr::from(i * LARGE_PAGE_SIZE), LARGE_PAGE_SIZE, i))
+            .collect();
+        assert_eq!(
+            unsafe { GlobalMemory::new(&regions) }.err(),
+            Some(KError::TooManyNodes {
+                count: MAX_NUMA_NODES + 1
+            })
+        );
+    }
+
+    /// Base-pages moved between node-caches carry the affinity of their new
+    /// node.
+    #[test]
+    fn global_memory_move_base_pages() {
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        for node in 0..2 {
+            let frame = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+            regions.push(Frame::new(frame.base, frame.size(), node));
+        }
+        let gm = unsafe { GlobalMemory::new(&regions) }.expect("Can construct GlobalMemory");
+
+        {
+            // Make sure node 0 has some base-pages to hand out
+            let mut ncache = gm.node_caches[0].lock();
+            let large_page = ncache.allocate_large_page().unwrap();
+            ncache
+                .release_base_pages(&large_page.into_iter().collect::<Vec<Frame>>())
+                .unwrap();
+        }
+        let free_base_pages = |node: usize| gm.node_caches[node].lock().free_base_pages();
+        let (before0, before1) = (free_base_pages(0), free_base_pages(1));
+        let top = gm.node_caches[0].lock().allocate_base_page().unwrap();
+        gm.node_caches[0].lock().release_base_page(top).unwrap();
+
+        assert_eq!(gm.move_base_pages(0, 1, 1), Ok(1));
+        assert_eq!(free_base_pages(0), before0 - 1);
+        assert_eq!(free_base_pages(1), before1 + 1);
+
+        let moved = gm.node_caches[1].lock().allocate_base_page().unwrap();
+        assert_eq!(moved.base, top.base);
+        assert_eq!(moved.affinity, 1);
+        gm.node_caches[1].lock().release_base_page(moved).unwrap();
+
+        // Never moves more than what's there
+        assert_eq!(gm.move_base_pages(1, 0, usize::MAX), Ok(before1 + 1));
+        assert_eq!(free_base_pages(1), 0);
+        assert_eq!(free_base_pages(0), before0 + before1);
+        assert_eq!(gm.move_base_pages(0, 0, 1), Ok(0));
+        assert_eq!(gm.move_base_pages(0, 2, 1), Err(KError::InvalidAffinityId));
+    }
+
+    /// The lock-free stats follow the node-caches as they change and agree
+    /// with the locked ones once the node-caches are unlocked.
+    #[test]
+    fn global_memory_stats() {
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        for node in 0..2 {
+            let frame = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+            regions.push(Frame::new(frame.base, frame.size(), node));
+        }
+        let gm = unsafe { GlobalMemory::new(&regions) }.expect("Can construct GlobalMemory");
+        let initial = gm.stats();
+        assert_eq!(initial.len(), 2);
+        assert_eq!(initial, gm.stats_precise());
+
+        let large_page = gm.lock_node(0).allocate_large_page().unwrap();
+        let mut stats = gm.stats();
+        assert_eq!(stats[0].free(), initial[0].free() - LARGE_PAGE_SIZE);
+        assert_eq!(stats[1], initial[1]);
+        assert_eq!(stats, gm.stats_precise());
+
+        let mut frames = large_page.into_iter();
+        gm.lock_node(0)
+            .release_base_pages(&frames.by_ref().take(10).collect::<Vec<Frame>>())
+            .unwrap();
+        stats = gm.stats();
+        assert_eq!(stats[0].free_base_pages, initial[0].free_base_pages + 10);
+        assert_eq!(stats, gm.stats_precise());
+
+        {
+            // Not visible until the next `lock_node` on the node
+            let mut ncache = gm.node_caches[0].lock();
+            ncache.release_base_page(frames.next().unwrap()).unwrap();
+        }
+        assert_eq!(gm.stats(), stats);
+        stats = gm.stats_precise();
+        assert_eq!(stats[0].free_base_pages, initial[0].free_base_pages + 11);
+        assert_eq!(gm.stats(), stats);
+
+        // Give the rest back so the node has all its memory again
+        gm.lock_node(0)
+            .release_base_pages(&frames.collect::<Vec<Frame>>())
+            .unwrap();
+        assert_eq!(gm.stats()[0].free(), initial[0].free());
+        assert_eq!(gm.stats(), gm.stats_precise());
+    }
+
+    /// Locking overlapping sets of nodes in opposite orders doesn't deadlock.
+    #[test]
+    fn global_memory_lock_nodes_ordered() {
+        extern crate std;
+        use std::boxed::Box;
+
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        for node in 0..3 {
+            let frame = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+            regions.push(Frame::new(frame.base, frame.size(), node));
+        }
+        let gm: &'static GlobalMemory = Box::leak(Box::new(
+            unsafe { GlobalMemory::new(&regions) }.expect("Can construct GlobalMemory"),
+        ));
+
+        let locked = gm.lock_nodes_ordered(&[2, 0, 2]).expect("Can lock");
+        let order: ArrayVec<atopology::NodeId, MAX_NUMA_NODES> =
+            locked.iter().map(|(node, _)| *node).collect();
+        assert_eq!(order.as_slice(), &[0, 2]);
+        drop(locked);
+        assert_eq!(
+            gm.lock_nodes_ordered(&[0, 3]).err(),
+            Some(KError::InvalidAffinityId)
+        );
+
+        let forward = std::thread::spawn(move || {
+            for _i in 0..10_000 {
+                let _locked = gm.lock_nodes_ordered(&[0, 1, 2]).expect("Can lock");
+            }
+        });
+        let backward = std::thread::spawn(move || {
+            for _i in 0..10_000 {
+                let _locked = gm.lock_nodes_ordered(&[2, 1]).expect("Can lock");
+            }
+        });
+        forward.join().unwrap();
+        backward.join().unwrap();
+    }
+
+    /// A node that is drained below its low watermark gets reported.
+    #[test]
+    fn global_memory_watermarks() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static LOW_NODES: AtomicUsize = AtomicUsize::new(0);
+        fn on_low_memory(node: atopology::NodeId) {
+            LOW_NODES.fetch_or(1 << node, Ordering::SeqCst);
+        }
+
+        let mut mm = crate::arch::memory::MemoryMapper::default();
+        let node0 = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+        let frame = mm.allocate_frame(8 * LARGE_PAGE_SIZE).unwrap();
+        let node1 = Frame::new(frame.base, frame.size(), 1);
+
+        let mut regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+        regions.push(node0);
+        regions.push(node1);
+        let gm = unsafe { GlobalMemory::new(&regions) }.expect("Can construct GlobalMemory");
+        gm.set_low_memory_callback(on_low_memory);
+
+        // Without watermarks every node has plenty of memory
+        let states = gm.check_watermarks();
+        assert_eq!(
+            states.as_slice(),
+            &[(0, WatermarkState::High), (1, WatermarkState::High)]
+        );
+
+        let free = AllocatorStatistics::free(&**gm.node_caches[0].lock());
+        assert_eq!(gm.set_watermarks(0, free, 2 * free), Ok(()));
+        assert_eq!(gm.set_watermarks(1, 0, usize::MAX), Ok(()));
+        assert_eq!(gm.set_watermarks(2, 0, 0), Err(KError::InvalidAffinityId));
+        assert_eq!(gm.set_watermarks(0, 2, 1), Err(KError::InvalidLength));
+
+        let states = gm.check_watermarks();
+        assert_eq!(
+            states.as_slice(),
+            &[(0, WatermarkState::Normal), (1, WatermarkState::Normal)]
+        );
+        assert_eq!(LOW_NODES.load(Ordering::SeqCst), 0);
+
+        // Drain node 0 below its low watermark
+        let _frame = gm.node_caches[0]
+            .lock()
+            .allocate_base_page()
+            .expect("Can allocate");
+        let states = gm.check_watermarks();
+        assert_eq!(
+            states.as_slice(),
+            &[(0, WatermarkState::Low), (1, WatermarkState::Normal)]
+        );
+        assert_eq!(LOW_NODES.load(Ordering::SeqCst), 0b1);
+    }
+
     #[test]
     #[should_panic]
     /// Frames should be aligned to BASE_PAGE_SIZE.
@@ -1376,4 +2697,94 @@ mod tests {
         let l = unsafe { Layout::from_size_align_unchecked(LARGE_PAGE_SIZE + 1, LARGE_PAGE_SIZE) };
         assert_eq!(KernelAllocator::allocator_for(l), AllocatorType::MapBig);
     }
+
+    fn test_allocator() -> KernelAllocator {
+        KernelAllocator {
+            big_objects_sbrk: AtomicU64::new(0),
+            panic_on_oom: AtomicBool::new(false),
+            #[cfg(feature = "alloc-trace")]
+            trace: trace::AllocTrace::new(),
+            #[cfg(feature = "alloc-check")]
+            layouts: alloc_check::LayoutTable::new(),
+        }
+    }
+
+    /// Growing an allocation within its size class keeps the pointer, and
+    /// the later `dealloc` uses the new size.
+    #[test]
+    #[cfg(feature = "alloc-check")]
+    fn realloc_in_place_updates_layout() {
+        let allocator = test_allocator();
+        let ptr = 0x10_0000 as *mut u8;
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let grown = Layout::from_size_align(30, 8).unwrap();
+        allocator.layouts.insert(ptr, layout);
+
+        assert!(unsafe { allocator.realloc_in_place(ptr, layout, grown.size()) });
+        assert!(allocator.layouts.remove(ptr, layout).is_err());
+        allocator.layouts.insert(ptr, layout);
+        assert!(unsafe { allocator.realloc_in_place(ptr, layout, grown.size()) });
+        assert_eq!(allocator.layouts.remove(ptr, grown), Ok(()));
+    }
+
+    /// Frames handed back to `dealloc` must be aligned to their page size,
+    /// misaligned pointers are reported (and leaked) instead of panicking.
+    #[test]
+    fn dealloc_frame_alignment() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        let base = 0x20_0000usize;
+
+        let page = layout(BASE_PAGE_SIZE, BASE_PAGE_SIZE);
+        assert!(KernelAllocator::is_frame_aligned(base as *mut u8, page));
+        assert!(KernelAllocator::is_frame_aligned((base + BASE_PAGE_SIZE) as *mut u8, page));
+        assert!(!KernelAllocator::is_frame_aligned((base + 8) as *mut u8, page));
+
+        let large = layout(LARGE_PAGE_SIZE, BASE_PAGE_SIZE);
+        assert!(KernelAllocator::is_frame_aligned(base as *mut u8, large));
+        assert!(!KernelAllocator::is_frame_aligned((base + BASE_PAGE_SIZE) as *mut u8, large));
+    }
+
+    /// An allocation that fails after refilling reports the caches and
+    /// returns null.
+    #[test]
+    fn refill_exhausted_returns_null() {
+        let allocator = test_allocator();
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        assert!(allocator.refill_exhausted(layout).is_null());
+
+        let diagnostic = RefillDiagnostic {
+            layout,
+            allocator: KernelAllocator::allocator_for(layout),
+            refill_amount: KernelAllocator::refill_amount(layout),
+            tcache: Some((0, 1)),
+            ncache: None,
+        };
+        let msg = format!("{}", diagnostic);
+        assert!(msg.contains("Zone"), "{}", msg);
+        assert!(msg.contains("refill needs 1 bp 0 lp"), "{}", msg);
+        assert!(msg.contains("free in TCache: 0 bp 1 lp"), "{}", msg);
+        assert!(msg.contains("free in NCache: unavailable"), "{}", msg);
+    }
+
+    /// By default a failed allocation returns null.
+    #[test]
+    fn oom_policy_return_null() {
+        let allocator = test_allocator();
+        assert_eq!(allocator.oom_policy(), OomPolicy::ReturnNull);
+        let layout = Layout::from_size_align(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+        assert!(allocator
+            .out_of_memory(layout, KError::OutOfMemory)
+            .is_null());
+    }
+
+    /// With `OomPolicy::Panic` a failed allocation panics.
+    #[test]
+    #[should_panic(expected = "Out of memory")]
+    fn oom_policy_panic() {
+        let allocator = test_allocator();
+        allocator.set_oom_policy(OomPolicy::Panic);
+        assert_eq!(allocator.oom_policy(), OomPolicy::Panic);
+        let layout = Layout::from_size_align(BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+        allocator.out_of_memory(layout, KError::OutOfMemory);
+    }
 }
@@ -0,0 +1,403 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A pool for fixed-size kernel objects, backed by base-page frames.
+//!
+//! Kernel data-structures that get allocated and freed over and over at the
+//! same size (e.g., `FileDesc`, `Fd`) can be carved out of dedicated frames
+//! instead of going through the zone allocator. Every frame is split into
+//! equally sized slots, free slots are kept on an intrusive free-list.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use arrayvec::ArrayVec;
+
+use super::{Frame, PhysicalPageProvider, BASE_PAGE_SIZE};
+use crate::error::KError;
+use crate::round_up;
+
+/// How many base-pages a single `ObjectPool` can grow to.
+pub const MAX_POOL_FRAMES: usize = 64;
+
+/// Header we store in a slot while it's on the free-list.
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// A pool that hands out fixed-size slots for objects of type `T`.
+///
+/// Frames are requested from a `PhysicalPageProvider` on demand and are only
+/// handed back with `release_frames` once all objects have been freed.
+///
+/// # Panics
+/// The pool can't reach its page provider when it's dropped, so it panics if
+/// it's dropped while it still holds frames (i.e., before `release_frames`
+/// succeeded).
+pub struct ObjectPool<T> {
+    /// Frames the slots are carved from.
+    frames: ArrayVec<Frame, MAX_POOL_FRAMES>,
+    /// Head of the free-list.
+    free: Option<NonNull<FreeSlot>>,
+    /// Number of slots currently handed out.
+    allocated: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for ObjectPool<T> {}
+
+impl<T> ObjectPool<T> {
+    /// Alignment of a slot (needs to fit both `T` and the free-list header).
+    const SLOT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<FreeSlot>() {
+        mem::align_of::<T>()
+    } else {
+        mem::align_of::<FreeSlot>()
+    };
+
+    /// Size of a slot, a multiple of `SLOT_ALIGN`.
+    pub const SLOT_SIZE: usize = round_up!(
+        if mem::size_of::<T>() > mem::size_of::<FreeSlot>() {
+            mem::size_of::<T>()
+        } else {
+            mem::size_of::<FreeSlot>()
+        },
+        Self::SLOT_ALIGN
+    );
+
+    /// How many objects fit in a single base-page.
+    pub const SLOTS_PER_FRAME: usize = BASE_PAGE_SIZE / Self::SLOT_SIZE;
+
+    /// Create an empty pool.
+    ///
+    /// # Panics
+    /// If `T` doesn't fit in a base-page.
+    pub fn new() -> ObjectPool<T> {
+        assert!(Self::SLOT_SIZE <= BASE_PAGE_SIZE, "Object too big for pool");
+        assert!(
+            Self::SLOT_ALIGN <= BASE_PAGE_SIZE,
+            "Object alignment too big"
+        );
+        ObjectPool {
+            frames: ArrayVec::new(),
+            free: None,
+            allocated: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Number of objects currently allocated from the pool.
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Number of objects the pool can hold without asking for more frames.
+    pub fn capacity(&self) -> usize {
+        self.frames.len() * Self::SLOTS_PER_FRAME
+    }
+
+    /// Move `obj` into a free slot of the pool and return a pointer to it.
+    ///
+    /// Asks `pager` for another base-page in case the pool has no free slots
+    /// left.
+    pub fn alloc(
+        &mut self,
+        pager: &mut dyn PhysicalPageProvider,
+        obj: T,
+    ) -> Result<NonNull<T>, KError> {
+        if self.free.is_none() {
+            self.grow(pager)?;
+        }
+
+        let slot = self.free.ok_or(KError::OutOfMemory)?;
+        // Safety: Everything on the free-list is a valid, unused slot.
+        self.free = unsafe { slot.as_ref().next };
+        self.allocated += 1;
+
+        let ptr = slot.cast::<T>();
+        unsafe { ptr::write(ptr.as_ptr(), obj) };
+        Ok(ptr)
+    }
+
+    /// Drop the object at `obj` and put its slot back on the free-list.
+    ///
+    /// # Safety
+    /// `obj` must have been returned by `alloc` of this pool and must not have
+    /// been freed already.
+    pub unsafe fn free(&mut self, obj: NonNull<T>) {
+        debug_assert!(self.owns(obj), "Object doesn't belong to this pool");
+        ptr::drop_in_place(obj.as_ptr());
+
+        let mut slot = obj.cast::<FreeSlot>();
+        slot.as_mut().next = self.free;
+        self.free = Some(slot);
+        self.allocated -= 1;
+    }
+
+    /// Give all frames back to `pager`.
+    ///
+    /// Fails with `NotSupported` (and keeps the frames) while there are still
+    /// objects allocated from the pool.
+    pub fn release_frames(&mut self, pager: &mut dyn PhysicalPageProvider) -> Result<(), KError> {
+        if self.allocated > 0 {
+            return Err(KError::NotSupported);
+        }
+
+        self.free = None;
+        while let Some(frame) = self.frames.pop() {
+            if let Err(e) = pager.release_base_page(frame) {
+                // Keep what we couldn't hand back, the pool is empty either way
+                self.frames.push(frame);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Does `obj` point into one of the frames of this pool?
+    fn owns(&self, obj: NonNull<T>) -> bool {
+        let addr = obj.as_ptr() as usize;
+        self.frames.iter().any(|f| {
+            let base = f.kernel_vaddr().as_usize();
+            addr >= base && addr < base + Self::SLOTS_PER_FRAME * Self::SLOT_SIZE
+        })
+    }
+
+    /// Add another base-page worth of slots to the free-list.
+    fn grow(&mut self, pager: &mut dyn PhysicalPageProvider) -> Result<(), KError> {
+        if self.frames.is_full() {
+            return Err(KError::OutOfMemory);
+        }
+        let frame = pager.allocate_base_page()?;
+        self.frames.push(frame);
+
+        // Link in reverse so slots are handed out in ascending address order
+        let base = frame.kernel_vaddr().as_usize();
+        for idx in (0..Self::SLOTS_PER_FRAME).rev() {
+            let slot = (base + idx * Self::SLOT_SIZE) as *mut FreeSlot;
+            unsafe {
+                ptr::write(slot, FreeSlot { next: self.free });
+                self.free = Some(NonNull::new_unchecked(slot));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Default for ObjectPool<T> {
+    fn default() -> ObjectPool<T> {
+        ObjectPool::new()
+    }
+}
+
+impl<T> Drop for ObjectPool<T> {
+    fn drop(&mut self) {
+        assert!(
+            self.frames.is_empty(),
+            "ObjectPool dropped with {} frames ({} objects), call release_frames first",
+            self.frames.len(),
+            self.allocated
+        );
+    }
+}
+
+/// How many free page-table frames a `TableFramePool` holds on to.
+pub const MAX_TABLE_FRAMES: usize = 32;
+
+/// A cache of zeroed base-pages for page-tables.
+///
+/// Frames for page-tables are requested and handed back at a steady rate
+/// while address spaces are built and torn down. Keeping the freed ones
+/// around (already zeroed) avoids churning the general physical memory
+/// manager with them.
+pub struct TableFramePool {
+    /// Free frames, all of them are zeroed.
+    frames: ArrayVec<Frame, MAX_TABLE_FRAMES>,
+}
+
+impl TableFramePool {
+    /// Create an empty pool.
+    pub const fn new() -> TableFramePool {
+        TableFramePool {
+            frames: ArrayVec::new_const(),
+        }
+    }
+
+    /// Number of free frames in the pool.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Is the pool empty?
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Return a zeroed base-page for a page-table.
+    ///
+    /// Takes a frame from the pool if there is one, otherwise asks `pager`.
+    pub fn alloc_table_frame<P>(&mut self, pager: &mut P) -> Result<Frame, KError>
+    where
+        P: PhysicalPageProvider + ?Sized,
+    {
+        if let Some(frame) = self.frames.pop() {
+            return Ok(frame);
+        }
+
+        let mut frame = pager.allocate_base_page()?;
+        unsafe { frame.zero() };
+        Ok(frame)
+    }
+
+    /// Give a page-table frame back to the pool.
+    ///
+    /// The frame is zeroed before it goes back on the pool, in case the pool
+    /// is full it's released to `pager` instead.
+    pub fn free_table_frame<P>(&mut self, pager: &mut P, mut frame: Frame) -> Result<(), KError>
+    where
+        P: PhysicalPageProvider + ?Sized,
+    {
+        debug_assert_eq!(frame.size(), BASE_PAGE_SIZE);
+        if self.frames.is_full() {
+            return pager.release_base_page(frame);
+        }
+
+        unsafe { frame.zero() };
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    /// Give all free frames back to `pager`.
+    pub fn release_frames<P>(&mut self, pager: &mut P) -> Result<(), KError>
+    where
+        P: PhysicalPageProvider + ?Sized,
+    {
+        while let Some(frame) = self.frames.pop() {
+            if let Err(e) = pager.release_base_page(frame) {
+                self.frames.push(frame);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for TableFramePool {
+    fn default() -> TableFramePool {
+        TableFramePool::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::mcache::TCache;
+    use crate::memory::AllocatorStatistics;
+
+    #[derive(Debug, PartialEq)]
+    struct Object {
+        id: u64,
+        data: [u64; 7],
+    }
+
+    fn tcache_with_pages(pages: usize) -> TCache {
+        let frame = crate::arch::memory::MemoryMapper::default()
+            .allocate_frame(pages * BASE_PAGE_SIZE)
+            .expect("Can't allocate frame");
+        TCache::new_with_frame(frame.affinity, frame)
+    }
+
+    /// Slots of freed objects get handed out again.
+    #[test]
+    fn object_pool_reuses_slots() {
+        let mut tcache = tcache_with_pages(4);
+        let mut pool: ObjectPool<Object> = ObjectPool::new();
+        assert_eq!(ObjectPool::<Object>::SLOT_SIZE, 64);
+
+        // Enough objects to need two frames
+        let count = ObjectPool::<Object>::SLOTS_PER_FRAME + 1;
+        let mut objs = Vec::new();
+        for id in 0..count as u64 {
+            let obj = pool
+                .alloc(&mut tcache, Object { id, data: [id; 7] })
+                .expect("Can allocate");
+            objs.push(obj);
+        }
+        assert_eq!(pool.allocated(), count);
+        assert_eq!(pool.capacity(), 2 * ObjectPool::<Object>::SLOTS_PER_FRAME);
+        for (id, obj) in objs.iter().enumerate() {
+            let obj = unsafe { obj.as_ref() };
+            assert_eq!(obj.id, id as u64);
+            assert_eq!(obj.data, [id as u64; 7]);
+        }
+
+        let mut addresses: Vec<usize> = objs.iter().map(|o| o.as_ptr() as usize).collect();
+        for obj in objs.drain(..) {
+            unsafe { pool.free(obj) };
+        }
+        assert_eq!(pool.allocated(), 0);
+
+        // Allocating again only uses slots from the free-list
+        for id in 0..count as u64 {
+            objs.push(
+                pool.alloc(&mut tcache, Object { id, data: [0; 7] })
+                    .expect("Can allocate"),
+            );
+        }
+        assert_eq!(pool.capacity(), 2 * ObjectPool::<Object>::SLOTS_PER_FRAME);
+        let mut reused: Vec<usize> = objs.iter().map(|o| o.as_ptr() as usize).collect();
+        reused.sort_unstable();
+        addresses.sort_unstable();
+        assert_eq!(reused, addresses);
+
+        assert_eq!(pool.release_frames(&mut tcache), Err(KError::NotSupported));
+        for obj in objs.drain(..) {
+            unsafe { pool.free(obj) };
+        }
+        assert_eq!(pool.release_frames(&mut tcache), Ok(()));
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    /// Dropping a pool that still holds frames is a bug (they'd leak).
+    #[test]
+    #[should_panic(expected = "call release_frames first")]
+    fn object_pool_drop_needs_release() {
+        let mut tcache = tcache_with_pages(1);
+        let mut pool: ObjectPool<Object> = ObjectPool::new();
+        let obj = pool
+            .alloc(&mut tcache, Object { id: 1, data: [1; 7] })
+            .expect("Can allocate");
+        unsafe { pool.free(obj) };
+        drop(pool);
+    }
+
+    /// Freed table frames are handed out again (zeroed) before the pool
+    /// asks the page provider for more.
+    #[test]
+    fn table_frame_pool_reuses_frames() {
+        let mut tcache = tcache_with_pages(4);
+        let mut pool = TableFramePool::new();
+        assert!(pool.is_empty());
+
+        let mut frame = pool.alloc_table_frame(&mut tcache).expect("Can allocate");
+        assert_eq!(frame.size(), BASE_PAGE_SIZE);
+        assert!(unsafe { frame.as_slice::<u64>() }.unwrap().iter().all(|b| *b == 0));
+        assert_eq!(tcache.free_base_pages(), 3);
+
+        unsafe { frame.as_mut_slice::<u64>() }.unwrap()[7] = 0xdead_beef;
+        pool.free_table_frame(&mut tcache, frame).expect("Can free");
+        assert_eq!(pool.len(), 1);
+
+        let again = pool.alloc_table_frame(&mut tcache).expect("Can allocate");
+        assert_eq!(again, frame);
+        assert!(unsafe { again.as_slice::<u64>() }.unwrap().iter().all(|b| *b == 0));
+        assert_eq!(tcache.free_base_pages(), 3);
+        assert!(pool.is_empty());
+
+        pool.free_table_frame(&mut tcache, again).expect("Can free");
+        assert_eq!(pool.release_frames(&mut tcache), Ok(()));
+        assert!(pool.is_empty());
+        assert_eq!(tcache.free_base_pages(), 4);
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A ring-buffer that records the most recent allocator events.
+//!
+//! Used by the `KernelAllocator` (with the `alloc-trace` feature) so we can
+//! print what happened right before an OOM or a panic.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use klogger::sprintln;
+
+/// How many events we keep around.
+pub const ALLOC_TRACE_ENTRIES: usize = 256;
+
+/// What kind of event was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocEventKind {
+    Alloc = 1,
+    Dealloc = 2,
+}
+
+/// A single allocator event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocEvent {
+    pub size: usize,
+    pub align: usize,
+    /// Returned pointer for `Alloc` (0 if the allocation failed), freed
+    /// pointer for `Dealloc`.
+    pub ptr: u64,
+    pub kind: AllocEventKind,
+}
+
+/// Storage for one event.
+///
+/// `seq` is written last and holds the (1-based) position of the event in
+/// the trace, so readers can skip slots that are being overwritten.
+struct Slot {
+    seq: AtomicUsize,
+    size: AtomicUsize,
+    align: AtomicUsize,
+    ptr: AtomicU64,
+    kind: AtomicU8,
+}
+
+impl Slot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot = Slot {
+        seq: AtomicUsize::new(0),
+        size: AtomicUsize::new(0),
+        align: AtomicUsize::new(0),
+        ptr: AtomicU64::new(0),
+        kind: AtomicU8::new(0),
+    };
+}
+
+/// Lock-free ring of the last `ALLOC_TRACE_ENTRIES` allocator events.
+///
+/// Recording is a single `fetch_add` plus a few relaxed stores so it can sit
+/// on the allocation path. If the ring wraps around while it's being read,
+/// the overwritten events are skipped.
+pub struct AllocTrace {
+    next: AtomicUsize,
+    slots: [Slot; ALLOC_TRACE_ENTRIES],
+}
+
+impl AllocTrace {
+    pub const fn new() -> AllocTrace {
+        AllocTrace {
+            next: AtomicUsize::new(0),
+            slots: [Slot::EMPTY; ALLOC_TRACE_ENTRIES],
+        }
+    }
+
+    /// Add an event to the trace, overwriting the oldest one.
+    pub fn record(&self, layout: Layout, ptr: *const u8, kind: AllocEventKind) {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[idx % ALLOC_TRACE_ENTRIES];
+
+        slot.seq.store(0, Ordering::Relaxed);
+        slot.size.store(layout.size(), Ordering::Relaxed);
+        slot.align.store(layout.align(), Ordering::Relaxed);
+        slot.ptr.store(ptr as u64, Ordering::Relaxed);
+        slot.kind.store(kind as u8, Ordering::Relaxed);
+        slot.seq.store(idx + 1, Ordering::Release);
+    }
+
+    /// Calls `f` for every recorded event, from oldest to newest.
+    pub fn for_each<F: FnMut(AllocEvent)>(&self, mut f: F) {
+        let end = self.next.load(Ordering::Acquire);
+        let start = end.saturating_sub(ALLOC_TRACE_ENTRIES);
+
+        for idx in start..end {
+            let slot = &self.slots[idx % ALLOC_TRACE_ENTRIES];
+            if slot.seq.load(Ordering::Acquire) != idx + 1 {
+                // Not written yet or already overwritten
+                continue;
+            }
+
+            let kind = match slot.kind.load(Ordering::Relaxed) {
+                1 => AllocEventKind::Alloc,
+                _ => AllocEventKind::Dealloc,
+            };
+            f(AllocEvent {
+                size: slot.size.load(Ordering::Relaxed),
+                align: slot.align.load(Ordering::Relaxed),
+                ptr: slot.ptr.load(Ordering::Relaxed),
+                kind,
+            });
+        }
+    }
+
+    /// Prints the trace on the serial console.
+    ///
+    /// Doesn't allocate, so it's safe to call from the panic handler.
+    pub fn dump(&self) {
+        sprintln!("Last allocator events (oldest first):");
+        self.for_each(|e| {
+            sprintln!(
+                "  {:?} size={:#x} align={:#x} ptr={:#x}",
+                e.kind,
+                e.size,
+                e.align,
+                e.ptr
+            );
+        });
+    }
+}
+
+impl Default for AllocTrace {
+    fn default() -> AllocTrace {
+        AllocTrace::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The trace holds the most recent events in the order they happened.
+    #[test]
+    fn alloc_trace_order() {
+        let trace = AllocTrace::new();
+        let mut events = Vec::new();
+        trace.for_each(|e| events.push(e));
+        assert!(events.is_empty());
+
+        let small = Layout::from_size_align(8, 8).unwrap();
+        let big = Layout::from_size_align(4096, 4096).unwrap();
+        trace.record(small, 0x1000 as *const u8, AllocEventKind::Alloc);
+        trace.record(big, 0x2000 as *const u8, AllocEventKind::Alloc);
+        trace.record(small, 0x1000 as *const u8, AllocEventKind::Dealloc);
+        trace.record(big, core::ptr::null(), AllocEventKind::Alloc);
+
+        trace.for_each(|e| events.push(e));
+        assert_eq!(
+            events,
+            &[
+                AllocEvent {
+                    size: 8,
+                    align: 8,
+                    ptr: 0x1000,
+                    kind: AllocEventKind::Alloc
+                },
+                AllocEvent {
+                    size: 4096,
+                    align: 4096,
+                    ptr: 0x2000,
+                    kind: AllocEventKind::Alloc
+                },
+                AllocEvent {
+                    size: 8,
+                    align: 8,
+                    ptr: 0x1000,
+                    kind: AllocEventKind::Dealloc
+                },
+                AllocEvent {
+                    size: 4096,
+                    align: 4096,
+                    ptr: 0,
+                    kind: AllocEventKind::Alloc
+                },
+            ]
+        );
+
+        // Once the ring wraps around only the newest events are left
+        for i in 0..(ALLOC_TRACE_ENTRIES + 10) {
+            trace.record(small, (i * 8) as *const u8, AllocEventKind::Alloc);
+        }
+        events.clear();
+        trace.for_each(|e| events.push(e));
+        assert_eq!(events.len(), ALLOC_TRACE_ENTRIES);
+        assert_eq!(events[0].ptr, 10 * 8);
+        assert_eq!(
+            events[ALLOC_TRACE_ENTRIES - 1].ptr,
+            ((ALLOC_TRACE_ENTRIES + 9) * 8) as u64
+        );
+        assert!(events.windows(2).all(|w| w[0].ptr + 8 == w[1].ptr));
+    }
+}
@@ -3,20 +3,54 @@
 
 //! A trait defining architecture independent address spaces.
 
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cmp::PartialEq;
 use core::fmt;
+use core::ops::Range;
 
 use crate::error::KError;
 use bit_field::BitField;
+use fallible_collections::vec::FallibleVec;
 use x86::current::paging::{PDFlags, PDPTFlags, PTFlags};
 
-use super::{Frame, PAddr, VAddr};
+use super::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
+
+/// Above this many base-pages we flush the entire TLB instead of
+/// invalidating every page individually.
+pub const TLB_FLUSH_ALL_THRESHOLD: usize = 20;
+
+/// Invalidates TLB entries on the current core.
+///
+/// Implemented by the architecture, tests can plug in their own to check
+/// what would get flushed.
+pub trait TlbFlusher {
+    /// Invalidate the TLB entry (if any) of the page at `vaddr`.
+    fn flush_page(&mut self, vaddr: VAddr);
+    /// Invalidate all (non-global) TLB entries.
+    fn flush_all(&mut self);
+}
+
+/// Invalidates the TLB entries for the virtual addresses in `vregion`.
+pub fn flush_range<T: TlbFlusher>(tlb: &mut T, vregion: Range<usize>) {
+    let pages = vregion.len().saturating_add(BASE_PAGE_SIZE - 1) / BASE_PAGE_SIZE;
+    if pages > TLB_FLUSH_ALL_THRESHOLD {
+        tlb.flush_all();
+    } else {
+        for va in vregion.step_by(BASE_PAGE_SIZE) {
+            tlb.flush_page(VAddr::from(va));
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct TlbFlushHandle {
     pub vaddr: VAddr,
     pub frame: Frame,
     pub core_map: CoreBitMap,
+    /// Length of the range to flush (starting at `vaddr`), differs from
+    /// `frame.size()` after a `merge`.
+    vlen: usize,
 }
 
 impl TlbFlushHandle {
@@ -25,6 +59,7 @@ impl TlbFlushHandle {
             vaddr,
             frame,
             core_map: Default::default(),
+            vlen: frame.size(),
         }
     }
 
@@ -35,6 +70,69 @@ impl TlbFlushHandle {
     pub fn cores(&self) -> CoreBitMapIter {
         CoreBitMapIter(self.core_map)
     }
+
+    /// The virtual address range that has to be flushed.
+    pub fn vrange(&self) -> Range<usize> {
+        self.vaddr.as_usize()..self.vaddr.as_usize() + self.vlen
+    }
+
+    /// Extends the flush range (and cores) of the handle to also cover `other`.
+    ///
+    /// The range then spans from the lowest to the highest unmapped address
+    /// (including gaps in between). `frame` stays untouched, the caller has
+    /// to keep track of the frame in `other` itself.
+    pub fn merge(&mut self, other: &TlbFlushHandle) {
+        let start = core::cmp::min(self.vrange().start, other.vrange().start);
+        let end = core::cmp::max(self.vrange().end, other.vrange().end);
+        self.vaddr = VAddr::from(start);
+        self.vlen = end - start;
+        self.core_map.low |= other.core_map.low;
+        self.core_map.high |= other.core_map.high;
+    }
+
+    /// Invalidates the TLB entries of the unmapped range on the current core.
+    ///
+    /// The other cores in `core_map` are not notified, on x86-64
+    /// `tlb::shootdown` takes care of that.
+    pub fn flush(self) {
+        self.flush_with(&mut crate::arch::tlb::LocalTlb);
+    }
+
+    /// Same as `flush` but invalidates the entries through `tlb`.
+    pub fn flush_with<T: TlbFlusher>(&self, tlb: &mut T) {
+        flush_range(tlb, self.vrange());
+    }
+}
+
+/// The mappings removed by `AddressSpace::unmap_range`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnmappedRange {
+    /// One handle to flush the TLB for all removed mappings.
+    pub handle: TlbFlushHandle,
+    /// The frames that were mapped in the range.
+    pub frames: Vec<Frame>,
+}
+
+/// `AddressSpace::unmap_range` failed with `error`.
+///
+/// The mappings removed before the error are in `unmapped`, they are gone
+/// from the address space and still need a TLB flush.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnmapRangeError {
+    pub error: KError,
+    pub unmapped: Option<UnmappedRange>,
+}
+
+impl UnmapRangeError {
+    fn new(error: KError, unmapped: Option<UnmappedRange>) -> UnmapRangeError {
+        UnmapRangeError { error, unmapped }
+    }
+}
+
+impl From<KError> for UnmapRangeError {
+    fn from(error: KError) -> Self {
+        UnmapRangeError::new(error, None)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -94,10 +192,54 @@ pub enum MappingType {
     Heap,
 }
 
+/// A frame that can be mapped into multiple address spaces.
+///
+/// Every mapping of the frame holds a reference to it, so the frame can only
+/// be given back (see `declassify`) once all mappings of it are removed again.
+#[derive(Debug)]
+pub struct SharedFrame {
+    pub frame: Frame,
+    mappings: Arc<()>,
+}
+
+impl SharedFrame {
+    pub fn new(frame: Frame) -> Result<SharedFrame, KError> {
+        Ok(SharedFrame {
+            frame,
+            mappings: Arc::try_new(())?,
+        })
+    }
+
+    /// How many address space mappings currently refer to the frame.
+    pub fn mapping_count(&self) -> usize {
+        Arc::strong_count(&self.mappings) - 1
+    }
+
+    /// Returns the frame (e.g., to give it back to the allocator) in case it
+    /// is no longer mapped anywhere, otherwise hands back `self`.
+    pub fn declassify(self) -> Result<Frame, SharedFrame> {
+        if self.mapping_count() == 0 {
+            Ok(self.frame)
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Create another reference to the frame (held by a mapping).
+    fn share(&self) -> SharedFrame {
+        SharedFrame {
+            frame: self.frame,
+            mappings: self.mappings.clone(),
+        }
+    }
+}
+
 pub struct MappingInfo {
     pub frame: Frame,
     pub rights: MapAction,
     pub typ: MappingType,
+    /// Set if the mapping is for a frame shared with other address spaces.
+    pub shared: Option<SharedFrame>,
 }
 
 impl MappingInfo {
@@ -106,6 +248,18 @@ impl MappingInfo {
             frame,
             rights,
             typ: MappingType::Heap,
+            shared: None,
+        }
+    }
+
+    /// A mapping for a shared frame, the frame is considered mapped for as
+    /// long as the returned `MappingInfo` is alive.
+    pub fn new_shared(shared: &SharedFrame, rights: MapAction) -> Self {
+        MappingInfo {
+            frame: shared.frame,
+            rights,
+            typ: MappingType::Heap,
+            shared: Some(shared.share()),
         }
     }
 
@@ -121,6 +275,7 @@ impl fmt::Debug for MappingInfo {
             .field("frame", &self.frame)
             .field("rights", &self.rights)
             .field("typ", &self.typ)
+            .field("shared", &self.shared.is_some())
             .finish()
     }
 }
@@ -150,6 +305,18 @@ pub trait AddressSpace {
     /// something already mapped.
     fn map_frame(&mut self, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError>;
 
+    /// Maps the frame of `shared` at `base` in the address space
+    /// with the access rights defined by `action`.
+    ///
+    /// The frame stays referenced by `shared` until the mapping is removed
+    /// with `unmap`.
+    fn map_frame_shared(
+        &mut self,
+        base: VAddr,
+        shared: &SharedFrame,
+        action: MapAction,
+    ) -> Result<(), KError>;
+
     /// Estimates how many base-pages are needed (for page-tables)
     /// to map the given list of frames in the address space starting at `base`.
     ///
@@ -167,6 +334,14 @@ pub trait AddressSpace {
     /// and access rights or an error in case no mapping is found.
     fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError>;
 
+    /// Checks if `vaddr` is mapped in the address space.
+    ///
+    /// Implementations should override this if they can answer it more
+    /// cheaply than a full `resolve`.
+    fn is_mapped(&self, vaddr: VAddr) -> bool {
+        self.resolve(vaddr).is_ok()
+    }
+
     /// Removes the frame from the address space that contains `vaddr`.
     ///
     /// # Returns
@@ -174,10 +349,69 @@ pub trait AddressSpace {
     /// invoked to flush the TLB.
     fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError>;
 
+    /// Removes all mappings that overlap with [`base`, `base` + `size`).
+    ///
+    /// # Returns
+    /// The removed frames along with a single `TlbFlushHandle` covering all of
+    /// them, so the caller only needs one shootdown for the whole range. On
+    /// error, whatever got removed before the failure is part of the error.
+    fn unmap_range(&mut self, base: VAddr, size: usize) -> Result<UnmappedRange, UnmapRangeError> {
+        let vrange = checked_vrange(base, size)?;
+
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut handle: Option<TlbFlushHandle> = None;
+        let mut cursor = vrange.start;
+        while cursor < vrange.end {
+            if !self.is_mapped(VAddr::from(cursor)) {
+                cursor += BASE_PAGE_SIZE;
+                continue;
+            }
+
+            // Make room for the frame first, so we never lose an unmapped one
+            let unmapped = frames
+                .try_reserve(1)
+                .map_err(KError::from)
+                .and_then(|_| self.unmap(VAddr::from(cursor)));
+            let unmapped = match unmapped {
+                Ok(unmapped) => unmapped,
+                Err(e) => {
+                    let partial = handle.map(|handle| UnmappedRange { handle, frames });
+                    return Err(UnmapRangeError::new(e, partial));
+                }
+            };
+
+            cursor = unmapped.vrange().end;
+            frames.push(unmapped.frame);
+            if let Some(handle) = handle.as_mut() {
+                handle.merge(&unmapped);
+            } else {
+                handle = Some(unmapped);
+            }
+        }
+
+        handle
+            .map(|handle| UnmappedRange { handle, frames })
+            .ok_or_else(|| KError::NotMapped.into())
+    }
+
     // Returns an iterator of all currently mapped memory regions.
     //fn mappings()
 }
 
+/// Checks that [`base`, `base` + `size`) is a valid range to unmap.
+pub(crate) fn checked_vrange(base: VAddr, size: usize) -> Result<Range<usize>, KError> {
+    if !base.is_base_page_aligned() {
+        return Err(KError::InvalidBase);
+    }
+    let end = base
+        .as_usize()
+        .checked_add(size)
+        .ok_or(KError::BaseOverflow {
+            base: base.as_u64(),
+        })?;
+    Ok(base.as_usize()..end)
+}
+
 /// Mapping rights to give to address translation.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[allow(unused)]
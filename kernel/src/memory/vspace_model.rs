@@ -14,6 +14,8 @@ use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
 pub(crate) struct ModelAddressSpace {
     // Stores all mappings [VAddr, VAddr+length] -> [PAddr, PAddr+length]
     oplog: Vec<(VAddr, PAddr, usize, MapAction)>,
+    // References to shared frames held by mappings in `oplog` (by VAddr)
+    shared: Vec<(VAddr, MappingInfo)>,
 }
 
 impl ModelAddressSpace {
@@ -48,11 +50,24 @@ impl Default for ModelAddressSpace {
     fn default() -> ModelAddressSpace {
         ModelAddressSpace {
             oplog: Vec::with_capacity(512),
+            shared: Vec::new(),
         }
     }
 }
 
 impl AddressSpace for ModelAddressSpace {
+    fn map_frame_shared(
+        &mut self,
+        base: VAddr,
+        shared: &SharedFrame,
+        action: MapAction,
+    ) -> Result<(), KError> {
+        self.map_frame(base, shared.frame, action)?;
+        self.shared
+            .push((base, MappingInfo::new_shared(shared, action)));
+        Ok(())
+    }
+
     fn map_frame(&mut self, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError> {
         // Don't allow mapping of zero-sized frames
         if frame.size() == 0 {
@@ -177,6 +192,9 @@ impl AddressSpace for ModelAddressSpace {
         if element.is_some() {
             let (cur_vaddr, cur_paddr, cur_length, _cur_rights) = element.unwrap();
             assert!(found.next().is_none(), "Only found one relevant mapping");
+            drop(found);
+            // Drop the reference to a shared frame (if there is any)
+            self.shared.retain(|(vaddr, _mapping)| *vaddr != cur_vaddr);
             Ok(TlbFlushHandle::new(
                 cur_vaddr,
                 Frame::new(cur_paddr, cur_length, 0),
@@ -228,6 +246,30 @@ fn model_sanity_check() {
     assert_eq!(e, KError::NotMapped);
 }
 
+/// A shared frame can only be reclaimed after all address spaces unmapped it.
+#[test]
+fn model_shared_frame() {
+    let mut a: ModelAddressSpace = Default::default();
+    let mut b: ModelAddressSpace = Default::default();
+
+    let frame = Frame::new(PAddr::from(0xdeaf_0000u64), BASE_PAGE_SIZE, 0);
+    let shared = SharedFrame::new(frame).expect("Can't allocate shared frame");
+
+    a.map_frame_shared(VAddr::from(0x1000u64), &shared, MapAction::ReadUser)
+        .expect("Can't map frame");
+    b.map_frame_shared(VAddr::from(0x2000u64), &shared, MapAction::ReadWriteUser)
+        .expect("Can't map frame");
+    assert_eq!(shared.mapping_count(), 2);
+
+    a.unmap(VAddr::from(0x1000u64)).expect("Can't unmap");
+    assert_eq!(shared.mapping_count(), 1);
+    let shared = shared.declassify().expect_err("Frame is still mapped in b");
+
+    b.unmap(VAddr::from(0x2000u64)).expect("Can't unmap");
+    assert_eq!(shared.mapping_count(), 0);
+    assert_eq!(shared.declassify().ok(), Some(frame));
+}
+
 #[test]
 fn model_bug_already_mapped() {
     let mut a: ModelAddressSpace = Default::default();
@@ -419,3 +461,175 @@ fn tlb_flush_handle_invalid_core() {
     let gtid = core::cmp::max(MAX_CORES + 1, (u128::BITS * 2) as usize);
     t.add_core(gtid);
 }
+
+/// The handle returned by `unmap` covers the whole mapping and flushes it
+/// page by page (or entirely if it's big).
+#[test]
+fn tlb_flush_handle_flush_range() {
+    use crate::memory::LARGE_PAGE_SIZE;
+
+    #[derive(Default)]
+    struct RecordingTlb {
+        pages: Vec<VAddr>,
+        all: usize,
+    }
+
+    impl TlbFlusher for RecordingTlb {
+        fn flush_page(&mut self, vaddr: VAddr) {
+            self.pages.push(vaddr);
+        }
+
+        fn flush_all(&mut self) {
+            self.all += 1;
+        }
+    }
+
+    let mut a: ModelAddressSpace = Default::default();
+    let va = VAddr::from(0x20_0000u64);
+    let frame = Frame::new(PAddr::from(0x4000_0000u64), 2 * BASE_PAGE_SIZE, 0);
+    a.map_frame(va, frame, MapAction::ReadWriteUser)
+        .expect("Can't map frame");
+
+    let handle = a.unmap(va + BASE_PAGE_SIZE).expect("Can't unmap");
+    assert_eq!(handle.vaddr, va);
+    assert_eq!(
+        handle.vrange(),
+        va.as_usize()..va.as_usize() + 2 * BASE_PAGE_SIZE
+    );
+    let mut tlb: RecordingTlb = Default::default();
+    handle.flush_with(&mut tlb);
+    assert_eq!(tlb.pages, [va, va + BASE_PAGE_SIZE]);
+    assert_eq!(tlb.all, 0);
+    // Nothing to invalidate when running as a process
+    handle.flush();
+
+    let large = Frame::new(PAddr::from(0x4020_0000u64), LARGE_PAGE_SIZE, 0);
+    a.map_frame(va, large, MapAction::ReadWriteUser)
+        .expect("Can't map frame");
+    let handle = a.unmap(va).expect("Can't unmap");
+    assert_eq!(handle.vrange().len(), LARGE_PAGE_SIZE);
+    let mut tlb: RecordingTlb = Default::default();
+    handle.flush_with(&mut tlb);
+    assert!(tlb.pages.is_empty());
+    assert_eq!(tlb.all, 1);
+}
+
+/// `unmap_range` removes all mappings in the range with one handle.
+#[test]
+fn model_unmap_range() {
+    let mut a: ModelAddressSpace = Default::default();
+    let va = VAddr::from(0x10_0000u64);
+    for page in 0..3 {
+        let pbase = PAddr::from(0x4000_0000u64) + 2 * page * BASE_PAGE_SIZE;
+        let frame = Frame::new(pbase, BASE_PAGE_SIZE, 0);
+        a.map_frame(va + page * BASE_PAGE_SIZE, frame, MapAction::ReadUser)
+            .expect("Can't map frame");
+    }
+    let after = va + 3 * BASE_PAGE_SIZE;
+    let frame = Frame::new(PAddr::from(0x5000_0000u64), BASE_PAGE_SIZE, 0);
+    a.map_frame(after, frame, MapAction::ReadUser)
+        .expect("Can't map frame");
+
+    // Leave a gap in the middle
+    a.unmap(va + BASE_PAGE_SIZE).expect("Can't unmap");
+
+    let unmapped = a
+        .unmap_range(va, 3 * BASE_PAGE_SIZE)
+        .expect("Can't unmap range");
+    assert_eq!(
+        unmapped.handle.vrange(),
+        va.as_usize()..va.as_usize() + 3 * BASE_PAGE_SIZE
+    );
+    assert_eq!(
+        unmapped.frames,
+        [
+            Frame::new(PAddr::from(0x4000_0000u64), BASE_PAGE_SIZE, 0),
+            Frame::new(PAddr::from(0x4000_4000u64), BASE_PAGE_SIZE, 0),
+        ]
+    );
+    for page in 0..3 {
+        assert_eq!(a.resolve(va + page * BASE_PAGE_SIZE), Err(KError::NotMapped));
+    }
+    assert!(a.resolve(after).is_ok());
+}
+
+/// If `unmap` fails halfway through `unmap_range` the mappings removed so far
+/// are returned with the error.
+#[test]
+fn model_unmap_range_partial_failure() {
+    /// Fails to unmap `fail_at`, otherwise behaves like the model.
+    struct FailingUnmap {
+        model: ModelAddressSpace,
+        fail_at: VAddr,
+    }
+
+    impl AddressSpace for FailingUnmap {
+        fn map_frame(
+            &mut self,
+            base: VAddr,
+            frame: Frame,
+            action: MapAction,
+        ) -> Result<(), KError> {
+            self.model.map_frame(base, frame, action)
+        }
+
+        fn map_frame_shared(
+            &mut self,
+            base: VAddr,
+            shared: &SharedFrame,
+            action: MapAction,
+        ) -> Result<(), KError> {
+            self.model.map_frame_shared(base, shared, action)
+        }
+
+        fn map_memory_requirements(base: VAddr, frames: &[Frame]) -> usize {
+            ModelAddressSpace::map_memory_requirements(base, frames)
+        }
+
+        fn adjust(&mut self, vaddr: VAddr, rights: MapAction) -> Result<(VAddr, usize), KError> {
+            self.model.adjust(vaddr, rights)
+        }
+
+        fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError> {
+            self.model.resolve(vaddr)
+        }
+
+        fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
+            if vaddr == self.fail_at {
+                return Err(KError::OutOfMemory);
+            }
+            self.model.unmap(vaddr)
+        }
+    }
+
+    let va = VAddr::from(0x10_0000u64);
+    let mut a = FailingUnmap {
+        model: Default::default(),
+        fail_at: va + 2 * BASE_PAGE_SIZE,
+    };
+    for page in 0..3 {
+        let pbase = PAddr::from(0x4000_0000u64) + page * BASE_PAGE_SIZE;
+        let frame = Frame::new(pbase, BASE_PAGE_SIZE, 0);
+        a.map_frame(va + page * BASE_PAGE_SIZE, frame, MapAction::ReadUser)
+            .expect("Can't map frame");
+    }
+
+    let e = a
+        .unmap_range(va, 3 * BASE_PAGE_SIZE)
+        .expect_err("Unmap should fail");
+    assert_eq!(e.error, KError::OutOfMemory);
+    let unmapped = e.unmapped.expect("Lost the unmapped frames");
+    assert_eq!(
+        unmapped.handle.vrange(),
+        va.as_usize()..va.as_usize() + 2 * BASE_PAGE_SIZE
+    );
+    assert_eq!(
+        unmapped.frames,
+        [
+            Frame::new(PAddr::from(0x4000_0000u64), BASE_PAGE_SIZE, 0),
+            Frame::new(PAddr::from(0x4000_1000u64), BASE_PAGE_SIZE, 0),
+        ]
+    );
+    assert_eq!(a.resolve(va), Err(KError::NotMapped));
+    assert!(a.resolve(va + 2 * BASE_PAGE_SIZE).is_ok());
+}
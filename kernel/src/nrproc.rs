@@ -16,7 +16,7 @@ use crate::error::KError;
 use crate::memory::detmem::DA;
 use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
 use crate::memory::{Frame, PAddr, VAddr};
-use crate::process::{Eid, Executor, Pid, Process, MAX_PROCESSES};
+use crate::process::{Eid, Executor, LazySegment, Pid, Process, MAX_PROCESSES};
 
 use crate::kcb::{ArchSpecificKcb, Kcb};
 
@@ -25,6 +25,8 @@ use crate::kcb::{ArchSpecificKcb, Kcb};
 pub enum ReadOps {
     ProcessInfo,
     MemResolve(VAddr),
+    /// Find the lazily loaded ELF segment containing the address.
+    LazySegment(VAddr),
 }
 
 /// Mutable operations on the NrProcess.
@@ -48,6 +50,8 @@ pub enum Op {
     MemMapFrameId(VAddr, FrameId, MapAction),
     MemAdjust,
     MemUnmap(VAddr),
+    /// Map a (filled) frame for a page of a lazily loaded ELF segment.
+    MemMapLazy(VAddr, Frame),
 }
 
 /// Possible return values from the NrProcess.
@@ -65,6 +69,9 @@ pub enum NodeResult<E: Executor> {
     Unmapped(TlbFlushHandle),
     Resolved(PAddr, MapAction),
     FrameId(usize),
+    LazySegment(LazySegment),
+    /// Whether the frame passed with `Op::MemMapLazy` got mapped.
+    LazyMapped(bool),
 }
 
 /// Advances the replica of all the processes on the current NUMA node.
@@ -132,6 +139,44 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// Maps the page containing `fault_addr` in case it's part of a lazily
+    /// loaded ELF segment of the process.
+    pub fn handle_page_fault(pid: Pid, fault_addr: VAddr) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::get_kcb();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute(ReadOps::LazySegment(fault_addr), kcb.process_token[pid]);
+        let segment = match response {
+            Ok(NodeResult::LazySegment(segment)) => segment,
+            Err(e) => return Err(e),
+            _ => unreachable!("Got unexpected response"),
+        };
+
+        // Fill the frame before any replica can map it, replicas that apply
+        // the operation later must not overwrite what's already in use
+        crate::memory::KernelAllocator::try_refill_tcache(1, 0)?;
+        let mut frame = kcb.mem_manager().allocate_base_page()?;
+        if let Err(e) = segment.fill(fault_addr, &mut frame) {
+            kcb.mem_manager().release_base_page(frame)?;
+            return Err(e);
+        }
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut(Op::MemMapLazy(fault_addr, frame), kcb.process_token[pid]);
+        match response {
+            Ok(NodeResult::LazyMapped(true)) => Ok(()),
+            Ok(NodeResult::LazyMapped(false)) => kcb.mem_manager().release_base_page(frame),
+            Err(e) => {
+                kcb.mem_manager().release_base_page(frame)?;
+                Err(e)
+            }
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn synchronize(pid: Pid) {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
         let kcb = super::kcb::get_kcb();
@@ -313,6 +358,14 @@ where
                 let (paddr, rights) = self.process.vspace().resolve(base)?;
                 Ok(NodeResult::Resolved(paddr, rights))
             }
+            ReadOps::LazySegment(vaddr) => {
+                let segment = self
+                    .process
+                    .lazy_segments()
+                    .and_then(|lazy| lazy.segment(vaddr))
+                    .ok_or(KError::NotMapped)?;
+                Ok(NodeResult::LazySegment(segment.clone()))
+            }
         }
     }
 
@@ -364,6 +417,18 @@ where
                 Ok(NodeResult::Unmapped(shootdown_handle))
             }
 
+            Op::MemMapLazy(vaddr, frame) => {
+                let segment = self
+                    .process
+                    .lazy_segments()
+                    .and_then(|lazy| lazy.segment(vaddr))
+                    .cloned()
+                    .ok_or(KError::NotMapped)?;
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
+                let mapped = segment.map(self.process.vspace_mut(), vaddr, frame)?;
+                Ok(NodeResult::LazyMapped(mapped))
+            }
+
             Op::AssignExecutor(gtid, region) => {
                 let executor = self.process.get_executor(region)?;
                 let eid = executor.id();
@@ -205,6 +205,9 @@ pub fn panic_impl(info: &PanicInfo) -> ! {
         sprintln!("");
     }
 
+    #[cfg(feature = "alloc-trace")]
+    crate::memory::dump_alloc_trace();
+
     // We need memory allocation for a backtrace, can't do that without a KCB
     kcb::try_get_kcb().map(|k| {
         // If we're already panicking, it usually doesn't help to panic more
@@ -279,6 +282,8 @@ pub fn oom(layout: Layout) -> ! {
         layout.size(),
         layout.align()
     );
+    #[cfg(feature = "alloc-trace")]
+    crate::memory::dump_alloc_trace();
     backtrace_no_context();
 
     // Not worth initiating a backtrace as it would require memory.
@@ -14,20 +14,45 @@ use cstr_core::CStr;
 use fallible_collections::vec::FallibleVecGlobal;
 use fallible_collections::vec::TryCollect;
 use fallible_collections::TryReserveError;
+use hashbrown::HashMap;
 use kpi::process::{FrameId, ELF_OFFSET};
 use log::{debug, info, trace};
 
 use crate::arch::memory::{paddr_to_kernel_vaddr, LARGE_PAGE_SIZE};
-use crate::arch::process::UserPtr;
+use crate::arch::process::{UserPtr, UserSlice};
 use crate::arch::{Module, MAX_CORES, MAX_NUMA_NODES};
 use crate::error::KError;
 use crate::fallible_string::TryString;
 use crate::fs::Fd;
-use crate::memory::vspace::AddressSpace;
-use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr};
+use crate::memory::vspace::{AddressSpace, MapAction, SharedFrame};
+use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE};
 use crate::prelude::overlaps;
 use crate::{cnrfs, kcb, nr, nrproc, round_up};
 
+impl<'a> UserSlice<'a> {
+    /// Copies as much of `src` as fits into the slice (starting at the
+    /// beginning of the slice).
+    ///
+    /// # Returns
+    /// The number of bytes copied, i.e., `min(self.len(), src.len())`.
+    pub fn copy_from(&mut self, src: &[u8]) -> Result<usize, KError> {
+        self.copy_from_at(0, src)
+    }
+
+    /// Copies as much of `src` as fits into the slice starting at byte
+    /// `offset` of the slice.
+    ///
+    /// # Returns
+    /// The number of bytes copied, or `InvalidOffset` if `offset` is past
+    /// the end of the slice.
+    pub fn copy_from_at(&mut self, offset: usize, src: &[u8]) -> Result<usize, KError> {
+        let dst = self.get_mut(offset..).ok_or(KError::InvalidOffset)?;
+        let len = core::cmp::min(dst.len(), src.len());
+        dst[..len].copy_from_slice(&src[..len]);
+        Ok(len)
+    }
+}
+
 /// How many (concurrent) processes the systems supports.
 pub const MAX_PROCESSES: usize = 12;
 
@@ -37,6 +62,9 @@ pub const MAX_FRAMES_PER_PROCESS: usize = MAX_CORES;
 /// How many writable sections a process can have (part of the ELF file).
 pub const MAX_WRITEABLE_SECTIONS_PER_PROCESS: usize = 4;
 
+/// How many lazily loaded segments a process can have (part of the ELF file).
+pub const MAX_LAZY_SEGMENTS_PER_PROCESS: usize = 8;
+
 /// This struct is used to copy the user buffer into kernel space, so that the
 /// user-application doesn't have any reference to any log operation in kernel space.
 #[derive(PartialEq, Clone, Debug)]
@@ -118,6 +146,11 @@ pub trait Process {
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, KError>;
     fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, KError>;
     fn deallocate_frame(&mut self, fid: FrameId) -> Result<Frame, KError>;
+
+    /// ELF segments of the process that get mapped on first access (if any).
+    fn lazy_segments(&self) -> Option<&LazySegments> {
+        None
+    }
 }
 
 /// ResumeHandle is the HW specific logic that switches the CPU
@@ -146,6 +179,273 @@ pub trait Executor {
     fn vcpu_kernel(&self) -> *mut kpi::arch::VirtualCpu;
 }
 
+/// How the read-only segments of an ELF binary get loaded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ElfLoading {
+    /// Map and copy all segments while the process is created.
+    Eager,
+    /// Map every page of a read-only segment on its first access (see
+    /// `LazySegments`).
+    Lazy,
+    /// Map read-only segments with frames shared by all processes running
+    /// the same binary (see `SharedSegments`).
+    Shared,
+}
+
+impl Default for ElfLoading {
+    fn default() -> Self {
+        ElfLoading::Eager
+    }
+}
+
+/// Parses the strategy from the kernel command-line
+/// (`elfload=eager|lazy|shared`).
+impl core::str::FromStr for ElfLoading {
+    type Err = ();
+
+    fn from_str(loading: &str) -> Result<ElfLoading, ()> {
+        match loading {
+            "eager" => Ok(ElfLoading::Eager),
+            "lazy" => Ok(ElfLoading::Lazy),
+            "shared" => Ok(ElfLoading::Shared),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An ELF segment that is mapped page by page on first access.
+#[derive(Debug, Clone)]
+pub struct LazySegment {
+    /// Page-aligned virtual address range of the segment.
+    pub vrange: core::ops::Range<usize>,
+    /// Access rights for the pages of the segment.
+    pub rights: MapAction,
+    /// Virtual address where `content` starts.
+    pub content_base: usize,
+    /// Bytes from the ELF file, the rest of the segment is zero.
+    pub content: &'static [u8],
+}
+
+impl LazySegment {
+    /// Zeroes the base-page `frame` and copies the part of the segment content
+    /// that belongs to the page containing `vaddr` into it.
+    pub fn fill(&self, vaddr: VAddr, frame: &mut Frame) -> Result<(), KError> {
+        if frame.size() != BASE_PAGE_SIZE {
+            return Err(KError::InvalidFrame);
+        }
+        if !self.vrange.contains(&vaddr.as_usize()) {
+            return Err(KError::NotMapped);
+        }
+
+        unsafe { frame.zero() };
+        let page = vaddr.as_usize() & !(BASE_PAGE_SIZE - 1);
+        let content = self.content_base..self.content_base + self.content.len();
+        let start = core::cmp::max(page, content.start);
+        let end = core::cmp::min(page + BASE_PAGE_SIZE, content.end);
+        if start < end {
+            let src = &self.content[start - content.start..end - content.start];
+            let dst: *mut u8 = (frame.kernel_vaddr() + (start - page)).as_mut_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+        }
+        Ok(())
+    }
+
+    /// Maps the (already filled) base-page `frame` for the page containing
+    /// `vaddr` in `vspace`.
+    ///
+    /// # Returns
+    /// Whether `frame` got used, it doesn't if the page is mapped already.
+    pub fn map<A: AddressSpace>(
+        &self,
+        vspace: &mut A,
+        vaddr: VAddr,
+        frame: Frame,
+    ) -> Result<bool, KError> {
+        if !self.vrange.contains(&vaddr.as_usize()) {
+            return Err(KError::NotMapped);
+        }
+        let page = VAddr::from(vaddr.as_usize() & !(BASE_PAGE_SIZE - 1));
+        if vspace.resolve(page).is_ok() {
+            // Someone else was faster
+            return Ok(false);
+        }
+
+        vspace.map_frame(page, frame, self.rights)?;
+        Ok(true)
+    }
+}
+
+/// Segments of a process that are loaded on demand (instead of being
+/// mapped and copied when the process is created).
+#[derive(Debug, Default)]
+pub struct LazySegments {
+    segments: ArrayVec<LazySegment, MAX_LAZY_SEGMENTS_PER_PROCESS>,
+}
+
+impl LazySegments {
+    /// Registers the (page-aligned) range `vrange` without mapping anything.
+    pub fn add(
+        &mut self,
+        vrange: core::ops::Range<usize>,
+        rights: MapAction,
+    ) -> Result<(), KError> {
+        if vrange.start % BASE_PAGE_SIZE != 0 || vrange.end % BASE_PAGE_SIZE != 0 {
+            return Err(KError::InvalidBase);
+        }
+        if self.segments.iter().any(|s| overlaps(&s.vrange, &vrange)) {
+            return Err(KError::AlreadyMapped {
+                base: VAddr::from(vrange.start),
+            });
+        }
+
+        self.segments
+            .try_push(LazySegment {
+                content_base: vrange.start,
+                vrange,
+                rights,
+                content: &[],
+            })
+            .map_err(|_e| KError::TooManyRegisteredFrames)
+    }
+
+    /// Sets the bytes that get copied into the segment starting at `base`.
+    pub fn set_content(&mut self, base: usize, content: &'static [u8]) -> Result<(), KError> {
+        let segment = self
+            .segments
+            .iter_mut()
+            .find(|s| s.vrange.contains(&base))
+            .ok_or(KError::NotMapped)?;
+        if base + content.len() > segment.vrange.end {
+            return Err(KError::InvalidLength);
+        }
+
+        segment.content_base = base;
+        segment.content = content;
+        Ok(())
+    }
+
+    /// The lazily loaded segment that contains `vaddr`.
+    pub fn segment(&self, vaddr: VAddr) -> Option<&LazySegment> {
+        self.segments
+            .iter()
+            .find(|s| s.vrange.contains(&vaddr.as_usize()))
+    }
+
+    /// Is `vaddr` part of a lazily loaded segment?
+    pub fn contains(&self, vaddr: VAddr) -> bool {
+        self.segment(vaddr).is_some()
+    }
+
+    /// Maps the page containing `fault_addr` in `vspace` using the base-page
+    /// `frame` and copies the segment content for that page into it.
+    ///
+    /// Doesn't touch `frame` if the page is already mapped.
+    ///
+    /// # Returns
+    /// Whether `frame` got used for the mapping.
+    pub fn handle_page_fault<A: AddressSpace>(
+        &self,
+        vspace: &mut A,
+        fault_addr: VAddr,
+        mut frame: Frame,
+    ) -> Result<bool, KError> {
+        let segment = self.segment(fault_addr).ok_or(KError::NotMapped)?;
+        if frame.size() != BASE_PAGE_SIZE {
+            return Err(KError::InvalidFrame);
+        }
+
+        let page = VAddr::from(fault_addr.as_usize() & !(BASE_PAGE_SIZE - 1));
+        if vspace.resolve(page).is_ok() {
+            // Someone else was faster
+            return Ok(false);
+        }
+
+        segment.fill(fault_addr, &mut frame)?;
+        segment.map(vspace, fault_addr, frame)
+    }
+}
+
+/// Identifies a page of a read-only ELF segment that can be shared between
+/// processes.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct SegmentKey {
+    /// Hash of the ELF binary (see `binary_hash`).
+    pub binary: u64,
+    /// NUMA node of the frame, every node has its own copy (like the
+    /// process replicas).
+    pub node: atopology::NodeId,
+    /// Where the page is mapped in the process.
+    pub vaddr: VAddr,
+}
+
+/// Frames of read-only ELF segments shared by all processes that run the
+/// same binary, so N instances of a program only need one copy of `.text`.
+#[derive(Default)]
+pub struct SharedSegments {
+    frames: HashMap<SegmentKey, SharedFrame>,
+}
+
+impl SharedSegments {
+    /// Maps the page `key` in `vspace`, with a frame from an earlier process
+    /// that ran the same binary or (if there is none) a new frame from
+    /// `alloc`.
+    ///
+    /// A new frame isn't visible to other processes until it's filled and
+    /// handed to `publish`.
+    ///
+    /// # Returns
+    /// The new frame, or `None` if an already filled frame got mapped.
+    pub fn map<A: AddressSpace>(
+        &self,
+        vspace: &mut A,
+        key: SegmentKey,
+        rights: MapAction,
+        alloc: impl FnOnce() -> Result<Frame, KError>,
+    ) -> Result<Option<SharedFrame>, KError> {
+        if let Some(shared) = self.frames.get(&key) {
+            vspace.map_frame_shared(key.vaddr, shared, rights)?;
+            return Ok(None);
+        }
+
+        let shared = SharedFrame::new(alloc()?)?;
+        vspace.map_frame_shared(key.vaddr, &shared, rights)?;
+        Ok(Some(shared))
+    }
+
+    /// Lets later processes map the (filled) frame `shared` for `key`.
+    ///
+    /// Keeps the frame that is already there in case another process
+    /// published one for `key` in the meantime.
+    pub fn publish(&mut self, key: SegmentKey, shared: SharedFrame) -> Result<(), KError> {
+        if self.frames.contains_key(&key) {
+            return Ok(());
+        }
+        self.frames.try_reserve(1)?;
+        self.frames.insert(key, shared);
+        Ok(())
+    }
+
+    /// How many frames are cached.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Are there no frames in the cache?
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+/// Computes the key (64-bit FNV-1a) that `SharedSegments` uses for `binary`.
+pub fn binary_hash(binary: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    binary.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
 /// An elfloader implementation that only loads the writeable sections of the program.
 struct DataSecAllocator {
     offset: VAddr,
@@ -420,8 +720,10 @@ pub fn allocate_dispatchers<P: Process>(pid: Pid) -> Result<(), KError> {
             KernelAllocator::try_refill_tcache(20, 1)?;
             let mut frame = {
                 let kcb = crate::kcb::get_kcb();
-                kcb.physical_memory.gmanager.unwrap().node_caches[affinity as usize]
-                    .lock()
+                kcb.physical_memory
+                    .gmanager
+                    .unwrap()
+                    .lock_node(affinity as usize)
                     .allocate_large_page()?
             };
 
@@ -441,3 +743,133 @@ pub fn allocate_dispatchers<P: Process>(pid: Pid) -> Result<(), KError> {
     debug!("Allocated dispatchers");
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::vspace_model::ModelAddressSpace;
+    use crate::memory::PAddr;
+
+    /// Only the page that faulted gets mapped and filled.
+    #[test]
+    fn lazy_segment_fault() {
+        static CONTENT: [u8; 2 * BASE_PAGE_SIZE] = [0xab; 2 * BASE_PAGE_SIZE];
+        let base = 0x40_0000;
+        let mut vspace: ModelAddressSpace = Default::default();
+        let mut lazy: LazySegments = Default::default();
+
+        lazy.add(base..base + 4 * BASE_PAGE_SIZE, MapAction::ReadUser)
+            .expect("Can't add segment");
+        // Content starts in the middle of the first page
+        let content_base = base + BASE_PAGE_SIZE / 2;
+        lazy.set_content(content_base, &CONTENT)
+            .expect("Can't set content");
+        for page in 0..4 {
+            assert!(vspace
+                .resolve(VAddr::from(base + page * BASE_PAGE_SIZE))
+                .is_err());
+        }
+
+        let frame = crate::arch::memory::MemoryMapper::default()
+            .allocate_frame(BASE_PAGE_SIZE)
+            .expect("Can't allocate frame");
+        let fault_addr = VAddr::from(base + BASE_PAGE_SIZE + 0x10);
+        assert_eq!(
+            lazy.handle_page_fault(&mut vspace, fault_addr, frame),
+            Ok(true)
+        );
+
+        // Only the faulting page is mapped
+        assert_eq!(
+            vspace.resolve(VAddr::from(base + BASE_PAGE_SIZE)),
+            Ok((frame.base, MapAction::ReadUser))
+        );
+        for page in [0, 2, 3].iter() {
+            assert!(vspace
+                .resolve(VAddr::from(base + page * BASE_PAGE_SIZE))
+                .is_err());
+        }
+        let copied: &[u8] =
+            unsafe { core::slice::from_raw_parts(frame.kernel_vaddr().as_ptr(), BASE_PAGE_SIZE) };
+        assert!(copied.iter().all(|b| *b == 0xab));
+
+        // A second fault on the same page doesn't need the frame
+        assert_eq!(
+            lazy.handle_page_fault(&mut vspace, fault_addr, frame),
+            Ok(false)
+        );
+        // The last page is past the content and stays zero
+        let zero_frame = crate::arch::memory::MemoryMapper::default()
+            .allocate_frame(BASE_PAGE_SIZE)
+            .expect("Can't allocate frame");
+        let fault_addr = VAddr::from(base + 3 * BASE_PAGE_SIZE);
+        assert_eq!(
+            lazy.handle_page_fault(&mut vspace, fault_addr, zero_frame),
+            Ok(true)
+        );
+        let copied: &[u8] = unsafe {
+            core::slice::from_raw_parts(zero_frame.kernel_vaddr().as_ptr(), BASE_PAGE_SIZE)
+        };
+        assert!(copied.iter().all(|b| *b == 0));
+
+        assert_eq!(
+            lazy.handle_page_fault(&mut vspace, VAddr::from(base - 1), zero_frame),
+            Err(KError::NotMapped)
+        );
+    }
+
+    /// Loading the same read-only segment twice maps the same frame.
+    #[test]
+    fn shared_segment_same_frame() {
+        let mut segments: SharedSegments = Default::default();
+        let mut a: ModelAddressSpace = Default::default();
+        let mut b: ModelAddressSpace = Default::default();
+        let vaddr = VAddr::from(ELF_OFFSET);
+        let key = SegmentKey {
+            binary: binary_hash(b"init"),
+            node: 0,
+            vaddr,
+        };
+        let frame = Frame::new(PAddr::from(0x20_0000u64), LARGE_PAGE_SIZE, 0);
+
+        let rights = MapAction::ReadExecuteUser;
+        let fresh = segments
+            .map(&mut a, key, rights, || Ok(frame))
+            .expect("Can't map segment")
+            .expect("Frame should be new");
+        assert_eq!(fresh.frame, frame);
+        // Not filled yet, so nobody else gets it
+        assert!(segments.is_empty());
+        let unfilled = Frame::new(PAddr::from(0x60_0000u64), LARGE_PAGE_SIZE, 0);
+        let mut early: ModelAddressSpace = Default::default();
+        let private = segments
+            .map(&mut early, key, rights, || Ok(unfilled))
+            .expect("Can't map segment")
+            .expect("Frame should be new");
+        assert_eq!(early.resolve(vaddr), Ok((unfilled.base, rights)));
+
+        segments.publish(key, fresh).expect("Can't publish");
+        // The first one wins
+        segments.publish(key, private).expect("Can't publish");
+        assert_eq!(segments.len(), 1);
+        let reused = segments
+            .map(&mut b, key, rights, || panic!("Frame wasn't reused"))
+            .expect("Can't map segment");
+        assert!(reused.is_none());
+        assert_eq!(a.resolve(vaddr), Ok((frame.base, rights)));
+        assert_eq!(b.resolve(vaddr), a.resolve(vaddr));
+
+        // A different binary gets its own frame
+        let other = Frame::new(PAddr::from(0x40_0000u64), LARGE_PAGE_SIZE, 0);
+        let key = SegmentKey {
+            binary: binary_hash(b"other"),
+            ..key
+        };
+        let mut c: ModelAddressSpace = Default::default();
+        let fresh = segments
+            .map(&mut c, key, rights, || Ok(other))
+            .expect("Can't map segment");
+        assert!(fresh.is_some());
+        assert_eq!(c.resolve(vaddr), Ok((other.base, rights)));
+    }
+}
@@ -879,6 +879,24 @@ fn s01_alloc() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Measures allocating and freeing objects from an `ObjectPool` against the
+/// global allocator.
+#[test]
+fn s01_objectpool() {
+    let cmdline = RunnerArgs::new("test-objectpool").release();
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("ObjectPool alloc+free:")?.as_str();
+        output += p.exp_string("Global allocator alloc+free:")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 /// Test that makes use of SSE in kernel-space and see if it works.AsMut
 ///
 /// Tests that we have correctly set-up the hardware to deal with floating
@@ -1988,6 +2006,29 @@ fn s06_test_fs() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Tests that system calls can use pages of a lazily loaded ELF segment
+/// that the process never touched.
+#[test]
+fn s06_lazy_elf() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .module("init")
+        .user_feature("test-lazy-elf")
+        .cmd("elfload=lazy")
+        .release()
+        .timeout(20_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+
+        p.exp_string("lazy_elf_test OK")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 /// Property tests for file-system support.
 ///
 /// This tests various file-system systemcalls such as:
@@ -1,13 +1,105 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use core::convert::TryFrom;
+
 use bitflags::*;
 
 /// Struct used in `file_getinfo` systemcall.
 #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct FileInfo {
     pub ftype: u64,
+    /// Size in bytes, or the number of entries for a directory.
+    pub fsize: u64,
+}
+
+/// A user-space buffer for vectored reads and writes (`readv`/`writev`).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(C)]
+pub struct IoVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+/// Access pattern hints for `Fs::fadvise` (like `posix_fadvise`).
+///
+/// They only decide whether the kernel reads ahead of sequential reads on
+/// a file descriptor.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+#[repr(u64)]
+pub enum FileAdvice {
+    /// Read ahead once the reads on the file descriptor turn out to be
+    /// sequential (the default).
+    Normal = 0,
+    /// Always read ahead.
+    Sequential = 1,
+    /// Never read ahead.
+    Random = 2,
+}
+
+impl Default for FileAdvice {
+    fn default() -> FileAdvice {
+        FileAdvice::Normal
+    }
+}
+
+impl TryFrom<u64> for FileAdvice {
+    type Error = ();
+
+    fn try_from(advice: u64) -> Result<FileAdvice, ()> {
+        match advice {
+            0 => Ok(FileAdvice::Normal),
+            1 => Ok(FileAdvice::Sequential),
+            2 => Ok(FileAdvice::Random),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Operations of `Fs::flock` (like `flock(2)` with `LOCK_NB`).
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
+#[repr(u64)]
+pub enum LockOp {
+    /// Take a shared lock, any number of owners can hold one at a time.
+    Shared = 1,
+    /// Take an exclusive lock, nobody else can hold a lock at the same time.
+    Exclusive = 2,
+    /// Release the lock.
+    Unlock = 3,
+}
+
+impl TryFrom<u64> for LockOp {
+    type Error = ();
+
+    fn try_from(op: u64) -> Result<LockOp, ()> {
+        match op {
+            1 => Ok(LockOp::Shared),
+            2 => Ok(LockOp::Exclusive),
+            3 => Ok(LockOp::Unlock),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Full metadata of a file, `FileInfo` is a subset of it.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct FileStat {
+    pub ftype: u64,
+    /// Size in bytes, or the number of entries for a directory.
     pub fsize: u64,
+    /// Number of names referring to the file.
+    pub nlink: u64,
+    /// The `FileModes` bits of the file.
+    pub mode: u64,
+}
+
+impl From<FileStat> for FileInfo {
+    fn from(stat: FileStat) -> FileInfo {
+        FileInfo {
+            ftype: stat.ftype,
+            fsize: stat.fsize,
+        }
+    }
 }
 
 /// Each file-node can be of two types: directory or a file.
@@ -39,6 +131,8 @@ bitflags! {
         const O_CREAT = 0x0200; /* create if nonexistant */
         const O_TRUNC = 0x0400; /* truncate to zero length */
         const O_APPEND = 0x02000; /* append at the EOF */
+        const O_DIRECTORY = 0x200000; /* fail if not a directory */
+        const O_TMPFILE = 0x400000; /* create an unnamed file in the given directory */
     }
 }
 
@@ -85,6 +179,41 @@ impl FileFlags {
     pub fn is_append(&self) -> bool {
         (*self & FileFlags::O_APPEND) == FileFlags::O_APPEND
     }
+
+    pub fn is_directory(&self) -> bool {
+        (*self & FileFlags::O_DIRECTORY) == FileFlags::O_DIRECTORY
+    }
+
+    pub fn is_tmpfile(&self) -> bool {
+        (*self & FileFlags::O_TMPFILE) == FileFlags::O_TMPFILE
+    }
+}
+
+bitflags! {
+    /// Flags for `Fs::rename2` (like `renameat2`).
+    pub struct RenameFlags: u64 {
+        const RENAME_NOREPLACE = 0x1; /* fail if the destination exists */
+    }
+}
+
+/// Convert u64 to RenameFlags.
+impl From<u64> for RenameFlags {
+    fn from(flags: u64) -> RenameFlags {
+        RenameFlags::from_bits_truncate(flags)
+    }
+}
+
+/// Convert RenameFlags to u64.
+impl From<RenameFlags> for u64 {
+    fn from(flags: RenameFlags) -> u64 {
+        flags.bits()
+    }
+}
+
+impl RenameFlags {
+    pub fn is_noreplace(&self) -> bool {
+        self.contains(RenameFlags::RENAME_NOREPLACE)
+    }
 }
 
 bitflags! {
@@ -54,6 +54,22 @@ pub enum SystemCallError {
     PermissionError = 9,
     /// Bad offset
     OffsetError = 10,
+    /// The file doesn't exist (or is otherwise invalid).
+    InvalidFile = 11,
+    /// The file or directory already exists.
+    AlreadyPresent = 12,
+    /// Tried to read or write a directory.
+    DirectoryError = 13,
+    /// The path doesn't refer to a directory.
+    NotDirectory = 14,
+    /// The directory still has entries in it.
+    DirectoryNotEmpty = 15,
+    /// The process has too many open files.
+    OpenFileLimit = 16,
+    /// The file would grow past the maximum file size.
+    FileTooBig = 17,
+    /// The file is locked by someone else.
+    WouldBlock = 18,
     /// Placeholder for an invalid, unknown error code.
     Unknown,
 }
@@ -72,6 +88,14 @@ impl From<u64> for SystemCallError {
             8 => SystemCallError::BadFlags,
             9 => SystemCallError::PermissionError,
             10 => SystemCallError::OffsetError,
+            11 => SystemCallError::InvalidFile,
+            12 => SystemCallError::AlreadyPresent,
+            13 => SystemCallError::DirectoryError,
+            14 => SystemCallError::NotDirectory,
+            15 => SystemCallError::DirectoryNotEmpty,
+            16 => SystemCallError::OpenFileLimit,
+            17 => SystemCallError::FileTooBig,
+            18 => SystemCallError::WouldBlock,
             _ => SystemCallError::Unknown,
         }
     }
@@ -208,6 +232,38 @@ pub enum FileOperation {
     FileRename = 11,
     /// Create a directory.
     MkDir = 12,
+    /// Remove an empty directory.
+    RmDir = 13,
+    /// Open a file by its mnode number.
+    OpenByMnode = 14,
+    /// Mount a new (empty) filesystem at a path.
+    Mount = 15,
+    /// Unmount the filesystem mounted at a path.
+    Unmount = 16,
+    /// Find the next data region in a file.
+    SeekData = 17,
+    /// Find the next hole in a file.
+    SeekHole = 18,
+    /// Change the current working directory.
+    ChDir = 19,
+    /// Get the current working directory.
+    GetCwd = 20,
+    /// Open a file, creating it with the given size if it doesn't exist.
+    OpenWithSize = 21,
+    /// Write multiple buffers to a file in one operation.
+    WriteV = 22,
+    /// Read from a file into multiple buffers in one operation.
+    ReadV = 23,
+    /// Give a hint about how a file descriptor is going to be read.
+    FAdvise = 24,
+    /// Flush all file-systems (like `sync`).
+    SyncAll = 25,
+    /// Change the modes of a file.
+    Chmod = 26,
+    /// Take or release an advisory lock on a file.
+    FLock = 27,
+    /// Write to a file if it holds some expected data at an offset.
+    CompareAndWrite = 28,
     Unknown,
 }
 
@@ -227,6 +283,22 @@ impl From<u64> for FileOperation {
             10 => FileOperation::WriteDirect,
             11 => FileOperation::FileRename,
             12 => FileOperation::MkDir,
+            13 => FileOperation::RmDir,
+            14 => FileOperation::OpenByMnode,
+            15 => FileOperation::Mount,
+            16 => FileOperation::Unmount,
+            17 => FileOperation::SeekData,
+            18 => FileOperation::SeekHole,
+            19 => FileOperation::ChDir,
+            20 => FileOperation::GetCwd,
+            21 => FileOperation::OpenWithSize,
+            22 => FileOperation::WriteV,
+            23 => FileOperation::ReadV,
+            24 => FileOperation::FAdvise,
+            25 => FileOperation::SyncAll,
+            26 => FileOperation::Chmod,
+            27 => FileOperation::FLock,
+            28 => FileOperation::CompareAndWrite,
             _ => FileOperation::Unknown,
         }
     }
@@ -248,6 +320,22 @@ impl From<&str> for FileOperation {
             "WriteDirect" => FileOperation::WriteDirect,
             "Rename" => FileOperation::FileRename,
             "MkDir" => FileOperation::MkDir,
+            "RmDir" => FileOperation::RmDir,
+            "OpenByMnode" => FileOperation::OpenByMnode,
+            "Mount" => FileOperation::Mount,
+            "Unmount" => FileOperation::Unmount,
+            "SeekData" => FileOperation::SeekData,
+            "SeekHole" => FileOperation::SeekHole,
+            "ChDir" => FileOperation::ChDir,
+            "GetCwd" => FileOperation::GetCwd,
+            "OpenWithSize" => FileOperation::OpenWithSize,
+            "WriteV" => FileOperation::WriteV,
+            "ReadV" => FileOperation::ReadV,
+            "FAdvise" => FileOperation::FAdvise,
+            "SyncAll" => FileOperation::SyncAll,
+            "Chmod" => FileOperation::Chmod,
+            "FLock" => FileOperation::FLock,
+            "CompareAndWrite" => FileOperation::CompareAndWrite,
             _ => FileOperation::Unknown,
         }
     }
@@ -3,6 +3,8 @@
 
 //! Abstraction for system calls to access the global file-system and control interrupts.
 
+use alloc::vec::Vec;
+
 use crate::io::*;
 use crate::*;
 
@@ -66,6 +68,151 @@ impl Fs {
         }
     }
 
+    /// Open a file like `open`, if the file gets created (with `O_CREAT`) it
+    /// starts out `size` bytes long (reading as zeroes).
+    ///
+    /// The memory for the file is allocated up front; if that fails, the
+    /// open fails and no file is created. The size is ignored for files that
+    /// exist already.
+    pub fn open_with_size(
+        pathname: u64,
+        flags: u64,
+        modes: u64,
+        size: u64,
+    ) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::OpenWithSize as u64,
+                pathname,
+                flags,
+                modes,
+                size,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Open a file like `open`, additionally returns whether the file was
+    /// created by this call (with `O_CREAT`) or existed already.
+    pub fn open_ex(pathname: u64, flags: u64, modes: u64) -> Result<(u64, bool), SystemCallError> {
+        let (r, fd, created) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::Open as u64,
+                pathname,
+                flags,
+                modes,
+                3
+            )
+        };
+
+        if r == 0 {
+            Ok((fd, created != 0))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Open a file by its mnode number, skipping the path lookup.
+    pub fn open_by_mnode(mnode: u64, flags: u64) -> Result<u64, SystemCallError> {
+        let (r, fd) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::OpenByMnode as u64,
+                mnode,
+                flags,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(fd)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the first offset >= `offset` in the file that holds data.
+    ///
+    /// Fails if `offset` is past the end of the file or only holes follow it.
+    pub fn seek_data(fd: u64, offset: u64) -> Result<u64, SystemCallError> {
+        Fs::seek(FileOperation::SeekData, fd, offset)
+    }
+
+    /// Returns the first offset >= `offset` in the file that is in a hole
+    /// (the end of the file counts as a hole).
+    pub fn seek_hole(fd: u64, offset: u64) -> Result<u64, SystemCallError> {
+        Fs::seek(FileOperation::SeekHole, fd, offset)
+    }
+
+    fn seek(op: FileOperation, fd: u64, offset: u64) -> Result<u64, SystemCallError> {
+        let (r, found) = unsafe { syscall!(SystemCall::FileIO as u64, op as u64, fd, offset, 2) };
+
+        if r == 0 {
+            Ok(found)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Tells the kernel how `fd` is going to be read, see `FileAdvice`.
+    pub fn fadvise(fd: u64, advice: FileAdvice) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::FAdvise,
+                fd,
+                advice as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Takes or releases an advisory lock on the file behind `fd`.
+    ///
+    /// Fails with `WouldBlock` if another process holds a conflicting lock.
+    pub fn flock(fd: u64, op: LockOp) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::FLock,
+                fd,
+                op as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Flush all file-systems (like `sync`).
+    pub fn sync_all() -> Result<(), SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::SyncAll, 1) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Close a file. This function will remove the file descriptor from the process.
     /// It doesn't do anything to the file.
     pub fn close(fd: u64) -> Result<u64, SystemCallError> {
@@ -150,6 +297,103 @@ impl Fs {
         }
     }
 
+    /// Write all buffers in `iovecs` to the file, back to back, starting at
+    /// `offset` (or at the current position of `fd` if `offset` is -1).
+    ///
+    /// All buffers are written in one operation, a concurrent reader never
+    /// sees only some of them. Returns the total number of bytes written.
+    pub fn writev(fd: u64, iovecs: &[&[u8]], offset: i64) -> Result<u64, SystemCallError> {
+        let iovecs: Vec<IoVec> = iovecs
+            .iter()
+            .map(|buf| IoVec {
+                base: buf.as_ptr() as u64,
+                len: buf.len() as u64,
+            })
+            .collect();
+        Fs::fileio_v(FileOperation::WriteV, fd, &iovecs, offset)
+    }
+
+    /// Read from the file into the buffers in `iovecs`, filling one after
+    /// the other, starting at `offset` (or at the current position of `fd`
+    /// if `offset` is -1).
+    ///
+    /// Returns the total number of bytes read, this is less than the
+    /// combined length of the buffers if we reached the end of the file.
+    pub fn readv(fd: u64, iovecs: &mut [&mut [u8]], offset: i64) -> Result<u64, SystemCallError> {
+        let iovecs: Vec<IoVec> = iovecs
+            .iter_mut()
+            .map(|buf| IoVec {
+                base: buf.as_mut_ptr() as u64,
+                len: buf.len() as u64,
+            })
+            .collect();
+        Fs::fileio_v(FileOperation::ReadV, fd, &iovecs, offset)
+    }
+
+    /// Vectored read or write of an opened file.
+    fn fileio_v(
+        op: FileOperation,
+        fd: u64,
+        iovecs: &[IoVec],
+        offset: i64,
+    ) -> Result<u64, SystemCallError> {
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                op as u64,
+                fd,
+                iovecs.as_ptr() as u64,
+                iovecs.len() as u64,
+                offset as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(len)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Writes `new` at `offset` of the file behind `fd`, but only if the
+    /// file holds `expected` there (both need to have the same length).
+    ///
+    /// Returns whether the file was written.
+    pub fn compare_and_write(
+        fd: u64,
+        expected: &[u8],
+        new: &[u8],
+        offset: u64,
+    ) -> Result<bool, SystemCallError> {
+        let iovecs = [
+            IoVec {
+                base: expected.as_ptr() as u64,
+                len: expected.len() as u64,
+            },
+            IoVec {
+                base: new.as_ptr() as u64,
+                len: new.len() as u64,
+            },
+        ];
+        let (r, written) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::CompareAndWrite,
+                fd,
+                iovecs.as_ptr() as u64,
+                offset,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(written != 0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Retrieve information about a file.
     pub fn getinfo(name: u64) -> Result<FileInfo, SystemCallError> {
         let fileinfo: FileInfo = Default::default();
@@ -218,12 +462,42 @@ impl Fs {
     }
 
     pub fn rename(old_name: u64, new_name: u64) -> Result<u64, SystemCallError> {
+        Fs::rename2(old_name, new_name, RenameFlags::empty())
+    }
+
+    /// Rename `old_name` to `new_name`, with `RENAME_NOREPLACE` this fails
+    /// (instead of overwriting) if `new_name` exists.
+    pub fn rename2(
+        old_name: u64,
+        new_name: u64,
+        flags: RenameFlags,
+    ) -> Result<u64, SystemCallError> {
         let r = unsafe {
             syscall!(
                 SystemCall::FileIO as u64,
                 FileOperation::FileRename,
                 old_name,
                 new_name,
+                u64::from(flags),
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Change the modes of the file at `pathname`.
+    pub fn chmod(pathname: u64, modes: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::Chmod,
+                pathname,
+                modes,
                 1
             )
         };
@@ -252,4 +526,74 @@ impl Fs {
             Err(SystemCallError::from(r))
         }
     }
+
+    pub fn rmdir(pathname: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::RmDir, pathname, 1) };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Mount an empty filesystem at `pathname`.
+    pub fn mount(pathname: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::Mount, pathname, 1) };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Unmount the filesystem at `pathname`, discarding its contents.
+    pub fn unmount(pathname: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::Unmount,
+                pathname,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Change the current working directory to `pathname`.
+    pub fn chdir(pathname: u64) -> Result<u64, SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::ChDir, pathname, 1) };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Copy the current working directory into `buffer` (not NUL-terminated)
+    /// and return its length.
+    pub fn getcwd(buffer: u64, len: u64) -> Result<u64, SystemCallError> {
+        let (r, cwd_len) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::GetCwd,
+                buffer,
+                len,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(cwd_len)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
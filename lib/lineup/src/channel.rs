@@ -0,0 +1,140 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A bounded, blocking multi-producer/multi-consumer channel.
+
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+
+use crate::condvar::CondVar;
+use crate::mutex::Mutex;
+
+/// Passes values of type `T` between threads.
+///
+/// `send` blocks while the channel is full and `recv` blocks while it's
+/// empty. A blocked thread is made runnable again (through the `CondVar`)
+/// once the counterpart made room or provided a value.
+#[derive(Debug)]
+pub struct Channel<T> {
+    /// Values in the order they were sent, only accessed with `mutex` held.
+    buffer: UnsafeCell<VecDeque<T>>,
+    capacity: usize,
+    mutex: Mutex,
+    /// Signaled when a value was removed.
+    not_full: CondVar,
+    /// Signaled when a value was added.
+    not_empty: CondVar,
+}
+
+unsafe impl<T: Send> Send for Channel<T> {}
+unsafe impl<T: Send> Sync for Channel<T> {}
+
+impl<T> Channel<T> {
+    /// Creates a channel that holds at most `capacity` values.
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    pub fn new(capacity: usize) -> Channel<T> {
+        assert!(capacity > 0, "Channel needs room for at least one value");
+        Channel {
+            buffer: UnsafeCell::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            mutex: Mutex::new_kmutex(),
+            not_full: CondVar::new(),
+            not_empty: CondVar::new(),
+        }
+    }
+
+    /// Sends `value`, waits for room in case the channel is full.
+    pub fn send(&self, value: T) {
+        self.mutex.enter();
+        // We might not be the only sender that got woken up
+        while self.len_locked() >= self.capacity {
+            self.not_full.wait(&self.mutex);
+        }
+        unsafe { (*self.buffer.get()).push_back(value) };
+        self.not_empty.signal();
+        self.mutex.exit();
+    }
+
+    /// Receives the oldest value, waits for one in case the channel is empty.
+    pub fn recv(&self) -> T {
+        self.mutex.enter();
+        let value = loop {
+            if let Some(value) = unsafe { (*self.buffer.get()).pop_front() } {
+                break value;
+            }
+            self.not_empty.wait(&self.mutex);
+        };
+        self.not_full.signal();
+        self.mutex.exit();
+        value
+    }
+
+    /// How many values are currently buffered.
+    pub fn len(&self) -> usize {
+        self.mutex.enter();
+        let len = self.len_locked();
+        self.mutex.exit();
+        len
+    }
+
+    /// True if no values are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn len_locked(&self) -> usize {
+        unsafe { (*self.buffer.get()).len() }
+    }
+}
+
+#[test]
+fn test_channel() {
+    use alloc::sync::Arc;
+    use core::ptr;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::scheduler::SmpScheduler;
+    use crate::stack::DEFAULT_STACK_SIZE_BYTES;
+    use crate::tls2::SchedulerControlBlock;
+
+    const ITEMS: usize = 100;
+
+    let s: SmpScheduler = Default::default();
+    let chan: Arc<Channel<usize>> = Arc::new(Channel::new(1));
+    let received = Arc::new(AtomicUsize::new(0));
+
+    let sender = chan.clone();
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_yielder| {
+            for i in 0..ITEMS {
+                sender.send(i);
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let receiver = chan.clone();
+    let count = received.clone();
+    s.spawn(
+        DEFAULT_STACK_SIZE_BYTES,
+        move |_yielder| {
+            for i in 0..ITEMS {
+                assert_eq!(receiver.recv(), i);
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+
+    assert_eq!(received.load(Ordering::Relaxed), ITEMS);
+}
@@ -0,0 +1,61 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Time sources for the scheduler.
+//!
+//! The scheduler and its threads never call `Instant::now()` directly but
+//! ask a `Clock` instead. This way tests can replace real time with a
+//! `MockClock` and decide exactly when a timeout expires.
+
+use core::ops::Add;
+
+use rawtime::{Duration, Instant};
+
+/// A source for the current time.
+pub trait Clock: Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// The default clock, reads the time from `rawtime`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves forward when told to (with `advance`).
+#[derive(Debug)]
+pub struct MockClock {
+    now: spin::Mutex<Instant>,
+}
+
+impl MockClock {
+    /// Creates a clock that starts at the current (real) time.
+    pub fn new() -> Self {
+        MockClock {
+            now: spin::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        let mut now = self.now.lock();
+        *now = now.add(d);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
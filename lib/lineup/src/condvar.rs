@@ -9,7 +9,6 @@ use core::sync::atomic::Ordering;
 use core::time::Duration;
 
 use log::trace;
-use rawtime::Instant;
 
 use crate::mutex::Mutex;
 use crate::threads::{ThreadId, YieldRequest};
@@ -172,7 +171,7 @@ impl CondVarInner {
     /// Returns false on time-out, or true if woken up by other event
     pub fn timed_wait(&mut self, mtx: &Mutex, d: Duration) -> bool {
         let mut rid: i32 = 0;
-        let wakup_time = Instant::now().add(d);
+        let wakup_time = Environment::thread().now().add(d);
         let tid = Environment::tid();
         self.dbg_mutex = mtx as *const Mutex;
 
@@ -185,11 +184,9 @@ impl CondVarInner {
         self.cv_schedule_enter(mtx, &rid);
         remove_item(&mut self.waiters, &tid);
 
-        trace!(
-            "timed_wait: cv_schedule_enter done Instant::now() < wakup_time = {}",
-            Instant::now() < wakup_time
-        );
-        Instant::now() < wakup_time
+        let in_time = Environment::thread().now() < wakup_time;
+        trace!("timed_wait: cv_schedule_enter done now < wakup_time = {}", in_time);
+        in_time
     }
 
     /// TODO(smp): see comment
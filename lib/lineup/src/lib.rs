@@ -12,6 +12,8 @@
 
 extern crate alloc;
 
+pub mod channel;
+pub mod clock;
 pub mod condvar;
 pub mod mutex;
 pub mod rwlock;
@@ -25,5 +27,8 @@ pub mod upcalls;
 /// Type to represent a core id for the scheduler.
 type CoreId = usize;
 
+/// Type to represent a NUMA node id.
+type NodeId = usize;
+
 /// Type to represent an IRQ vector.
 type IrqVector = u64;
@@ -20,11 +20,12 @@ use fringe::generator::Generator;
 use log::{error, trace};
 use rawtime::Instant;
 
+use crate::clock::{Clock, SystemClock};
 use crate::stack::LineupStack;
 use crate::threads::{Runnable, Thread, ThreadId, YieldRequest, YieldResume};
 use crate::tls2::{self, SchedulerControlBlock, ThreadControlBlock};
 use crate::upcalls::Upcalls;
-use crate::{CoreId, IrqVector};
+use crate::{CoreId, IrqVector, NodeId};
 
 /// Scheduler per-core state.
 ///
@@ -62,6 +63,8 @@ pub struct SmpScheduler<'a> {
     threads: spin::Mutex<hashbrown::HashMap<ThreadId, Thread>>,
     /// Scheduler upcalls (as set by the client).
     upcalls: Upcalls,
+    /// Where the scheduler (and its threads) get the current time from.
+    clock: &'static dyn Clock,
     /// Per-core scheduler state
     ///
     /// This is slightly different from SchedulerControlBlock
@@ -71,6 +74,14 @@ pub struct SmpScheduler<'a> {
     tid_counter: AtomicUsize,
     /// Maps interrupt vectors to ThreadId
     irqvec_to_tid: spin::Mutex<hashbrown::HashMap<IrqVector, ThreadId>>,
+    /// Invoked by `run` whenever a core runs out of runnable threads.
+    idle_hook: spin::Mutex<Option<fn()>>,
+    /// Exit values of threads that exited before anyone joined on them.
+    ///
+    /// Lock order: `threads` before `exit_values`.
+    exit_values: spin::Mutex<hashbrown::HashMap<ThreadId, *mut u8>>,
+    /// The NUMA node of every core (indexed by `CoreId`), see `set_topology`.
+    core_nodes: spin::Mutex<Vec<NodeId>>,
 }
 
 unsafe impl Send for SmpScheduler<'static> {}
@@ -86,18 +97,85 @@ impl<'a> SmpScheduler<'a> {
     pub const MAX_THREADS: usize = 2048;
 
     pub fn with_upcalls(upcalls: Upcalls) -> Self {
+        SmpScheduler::with_upcalls_and_clock(upcalls, &SystemClock)
+    }
+
+    /// Creates a scheduler that uses `clock` for all timeouts.
+    ///
+    /// Mostly useful for testing (see `clock::MockClock`).
+    pub fn with_upcalls_and_clock(upcalls: Upcalls, clock: &'static dyn Clock) -> Self {
         Self {
             generators: spin::Mutex::new(hashbrown::HashMap::with_capacity(
                 SmpScheduler::MAX_THREADS,
             )),
             threads: spin::Mutex::new(hashbrown::HashMap::with_capacity(SmpScheduler::MAX_THREADS)),
             upcalls,
+            clock,
             tid_counter: AtomicUsize::new(0),
             per_core: arr![SchedulerCoreState::new(); 96], // MAX_THREADS
             irqvec_to_tid: spin::Mutex::new(hashbrown::HashMap::with_capacity(8)),
+            idle_hook: spin::Mutex::new(None),
+            exit_values: spin::Mutex::new(hashbrown::HashMap::new()),
+            core_nodes: spin::Mutex::new(Vec::new()),
         }
     }
 
+    /// Tells the scheduler which NUMA node every core belongs to,
+    /// `core_nodes[core]` is the node of `core`.
+    ///
+    /// This is only used to place threads with `spawn_on_node`.
+    pub fn set_topology(&self, core_nodes: &[NodeId]) {
+        let mut nodes = self.core_nodes.lock();
+        nodes.clear();
+        nodes.extend_from_slice(&core_nodes[..core_nodes.len().min(self.per_core.len())]);
+    }
+
+    /// Number of threads in the run-list of `core`.
+    pub fn runnable_count(&self, core: CoreId) -> usize {
+        self.per_core[core].runnable.lock().len()
+    }
+
+    /// Picks the core with the fewest runnable threads on `node`.
+    ///
+    /// If `node` has no cores (or there is no topology) this picks the least
+    /// loaded of all cores we know about, or core 0.
+    fn least_loaded_core(&self, node: NodeId) -> CoreId {
+        let core_nodes = self.core_nodes.lock();
+        let cores = || 0..core_nodes.len();
+        cores()
+            .filter(|&core| core_nodes[core] == node)
+            .min_by_key(|&core| self.runnable_count(core))
+            .or_else(|| cores().min_by_key(|&core| self.runnable_count(core)))
+            .unwrap_or(0)
+    }
+
+    /// Spawns a thread on the least loaded core of NUMA `node` (see
+    /// `set_topology`).
+    pub fn spawn_on_node<F>(
+        &self,
+        stack_size: usize,
+        f: F,
+        arg: *mut u8,
+        node: NodeId,
+    ) -> Option<ThreadId>
+    where
+        F: 'static + FnOnce(*mut u8) + Send,
+    {
+        let core = self.least_loaded_core(node);
+        trace!("spawn_on_node: node {} -> core {}", node, core);
+        self.spawn(stack_size, f, arg, core, None)
+    }
+
+    /// Install a hook that is called when a core has no runnable threads left.
+    ///
+    /// This lets the platform halt/wait for an interrupt instead of spinning.
+    /// The hook has to return eventually, afterwards the scheduler checks
+    /// again for runnable threads (e.g., because an IRQ arrived) before `run`
+    /// returns. The hook is invoked once per idle period.
+    pub fn set_idle_hook(&self, f: fn()) {
+        *self.idle_hook.lock() = Some(f);
+    }
+
     /// Returns true as long as we have 'active', unfinished thread.
     ///
     /// A thread that is currently blocked/waiting still counts as active.
@@ -132,6 +210,7 @@ impl<'a> SmpScheduler<'a> {
                 f,
                 arg,
                 self.upcalls,
+                self.clock,
                 interrupt_vector,
                 tls,
             )
@@ -209,9 +288,20 @@ impl<'a> SmpScheduler<'a> {
     /// up using `signal` and `broadcast` so we can remove calls
     /// here except in these situation if we track it better
     /// i.e. save in thread state if its waiting...
-    fn waitlist_remove(&self, tid: ThreadId, affinity: CoreId) {
+    ///
+    /// Returns true if the thread was in the waitlist.
+    fn waitlist_remove(&self, tid: ThreadId, affinity: CoreId) -> bool {
         let mut waiting = self.per_core[affinity].waiting.lock();
+        let len = waiting.len();
         waiting.retain(|&(_instant, wtid)| wtid != tid);
+        len != waiting.len()
+    }
+
+    /// Set the value the thread gets when it is resumed the next time.
+    fn set_return_with(&self, tid: ThreadId, resume: YieldResume) {
+        if let Some(thread) = self.threads.lock().get_mut(&tid) {
+            thread.return_with = Some(resume);
+        }
     }
 
     /// Insert thread in a sorted waitlist
@@ -234,11 +324,18 @@ impl<'a> SmpScheduler<'a> {
             None => {
                 trace!("Thread {} has terminated.", tid);
                 self.mark_unrunnable(tid, affinity);
-                let thread = self
-                    .threads
-                    .lock()
-                    .remove(&tid)
-                    .expect("Can't remove thread?");
+                let (thread, exit_value) = {
+                    let mut threads = self.threads.lock();
+                    let thread = threads.remove(&tid).expect("Can't remove thread?");
+                    // The TCB is still around (we never free it)
+                    let exit_value = unsafe { (*thread.state).exit_value };
+                    if thread.joinlist.is_empty() && !exit_value.is_null() {
+                        // Keep it for whoever joins on us later, still holding
+                        // `threads` so a concurrent `JoinOn` finds it
+                        self.exit_values.lock().insert(tid, exit_value);
+                    }
+                    (thread, exit_value)
+                };
 
                 // Wake up all the waiters
                 for (sleeping_tid, sleeping_affinity) in thread.joinlist {
@@ -247,6 +344,7 @@ impl<'a> SmpScheduler<'a> {
                         sleeping_tid,
                         sleeping_affinity
                     );
+                    self.set_return_with(sleeping_tid, YieldResume::Joined(exit_value));
                     self.mark_runnable(sleeping_tid, sleeping_affinity);
                 }
                 YieldResume::DoNotResume
@@ -260,6 +358,17 @@ impl<'a> SmpScheduler<'a> {
                 self.mark_runnable(tid, affinity);
                 YieldResume::Interrupted
             }
+            Some(YieldRequest::YieldTo(rtid)) => {
+                trace!("YieldRequest::YieldTo {:?} {}", rtid, affinity);
+                let mut runnable = self.per_core[affinity].runnable.lock();
+                // Only a hint: if rtid isn't queued on our core this is a plain yield
+                if let Some(pos) = runnable.iter().position(|&ltid| ltid == rtid) {
+                    runnable.remove(pos);
+                    runnable.push_front(rtid);
+                }
+                runnable.push_back(tid);
+                YieldResume::Interrupted
+            }
             Some(YieldRequest::Runnable(rtid)) => {
                 trace!("YieldRequest::Runnable {:?} {}", rtid, affinity);
                 let rtid_affinity = self
@@ -313,12 +422,43 @@ impl<'a> SmpScheduler<'a> {
                 trace!(
                     "The thread #{:?} has suspended itself until {:?}.",
                     tid,
-                    until.duration_since(Instant::now()),
+                    until.duration_since(self.clock.now()),
                 );
                 self.waitlist_insert(tid, affinity, until);
                 // Already popped from running, force context switch
                 YieldResume::Interrupted
             }
+            Some(YieldRequest::TimeoutCancellable(until, token)) => {
+                trace!(
+                    "The thread #{:?} has suspended itself until {:?} (cancellable).",
+                    tid,
+                    until.duration_since(self.clock.now()),
+                );
+                let mut state = token.state();
+                if state.cancelled {
+                    // Cancelled before we went to sleep, wake up right away
+                    self.set_return_with(tid, YieldResume::Interrupted);
+                    self.mark_runnable(tid, affinity);
+                } else {
+                    state.sleeper = Some((tid, affinity));
+                    self.waitlist_insert(tid, affinity, until);
+                }
+                // Already popped from running, force context switch
+                YieldResume::Interrupted
+            }
+            Some(YieldRequest::Cancel(token)) => {
+                let mut state = token.state();
+                state.cancelled = true;
+                if let Some((stid, saffinity)) = state.sleeper.take() {
+                    trace!("{} cancels the sleep of {}", tid, stid);
+                    // Only wake it up if the timeout didn't already do so
+                    if self.waitlist_remove(stid, saffinity) {
+                        self.set_return_with(stid, YieldResume::Interrupted);
+                        self.mark_runnable(stid, saffinity);
+                    }
+                }
+                YieldResume::Completed
+            }
             Some(YieldRequest::JoinOn(wait_on_tid)) => {
                 trace!(
                     "The thread #{:?} is waiting for #{:?} to complete.",
@@ -341,7 +481,10 @@ impl<'a> SmpScheduler<'a> {
                     // exited, (note this implementation means we'll never
                     // reuse thread ids because otherwise we would have
                     // a race here)
-                    None => YieldResume::Completed,
+                    None => match self.exit_values.lock().remove(&wait_on_tid) {
+                        Some(exit_value) => YieldResume::Joined(exit_value),
+                        None => YieldResume::Completed,
+                    },
                 }
             }
             Some(YieldRequest::Spawn(function, arg, affinity, irq_vector)) => {
@@ -350,7 +493,8 @@ impl<'a> SmpScheduler<'a> {
                     .spawn(
                         64 * 4096,
                         move |arg| unsafe {
-                            (function.unwrap())(arg);
+                            let exit_value = (function.unwrap())(arg);
+                            tls2::Environment::thread().set_exit_value(exit_value);
                         },
                         arg,
                         affinity,
@@ -372,7 +516,8 @@ impl<'a> SmpScheduler<'a> {
                     .spawn_with_args(
                         stack,
                         move |arg| unsafe {
-                            (function.unwrap())(arg);
+                            let exit_value = (function.unwrap())(arg);
+                            tls2::Environment::thread().set_exit_value(exit_value);
                         },
                         arg,
                         affinity,
@@ -385,14 +530,26 @@ impl<'a> SmpScheduler<'a> {
         }
     }
 
+    /// Checks that the thread didn't overflow into the guard area of its stack.
+    ///
+    /// Always returns true for threads without a guarded stack.
+    fn is_stack_guard_intact(&self, tid: ThreadId) -> bool {
+        self.threads
+            .lock()
+            .get(&tid)
+            .and_then(|thread| thread.stack_guard)
+            .map_or(true, |guard| guard.is_intact())
+    }
+
     /// Finds threads with expired timeouts and re-inserts them from `waiting` into `runnable`
     ///
     /// Acquires lock on `waiting` and `runnable`.
     /// TODO(style): Maybe should avoid taking both locks here to avoid deadlock.
     /// TODO(efficiency): Should probably avoid taking `runnable` lock multiple times.
     fn check_wakeups(&self, affinity: CoreId) {
+        let now = self.clock.now();
         let mut waiting = self.per_core[affinity].waiting.lock();
-        while !waiting.is_empty() && waiting.last().unwrap().0 <= Instant::now() {
+        while !waiting.is_empty() && waiting.last().unwrap().0 <= now {
             if let Some((_wakeup, tid)) = waiting.pop() {
                 self.mark_runnable(tid, affinity);
             }
@@ -434,6 +591,8 @@ impl<'a> SmpScheduler<'a> {
         }
 
         let mut prev_rumprun_lwp: *mut u8 = ptr::null_mut();
+        // Did we already call the idle hook since the last dispatch?
+        let mut idled = false;
         // Run until `runnable` is empty.
         loop {
             self.check_interrupt(scb);
@@ -443,6 +602,7 @@ impl<'a> SmpScheduler<'a> {
             let next_tid = self.per_core[core_id].runnable.lock().pop_front();
             match next_tid {
                 Some(tid) => {
+                    idled = false;
                     let mut generator = self
                         .generators
                         .lock()
@@ -450,8 +610,8 @@ impl<'a> SmpScheduler<'a> {
                         .expect("Can't find generator thread state?");
 
                     let mut resume_action: YieldResume = {
-                        let thread_map = self.threads.lock();
-                        let thread = thread_map.get(&tid).expect("Can't find thread state?");
+                        let mut thread_map = self.threads.lock();
+                        let thread = thread_map.get_mut(&tid).expect("Can't find thread state?");
                         trace!("Thread = {:?}", thread);
 
                         // TODO(api-ergonomics): `context_switch` should be a generic (non-rump specific) interface
@@ -464,7 +624,7 @@ impl<'a> SmpScheduler<'a> {
                         unsafe {
                             tls2::arch::set_tcb(thread.state);
                         }
-                        thread.return_with.unwrap_or(YieldResume::Completed)
+                        thread.return_with.take().unwrap_or(YieldResume::Completed)
                     };
 
                     // Run the thread until `handle_yield_request` decides on a context-switch
@@ -473,6 +633,11 @@ impl<'a> SmpScheduler<'a> {
                         trace!("{:?} generator.resume = {:?}", tid, resume_action);
                         let yielded_with = generator.resume(resume_action);
                         trace!("yielded_with = {:?}", yielded_with);
+                        if !self.is_stack_guard_intact(tid) {
+                            // Don't drop the generator, its stack is corrupted
+                            core::mem::forget(generator);
+                            panic!("{} overflowed its stack (stack guard corrupted)", tid);
+                        }
                         resume_action = self.handle_yield_request(tid, yielded_with);
                         trace!("{:?} resume_action = {:?}", tid, resume_action);
                         if resume_action == YieldResume::Interrupted {
@@ -506,9 +671,17 @@ impl<'a> SmpScheduler<'a> {
                     }
                 }
                 None => {
-                    // Nothing to dispatch
-                    // Maybe return the next event that will happen on that scheduler?
-                    break;
+                    // Nothing to dispatch, give the platform a chance to wait
+                    // for an interrupt before checking one last time
+                    let idle_hook = *self.idle_hook.lock();
+                    match idle_hook {
+                        Some(hook) if !idled => {
+                            idled = true;
+                            hook();
+                        }
+                        // Maybe return the next event that will happen on that scheduler?
+                        _ => break,
+                    }
                 }
             }
         }
@@ -522,6 +695,7 @@ impl<'a> SmpScheduler<'a> {
 #[cfg(test)]
 mod tests {
     use alloc::sync::Arc;
+    use core::ops::Add;
     use core::time::Duration;
     use std::thread;
 
@@ -806,6 +980,308 @@ mod tests {
         assert!(exp_duration <= ref_duration + bound, "Lineup was too slow?");
     }
 
+    /// Checks that a thread writing into the guard area of its stack is
+    /// detected by the scheduler.
+    #[test]
+    #[should_panic(expected = "ThreadId { id=0 } overflowed its stack")]
+    fn stack_guard_violation() {
+        let s: SmpScheduler = Default::default();
+        let stack = LineupStack::from_size_with_guard(DEFAULT_STACK_SIZE_BYTES);
+        let guard = stack.guard().expect("Stack is guarded");
+        assert!(guard.is_intact());
+        let canary = guard.canary_top() as usize;
+
+        let tls = unsafe { tls2::ThreadControlBlock::new_tls_area() };
+        s.spawn_with_args(
+            stack,
+            move |_| unsafe {
+                // Pretend we grew the stack into the guard area
+                *(canary as *mut u64) = 0x0;
+            },
+            ptr::null_mut(),
+            0,
+            None,
+            tls,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+    }
+
+    /// Threads on a guarded stack that behave don't trigger the guard.
+    #[test]
+    fn stack_guard_intact() {
+        let s: SmpScheduler = Default::default();
+        let stack = LineupStack::from_size_with_guard(DEFAULT_STACK_SIZE_BYTES);
+        let tls = unsafe { tls2::ThreadControlBlock::new_tls_area() };
+        s.spawn_with_args(
+            stack,
+            move |_| {
+                let buf = [0xau8; 4096];
+                core::hint::black_box(&buf);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+            tls,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+    }
+
+    /// Checks that the idle hook is invoked once when all threads are blocked.
+    #[test]
+    fn idle_hook_when_blocked() {
+        static IDLE_CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn count_idle() {
+            IDLE_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let s: SmpScheduler = Default::default();
+        s.set_idle_hook(count_idle);
+
+        // A thread that immediately blocks for a long time
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().sleep(Duration::from_secs(3600));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(s.has_active_threads());
+        assert_eq!(IDLE_CALLS.load(Ordering::Relaxed), 1);
+
+        // Every subsequent idle period calls the hook once more
+        s.run(&scb);
+        assert_eq!(IDLE_CALLS.load(Ordering::Relaxed), 2);
+    }
+
+    /// A sleeping thread can be woken up early by cancelling its token.
+    #[test]
+    fn cancel_sleep() {
+        let s: SmpScheduler = Default::default();
+        let token = CancelToken::new();
+        let was_cancelled = Arc::new(AtomicUsize::new(0));
+
+        let sleeper_token = token.clone();
+        let sleeper_result = was_cancelled.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let deadline = Instant::now().add(Duration::from_secs(60));
+                let cancelled = Environment::thread()
+                    .sleep_cancellable(Duration::from_secs(3600), &sleeper_token);
+                assert!(Instant::now() < deadline, "Woken up promptly");
+                sleeper_result.store(cancelled as usize + 1, Ordering::SeqCst);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                token.cancel();
+                assert!(token.is_cancelled());
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+        // 2 means the sleep returned and reported the cancellation
+        assert_eq!(was_cancelled.load(Ordering::SeqCst), 2);
+    }
+
+    /// `spawn_on_node` puts the thread on the core of the node with the
+    /// fewest runnable threads.
+    #[test]
+    fn spawn_on_least_loaded_core() {
+        let s: SmpScheduler = Default::default();
+        // Cores 0, 1 are on node 0 and cores 2, 3 on node 1
+        s.set_topology(&[0, 0, 1, 1]);
+
+        for core in &[2, 2, 3] {
+            s.spawn(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), *core, None);
+        }
+        assert_eq!(s.runnable_count(2), 2);
+        assert_eq!(s.runnable_count(3), 1);
+
+        let tid = s
+            .spawn_on_node(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 1)
+            .expect("Can't spawn");
+        assert_eq!(s.runnable_count(3), 2);
+        assert!(s.per_core[3].runnable.lock().contains(&tid));
+
+        // A node without cores falls back to the least loaded core
+        s.spawn(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 0, None);
+        let tid = s
+            .spawn_on_node(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 7)
+            .expect("Can't spawn");
+        assert!(s.per_core[1].runnable.lock().contains(&tid));
+
+        for core in 0..4 {
+            let scb: SchedulerControlBlock = SchedulerControlBlock::new(core);
+            s.run(&scb);
+        }
+        assert!(!s.has_active_threads());
+    }
+
+    /// A sleeping thread wakes up exactly when the (mock) clock reaches its
+    /// deadline.
+    #[test]
+    fn mock_clock_timeout() {
+        use crate::clock::MockClock;
+
+        let clock: &'static MockClock = Box::leak(Box::new(MockClock::new()));
+        let s = SmpScheduler::with_upcalls_and_clock(Default::default(), clock);
+        let woken_up = Arc::new(AtomicUsize::new(0));
+
+        let sleeper_woken_up = woken_up.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let start = Environment::thread().now();
+                Environment::thread().sleep(Duration::from_secs(10));
+                assert_eq!(
+                    Environment::thread().now().duration_since(start),
+                    Duration::from_secs(10)
+                );
+                sleeper_woken_up.store(1, Ordering::SeqCst);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(s.has_active_threads());
+
+        // Just before the deadline, the thread keeps sleeping
+        clock.advance(Duration::from_secs(10) - Duration::from_nanos(1));
+        s.run(&scb);
+        assert!(s.has_active_threads());
+        assert_eq!(woken_up.load(Ordering::SeqCst), 0);
+
+        // Reaching the deadline wakes it up
+        clock.advance(Duration::from_nanos(1));
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+        assert_eq!(woken_up.load(Ordering::SeqCst), 1);
+    }
+
+    /// Joining on a thread returns its exit value, whether it exits before
+    /// or after the join.
+    #[test]
+    fn join_exit_value() {
+        let s: SmpScheduler = Default::default();
+        let results: Arc<ArrayQueue<usize>> = Arc::new(ArrayQueue::new(2));
+
+        unsafe extern "C" fn square(arg: *mut u8) -> *mut u8 {
+            let n = arg as usize;
+            (n * n) as *mut u8
+        }
+
+        let r = results.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                // Joins before the child got to run
+                let child = Environment::thread()
+                    .spawn(Some(square), 3 as *mut u8)
+                    .expect("Can't spawn");
+                let _r = r.push(Environment::thread().join(child) as usize);
+
+                // Joins after the child already exited
+                let child = Environment::thread()
+                    .spawn(Some(square), 7 as *mut u8)
+                    .expect("Can't spawn");
+                Environment::thread().relinquish();
+                let _r = r.push(Environment::thread().join(child) as usize);
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+
+        assert_eq!(results.pop(), Some(9));
+        assert_eq!(results.pop(), Some(49));
+        assert!(s.exit_values.lock().is_empty());
+    }
+
+    /// A thread yielding to another thread runs it ahead of the ready queue.
+    #[test]
+    fn yield_to() {
+        let s: SmpScheduler = Default::default();
+        let order: Arc<ArrayQueue<char>> = Arc::new(ArrayQueue::new(4));
+        let target: Arc<spin::Mutex<Option<ThreadId>>> = Arc::new(spin::Mutex::new(None));
+
+        let a_order = order.clone();
+        let a_target = target.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let _r = a_order.push('a');
+                let b = a_target.lock().expect("b was spawned");
+                Environment::thread().yield_to(b);
+                let _r = a_order.push('a');
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let c_order = order.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let _r = c_order.push('c');
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let b_order = order.clone();
+        let b = s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let _r = b_order.push('b');
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+        *target.lock() = b;
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+        assert!(!s.has_active_threads());
+
+        let mut seen = Vec::new();
+        while let Some(t) = order.pop() {
+            seen.push(t);
+        }
+        // Without the hint c would run before b
+        assert_eq!(seen, vec!['a', 'b', 'c', 'a']);
+    }
+
     /// Test that waitlist inserts are inserted with correct order.
     #[test]
     fn waitlist_inserts_are_sorted() {
@@ -9,12 +9,50 @@ use fringe::Stack;
 /// Default stack size in bytes.
 pub const DEFAULT_STACK_SIZE_BYTES: usize = 32 * 4096;
 
-/// LineupStack holds a non-guarded, heap-allocated stack.
+/// Size of the guard area at the bottom of a guarded stack.
+pub const STACK_GUARD_SIZE_BYTES: usize = 4096;
+
+/// Pattern written to the guard area of a stack.
+const STACK_CANARY: u64 = 0xdead_beef_cafe_babe;
+
+/// LineupStack holds a heap-allocated stack.
+///
+/// The stack can optionally reserve a guard area at its lowest addresses
+/// which is filled with a canary (see `from_size_with_guard`).
 #[derive(Debug, PartialEq)]
 pub struct LineupStack {
     base_ptr: *mut u8,
     layout: Layout,
     dealloc: bool,
+    guarded: bool,
+}
+
+/// Remembers the location of the canary area of a guarded stack.
+///
+/// This is kept separately from the stack since the stack is owned by the
+/// generator once a thread is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct StackGuard {
+    canary: *mut u64,
+    len: usize,
+}
+
+impl StackGuard {
+    /// Checks that the canary at the bottom of the stack is still intact.
+    pub(crate) fn is_intact(&self) -> bool {
+        unsafe {
+            core::slice::from_raw_parts(self.canary, self.len)
+                .iter()
+                .all(|&c| c == STACK_CANARY)
+        }
+    }
+
+    /// The last (highest) word of the canary area, i.e., the first one a
+    /// stack growing down into the guard area overwrites.
+    #[cfg(test)]
+    pub(crate) fn canary_top(&self) -> *mut u64 {
+        unsafe { self.canary.add(self.len - 1) }
+    }
 }
 
 impl Default for LineupStack {
@@ -38,10 +76,27 @@ impl LineupStack {
                 base_ptr,
                 layout,
                 dealloc: true,
+                guarded: false,
             }
         }
     }
 
+    /// Allocates a new stack with `size` accessible bytes and an additional
+    /// guard area of `STACK_GUARD_SIZE_BYTES` below it.
+    ///
+    /// The guard area is filled with a canary, the scheduler checks the canary
+    /// whenever the thread yields and panics in case it was overwritten.
+    pub fn from_size_with_guard(size: usize) -> LineupStack {
+        let mut stack = LineupStack::from_size(size + STACK_GUARD_SIZE_BYTES);
+        stack.guarded = true;
+
+        let guard = stack.guard().expect("Stack is guarded");
+        unsafe {
+            core::slice::from_raw_parts_mut(guard.canary, guard.len).fill(STACK_CANARY);
+        }
+        stack
+    }
+
     pub fn from_ptr(base_ptr: *mut u8, size: usize, dealloc: bool) -> LineupStack {
         unsafe {
             let aligned_size = size & !(fringe::STACK_ALIGNMENT - 1);
@@ -52,9 +107,22 @@ impl LineupStack {
                 base_ptr,
                 layout,
                 dealloc,
+                guarded: false,
             }
         }
     }
+
+    /// Returns the canary area of the stack (if it has one).
+    pub(crate) fn guard(&self) -> Option<StackGuard> {
+        if self.guarded {
+            Some(StackGuard {
+                canary: self.base_ptr as *mut u64,
+                len: STACK_GUARD_SIZE_BYTES / core::mem::size_of::<u64>(),
+            })
+        } else {
+            None
+        }
+    }
 }
 
 impl Drop for LineupStack {
@@ -71,11 +139,15 @@ unsafe impl Stack for LineupStack {
         // The slice cannot wrap around the address space, so the conversion from usize
         // to isize will not wrap either.
         let len = self.layout.size() as isize;
-        unsafe { self.limit().offset(len) }
+        unsafe { self.base_ptr.offset(len) }
     }
 
     #[inline(always)]
     fn limit(&self) -> *mut u8 {
-        self.base_ptr
+        if self.guarded {
+            unsafe { self.base_ptr.add(STACK_GUARD_SIZE_BYTES) }
+        } else {
+            self.base_ptr
+        }
     }
 }
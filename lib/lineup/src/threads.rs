@@ -1,6 +1,7 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::hash::{Hash, Hasher};
 use core::{fmt, mem, ptr};
@@ -8,7 +9,8 @@ use core::{fmt, mem, ptr};
 use fringe::generator::{Generator, Yielder};
 use rawtime::Instant;
 
-use crate::stack::LineupStack;
+use crate::clock::Clock;
+use crate::stack::{LineupStack, StackGuard};
 use crate::tls2::{self, ThreadControlBlock};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
@@ -49,6 +51,9 @@ pub(crate) struct Thread {
     /// Threads currently waiting (join, blocked) on us to exit.
     pub(crate) joinlist: Vec<(ThreadId, CoreId)>,
 
+    /// Canary area of the thread's stack (if the stack is guarded).
+    pub(crate) stack_guard: Option<StackGuard>,
+
     /// Storage to remember the pointer to the TCB
     ///
     /// TODO(correctness): It's not really static (it's on the thread's stack),
@@ -84,6 +89,7 @@ impl Thread {
         f: F,
         arg: *mut u8,
         upcalls: Upcalls,
+        clock: &'static dyn Clock,
         _interrupt_vector: Option<IrqVector>,
         tcb: *mut ThreadControlBlock<'static>,
     ) -> (
@@ -97,6 +103,7 @@ impl Thread {
         (*tcb).tid = tid;
         (*tcb).current_core = affinity;
         (*tcb).upcalls = upcalls;
+        (*tcb).clock = clock;
 
         let thread = Thread {
             id: tid,
@@ -104,6 +111,7 @@ impl Thread {
             return_with: None,
             _interrupt_vector,
             joinlist: Vec::with_capacity(crate::scheduler::SmpScheduler::MAX_THREADS),
+            stack_guard: stack.guard(),
             state: tcb,
         };
 
@@ -130,6 +138,50 @@ impl Thread {
     }
 }
 
+/// A token to wake up a thread early that sleeps with
+/// `ThreadControlBlock::sleep_cancellable`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<spin::Mutex<CancelState>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CancelState {
+    /// Set once the token was cancelled.
+    pub(crate) cancelled: bool,
+    /// The thread (and its core) currently sleeping on the token.
+    pub(crate) sleeper: Option<(ThreadId, CoreId)>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        Default::default()
+    }
+
+    /// Wakes up the thread sleeping on this token, a later
+    /// `sleep_cancellable` with the token returns immediately.
+    ///
+    /// Has to be called from a thread managed by the scheduler.
+    pub fn cancel(&self) {
+        tls2::Environment::thread().suspend(YieldRequest::Cancel(self.clone()));
+    }
+
+    /// Was `cancel` called on the token?
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.lock().cancelled
+    }
+
+    pub(crate) fn state(&self) -> spin::MutexGuard<CancelState> {
+        self.inner.lock()
+    }
+}
+
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
 /// Requests that go from the thread-context to the scheduler.
 #[derive(Debug, PartialEq)]
 pub(crate) enum YieldRequest {
@@ -137,6 +189,12 @@ pub(crate) enum YieldRequest {
     None,
     /// Block thread until we reach Instant.
     Timeout(Instant),
+    /// Block thread until we reach Instant or the token is cancelled.
+    TimeoutCancellable(Instant, CancelToken),
+    /// Wake up the thread sleeping on the token.
+    Cancel(CancelToken),
+    /// Yield and run ThreadId next if it is runnable on the same core.
+    YieldTo(ThreadId),
     /// Tell scheduler to make ThreadId runnable.
     Runnable(ThreadId),
     /// Tell scheduler to make ThreadId unrunnable.
@@ -173,6 +231,8 @@ pub(crate) enum YieldResume {
     Interrupted,
     /// A child thread was spawned with the given ThreadId.
     Spawned(ThreadId),
+    /// The thread we joined on has exited with the given exit value.
+    Joined(*mut u8),
     /// Thread has completed (and has been removed from the scheduler state)
     DoNotResume,
 }
@@ -26,8 +26,9 @@ use fringe::generator::Yielder;
 use crossbeam_queue::ArrayQueue;
 use rawtime::{Duration, Instant};
 
+use crate::clock::{Clock, SystemClock};
 use crate::stack::LineupStack;
-use crate::threads::{ThreadId, YieldRequest, YieldResume};
+use crate::threads::{CancelToken, ThreadId, YieldRequest, YieldResume};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
 
@@ -83,6 +84,8 @@ pub struct ThreadControlBlock<'a> {
     pub current_core: CoreId,
     /// Contains upcalls (TODO: can't this be in SchedulerControlBlock?)
     pub upcalls: Upcalls,
+    /// Time source of the scheduler that runs this thread.
+    pub(crate) clock: &'static dyn Clock,
 
     /// Stores pointer to lwp (TODO: figure this out can probably be thread local now)
     pub rump_lwp: AtomicPtr<u64>,
@@ -91,6 +94,9 @@ pub struct ThreadControlBlock<'a> {
 
     /// The current errno variable (for libc compatibility).
     pub errno: i32,
+
+    /// Value handed to the thread(s) joining on us once we exit.
+    pub(crate) exit_value: *mut u8,
 }
 
 impl<'a> ThreadControlBlock<'a> {
@@ -104,8 +110,10 @@ impl<'a> ThreadControlBlock<'a> {
             current_core: 0,
             errno: 0,
             upcalls: Default::default(),
+            clock: &SystemClock,
             rump_lwp: AtomicPtr::new(ptr::null_mut()),
             rumprun_lwp: ptr::null_mut(),
+            exit_value: ptr::null_mut(),
         };
 
         let (initial_tdata, tls_layout) = arch::get_tls_info();
@@ -198,11 +206,27 @@ impl<'a> ThreadControlBlock<'a> {
         }
     }
 
+    /// Returns the current time (as seen by the scheduler).
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     pub fn sleep(&self, d: Duration) {
-        let request = YieldRequest::Timeout(Instant::now().add(d));
+        let request = YieldRequest::Timeout(self.now().add(d));
         self.yielder().suspend(request);
     }
 
+    /// Sleeps for `d` unless `token` gets cancelled first.
+    ///
+    /// Returns true if we were woken up by `CancelToken::cancel`.
+    pub fn sleep_cancellable(&self, d: Duration, token: &CancelToken) -> bool {
+        let request = YieldRequest::TimeoutCancellable(self.now().add(d), token.clone());
+        let r = self.yielder().suspend(request);
+        // In case we timed out, make sure a later cancel doesn't find us
+        token.state().sleeper = None;
+        r == YieldResume::Interrupted
+    }
+
     pub fn block(&self) {
         let request = YieldRequest::Unrunnable(Environment::tid());
         self.yielder().suspend(request);
@@ -223,9 +247,26 @@ impl<'a> ThreadControlBlock<'a> {
         self.yielder().suspend(request);
     }
 
-    pub fn join(&self, tid: ThreadId) {
+    /// Waits for thread `tid` to exit.
+    ///
+    /// Returns the exit value of `tid` (null if it didn't set one, see
+    /// `set_exit_value`).
+    pub fn join(&self, tid: ThreadId) -> *mut u8 {
         let request = YieldRequest::JoinOn(tid);
-        self.yielder().suspend(request);
+        match self.yielder().suspend(request) {
+            YieldResume::Joined(exit_value) => exit_value,
+            _ => ptr::null_mut(),
+        }
+    }
+
+    /// Sets the value that threads joining on us get once we exit.
+    ///
+    /// Threads spawned with an `extern "C"` function (e.g., `spawn`) exit
+    /// with whatever the function returns. A non-null value is kept around
+    /// until someone joins on the thread, threads that nobody will join
+    /// should leave it null.
+    pub fn set_exit_value(&mut self, exit_value: *mut u8) {
+        self.exit_value = exit_value;
     }
 
     pub(crate) fn suspend(&self, request: YieldRequest) {
@@ -235,6 +276,13 @@ impl<'a> ThreadControlBlock<'a> {
     pub fn relinquish(&self) {
         self.suspend(YieldRequest::None);
     }
+
+    /// Yields to `tid` directly, ahead of any other runnable thread.
+    ///
+    /// Behaves like `relinquish` if `tid` is not runnable on our core.
+    pub fn yield_to(&self, tid: ThreadId) {
+        self.suspend(YieldRequest::YieldTo(tid));
+    }
 }
 
 /// This is global scheduler-state. Every thread (and also non-threaded upcall handlers)
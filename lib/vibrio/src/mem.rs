@@ -63,9 +63,18 @@ impl Pager {
         Some(vaddr.as_mut_ptr())
     }
 
-    /// Allocates a given `page_size`.
-    fn dealloc_page(&mut self, ptr: *mut u8, page_size: usize) {
-        warn!("NYI dealloc page {:p} {:#x}", ptr, page_size);
+    /// Releases a page of `page_size` that was handed out by `alloc_page` or
+    /// `allocate`.
+    ///
+    /// The backing memory is unmapped, but the virtual range isn't reused
+    /// since `sbrk` only ever grows.
+    pub(crate) fn dealloc_page(&mut self, ptr: *mut u8, page_size: usize) {
+        let base = ptr as u64;
+        debug_assert_eq!(base % page_size as u64, 0, "Page not aligned to its size");
+
+        if let Err(e) = unsafe { crate::syscalls::VSpace::unmap(base, page_size as u64) } {
+            error!("Unable to unmap page {:p} {:#x}: {:?}", ptr, page_size, e);
+        }
     }
 
     pub(crate) fn allocate(&mut self, layout: Layout) -> Result<(VAddr, PAddr), SystemCallError> {
@@ -7,10 +7,11 @@ use core::alloc::Layout;
 use core::{fmt, ptr};
 
 use hashbrown::HashMap;
+use kpi::SystemCallError;
 use lineup::tls2::Environment;
 use log::{error, info, trace, warn};
 use spin::Mutex;
-use x86::current::paging::{PAddr, VAddr};
+use x86::current::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::io;
 
 static PCI_CONF_ADDR: u16 = 0xcf8;
@@ -282,6 +283,110 @@ pub unsafe extern "C" fn rumpcomp_pci_dmafree(addr: c_ulong, size: usize) {
     error!("rumpcomp_pci_dmafree {:#x} {:#x}", addr, size);
 }
 
+/// A physically contiguous memory region that is used as a ring of
+/// equally sized DMA descriptors (e.g., for NIC TX/RX queues).
+///
+/// The ring is allocated once and freed as a whole, rather than allocating
+/// each descriptor through `rumpcomp_pci_dmalloc`.
+pub struct DmaRing {
+    vaddr: VAddr,
+    paddr: PAddr,
+    size: usize,
+    entries: usize,
+    descriptor_size: usize,
+}
+
+impl DmaRing {
+    /// Allocate a ring of `entries` descriptors of `descriptor_size` bytes each.
+    ///
+    /// The region is aligned to its size (4 KiB or 2 MiB), like in
+    /// `rumpcomp_pci_dmalloc` we can't have it bigger than a large-page
+    /// since it has to be physically consecutive.
+    pub fn new(entries: usize, descriptor_size: usize) -> Result<DmaRing, SystemCallError> {
+        assert!(entries > 0);
+        assert!(descriptor_size.is_power_of_two());
+        let ring_size = entries
+            .checked_mul(descriptor_size)
+            .ok_or(SystemCallError::OutOfMemory)?;
+        if ring_size > LARGE_PAGE_SIZE {
+            return Err(SystemCallError::NotSupported);
+        }
+        let size = if ring_size > BASE_PAGE_SIZE {
+            LARGE_PAGE_SIZE
+        } else {
+            BASE_PAGE_SIZE
+        };
+
+        let layout = Layout::from_size_align(size, size).unwrap();
+        let (vaddr, paddr) = {
+            let mut p = crate::mem::PAGER[Environment::core_id()].lock();
+            (*p).allocate(layout)?
+        };
+        trace!(
+            "DmaRing::new {} x {} at va:{:#x} pa:{:#x}",
+            entries,
+            descriptor_size,
+            vaddr.as_usize(),
+            paddr.as_usize()
+        );
+
+        Ok(DmaRing::from_region(
+            vaddr,
+            paddr,
+            size,
+            entries,
+            descriptor_size,
+        ))
+    }
+
+    /// Use an already allocated, physically contiguous region as a ring.
+    fn from_region(
+        vaddr: VAddr,
+        paddr: PAddr,
+        size: usize,
+        entries: usize,
+        descriptor_size: usize,
+    ) -> DmaRing {
+        assert!(entries * descriptor_size <= size);
+        DmaRing {
+            vaddr,
+            paddr,
+            size,
+            entries,
+            descriptor_size,
+        }
+    }
+
+    /// Physical address of the ring (to program the device).
+    pub fn phys_addr(&self) -> PAddr {
+        self.paddr
+    }
+
+    /// Virtual address of the ring.
+    pub fn virt_addr(&self) -> VAddr {
+        self.vaddr
+    }
+
+    /// Number of descriptors in the ring.
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Virtual and physical address of the descriptor at `idx`.
+    ///
+    /// `idx` wraps around at the end of the ring.
+    pub fn slot(&self, idx: usize) -> (VAddr, PAddr) {
+        let offset = (idx % self.entries) * self.descriptor_size;
+        (self.vaddr + offset, self.paddr + offset)
+    }
+
+    /// Give the whole region back to the pager (this unmaps it).
+    pub fn free(self) {
+        let mut p = crate::mem::PAGER[Environment::core_id()].lock();
+        (*p).dealloc_page(self.vaddr.as_mut_ptr(), self.size);
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct rumpcomp_pci_dmaseg {
@@ -322,3 +427,35 @@ pub unsafe extern "C" fn rumpcomp_pci_dmamem_map(
         panic!("nseg > 1")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dma_ring_slots() {
+        let layout = Layout::from_size_align(LARGE_PAGE_SIZE, LARGE_PAGE_SIZE).unwrap();
+        let region = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        assert!(!region.is_null());
+        let vaddr = VAddr::from(region as u64);
+        // Pretend we have an identity mapping
+        let paddr = PAddr::from(region as u64);
+
+        let ring = DmaRing::from_region(vaddr, paddr, LARGE_PAGE_SIZE, 256, 16);
+        assert_eq!(ring.entries(), 256);
+        assert_eq!(ring.virt_addr(), vaddr);
+        assert_eq!(ring.phys_addr(), paddr);
+
+        for idx in 0..ring.entries() {
+            let (va, pa) = ring.slot(idx);
+            assert_eq!(va, vaddr + idx * 16);
+            assert_eq!(pa, paddr + idx * 16);
+            assert_eq!(pa % 16, 0);
+        }
+        // Slots wrap around
+        assert_eq!(ring.slot(256), ring.slot(0));
+        assert_eq!(ring.slot(257), ring.slot(1));
+
+        unsafe { alloc::alloc::dealloc(region, layout) };
+    }
+}
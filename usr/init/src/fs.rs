@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 //! Test file-system syscall implementation using unit-tests.
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -18,8 +19,9 @@ use vibrio::io::*;
 use vibrio::SystemCallError;
 use x86::bits64::paging::{PAddr, VAddr};
 
-use log::trace;
+use log::{info, trace};
 use proptest::prelude::*;
+use proptest::test_runner::{RngAlgorithm, TestRng, TestRunner};
 
 pub type Mnode = u64;
 
@@ -123,10 +125,10 @@ impl FileDesc {
                         "deallocate_fd: Found fd at index {:?} but value wasn't actually set.",
                         fd
                     );
-                    Err(SystemCallError::InternalError)
+                    Err(SystemCallError::BadFileDescriptor)
                 }
             },
-            None => Err(SystemCallError::InternalError),
+            None => Err(SystemCallError::BadFileDescriptor),
         }
     }
 
@@ -135,7 +137,7 @@ impl FileDesc {
             Ok(fd)
         } else {
             trace!("get_fd: Failed to find fd at index {:?}", index);
-            Err(SystemCallError::InternalError)
+            Err(SystemCallError::BadFileDescriptor)
         }
     }
 
@@ -338,7 +340,7 @@ impl ModelFIO {
                 } else {
                     trace!("open() - no write permissions, so cannot truncate");
                     self.fds.deallocate_fd(fid)?;
-                    return Err(SystemCallError::InternalError);
+                    return Err(SystemCallError::PermissionError);
                 }
             }
 
@@ -348,7 +350,7 @@ impl ModelFIO {
         } else {
             if !flags.is_create() {
                 trace!("open() - called on non-existing file without create flag");
-                return Err(SystemCallError::InternalError);
+                return Err(SystemCallError::PermissionError);
             }
 
             *self.mnode_counter.borrow_mut() += 1;
@@ -389,7 +391,7 @@ impl ModelFIO {
         // check for write permissions
         if !flags.is_write() {
             trace!("write_at() - File {:?} lacks write flag permissions", fid);
-            return Err(SystemCallError::InternalError);
+            return Err(SystemCallError::PermissionError);
         }
 
         let mnode = fd.get_mnode();
@@ -413,7 +415,7 @@ impl ModelFIO {
                                 fid,
                                 mode
                             );
-                            return Err(SystemCallError::InternalError);
+                            return Err(SystemCallError::PermissionError);
                         }
                     }
                     _ => { /* The operation is not relevant */ }
@@ -436,7 +438,7 @@ impl ModelFIO {
             Ok(len)
         } else {
             trace!("write_at() - Failed to find mnode for fid {:?}", fid);
-            Err(SystemCallError::InternalError)
+            Err(SystemCallError::InvalidFile)
         }
     }
 
@@ -471,7 +473,7 @@ impl ModelFIO {
         // check for read permissions
         if !flags.is_read() {
             trace!("read_at() - File {:?} lacks read flag permissions", fid);
-            return Err(SystemCallError::InternalError);
+            return Err(SystemCallError::PermissionError);
         }
 
         let mnode = fd.get_mnode();
@@ -485,7 +487,7 @@ impl ModelFIO {
                                 fid,
                                 mode
                             );
-                            return Err(SystemCallError::InternalError);
+                            return Err(SystemCallError::PermissionError);
                         }
                     }
                     _ => {}
@@ -579,7 +581,7 @@ impl ModelFIO {
             Ok(expected_bytes as u64)
         } else {
             trace!("read_at() - Failed to find mnode for fid {:?}", fid);
-            Err(SystemCallError::InternalError)
+            Err(SystemCallError::InvalidFile)
         }
     }
 
@@ -598,7 +600,7 @@ impl ModelFIO {
             Ok(true)
         } else {
             trace!("delete() - Failed to find mnode for path {:?}", path);
-            Err(SystemCallError::InternalError)
+            Err(SystemCallError::InvalidFile)
         }
     }
 
@@ -898,13 +900,74 @@ fn model_equivalence(ops: Vec<TestAction>) {
     }
 }
 
-pub fn run_fio_syscall_proptests() {
-    //model_read();
-    //model_overlapping_writes();
+/// Every proptest case is written to this file before it runs, and removed
+/// once it passed. After a failure it holds the failing list of actions.
+const PROPTEST_CASE_FILE: &str = "fio-proptest-case.txt\0";
+
+/// A proptest runner that derives all its random choices from `seed`.
+fn seeded_runner(seed: u64) -> TestRunner {
+    let mut seed_bytes = [0u8; 32];
+    seed_bytes[..8].copy_from_slice(&seed.to_le_bytes());
     // Reduce the number of tests so we don't use up all the cache
-    proptest!(ProptestConfig::with_cases(100), |(ops in actions())| {
+    let config = ProptestConfig {
+        failure_persistence: None,
+        ..ProptestConfig::with_cases(100)
+    };
+    TestRunner::new_with_rng(config, TestRng::from_seed(RngAlgorithm::ChaCha, &seed_bytes))
+}
+
+/// Stores `ops` in `PROPTEST_CASE_FILE` (replacing what was there).
+fn persist_case(ops: &[TestAction]) {
+    let case = format!("{:#?}\n", ops);
+    let fd = vibrio::syscalls::Fs::open(
+        PROPTEST_CASE_FILE.as_ptr() as u64,
+        u64::from(FileFlags::O_WRONLY | FileFlags::O_CREAT | FileFlags::O_TRUNC),
+        FileModes::S_IRWXU.into(),
+    )
+    .expect("Can't open proptest case file");
+    vibrio::syscalls::Fs::write(fd, case.as_ptr() as u64, case.len() as u64)
+        .expect("Can't write proptest case file");
+    vibrio::syscalls::Fs::close(fd).expect("Can't close proptest case file");
+}
+
+/// Runs the model equivalence proptest with the actions generated from
+/// `seed`, the same seed always runs the same cases.
+pub fn run_fio_syscall_proptests_seeded(seed: u64) {
+    info!(
+        "run_fio_syscall_proptests: seed {} (replay with initargs=proptest-seed={})",
+        seed, seed
+    );
+    let result = seeded_runner(seed).run(&actions(), |ops| {
+        persist_case(&ops);
         model_equivalence(ops);
+        let _r = vibrio::syscalls::Fs::delete(PROPTEST_CASE_FILE.as_ptr() as u64);
+        Ok(())
     });
+    if let Err(e) = result {
+        panic!("run_fio_syscall_proptests failed with seed {}: {}", seed, e);
+    }
+}
+
+/// Runs the model equivalence proptest with a new seed.
+pub fn run_fio_syscall_proptests() {
+    //model_read();
+    //model_overlapping_writes();
+    let seed = TestRunner::new(ProptestConfig::default()).rng().next_u64();
+    run_fio_syscall_proptests_seeded(seed);
+}
+
+/// The same seed always generates the same list of actions.
+fn test_proptest_seed_is_deterministic() {
+    use proptest::strategy::ValueTree;
+
+    let generate = |seed| {
+        actions()
+            .new_tree(&mut seeded_runner(seed))
+            .expect("Can't generate actions")
+            .current()
+    };
+    assert_eq!(generate(42), generate(42));
+    assert_eq!(generate(0xdead_beef), generate(0xdead_beef));
 }
 
 /// Create a file with non-read permission and try to read it.
@@ -918,7 +981,7 @@ fn test_file_read_permission_error() {
     let mut rdata = [0u8; 6];
     assert_eq!(
         vibrio::syscalls::Fs::read(fd, rdata.as_mut_ptr() as u64, 6),
-        Err(SystemCallError::InternalError)
+        Err(SystemCallError::PermissionError)
     );
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
@@ -934,7 +997,7 @@ fn test_file_write_permission_error() {
     let mut wdata = [0u8; 6];
     assert_eq!(
         vibrio::syscalls::Fs::write(fd, wdata.as_mut_ptr() as u64, 6),
-        Err(SystemCallError::InternalError)
+        Err(SystemCallError::PermissionError)
     );
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
@@ -1007,12 +1070,12 @@ fn test_file_fake_open() {
         u64::from(FileFlags::O_RDWR),
         FileModes::S_IRWXU.into(),
     );
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::PermissionError));
 }
 
 fn test_file_fake_close() {
     let ret = vibrio::syscalls::Fs::close(10536);
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::BadFileDescriptor));
 }
 
 fn test_file_duplicate_close() {
@@ -1025,7 +1088,7 @@ fn test_file_duplicate_close() {
     assert_eq!(vibrio::syscalls::Fs::close(fd), Ok(0));
     assert_eq!(
         vibrio::syscalls::Fs::close(fd),
-        Err(SystemCallError::InternalError)
+        Err(SystemCallError::BadFileDescriptor)
     );
 }
 
@@ -1102,7 +1165,7 @@ fn test_file_delete() {
         u64::from(FileFlags::O_RDWR),
         FileModes::S_IRWXU.into(),
     );
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::PermissionError));
 }
 
 fn test_file_delete_open() {
@@ -1116,7 +1179,7 @@ fn test_file_delete_open() {
 
     // Delete file
     let ret = vibrio::syscalls::Fs::delete("test_file_info.txt\0".as_ptr() as u64);
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::PermissionError));
 
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
@@ -1144,7 +1207,7 @@ fn test_file_rename() {
         u64::from(FileFlags::O_RDWR),
         FileModes::S_IRWXU.into(),
     );
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::PermissionError));
 
     // Attempt to open new
     let ret = vibrio::syscalls::Fs::open(
@@ -1252,7 +1315,7 @@ fn test_file_rename_nonexistent_file() {
         "test_file_rename_nonexistent_file_old.txt\0".as_ptr() as u64,
         "test_file_rename_nonexistent_file_new.txt\0".as_ptr() as u64,
     );
-    assert_eq!(ret, Err(SystemCallError::InternalError));
+    assert_eq!(ret, Err(SystemCallError::InvalidFile));
 }
 
 fn test_file_rename_to_existent_file() {
@@ -1342,6 +1405,39 @@ fn test_file_position() {
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
 
+/// Tests writev and readv
+fn test_file_vectored_io() {
+    let fd = vibrio::syscalls::Fs::open(
+        "test_file_vectored_io.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+
+    let (wdata1, wdata2, wdata3) = ([1u8; 3], [2u8; 5], [3u8; 2]);
+    assert_eq!(
+        vibrio::syscalls::Fs::writev(fd, &[&wdata1, &wdata2, &wdata3], -1),
+        Ok(10)
+    );
+
+    let mut rdata = [0u8; 10];
+    assert_eq!(
+        vibrio::syscalls::Fs::read_at(fd, rdata.as_mut_ptr() as u64, 10, 0),
+        Ok(10)
+    );
+    assert_eq!(rdata, [1, 1, 1, 2, 2, 2, 2, 2, 3, 3]);
+
+    let (mut rdata1, mut rdata2) = ([0u8; 4], [0u8; 8]);
+    assert_eq!(
+        vibrio::syscalls::Fs::readv(fd, &mut [&mut rdata1, &mut rdata2], 1),
+        Ok(9)
+    );
+    assert_eq!(rdata1, [1, 1, 2, 2]);
+    assert_eq!(rdata2, [2, 2, 2, 3, 3, 0, 0, 0]);
+
+    vibrio::syscalls::Fs::close(fd).unwrap();
+}
+
 pub fn run_fio_syscall_tests() {
     test_file_read_permission_error();
     test_file_write_permission_error();
@@ -1362,4 +1458,6 @@ pub fn run_fio_syscall_tests() {
     test_file_rename_nonexistent_file();
     test_file_rename_to_existent_file();
     test_file_position();
+    test_file_vectored_io();
+    test_proptest_seed_is_deterministic();
 }
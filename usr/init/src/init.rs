@@ -45,7 +45,9 @@ mod fs;
 mod fxmark;
 mod histogram;
 
-use crate::fs::{run_fio_syscall_proptests, run_fio_syscall_tests};
+use crate::fs::{
+    run_fio_syscall_proptests, run_fio_syscall_proptests_seeded, run_fio_syscall_tests,
+};
 
 #[thread_local]
 pub static mut TLS_TEST: [&str; 2] = ["abcd", "efgh"];
@@ -566,8 +568,66 @@ fn fs_test() {
     info!("fs_test OK");
 }
 
+/// Page-aligned read-only data, nothing touches it before it's handed to a
+/// system call.
+#[repr(C, align(4096))]
+struct LazyPages([u8; 3 * 4096]);
+
+/// The path starts at the end of the first page and ends on the second page,
+/// the third page is written to the file.
+static LAZY_PAGES: LazyPages = LazyPages(lazy_pages());
+const LAZY_PATH: &[u8] = b"lazy.txt\0";
+const LAZY_PATH_OFFSET: usize = 4096 - 4;
+
+const fn lazy_pages() -> [u8; 3 * 4096] {
+    let mut pages = [0xc; 3 * 4096];
+    let mut i = 0;
+    while i < LAZY_PATH.len() {
+        pages[LAZY_PATH_OFFSET + i] = LAZY_PATH[i];
+        i += 1;
+    }
+    pages
+}
+
+/// Passes pages of a lazily loaded ELF segment that were never touched to
+/// system calls (needs the `elfload=lazy` kernel argument).
+fn lazy_elf_test() {
+    use vibrio::io::*;
+
+    let path = LAZY_PAGES.0[LAZY_PATH_OFFSET..].as_ptr() as u64;
+    let data = LAZY_PAGES.0[2 * 4096..].as_ptr() as u64;
+
+    let fd = vibrio::syscalls::Fs::open(
+        path,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    let ret = vibrio::syscalls::Fs::write_at(fd, data, 4096, 0).expect("FileWrite syscall failed");
+    assert_eq!(ret, 4096);
+
+    let mut rbuffer = alloc::vec![0u8; 4096];
+    let ret = vibrio::syscalls::Fs::read_at(fd, rbuffer.as_mut_ptr() as u64, 4096, 0)
+        .expect("FileRead syscall failed");
+    assert_eq!(ret, 4096);
+    assert!(rbuffer.iter().all(|b| *b == 0xc));
+
+    vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
+    vibrio::syscalls::Fs::delete(path).expect("FileDelete syscall failed");
+    info!("lazy_elf_test OK");
+}
+
 fn fs_prop_test() {
-    run_fio_syscall_proptests();
+    // Replay a failed run with `initargs=proptest-seed=<seed>`
+    let pinfo = vibrio::syscalls::Process::process_info().expect("Can't read process info");
+    match pinfo
+        .cmdline
+        .strip_prefix("proptest-seed=")
+        .and_then(|seed| seed.parse().ok())
+    {
+        Some(seed) => run_fio_syscall_proptests_seeded(seed),
+        None => run_fio_syscall_proptests(),
+    }
     info!("fs_prop_test OK");
 }
 
@@ -680,6 +740,9 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "test-fs")]
     fs_test();
 
+    #[cfg(feature = "test-lazy-elf")]
+    lazy_elf_test();
+
     #[cfg(feature = "fs-write")]
     fs_write_test();
 